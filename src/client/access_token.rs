@@ -2,11 +2,15 @@ use anyhow::{anyhow, Result};
 use chrono::Utc;
 use indexmap::IndexMap;
 use lazy_static::lazy_static;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
+use std::sync::Arc;
+use tokio::sync::Mutex as AsyncMutex;
 
 lazy_static! {
     static ref ACCESS_TOKENS: RwLock<IndexMap<String, (String, i64)>> =
         RwLock::new(IndexMap::new());
+    static ref REFRESH_LOCKS: Mutex<IndexMap<String, Arc<AsyncMutex<()>>>> =
+        Mutex::new(IndexMap::new());
 }
 
 pub fn get_access_token(client_name: &str) -> Result<String> {
@@ -32,3 +36,12 @@ pub fn set_access_token(client_name: &str, token: String, expires_at: i64) {
     entry.0 = token;
     entry.1 = expires_at;
 }
+
+/// Held while refreshing a client's token, so concurrent requests wait for one refresh.
+pub fn refresh_lock(client_name: &str) -> Arc<AsyncMutex<()>> {
+    REFRESH_LOCKS
+        .lock()
+        .entry(client_name.to_string())
+        .or_default()
+        .clone()
+}
@@ -1,15 +1,37 @@
+use super::access_token::*;
 use super::openai::*;
 use super::*;
 
-use anyhow::Result;
+use crate::utils::{base64_url_encode, pem_decode, random_hex, sign_jwt};
+
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use chrono::Utc;
 use reqwest::{Client as ReqwestClient, RequestBuilder};
+use ring::digest::{digest, SHA1_FOR_LEGACY_USE_ONLY};
 use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{env, time::Duration};
+
+const DEFAULT_API_VERSION: &str = "2024-02-01";
+const AUTHORITY_HOST: &str = "https://login.microsoftonline.com";
+const IMDS_HOST: &str = "http://169.254.169.254";
+const COGNITIVE_SERVICES_RESOURCE: &str = "https://cognitiveservices.azure.com";
+const TOKEN_REFRESH_MARGIN: i64 = 300;
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct AzureOpenAIConfig {
     pub name: Option<String>,
     pub api_base: Option<String>,
     pub api_key: Option<String>,
+    pub api_version: Option<String>,
+    pub tenant_id: Option<String>,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    pub client_certificate: Option<String>,
+    #[serde(default)]
+    pub managed_identity: bool,
+    pub bearer_token_env: Option<String>,
     #[serde(default)]
     pub models: Vec<ModelData>,
     pub patches: Option<ModelPatches>,
@@ -19,10 +41,15 @@ pub struct AzureOpenAIConfig {
 impl AzureOpenAIClient {
     config_get_fn!(api_base, get_api_base);
     config_get_fn!(api_key, get_api_key);
+    config_get_fn!(api_version, get_api_version);
+    config_get_fn!(tenant_id, get_tenant_id);
+    config_get_fn!(client_id, get_client_id);
+    config_get_fn!(client_secret, get_client_secret);
+    config_get_fn!(client_certificate, get_client_certificate);
 
     pub const PROMPTS: [PromptAction<'static>; 4] = [
         ("api_base", "API Base:", true, PromptKind::String),
-        ("api_key", "API Key:", true, PromptKind::String),
+        ("api_key", "API Key:", false, PromptKind::String),
         ("models[].name", "Model Name:", true, PromptKind::String),
         (
             "models[].max_input_tokens",
@@ -37,21 +64,14 @@ impl AzureOpenAIClient {
         client: &ReqwestClient,
         data: ChatCompletionsData,
     ) -> Result<RequestBuilder> {
-        let api_base = self.get_api_base()?;
-        let api_key = self.get_api_key()?;
-
         let mut body = openai_build_chat_completions_body(data, &self.model);
         self.patch_chat_completions_body(&mut body);
 
-        let url = format!(
-            "{}/openai/deployments/{}/chat/completions?api-version=2024-02-01",
-            &api_base,
-            self.model.name()
-        );
+        let url = self.deployment_url("chat/completions")?;
 
         debug!("AzureOpenAI Request: {url} {body}");
 
-        let builder = client.post(url).header("api-key", api_key).json(&body);
+        let builder = self.authorize(client.post(url))?.json(&body);
 
         Ok(builder)
     }
@@ -61,22 +81,240 @@ impl AzureOpenAIClient {
         client: &ReqwestClient,
         data: EmbeddingsData,
     ) -> Result<RequestBuilder> {
-        let api_base = self.get_api_base()?;
-        let api_key = self.get_api_key()?;
-
         let body = openai_build_embeddings_body(data, &self.model);
 
-        let url = format!("{api_base}/embeddings");
+        let url = self.deployment_url("embeddings")?;
 
-        let builder = client.post(url).bearer_auth(api_key).json(&body);
+        debug!("AzureOpenAI Embeddings Request: {url} {body}");
+
+        let builder = self.authorize(client.post(url))?.json(&body);
 
         Ok(builder)
     }
+
+    fn deployment_url(&self, path: &str) -> Result<String> {
+        let api_base = self.get_api_base()?;
+        let api_version = self
+            .get_api_version()
+            .unwrap_or_else(|_| DEFAULT_API_VERSION.into());
+        Ok(format!(
+            "{}/openai/deployments/{}/{path}?api-version={api_version}",
+            api_base.trim_end_matches('/'),
+            self.model.name()
+        ))
+    }
+
+    /// Entra ID is used instead of `api_key` once a tenant or managed identity is configured.
+    fn uses_entra_id(&self) -> bool {
+        self.config.managed_identity || self.get_tenant_id().is_ok()
+    }
+
+    fn authorize(&self, builder: RequestBuilder) -> Result<RequestBuilder> {
+        if let Some(env_name) = &self.config.bearer_token_env {
+            let token = env::var(env_name)
+                .map_err(|_| anyhow!("Miss the bearer token in env '{env_name}'"))?;
+            Ok(builder.bearer_auth(token))
+        } else if self.uses_entra_id() {
+            Ok(builder.bearer_auth(get_access_token(self.name())?))
+        } else {
+            Ok(builder.header("api-key", self.get_api_key()?))
+        }
+    }
+
+    async fn prepare_access_token(&self, client: &ReqwestClient) -> Result<()> {
+        if self.config.bearer_token_env.is_some()
+            || !self.uses_entra_id()
+            || is_valid_access_token(self.name())
+        {
+            return Ok(());
+        }
+        let lock = refresh_lock(self.name());
+        let _guard = lock.lock().await;
+        // Refreshed by another request while waiting.
+        if is_valid_access_token(self.name()) {
+            return Ok(());
+        }
+        let result = if self.config.managed_identity {
+            fetch_managed_identity_token(client, self.get_client_id().ok()).await
+        } else {
+            self.fetch_client_credentials_token(client).await
+        };
+        let (token, expires_at) =
+            result.map_err(|err| anyhow!("Failed to fetch Entra ID access token, {err:#}"))?;
+        set_access_token(self.name(), token, expires_at - TOKEN_REFRESH_MARGIN);
+        Ok(())
+    }
+
+    async fn fetch_client_credentials_token(
+        &self,
+        client: &ReqwestClient,
+    ) -> Result<(String, i64)> {
+        let tenant_id = self.get_tenant_id()?;
+        let client_id = self.get_client_id()?;
+        let authority_host =
+            env::var("AZURE_AUTHORITY_HOST").unwrap_or_else(|_| AUTHORITY_HOST.into());
+        let token_url = format!(
+            "{}/{tenant_id}/oauth2/v2.0/token",
+            authority_host.trim_end_matches('/')
+        );
+        let mut form = vec![
+            ("grant_type", "client_credentials".to_string()),
+            ("client_id", client_id.clone()),
+            ("scope", format!("{COGNITIVE_SERVICES_RESOURCE}/.default")),
+        ];
+        if let Ok(client_secret) = self.get_client_secret() {
+            form.push(("client_secret", client_secret));
+        } else if let Ok(path) = self.get_client_certificate() {
+            let pem = tokio::fs::read_to_string(&path)
+                .await
+                .with_context(|| format!("Failed to read client_certificate '{path}'"))?;
+            let assertion = client_assertion(&pem, &client_id, &token_url)?;
+            form.push((
+                "client_assertion_type",
+                "urn:ietf:params:oauth:client-assertion-type:jwt-bearer".to_string(),
+            ));
+            form.push(("client_assertion", assertion));
+        } else {
+            bail!("Miss 'client_secret' or 'client_certificate' in client configuration")
+        }
+        let value: Value = client
+            .post(token_url)
+            .form(&form)
+            .send()
+            .await?
+            .json()
+            .await?;
+        parse_entra_token(&value)
+    }
 }
 
-impl_client_trait!(
-    AzureOpenAIClient,
-    openai_chat_completions,
-    openai_chat_completions_streaming,
-    openai_embeddings
-);
+#[async_trait]
+impl Client for AzureOpenAIClient {
+    client_common_fns!();
+
+    async fn chat_completions_inner(
+        &self,
+        client: &ReqwestClient,
+        data: ChatCompletionsData,
+    ) -> Result<ChatCompletionsOutput> {
+        self.prepare_access_token(client).await?;
+        let builder = self.chat_completions_builder(client, data)?;
+        crate::trace::capture_upstream_request(&builder);
+        openai_chat_completions(builder).await
+    }
+
+    async fn chat_completions_streaming_inner(
+        &self,
+        client: &ReqwestClient,
+        handler: &mut SseHandler,
+        data: ChatCompletionsData,
+    ) -> Result<()> {
+        self.prepare_access_token(client).await?;
+        let builder = self.chat_completions_builder(client, data)?;
+        crate::trace::capture_upstream_request(&builder);
+        openai_chat_completions_streaming(builder, handler).await
+    }
+
+    async fn embeddings_inner(
+        &self,
+        client: &ReqwestClient,
+        data: EmbeddingsData,
+    ) -> Result<EmbeddingsOutput> {
+        self.prepare_access_token(client).await?;
+        let builder = self.embeddings_builder(client, data)?;
+        openai_embeddings(builder).await
+    }
+}
+
+/// Uses the App Service/Functions identity endpoint when present, otherwise IMDS.
+async fn fetch_managed_identity_token(
+    client: &ReqwestClient,
+    client_id: Option<String>,
+) -> Result<(String, i64)> {
+    let builder = match (env::var("IDENTITY_ENDPOINT"), env::var("IDENTITY_HEADER")) {
+        (Ok(endpoint), Ok(header)) => client
+            .get(endpoint)
+            .header("X-IDENTITY-HEADER", header)
+            .query(&[("api-version", "2019-08-01")]),
+        _ => {
+            let host =
+                env::var("AZURE_POD_IDENTITY_AUTHORITY_HOST").unwrap_or_else(|_| IMDS_HOST.into());
+            client
+                .get(format!(
+                    "{}/metadata/identity/oauth2/token",
+                    host.trim_end_matches('/')
+                ))
+                .header("Metadata", "true")
+                .query(&[("api-version", "2018-02-01")])
+                .timeout(Duration::from_secs(3))
+        }
+    };
+    let mut builder = builder.query(&[("resource", COGNITIVE_SERVICES_RESOURCE)]);
+    if let Some(client_id) = client_id {
+        builder = builder.query(&[("client_id", client_id)]);
+    }
+    let value: Value = builder.send().await?.json().await?;
+    parse_entra_token(&value)
+}
+
+/// Builds the signed JWT that stands in for a client secret, from a PEM file holding both the
+/// certificate and its PKCS#8 private key.
+fn client_assertion(pem: &str, client_id: &str, token_url: &str) -> Result<String> {
+    let certificate = pem_decode(pem, "CERTIFICATE")
+        .ok_or_else(|| anyhow!("No certificate in client_certificate"))?;
+    let thumbprint = digest(&SHA1_FOR_LEGACY_USE_ONLY, &certificate);
+    let header = json!({
+        "alg": "RS256",
+        "typ": "JWT",
+        "x5t": base64_url_encode(thumbprint),
+    });
+    let now = Utc::now().timestamp();
+    let claims = json!({
+        "aud": token_url,
+        "iss": client_id,
+        "sub": client_id,
+        "jti": random_hex(16),
+        "nbf": now,
+        "iat": now,
+        "exp": now + 600,
+    });
+    sign_jwt(&header, &claims, pem)
+}
+
+/// Returns the token with its expiry timestamp; managed identity endpoints send numbers as strings.
+fn parse_entra_token(value: &Value) -> Result<(String, i64)> {
+    let number = |v: &Value| v.as_i64().or_else(|| v.as_str()?.parse().ok());
+    let now = Utc::now().timestamp();
+    if let Some(access_token) = value["access_token"].as_str() {
+        let expires_at = number(&value["expires_on"])
+            .or_else(|| number(&value["expires_in"]).map(|v| now + v))
+            .unwrap_or(now + 3600);
+        Ok((access_token.to_string(), expires_at))
+    } else if let Some(err_msg) = value["error_description"].as_str() {
+        bail!("{err_msg}")
+    } else if let Some(err_msg) = value["error"]["message"].as_str() {
+        bail!("{err_msg}")
+    } else {
+        bail!("Invalid response data: {value}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_entra_token() {
+        let (token, expires_at) =
+            parse_entra_token(&json!({ "access_token": "t", "expires_on": "1700000000" })).unwrap();
+        assert_eq!((token.as_str(), expires_at), ("t", 1700000000));
+        let (_, expires_at) =
+            parse_entra_token(&json!({ "access_token": "t", "expires_in": 3599 })).unwrap();
+        assert!(expires_at > Utc::now().timestamp() + 3000);
+        let err = parse_entra_token(
+            &json!({ "error": "invalid_client", "error_description": "AADSTS7000215" }),
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "AADSTS7000215");
+    }
+}
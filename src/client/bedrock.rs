@@ -1,10 +1,9 @@
-use super::claude::*;
-use super::prompt_format::*;
+use super::access_token::*;
 use super::*;
 
-use crate::utils::{base64_decode, encode_uri, hex_encode, hmac_sha256, sha256};
+use crate::utils::{encode_uri, hex_encode, hmac_sha256, sha256};
 
-use anyhow::{anyhow, bail, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use async_trait::async_trait;
 use aws_smithy_eventstream::frame::{DecodedFrame, MessageFrameDecoder};
 use aws_smithy_eventstream::smithy::parse_response_headers;
@@ -16,16 +15,28 @@ use reqwest::{
     header::{HeaderMap, HeaderName, HeaderValue},
     Client as ReqwestClient, Method, RequestBuilder,
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use std::str::FromStr;
+use std::{env, path::PathBuf, str::FromStr, time::Duration};
+
+const ECS_METADATA_HOST: &str = "http://169.254.170.2";
+const IMDS_HOST: &str = "http://169.254.169.254";
+/// Credentials are refreshed this many seconds before they expire.
+const CREDENTIALS_REFRESH_MARGIN: i64 = 300;
+/// How long credentials without an expiration, such as static keys, stay cached.
+const CREDENTIALS_CACHE_TTL: i64 = 3600;
+/// How long `credential_process` may run.
+const CREDENTIAL_PROCESS_TIMEOUT: Duration = Duration::from_secs(60);
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct BedrockConfig {
     pub name: Option<String>,
     pub access_key_id: Option<String>,
     pub secret_access_key: Option<String>,
+    pub session_token: Option<String>,
     pub region: Option<String>,
+    /// Profile in `~/.aws/credentials` and `~/.aws/config`, defaulting to `AWS_PROFILE` or "default".
+    pub profile: Option<String>,
     #[serde(default)]
     pub models: Vec<ModelData>,
     pub patches: Option<ModelPatches>,
@@ -41,9 +52,10 @@ impl Client for BedrockClient {
         client: &ReqwestClient,
         data: ChatCompletionsData,
     ) -> Result<ChatCompletionsOutput> {
-        let model_category = ModelCategory::from_str(self.model.name())?;
-        let builder = self.chat_completions_builder(client, data, &model_category)?;
-        chat_completions(builder, &model_category).await
+        self.prepare_credentials(client).await?;
+        let builder = self.chat_completions_builder(client, data)?;
+        crate::trace::capture_upstream_request(&builder);
+        chat_completions(builder).await
     }
 
     async fn chat_completions_streaming_inner(
@@ -52,9 +64,42 @@ impl Client for BedrockClient {
         handler: &mut SseHandler,
         data: ChatCompletionsData,
     ) -> Result<()> {
-        let model_category = ModelCategory::from_str(self.model.name())?;
-        let builder = self.chat_completions_builder(client, data, &model_category)?;
-        chat_completions_streaming(builder, handler, &model_category).await
+        self.prepare_credentials(client).await?;
+        let builder = self.chat_completions_builder(client, data)?;
+        crate::trace::capture_upstream_request(&builder);
+        chat_completions_streaming(builder, handler).await
+    }
+
+    async fn embeddings_inner(
+        &self,
+        client: &ReqwestClient,
+        data: EmbeddingsData,
+    ) -> Result<Vec<Vec<f32>>> {
+        self.prepare_credentials(client).await?;
+        match EmbeddingsCategory::from_str(self.model.name())? {
+            EmbeddingsCategory::Titan => {
+                let mut output = vec![];
+                for text in data.texts {
+                    let builder = self.invoke_builder(client, json!({ "inputText": text }))?;
+                    let data = invoke(builder).await?;
+                    let embedding = serde_json::from_value(data["embedding"].clone())
+                        .map_err(|_| anyhow!("Invalid response data: {data}"))?;
+                    output.push(embedding);
+                }
+                Ok(output)
+            }
+            EmbeddingsCategory::Cohere => {
+                let input_type = match data.query {
+                    true => "search_query",
+                    false => "search_document",
+                };
+                let body = json!({ "texts": data.texts, "input_type": input_type });
+                let builder = self.invoke_builder(client, body)?;
+                let data = invoke(builder).await?;
+                serde_json::from_value(data["embeddings"].clone())
+                    .map_err(|_| anyhow!("Invalid response data: {data}"))
+            }
+        }
     }
 }
 
@@ -67,13 +112,13 @@ impl BedrockClient {
         (
             "access_key_id",
             "AWS Access Key ID",
-            true,
+            false,
             PromptKind::String,
         ),
         (
             "secret_access_key",
             "AWS Secret Access Key",
-            true,
+            false,
             PromptKind::String,
         ),
         ("region", "AWS Region", true, PromptKind::String),
@@ -83,71 +128,123 @@ impl BedrockClient {
         &self,
         client: &ReqwestClient,
         data: ChatCompletionsData,
-        model_category: &ModelCategory,
     ) -> Result<RequestBuilder> {
-        let access_key_id = self.get_access_key_id()?;
-        let secret_access_key = self.get_secret_access_key()?;
-        let region = self.get_region()?;
-
-        let model_name = &self.model.name();
+        let model_name = self.model.name();
         let uri = if data.stream {
-            format!("/model/{model_name}/invoke-with-response-stream")
+            format!("/model/{model_name}/converse-stream")
         } else {
-            format!("/model/{model_name}/invoke")
+            format!("/model/{model_name}/converse")
         };
-        let host = format!("bedrock-runtime.{region}.amazonaws.com");
+        let mut body = build_chat_completions_body(data, &self.model)?;
+        self.patch_chat_completions_body(&mut body);
+        self.request_builder(client, uri, body)
+    }
 
-        let headers = IndexMap::new();
+    fn invoke_builder(&self, client: &ReqwestClient, body: Value) -> Result<RequestBuilder> {
+        let uri = format!("/model/{}/invoke", self.model.name());
+        self.request_builder(client, uri, body)
+    }
 
-        let mut body = build_chat_completions_body(data, &self.model, model_category)?;
-        self.patch_chat_completions_body(&mut body);
+    fn request_builder(
+        &self,
+        client: &ReqwestClient,
+        uri: String,
+        body: Value,
+    ) -> Result<RequestBuilder> {
+        let credentials: AwsCredentials = serde_json::from_str(&get_access_token(self.name())?)?;
+        let host = format!("bedrock-runtime.{}.amazonaws.com", credentials.region);
 
         let builder = aws_fetch(
             client,
-            &AwsCredentials {
-                access_key_id,
-                secret_access_key,
-                region,
-            },
+            &credentials,
             AwsRequest {
                 method: Method::POST,
                 host,
                 service: "bedrock".into(),
                 uri,
                 querystring: "".into(),
-                headers,
+                headers: IndexMap::new(),
                 body: body.to_string(),
             },
         )?;
 
         Ok(builder)
     }
+
+    /// Resolves credentials through the provider chain, cached until shortly before they expire.
+    async fn prepare_credentials(&self, client: &ReqwestClient) -> Result<()> {
+        if is_valid_access_token(self.name()) {
+            return Ok(());
+        }
+        let lock = refresh_lock(self.name());
+        let _guard = lock.lock().await;
+        // Refreshed by another request while waiting.
+        if is_valid_access_token(self.name()) {
+            return Ok(());
+        }
+        let profile = self
+            .config
+            .profile
+            .clone()
+            .or_else(|| env::var("AWS_PROFILE").ok())
+            .unwrap_or_else(|| "default".into());
+        let profile_data = load_aws_profile(&profile).await;
+        let region = self
+            .get_region()
+            .ok()
+            .or_else(|| env::var("AWS_REGION").ok())
+            .or_else(|| env::var("AWS_DEFAULT_REGION").ok())
+            .or_else(|| profile_data.get("region").cloned())
+            .ok_or_else(|| anyhow!("Miss 'region' in client configuration"))?;
+        let (mut credentials, expiration) =
+            match (self.get_access_key_id(), self.get_secret_access_key()) {
+                (Ok(access_key_id), Ok(secret_access_key)) => {
+                    let credentials = AwsCredentials {
+                        access_key_id,
+                        secret_access_key,
+                        session_token: self.config.session_token.clone(),
+                        region: String::new(),
+                    };
+                    (credentials, None)
+                }
+                _ => fetch_aws_credentials(client, &profile, &profile_data, &region)
+                    .await
+                    .map_err(|err| anyhow!("Failed to load AWS credentials, {err:#}"))?,
+            };
+        credentials.region = region;
+        let now = Utc::now().timestamp();
+        let expires_at = match expiration {
+            Some(v) => v - CREDENTIALS_REFRESH_MARGIN,
+            None => now + CREDENTIALS_CACHE_TTL,
+        };
+        set_access_token(
+            self.name(),
+            serde_json::to_string(&credentials)?,
+            expires_at,
+        );
+        Ok(())
+    }
 }
 
-async fn chat_completions(
-    builder: RequestBuilder,
-    model_category: &ModelCategory,
-) -> Result<ChatCompletionsOutput> {
+async fn invoke(builder: RequestBuilder) -> Result<Value> {
     let res = builder.send().await?;
     let status = res.status();
     let data: Value = res.json().await?;
-
     if !status.is_success() {
         catch_error(&data, status.as_u16())?;
     }
+    Ok(data)
+}
 
+async fn chat_completions(builder: RequestBuilder) -> Result<ChatCompletionsOutput> {
+    let data = invoke(builder).await?;
     debug!("non-stream-data: {data}");
-    match model_category {
-        ModelCategory::Anthropic => claude_extract_chat_completions(&data),
-        ModelCategory::MetaLlama3 => llama_extract_chat_completions(&data),
-        ModelCategory::Mistral => mistral_extract_chat_completions(&data),
-    }
+    extract_chat_completions(&data)
 }
 
 async fn chat_completions_streaming(
     builder: RequestBuilder,
     handler: &mut SseHandler,
-    model_category: &ModelCategory,
 ) -> Result<()> {
     let res = builder.send().await?;
     let status = res.status();
@@ -166,38 +263,41 @@ async fn chat_completions_streaming(
             let response_headers = parse_response_headers(&message)?;
             let message_type = response_headers.message_type.as_str();
             let smithy_type = response_headers.smithy_type.as_str();
-            match (message_type, smithy_type) {
-                ("event", "chunk") => {
-                    let data: Value = decode_chunk(message.payload()).ok_or_else(|| {
+            match message_type {
+                "event" => {
+                    let data: Value = serde_json::from_slice(message.payload()).map_err(|_| {
                         anyhow!("Invalid chunk data: {}", hex_encode(message.payload()))
                     })?;
-                    debug!("stream-data: {data}");
-                    match model_category {
-                        ModelCategory::Anthropic => {
-                            if let Some(typ) = data["type"].as_str() {
-                                if typ == "content_block_delta" {
-                                    if let Some(text) = data["delta"]["text"].as_str() {
-                                        handler.text(text)?;
-                                    }
-                                }
+                    debug!("stream-data: {smithy_type} {data}");
+                    match smithy_type {
+                        "contentBlockStart" => {
+                            let tool_use = &data["start"]["toolUse"];
+                            if let (Some(id), Some(name)) =
+                                (tool_use["toolUseId"].as_str(), tool_use["name"].as_str())
+                            {
+                                let index = data["contentBlockIndex"].as_u64().unwrap_or_default();
+                                handler.tool_call_delta(index, Some(id), Some(name), "")?;
                             }
                         }
-                        ModelCategory::MetaLlama3 => {
-                            if let Some(text) = data["generation"].as_str() {
+                        "contentBlockDelta" => {
+                            if let Some(text) = data["delta"]["text"].as_str() {
                                 handler.text(text)?;
+                            } else if let Some(input) = data["delta"]["toolUse"]["input"].as_str() {
+                                let index = data["contentBlockIndex"].as_u64().unwrap_or_default();
+                                handler.tool_call_delta(index, None, None, input)?;
                             }
                         }
-                        ModelCategory::Mistral => {
-                            if let Some(text) = data["outputs"][0]["text"].as_str() {
-                                handler.text(text)?;
-                            }
+                        "metadata" => {
+                            handler.usage(
+                                data["usage"]["inputTokens"].as_u64(),
+                                data["usage"]["outputTokens"].as_u64(),
+                            );
                         }
+                        _ => {}
                     }
                 }
-                ("exception", _) => {
-                    let payload = base64_decode(message.payload())?;
-                    let data = String::from_utf8_lossy(&payload);
-
+                "exception" => {
+                    let data = String::from_utf8_lossy(message.payload());
                     bail!("Invalid response data: {data} (smithy_type: {smithy_type})")
                 }
                 _ => {
@@ -209,128 +309,223 @@ async fn chat_completions_streaming(
     Ok(())
 }
 
-fn build_chat_completions_body(
-    data: ChatCompletionsData,
-    model: &Model,
-    model_category: &ModelCategory,
-) -> Result<Value> {
-    match model_category {
-        ModelCategory::Anthropic => {
-            let mut body = claude_build_chat_completions_body(data, model)?;
-            if let Some(body_obj) = body.as_object_mut() {
-                body_obj.remove("model");
-                body_obj.remove("stream");
-            }
-            body["anthropic_version"] = "bedrock-2023-05-31".into();
-            Ok(body)
-        }
-        ModelCategory::MetaLlama3 => {
-            meta_llama_build_chat_completions_body(data, model, LLAMA3_PROMPT_FORMAT)
-        }
-        ModelCategory::Mistral => mistral_build_chat_completions_body(data, model),
-    }
-}
-
-fn meta_llama_build_chat_completions_body(
-    data: ChatCompletionsData,
-    model: &Model,
-    pt: PromptFormat,
-) -> Result<Value> {
+fn build_chat_completions_body(data: ChatCompletionsData, model: &Model) -> Result<Value> {
     let ChatCompletionsData {
-        messages,
+        mut messages,
         temperature,
         top_p,
-        functions: _,
+        functions,
         stream: _,
     } = data;
-    let prompt = generate_prompt(&messages, pt)?;
-    let mut body = json!({ "prompt": prompt });
 
-    if let Some(v) = model.max_tokens_param() {
-        body["max_gen_len"] = v.into();
-    }
-    if let Some(v) = temperature {
-        body["temperature"] = v.into();
-    }
-    if let Some(v) = top_p {
-        body["top_p"] = v.into();
+    let mut system = vec![];
+    if let Some(MessageRole::System) = messages.first().map(|v| v.role) {
+        let message = messages.remove(0);
+        let text = message.content.to_text();
+        if supports_system_prompt(model.name()) {
+            system.push(json!({ "text": text }));
+        } else if let Some(Message {
+            role: MessageRole::User,
+            content: MessageContent::Text(user),
+        }) = messages.first_mut()
+        {
+            *user = format!("{text}\n\n{user}");
+        }
     }
 
-    Ok(body)
-}
+    let mut network_image_urls = vec![];
+    let messages: Vec<Value> = messages
+        .into_iter()
+        .flat_map(|message| {
+            let Message { role, content } = message;
+            match content {
+                MessageContent::Text(text) => vec![json!({
+                    "role": role,
+                    "content": [{ "text": text }],
+                })],
+                MessageContent::Array(list) => {
+                    let content: Vec<_> = list
+                        .into_iter()
+                        .map(|item| match item {
+                            MessageContentPart::Text { text } => json!({ "text": text }),
+                            MessageContentPart::ImageUrl {
+                                image_url: ImageUrl { url },
+                            } => {
+                                if let Some((mime_type, data)) = url
+                                    .strip_prefix("data:")
+                                    .and_then(|v| v.split_once(";base64,"))
+                                {
+                                    let format = mime_type.trim_start_matches("image/");
+                                    json!({
+                                        "image": {
+                                            "format": format,
+                                            "source": { "bytes": data },
+                                        }
+                                    })
+                                } else {
+                                    network_image_urls.push(url.clone());
+                                    json!({ "url": url })
+                                }
+                            }
+                        })
+                        .collect();
+                    vec![json!({
+                        "role": role,
+                        "content": content,
+                    })]
+                }
+                MessageContent::ToolResults((tool_call_results, text)) => {
+                    let mut tool_use = vec![];
+                    let mut tool_result = vec![];
+                    if !text.is_empty() {
+                        tool_use.push(json!({ "text": text }))
+                    }
+                    for tool_call_result in tool_call_results {
+                        tool_use.push(json!({
+                            "toolUse": {
+                                "toolUseId": tool_call_result.call.id,
+                                "name": tool_call_result.call.name,
+                                "input": tool_call_result.call.arguments_object(),
+                            }
+                        }));
+                        let content = match &tool_call_result.output {
+                            output @ Value::Object(_) => json!({ "json": output }),
+                            output => json!({ "text": output.to_string() }),
+                        };
+                        tool_result.push(json!({
+                            "toolResult": {
+                                "toolUseId": tool_call_result.call.id,
+                                "content": [content],
+                            }
+                        }));
+                    }
+                    vec![
+                        json!({
+                            "role": "assistant",
+                            "content": tool_use,
+                        }),
+                        json!({
+                            "role": "user",
+                            "content": tool_result,
+                        }),
+                    ]
+                }
+            }
+        })
+        .collect();
+
+    if !network_image_urls.is_empty() {
+        bail!(
+            "The model does not support network images: {:?}",
+            network_image_urls
+        );
+    }
 
-fn mistral_build_chat_completions_body(data: ChatCompletionsData, model: &Model) -> Result<Value> {
-    let ChatCompletionsData {
-        messages,
-        temperature,
-        top_p,
-        functions: _,
-        stream: _,
-    } = data;
-    let prompt = generate_prompt(&messages, MISTRAL_PROMPT_FORMAT)?;
-    let mut body = json!({ "prompt": prompt });
+    let mut body = json!({ "messages": messages });
+    if !system.is_empty() {
+        body["system"] = system.into();
+    }
 
+    let mut inference_config = json!({});
     if let Some(v) = model.max_tokens_param() {
-        body["max_tokens"] = v.into();
+        inference_config["maxTokens"] = v.into();
     }
     if let Some(v) = temperature {
-        body["temperature"] = v.into();
+        inference_config["temperature"] = v.into();
     }
     if let Some(v) = top_p {
-        body["top_p"] = v.into();
+        inference_config["topP"] = v.into();
+    }
+    body["inferenceConfig"] = inference_config;
+
+    if let Some(functions) = functions {
+        let tools: Vec<Value> = functions
+            .iter()
+            .map(|v| {
+                json!({
+                    "toolSpec": {
+                        "name": v.name,
+                        "description": v.description,
+                        "inputSchema": { "json": v.parameters },
+                    }
+                })
+            })
+            .collect();
+        body["toolConfig"] = json!({ "tools": tools });
     }
 
     Ok(body)
 }
 
-fn llama_extract_chat_completions(data: &Value) -> Result<ChatCompletionsOutput> {
-    let text = data["generation"]
-        .as_str()
+fn extract_chat_completions(data: &Value) -> Result<ChatCompletionsOutput> {
+    let content = data["output"]["message"]["content"]
+        .as_array()
         .ok_or_else(|| anyhow!("Invalid response data: {data}"))?;
-    let output = ChatCompletionsOutput {
-        text: text.to_string(),
-        tool_calls: vec![],
+    let mut text = String::new();
+    let mut tool_calls = vec![];
+    for block in content {
+        if let Some(v) = block["text"].as_str() {
+            text.push_str(v);
+        } else if let (Some(name), Some(id)) = (
+            block["toolUse"]["name"].as_str(),
+            block["toolUse"]["toolUseId"].as_str(),
+        ) {
+            tool_calls.push(ToolCall::new(
+                name.to_string(),
+                block["toolUse"]["input"].clone(),
+                Some(id.to_string()),
+            ));
+        }
+    }
+    Ok(ChatCompletionsOutput {
+        text,
+        tool_calls,
         id: None,
-        input_tokens: data["prompt_token_count"].as_u64(),
-        output_tokens: data["generation_token_count"].as_u64(),
-    };
-    Ok(output)
+        input_tokens: data["usage"]["inputTokens"].as_u64(),
+        output_tokens: data["usage"]["outputTokens"].as_u64(),
+    })
 }
 
-fn mistral_extract_chat_completions(data: &Value) -> Result<ChatCompletionsOutput> {
-    let text = data["outputs"][0]["text"]
-        .as_str()
-        .ok_or_else(|| anyhow!("Invalid response data: {data}"))?;
-    Ok(ChatCompletionsOutput::new(text))
+/// Converse rejects `system` for a few older models; their system prompt goes into the first user message.
+fn supports_system_prompt(model_name: &str) -> bool {
+    ![
+        "mistral.mistral-7b-instruct",
+        "mistral.mixtral-8x7b-instruct",
+        "amazon.titan-text",
+        "cohere.command-text",
+        "cohere.command-light-text",
+        "ai21.j2",
+    ]
+    .iter()
+    .any(|v| model_name.starts_with(v))
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum ModelCategory {
-    Anthropic,
-    MetaLlama3,
-    Mistral,
+enum EmbeddingsCategory {
+    Titan,
+    Cohere,
 }
 
-impl FromStr for ModelCategory {
+impl FromStr for EmbeddingsCategory {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
-        if s.starts_with("anthropic.") {
-            Ok(ModelCategory::Anthropic)
-        } else if s.starts_with("meta.llama3") {
-            Ok(ModelCategory::MetaLlama3)
-        } else if s.starts_with("mistral") {
-            Ok(ModelCategory::Mistral)
+        if s.starts_with("amazon.titan-embed") {
+            Ok(EmbeddingsCategory::Titan)
+        } else if s.starts_with("cohere.embed") {
+            Ok(EmbeddingsCategory::Cohere)
         } else {
             unsupported_model!(s)
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 struct AwsCredentials {
     access_key_id: String,
     secret_access_key: String,
+    session_token: Option<String>,
+    #[serde(default)]
     region: String,
 }
 
@@ -368,6 +563,9 @@ fn aws_fetch(
     let date_stamp = amz_date[0..8].to_string();
     headers.insert("host".into(), host.clone());
     headers.insert("x-amz-date".into(), amz_date.clone());
+    if let Some(session_token) = &credentials.session_token {
+        headers.insert("x-amz-security-token".into(), session_token.clone());
+    }
 
     let canonical_headers = headers
         .iter()
@@ -440,9 +638,348 @@ fn gen_signing_key(key: &str, date_stamp: &str, region: &str, service: &str) ->
     hmac_sha256(&k_service, "aws4_request")
 }
 
-fn decode_chunk(data: &[u8]) -> Option<Value> {
-    let data = serde_json::from_slice::<Value>(data).ok()?;
-    let data = data["bytes"].as_str()?;
-    let data = base64_decode(data).ok()?;
-    serde_json::from_slice(&data).ok()
+/// The default AWS chain after static keys: environment, web identity, profile, container and
+/// instance metadata. Returns the credentials and when they expire.
+async fn fetch_aws_credentials(
+    client: &ReqwestClient,
+    profile: &str,
+    profile_data: &IndexMap<String, String>,
+    region: &str,
+) -> Result<(AwsCredentials, Option<i64>)> {
+    if let (Ok(access_key_id), Ok(secret_access_key)) = (
+        env::var("AWS_ACCESS_KEY_ID"),
+        env::var("AWS_SECRET_ACCESS_KEY"),
+    ) {
+        let credentials = AwsCredentials {
+            access_key_id,
+            secret_access_key,
+            session_token: env::var("AWS_SESSION_TOKEN").ok(),
+            region: String::new(),
+        };
+        return Ok((credentials, None));
+    }
+    if let (Ok(token_file), Ok(role_arn)) = (
+        env::var("AWS_WEB_IDENTITY_TOKEN_FILE"),
+        env::var("AWS_ROLE_ARN"),
+    ) {
+        let session_name = env::var("AWS_ROLE_SESSION_NAME").ok();
+        return assume_role_with_web_identity(client, &token_file, &role_arn, session_name, region)
+            .await;
+    }
+    if let (Some(access_key_id), Some(secret_access_key)) = (
+        profile_data.get("aws_access_key_id"),
+        profile_data.get("aws_secret_access_key"),
+    ) {
+        let credentials = AwsCredentials {
+            access_key_id: access_key_id.clone(),
+            secret_access_key: secret_access_key.clone(),
+            session_token: profile_data.get("aws_session_token").cloned(),
+            region: String::new(),
+        };
+        return Ok((credentials, None));
+    }
+    if let Some(command) = profile_data.get("credential_process") {
+        return run_credential_process(command)
+            .await
+            .with_context(|| format!("Failed to run credential_process of profile '{profile}'"));
+    }
+    if let (Some(token_file), Some(role_arn)) = (
+        profile_data.get("web_identity_token_file"),
+        profile_data.get("role_arn"),
+    ) {
+        let session_name = profile_data.get("role_session_name").cloned();
+        return assume_role_with_web_identity(client, token_file, role_arn, session_name, region)
+            .await;
+    }
+    if let Some(url) = ecs_credentials_url() {
+        let mut builder = client.get(url).timeout(Duration::from_secs(5));
+        if let Some(token) = ecs_authorization_token().await? {
+            builder = builder.header("Authorization", token);
+        }
+        let data: Value = builder.send().await?.error_for_status()?.json().await?;
+        return parse_aws_credentials(&data);
+    }
+    if env::var("AWS_EC2_METADATA_DISABLED").as_deref() != Ok("true") {
+        if let Ok(ret) = fetch_imds_credentials(client).await {
+            return Ok(ret);
+        }
+    }
+    bail!("No AWS credentials in the client config, environment, profile '{profile}' or instance metadata")
+}
+
+async fn assume_role_with_web_identity(
+    client: &ReqwestClient,
+    token_file: &str,
+    role_arn: &str,
+    session_name: Option<String>,
+    region: &str,
+) -> Result<(AwsCredentials, Option<i64>)> {
+    let token = tokio::fs::read_to_string(token_file)
+        .await
+        .with_context(|| format!("Failed to read web identity token at '{token_file}'"))?;
+    let session_name =
+        session_name.unwrap_or_else(|| format!("agent-panel-{}", Utc::now().timestamp()));
+    let data: Value = client
+        .post(format!("https://sts.{region}.amazonaws.com/"))
+        .header("Accept", "application/json")
+        .form(&[
+            ("Action", "AssumeRoleWithWebIdentity"),
+            ("Version", "2011-06-15"),
+            ("RoleArn", role_arn),
+            ("RoleSessionName", &session_name),
+            ("WebIdentityToken", token.trim()),
+        ])
+        .send()
+        .await?
+        .json()
+        .await?;
+    let credentials = &data["AssumeRoleWithWebIdentityResponse"]["AssumeRoleWithWebIdentityResult"]
+        ["Credentials"];
+    if credentials.is_object() {
+        parse_aws_credentials(credentials)
+    } else if let Some(message) = data["Error"]["Message"].as_str() {
+        bail!("{message}")
+    } else {
+        bail!("Invalid response data: {data}")
+    }
+}
+
+async fn run_credential_process(command: &str) -> Result<(AwsCredentials, Option<i64>)> {
+    let (shell, arg) = if cfg!(windows) {
+        ("cmd", "/C")
+    } else {
+        ("sh", "-c")
+    };
+    let output = tokio::process::Command::new(shell)
+        .arg(arg)
+        .arg(command)
+        .kill_on_drop(true)
+        .output();
+    let output = tokio::time::timeout(CREDENTIAL_PROCESS_TIMEOUT, output)
+        .await
+        .map_err(|_| anyhow!("Timed out after {}s", CREDENTIAL_PROCESS_TIMEOUT.as_secs()))??;
+    if !output.status.success() {
+        bail!(
+            "{}, {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let data: Value = serde_json::from_slice(&output.stdout)?;
+    parse_aws_credentials(&data)
+}
+
+fn ecs_credentials_url() -> Option<String> {
+    if let Ok(uri) = env::var("AWS_CONTAINER_CREDENTIALS_RELATIVE_URI") {
+        return Some(format!("{ECS_METADATA_HOST}{uri}"));
+    }
+    env::var("AWS_CONTAINER_CREDENTIALS_FULL_URI").ok()
+}
+
+async fn ecs_authorization_token() -> Result<Option<String>> {
+    if let Ok(path) = env::var("AWS_CONTAINER_AUTHORIZATION_TOKEN_FILE") {
+        let token = tokio::fs::read_to_string(path).await?;
+        return Ok(Some(token.trim().to_string()));
+    }
+    Ok(env::var("AWS_CONTAINER_AUTHORIZATION_TOKEN").ok())
+}
+
+/// IMDSv2: a session token first, then the credentials of the instance role.
+async fn fetch_imds_credentials(client: &ReqwestClient) -> Result<(AwsCredentials, Option<i64>)> {
+    let timeout = Duration::from_secs(1);
+    let token = client
+        .put(format!("{IMDS_HOST}/latest/api/token"))
+        .header("X-aws-ec2-metadata-token-ttl-seconds", "21600")
+        .timeout(timeout)
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    let url = format!("{IMDS_HOST}/latest/meta-data/iam/security-credentials/");
+    let role = client
+        .get(&url)
+        .header("X-aws-ec2-metadata-token", &token)
+        .timeout(timeout)
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    let role = role.lines().next().unwrap_or_default().trim();
+    let data: Value = client
+        .get(format!("{url}{role}"))
+        .header("X-aws-ec2-metadata-token", &token)
+        .timeout(timeout)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    parse_aws_credentials(&data)
+}
+
+/// Reads the credentials JSON shared by `credential_process`, STS and the metadata endpoints.
+fn parse_aws_credentials(data: &Value) -> Result<(AwsCredentials, Option<i64>)> {
+    let (Some(access_key_id), Some(secret_access_key)) = (
+        data["AccessKeyId"].as_str(),
+        data["SecretAccessKey"].as_str(),
+    ) else {
+        bail!("Invalid credentials data: {data}")
+    };
+    let session_token = data["SessionToken"]
+        .as_str()
+        .or_else(|| data["Token"].as_str())
+        .map(|v| v.to_string());
+    let expiration = match &data["Expiration"] {
+        Value::Number(v) => v.as_f64().map(|v| v as i64),
+        Value::String(v) => DateTime::parse_from_rfc3339(v).map(|v| v.timestamp()).ok(),
+        _ => None,
+    };
+    let credentials = AwsCredentials {
+        access_key_id: access_key_id.to_string(),
+        secret_access_key: secret_access_key.to_string(),
+        session_token,
+        region: String::new(),
+    };
+    Ok((credentials, expiration))
+}
+
+/// The settings of a profile, with `~/.aws/credentials` taking precedence over `~/.aws/config`.
+async fn load_aws_profile(profile: &str) -> IndexMap<String, String> {
+    let mut data = IndexMap::new();
+    let config_section = match profile {
+        "default" => "default".to_string(),
+        _ => format!("profile {profile}"),
+    };
+    let files = [
+        (aws_file("AWS_CONFIG_FILE", "config"), config_section),
+        (
+            aws_file("AWS_SHARED_CREDENTIALS_FILE", "credentials"),
+            profile.to_string(),
+        ),
+    ];
+    for (path, section) in files {
+        let Some(path) = path else {
+            continue;
+        };
+        if let Ok(text) = tokio::fs::read_to_string(&path).await {
+            if let Some(values) = parse_ini(&text).swap_remove(&section) {
+                data.extend(values);
+            }
+        }
+    }
+    data
+}
+
+fn aws_file(env_name: &str, file_name: &str) -> Option<PathBuf> {
+    match env::var(env_name) {
+        Ok(path) => Some(PathBuf::from(path)),
+        Err(_) => Some(dirs::home_dir()?.join(".aws").join(file_name)),
+    }
+}
+
+fn parse_ini(text: &str) -> IndexMap<String, IndexMap<String, String>> {
+    let mut sections: IndexMap<String, IndexMap<String, String>> = IndexMap::new();
+    let mut current = None;
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|v| v.strip_suffix(']')) {
+            let name = name.trim().to_string();
+            sections.entry(name.clone()).or_default();
+            current = Some(name);
+        } else if let (Some(section), Some((key, value))) = (&current, line.split_once('=')) {
+            if let Some(values) = sections.get_mut(section) {
+                values.insert(key.trim().to_lowercase(), value.trim().to_string());
+            }
+        }
+    }
+    sections
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::function::ToolCallResult;
+
+    #[test]
+    fn test_converse_body() {
+        let call = ToolCall::new("get_time".into(), json!({ "tz": "UTC" }), Some("t1".into()));
+        let data = ChatCompletionsData {
+            messages: vec![
+                Message::new(
+                    MessageRole::System,
+                    MessageContent::Text("Be brief.".into()),
+                ),
+                Message::new(MessageRole::User, MessageContent::Text("Time?".into())),
+                Message::new(
+                    MessageRole::Assistant,
+                    MessageContent::ToolResults((
+                        vec![ToolCallResult::new(call, json!("12:00"))],
+                        String::new(),
+                    )),
+                ),
+            ],
+            temperature: None,
+            top_p: None,
+            functions: None,
+            stream: false,
+        };
+        let mut model = Model::new("bedrock", "mistral.mixtral-8x7b-instruct-v0:1");
+        let body = build_chat_completions_body(data.clone(), &model).unwrap();
+        assert!(body["system"].is_null());
+        assert_eq!(
+            body["messages"][0]["content"][0]["text"],
+            "Be brief.\n\nTime?"
+        );
+        assert_eq!(
+            body["messages"][1]["content"][0]["toolUse"]["input"]["tz"],
+            "UTC"
+        );
+        assert_eq!(
+            body["messages"][2]["content"][0]["toolResult"],
+            json!({ "toolUseId": "t1", "content": [{ "text": "\"12:00\"" }] })
+        );
+        model = Model::new("bedrock", "anthropic.claude-3-haiku-20240307-v1:0");
+        let body = build_chat_completions_body(data, &model).unwrap();
+        assert_eq!(body["system"], json!([{ "text": "Be brief." }]));
+
+        let output = extract_chat_completions(&json!({
+            "output": { "message": { "content": [
+                { "text": "Checking." },
+                { "toolUse": { "toolUseId": "t2", "name": "get_time", "input": {} } },
+            ] } },
+            "usage": { "inputTokens": 10, "outputTokens": 4 },
+        }))
+        .unwrap();
+        assert_eq!(output.text, "Checking.");
+        assert_eq!(output.tool_calls[0].id.as_deref(), Some("t2"));
+        assert_eq!(output.output_tokens, Some(4));
+    }
+
+    #[test]
+    fn test_aws_profile_credentials() {
+        let sections = parse_ini(
+            "[default]\nregion = us-east-1\n\n# comment\n[profile ci]\nAWS_Access_Key_Id = AKID\ncredential_process = aws-vault export ci\n",
+        );
+        assert_eq!(sections["default"]["region"], "us-east-1");
+        assert_eq!(sections["profile ci"]["aws_access_key_id"], "AKID");
+        assert_eq!(
+            sections["profile ci"]["credential_process"],
+            "aws-vault export ci"
+        );
+
+        let (credentials, expiration) = parse_aws_credentials(&json!({
+            "AccessKeyId": "AKID",
+            "SecretAccessKey": "secret",
+            "Token": "token",
+            "Expiration": "2024-06-01T12:00:00Z",
+        }))
+        .unwrap();
+        assert_eq!(credentials.session_token.as_deref(), Some("token"));
+        assert_eq!(expiration, Some(1717243200));
+    }
 }
@@ -1,6 +1,6 @@
 use super::*;
 
-use anyhow::{bail, Context, Result};
+use anyhow::{bail, Result};
 use reqwest::{Client as ReqwestClient, RequestBuilder};
 use serde::Deserialize;
 use serde_json::{json, Value};
@@ -70,56 +70,36 @@ pub async fn claude_chat_completions_streaming(
     builder: RequestBuilder,
     handler: &mut SseHandler,
 ) -> Result<()> {
-    let mut function_name = String::new();
-    let mut function_arguments = String::new();
-    let mut function_id = String::new();
     let handle = |message: SseMmessage| -> Result<bool> {
         let data: Value = serde_json::from_str(&message.data)?;
         debug!("stream-data: {data}");
         if let Some(typ) = data["type"].as_str() {
             match typ {
+                "message_start" => {
+                    handler.usage(
+                        data["message"]["usage"]["input_tokens"].as_u64(),
+                        data["message"]["usage"]["output_tokens"].as_u64(),
+                    );
+                }
+                "message_delta" => {
+                    handler.usage(None, data["usage"]["output_tokens"].as_u64());
+                }
                 "content_block_start" => {
                     if let (Some("tool_use"), Some(name), Some(id)) = (
                         data["content_block"]["type"].as_str(),
                         data["content_block"]["name"].as_str(),
                         data["content_block"]["id"].as_str(),
                     ) {
-                        if !function_name.is_empty() {
-                            let arguments: Value =
-                                function_arguments.parse().with_context(|| {
-                                    format!("Tool call '{function_name}' is invalid: arguments must be in valid JSON format")
-                                })?;
-                            handler.tool_call(ToolCall::new(
-                                function_name.clone(),
-                                arguments,
-                                Some(function_id.clone()),
-                            ))?;
-                        }
-                        function_name = name.into();
-                        function_arguments.clear();
-                        function_id = id.into();
+                        let index = data["index"].as_u64().unwrap_or_default();
+                        handler.tool_call_delta(index, Some(id), Some(name), "")?;
                     }
                 }
                 "content_block_delta" => {
                     if let Some(text) = data["delta"]["text"].as_str() {
                         handler.text(text)?;
-                    } else if let (true, Some(partial_json)) = (
-                        !function_name.is_empty(),
-                        data["delta"]["partial_json"].as_str(),
-                    ) {
-                        function_arguments.push_str(partial_json);
-                    }
-                }
-                "content_block_stop" => {
-                    if !function_name.is_empty() {
-                        let arguments: Value = function_arguments.parse().with_context(|| {
-                            format!("Tool call '{function_name}' is invalid: arguments must be in valid JSON format")
-                        })?;
-                        handler.tool_call(ToolCall::new(
-                            function_name.clone(),
-                            arguments,
-                            Some(function_id.clone()),
-                        ))?;
+                    } else if let Some(partial_json) = data["delta"]["partial_json"].as_str() {
+                        let index = data["index"].as_u64().unwrap_or_default();
+                        handler.tool_call_delta(index, None, None, partial_json)?;
                     }
                 }
                 _ => {}
@@ -204,7 +184,7 @@ pub fn claude_build_chat_completions_body(
                             "type": "tool_use",
                             "id": tool_call_result.call.id,
                             "name": tool_call_result.call.name,
-                            "input": tool_call_result.call.arguments,
+                            "input": tool_call_result.call.arguments_object(),
                         }));
                         tool_result.push(json!({
                             "type": "tool_result",
@@ -1,5 +1,7 @@
 use super::*;
 
+use crate::function::{FunctionDeclaration, JsonSchema};
+
 use anyhow::{bail, Context, Result};
 use reqwest::{Client as ReqwestClient, RequestBuilder};
 use serde::Deserialize;
@@ -214,7 +216,7 @@ fn build_chat_completions_body(data: ChatCompletionsData, model: &Model) -> Resu
                 json!({
                     "call": {
                         "name": tool_call_result.call.name,
-                        "parameters": tool_call_result.call.arguments,
+                        "parameters": tool_call_result.call.arguments_object(),
                     },
                     "outputs": [
                         tool_call_result.output,
@@ -248,31 +250,62 @@ fn build_chat_completions_body(data: ChatCompletionsData, model: &Model) -> Resu
     }
 
     if let Some(functions) = functions {
-        body["tools"] = functions
-            .iter()
-            .map(|v| {
-                let required = v.parameters.required.clone().unwrap_or_default();
-                let mut parameter_definitions = json!({});
-                if let Some(properties) = &v.parameters.properties {
-                    for (key, value) in properties {
-                        let mut value: Value = json!(value);
-                        if value.is_object() && required.iter().any(|x| x == key) {
-                            value["required"] = true.into();
-                        }
-                        parameter_definitions[key] = value;
-                    }
-                }
-                json!({
-                    "name": v.name,
-                    "description": v.description,
-                    "parameter_definitions": parameter_definitions,
-                })
-            })
-            .collect();
+        body["tools"] = functions.iter().map(cohere_tool).collect();
     }
     Ok(body)
 }
 
+/// Cohere takes a flat map of top-level parameters, each with a description and a Python-style type.
+fn cohere_tool(function: &FunctionDeclaration) -> Value {
+    let (parameters, mut dropped) = function.parameters.inline_refs();
+    let required = parameters.required.unwrap_or_default();
+    let mut parameter_definitions = json!({});
+    for (key, value) in parameters.properties.unwrap_or_default() {
+        let path = format!("parameters.properties.{key}");
+        let mut definition = json!({ "type": cohere_type(&value) });
+        if let Some(description) = &value.description {
+            definition["description"] = description.as_str().into();
+        }
+        if required.contains(&key) {
+            definition["required"] = true.into();
+        }
+        if let Value::Object(mut rest) = json!(value) {
+            rest.remove("type");
+            rest.remove("description");
+            if value.items.as_ref().is_some_and(|v| v.is_simple()) {
+                rest.remove("items");
+            }
+            dropped.extend(rest.keys().map(|v| format!("{path}.{v}")));
+        }
+        parameter_definitions[key] = definition;
+    }
+    function.warn_lossy("Cohere", &dropped);
+    json!({
+        "name": function.name,
+        "description": function.description,
+        "parameter_definitions": parameter_definitions,
+    })
+}
+
+fn cohere_type(schema: &JsonSchema) -> String {
+    let types: Vec<&str> = schema
+        .types()
+        .into_iter()
+        .filter(|v| *v != "null")
+        .collect();
+    match types.as_slice() {
+        ["integer"] => "int".into(),
+        ["number"] => "float".into(),
+        ["boolean"] => "bool".into(),
+        ["array"] => match &schema.items {
+            Some(items) => format!("List[{}]", cohere_type(items)),
+            None => "List".into(),
+        },
+        ["object"] => "Dict".into(),
+        _ => "str".into(),
+    }
+}
+
 fn extract_chat_completions(data: &Value) -> Result<ChatCompletionsOutput> {
     let text = data["text"].as_str().unwrap_or_default();
 
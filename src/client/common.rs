@@ -2,7 +2,7 @@ use super::*;
 
 use crate::{
     config::{GlobalConfig, Input},
-    function::{ FunctionDeclaration, ToolCall},
+    function::{FunctionDeclaration, ToolCall},
     utils::{
         prompt_input_integer, prompt_input_string, tokenize, watch_abort_signal, AbortSignal,
         PromptKind,
@@ -157,6 +157,10 @@ macro_rules! register_client {
         pub fn list_chat_models(config: &$crate::config::Config) -> Vec<&'static $crate::client::Model> {
             list_models(config).into_iter().filter(|v| v.mode() == "chat").collect()
         }
+
+        pub fn list_embedding_models(config: &$crate::config::Config) -> Vec<&'static $crate::client::Model> {
+            list_models(config).into_iter().filter(|v| v.mode() == "embedding").collect()
+        }
     };
 }
 
@@ -202,6 +206,7 @@ macro_rules! impl_client_trait {
                 data: $crate::client::ChatCompletionsData,
             ) -> anyhow::Result<$crate::client::ChatCompletionsOutput> {
                 let builder = self.chat_completions_builder(client, data)?;
+                $crate::trace::capture_upstream_request(&builder);
                 $chat_completions(builder).await
             }
 
@@ -212,6 +217,7 @@ macro_rules! impl_client_trait {
                 data: $crate::client::ChatCompletionsData,
             ) -> Result<()> {
                 let builder = self.chat_completions_builder(client, data)?;
+                $crate::trace::capture_upstream_request(&builder);
                 $chat_completions_streaming(builder, handler).await
             }
         }
@@ -227,6 +233,7 @@ macro_rules! impl_client_trait {
                 data: $crate::client::ChatCompletionsData,
             ) -> anyhow::Result<$crate::client::ChatCompletionsOutput> {
                 let builder = self.chat_completions_builder(client, data)?;
+                $crate::trace::capture_upstream_request(&builder);
                 $chat_completions(builder).await
             }
 
@@ -237,6 +244,7 @@ macro_rules! impl_client_trait {
                 data: $crate::client::ChatCompletionsData,
             ) -> Result<()> {
                 let builder = self.chat_completions_builder(client, data)?;
+                $crate::trace::capture_upstream_request(&builder);
                 $chat_completions_streaming(builder, handler).await
             }
 
@@ -262,7 +270,11 @@ macro_rules! config_get_fn {
                     let env_prefix = Self::name(&self.config);
                     let env_name =
                         format!("{}_{}", env_prefix, stringify!($field_name)).to_ascii_uppercase();
-                    std::env::var(&env_name).ok()
+                    let value = std::env::var(&env_name).ok()?;
+                    if $crate::utils::is_secret_key(stringify!($field_name)) {
+                        $crate::utils::register_secret(&value);
+                    }
+                    Some(value)
                 })
                 .ok_or_else(|| {
                     anyhow::anyhow!("Miss '{}' in client configuration", stringify!($field_name))
@@ -308,7 +320,7 @@ pub trait Client: Sync + Send {
     async fn chat_completions(&self, input: Input) -> Result<ChatCompletionsOutput> {
         let client = self.build_client()?;
         let data = input.prepare_completion_data(self.model(), false)?;
-        self.chat_completions_inner(&client, data)
+        self.chat_completions_with_tools(&client, data)
             .await
             .with_context(|| "Failed to get chat completions")
     }
@@ -324,7 +336,7 @@ pub trait Client: Sync + Send {
             ret = async {
                 let client = self.build_client()?;
                 let data = input.prepare_completion_data(self.model(), true)?;
-                self.chat_completions_streaming_inner(&client, handler, data).await
+                self.chat_completions_streaming_with_tools(&client, handler, data).await
             } => {
                 handler.done()?;
                 ret.with_context(|| "Failed to get chat completions")
@@ -373,6 +385,40 @@ pub trait Client: Sync + Send {
     ) -> Result<Vec<Vec<f32>>> {
         bail!("No embeddings api")
     }
+
+    /// `chat_completions_inner`, emulating tool calls in the prompt when the model is flagged for it.
+    async fn chat_completions_with_tools(
+        &self,
+        client: &ReqwestClient,
+        mut data: ChatCompletionsData,
+    ) -> Result<ChatCompletionsOutput> {
+        if !self.model().emulates_function_calling() || data.functions.is_none() {
+            return self.chat_completions_inner(client, data).await;
+        }
+        render_tool_prompt(&mut data);
+        let mut output = self.chat_completions_inner(client, data).await?;
+        (output.text, output.tool_calls) = extract_tool_calls(&output.text);
+        Ok(output)
+    }
+
+    /// `chat_completions_streaming_inner`, emulating tool calls like `chat_completions_with_tools`.
+    async fn chat_completions_streaming_with_tools(
+        &self,
+        client: &ReqwestClient,
+        handler: &mut SseHandler,
+        mut data: ChatCompletionsData,
+    ) -> Result<()> {
+        if !self.model().emulates_function_calling() || data.functions.is_none() {
+            return self
+                .chat_completions_streaming_inner(client, handler, data)
+                .await;
+        }
+        render_tool_prompt(&mut data);
+        handler.parse_tool_calls();
+        self.chat_completions_streaming_inner(client, handler, data)
+            .await?;
+        handler.finish_tool_calls()
+    }
 }
 
 impl Default for ClientConfig {
@@ -411,7 +457,7 @@ pub fn select_model_patch<'a>(
     None
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ChatCompletionsData {
     pub messages: Vec<Message>,
     pub temperature: Option<f64>,
@@ -520,36 +566,59 @@ pub fn catch_error(data: &Value, status: u16) -> Result<()> {
         return Ok(());
     }
     debug!("Invalid response, status: {status}, data: {data}");
+    Err(UpstreamError {
+        status,
+        message: upstream_error_message(data, status),
+    }
+    .into())
+}
+
+/// A non-2xx response from a provider, kept typed so callers can classify it.
+#[derive(Debug)]
+pub struct UpstreamError {
+    pub status: u16,
+    pub message: String,
+}
+
+impl std::fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for UpstreamError {}
+
+fn upstream_error_message(data: &Value, status: u16) -> String {
     if let Some(error) = data["error"].as_object() {
         if let (Some(typ), Some(message)) = (
             get_str_field_from_json_map(error, "type"),
             get_str_field_from_json_map(error, "message"),
         ) {
-            bail!("{message} (type: {typ})");
+            return format!("{message} (type: {typ})");
         }
     } else if let Some(error) = data["errors"][0].as_object() {
         if let (Some(code), Some(message)) = (
             get_u64_field_from_json_map(error, "code"),
             get_str_field_from_json_map(error, "message"),
         ) {
-            bail!("{message} (status: {code})")
+            return format!("{message} (status: {code})");
         }
     } else if let Some(error) = data[0]["error"].as_object() {
         if let (Some(status), Some(message)) = (
             get_str_field_from_json_map(error, "status"),
             get_str_field_from_json_map(error, "message"),
         ) {
-            bail!("{message} (status: {status})")
+            return format!("{message} (status: {status})");
         }
     } else if let (Some(detail), Some(status)) = (data["detail"].as_str(), data["status"].as_i64())
     {
-        bail!("{detail} (status: {status})");
+        return format!("{detail} (status: {status})");
     } else if let Some(error) = data["error"].as_str() {
-        bail!("{error}");
+        return error.to_string();
     } else if let Some(message) = data["message"].as_str() {
-        bail!("{message}");
+        return message.to_string();
     }
-    bail!("Invalid response data: {data} (status: {status})");
+    format!("Invalid response data: {data} (status: {status})")
 }
 
 pub fn get_str_field_from_json_map<'a>(
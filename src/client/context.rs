@@ -0,0 +1,337 @@
+use super::{
+    ChatCompletionsData, Client, Message, MessageContent, MessageContentPart, MessageRole, Model,
+};
+
+use crate::config::{SUMMARIZE_PROMPT, SUMMARY_PROMPT};
+use crate::function::FunctionDeclaration;
+
+use anyhow::{bail, Result};
+use reqwest::Client as ReqwestClient;
+use serde::{Deserialize, Serialize};
+
+const MIN_KEEP_TOKENS: usize = 64;
+const MAX_TRUNCATE_ROUNDS: usize = 32;
+const TRUNCATION_MARKER: &str = "\n...[truncated]...\n";
+
+/// What to do when a request does not fit in `max_input_tokens`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContextStrategy {
+    /// Reject the request.
+    #[default]
+    None,
+    /// Drop the oldest turns, keeping the system prompt and the latest turn.
+    DropOldest,
+    /// Cut the middle out of the longest messages.
+    MiddleOut,
+    /// Replace the older turns with a summary written by the same model.
+    Summarize,
+}
+
+impl ContextStrategy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ContextStrategy::None => "none",
+            ContextStrategy::DropOldest => "drop_oldest",
+            ContextStrategy::MiddleOut => "middle_out",
+            ContextStrategy::Summarize => "summarize",
+        }
+    }
+}
+
+/// What a strategy trimmed to make a request fit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContextReport {
+    pub strategy: ContextStrategy,
+    pub dropped_messages: usize,
+    pub truncated_messages: usize,
+    pub summarized_messages: usize,
+    pub tokens_before: usize,
+    pub tokens_after: usize,
+    /// Usage of the extra call made by `summarize`.
+    pub summary_input_tokens: Option<u64>,
+    pub summary_output_tokens: Option<u64>,
+}
+
+impl ContextReport {
+    fn new(strategy: ContextStrategy, tokens_before: usize) -> Self {
+        Self {
+            strategy,
+            dropped_messages: 0,
+            truncated_messages: 0,
+            summarized_messages: 0,
+            tokens_before,
+            tokens_after: tokens_before,
+            summary_input_tokens: None,
+            summary_output_tokens: None,
+        }
+    }
+
+    /// Header value, e.g. `drop_oldest; dropped=4; tokens=9500->3900`.
+    pub fn header_value(&self) -> String {
+        let mut parts = vec![self.strategy.as_str().to_string()];
+        for (name, value) in [
+            ("dropped", self.dropped_messages),
+            ("truncated", self.truncated_messages),
+            ("summarized", self.summarized_messages),
+        ] {
+            if value > 0 {
+                parts.push(format!("{name}={value}"));
+            }
+        }
+        parts.push(format!(
+            "tokens={}->{}",
+            self.tokens_before, self.tokens_after
+        ));
+        parts.join("; ")
+    }
+}
+
+/// Makes `data.messages` fit the model's `max_input_tokens`, returning what was trimmed, if anything.
+pub async fn fit_context(
+    client: &dyn Client,
+    http_client: &ReqwestClient,
+    data: &mut ChatCompletionsData,
+    strategy: ContextStrategy,
+) -> Result<Option<ContextReport>> {
+    let model = client.model();
+    let functions = data.functions.as_deref();
+    let Some(max_input_tokens) = model.max_input_tokens() else {
+        return Ok(None);
+    };
+    if strategy == ContextStrategy::None {
+        model.guard_max_input_tokens(&data.messages, functions)?;
+        return Ok(None);
+    }
+    let tokens_before = model.input_tokens(&data.messages, functions);
+    if tokens_before < max_input_tokens {
+        return Ok(None);
+    }
+    let mut report = ContextReport::new(strategy, tokens_before);
+    match strategy {
+        ContextStrategy::None => unreachable!("checked above"),
+        ContextStrategy::DropOldest => {
+            report.dropped_messages = drop_oldest(model, &mut data.messages, functions);
+        }
+        ContextStrategy::MiddleOut => {
+            report.truncated_messages = middle_out(model, &mut data.messages, functions);
+        }
+        ContextStrategy::Summarize => {
+            let (start, end) = history_range(&data.messages);
+            if start == end {
+                bail!("Exceed max_input_tokens limit, no earlier turns to summarize");
+            }
+            let mut messages = data.messages[..end].to_vec();
+            messages.push(Message::new(
+                MessageRole::User,
+                MessageContent::Text(SUMMARIZE_PROMPT.into()),
+            ));
+            // The turns to summarize overflow the window too, so bound the summary request itself
+            middle_out(model, &mut messages, None);
+            drop_oldest(model, &mut messages, None);
+            let summary_data = ChatCompletionsData {
+                messages,
+                temperature: None,
+                top_p: None,
+                functions: None,
+                stream: false,
+            };
+            let output = client
+                .chat_completions_inner(http_client, summary_data)
+                .await?;
+            debug!("Summarized {} messages: {}", end - start, output.text);
+            data.messages.drain(start..end);
+            insert_summary(
+                &mut data.messages,
+                &format!("{SUMMARY_PROMPT}{}", output.text),
+            );
+            report.summarized_messages = end - start;
+            report.summary_input_tokens = output.input_tokens;
+            report.summary_output_tokens = output.output_tokens;
+        }
+    }
+    report.tokens_after = model.input_tokens(&data.messages, functions);
+    if report.tokens_after >= max_input_tokens {
+        bail!(
+            "Exceed max_input_tokens limit, even after applying context strategy '{}'",
+            strategy.as_str()
+        );
+    }
+    Ok(Some(report))
+}
+
+/// The turns between the leading system prompt and the latest turn, which starts at the last user message.
+fn history_range(messages: &[Message]) -> (usize, usize) {
+    let start = messages
+        .iter()
+        .position(|v| !v.role.is_system())
+        .unwrap_or(messages.len());
+    let end = messages
+        .iter()
+        .rposition(|v| v.role.is_user())
+        .unwrap_or(messages.len().saturating_sub(1))
+        .max(start);
+    (start, end)
+}
+
+/// Drops whole turns, oldest first, so the history still starts with a user message.
+fn drop_oldest(
+    model: &Model,
+    messages: &mut Vec<Message>,
+    functions: Option<&[FunctionDeclaration]>,
+) -> usize {
+    let max_input_tokens = model.max_input_tokens().unwrap_or(usize::MAX);
+    let mut dropped = 0;
+    loop {
+        let (start, end) = history_range(messages);
+        if start == end || model.input_tokens(messages, functions) < max_input_tokens {
+            break;
+        }
+        let turn_end = messages[start + 1..end]
+            .iter()
+            .position(|v| v.role.is_user())
+            .map(|i| start + 1 + i)
+            .unwrap_or(end);
+        messages.drain(start..turn_end);
+        dropped += turn_end - start;
+    }
+    dropped
+}
+
+/// Cuts the middle out of the longest text message until the request fits, keeping head and tail.
+fn middle_out(
+    model: &Model,
+    messages: &mut [Message],
+    functions: Option<&[FunctionDeclaration]>,
+) -> usize {
+    let max_input_tokens = model.max_input_tokens().unwrap_or(usize::MAX);
+    let tokenizer = model.tokenizer();
+    let mut truncated = vec![false; messages.len()];
+    for _ in 0..MAX_TRUNCATE_ROUNDS {
+        let total = model.input_tokens(messages, functions);
+        if total < max_input_tokens {
+            break;
+        }
+        let longest = messages
+            .iter()
+            .enumerate()
+            .filter_map(|(i, v)| match &v.content {
+                MessageContent::Text(text) => Some((i, tokenizer.count(text))),
+                _ => None,
+            })
+            .filter(|(_, tokens)| *tokens > MIN_KEEP_TOKENS)
+            .max_by_key(|(_, tokens)| *tokens);
+        let Some((index, tokens)) = longest else {
+            break;
+        };
+        let MessageContent::Text(text) = &messages[index].content else {
+            break;
+        };
+        let excess = total + 1 - max_input_tokens + tokenizer.count(TRUNCATION_MARKER);
+        let keep_tokens = tokens.saturating_sub(excess).max(MIN_KEEP_TOKENS);
+        let chars: Vec<char> = text.chars().collect();
+        let keep_chars = chars.len() * keep_tokens / tokens;
+        let head = keep_chars / 2;
+        let tail = keep_chars - head;
+        let text = format!(
+            "{}{TRUNCATION_MARKER}{}",
+            chars[..head].iter().collect::<String>(),
+            chars[chars.len() - tail..].iter().collect::<String>()
+        );
+        messages[index].content = MessageContent::Text(text);
+        truncated[index] = true;
+    }
+    truncated.into_iter().filter(|v| *v).count()
+}
+
+/// Appends to the system prompt, since several providers only take the first system message.
+fn insert_summary(messages: &mut Vec<Message>, summary: &str) {
+    match messages.first_mut() {
+        Some(Message {
+            role: MessageRole::System,
+            content: MessageContent::Text(text),
+        }) => {
+            text.push_str("\n\n");
+            text.push_str(summary);
+        }
+        Some(Message {
+            role: MessageRole::System,
+            content: MessageContent::Array(parts),
+        }) => parts.push(MessageContentPart::Text {
+            text: summary.into(),
+        }),
+        _ => messages.insert(
+            0,
+            Message::new(MessageRole::System, MessageContent::Text(summary.into())),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: MessageRole, text: &str) -> Message {
+        Message::new(role, MessageContent::Text(text.into()))
+    }
+
+    fn model(max_input_tokens: usize) -> Model {
+        let mut model = Model::new("test", "gpt-4");
+        model.data_mut().max_input_tokens = Some(max_input_tokens);
+        model
+    }
+
+    #[test]
+    fn test_drop_oldest() {
+        let mut messages = vec![
+            message(MessageRole::System, "You are terse."),
+            message(MessageRole::User, &"first question ".repeat(40)),
+            message(MessageRole::Assistant, &"first answer ".repeat(40)),
+            message(MessageRole::User, "second question"),
+            message(MessageRole::Assistant, "second answer"),
+            message(MessageRole::User, "latest question"),
+        ];
+        let dropped = drop_oldest(&model(60), &mut messages, None);
+        assert_eq!(dropped, 2);
+        assert_eq!(messages.len(), 4);
+        assert!(messages[0].role.is_system());
+        assert!(messages[1].role.is_user());
+        assert_eq!(messages[3].content.to_text(), "latest question");
+    }
+
+    #[test]
+    fn test_middle_out() {
+        let long_text = format!("BEGIN {} END", "filler ".repeat(500));
+        let mut messages = vec![message(MessageRole::User, &long_text)];
+        let model = model(200);
+        assert_eq!(middle_out(&model, &mut messages, None), 1);
+        let text = messages[0].content.to_text();
+        assert!(text.starts_with("BEGIN") && text.ends_with("END"));
+        assert!(text.contains("[truncated]"));
+        assert!(model.input_tokens(&messages, None) < 200);
+    }
+
+    #[test]
+    fn test_insert_summary() {
+        let mut messages = vec![
+            Message::new(
+                MessageRole::System,
+                MessageContent::Array(vec![MessageContentPart::Text {
+                    text: "You are terse.".into(),
+                }]),
+            ),
+            message(MessageRole::User, "latest question"),
+        ];
+        insert_summary(&mut messages, "Summary of earlier turns.");
+        assert_eq!(messages.len(), 2);
+        assert_eq!(
+            messages[0].content.to_text(),
+            "You are terse.\n\nSummary of earlier turns."
+        );
+
+        let mut messages = vec![message(MessageRole::User, "latest question")];
+        insert_summary(&mut messages, "Summary of earlier turns.");
+        assert!(messages[0].role.is_system());
+        assert_eq!(messages.len(), 2);
+    }
+}
@@ -85,6 +85,7 @@ impl Client for ErnieClient {
     ) -> Result<ChatCompletionsOutput> {
         self.prepare_access_token().await?;
         let builder = self.chat_completions_builder(client, data)?;
+        crate::trace::capture_upstream_request(&builder);
         chat_completions(builder).await
     }
 
@@ -96,6 +97,7 @@ impl Client for ErnieClient {
     ) -> Result<()> {
         self.prepare_access_token().await?;
         let builder = self.chat_completions_builder(client, data)?;
+        crate::trace::capture_upstream_request(&builder);
         chat_completions_streaming(builder, handler).await
     }
 }
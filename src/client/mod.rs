@@ -1,17 +1,22 @@
 #[macro_use]
 mod common;
 mod access_token;
+mod context;
 mod message;
 mod model;
 mod prompt_format;
 mod stream;
+mod tokenizer;
+mod tool_prompt;
 
 pub use crate::function::{ToolCall, ToolResults};
 pub use crate::utils::PromptKind;
 pub use common::*;
+pub use context::*;
 pub use message::*;
 pub use model::*;
 pub use stream::*;
+pub use tool_prompt::*;
 
 register_client!(
     (openai, "openai", OpenAIConfig, OpenAIClient),
@@ -1,15 +1,21 @@
 use super::{
-    message::{Message, MessageContent},
+    context::ContextStrategy,
+    message::{Message, MessageContent, MessageContentPart},
+    tokenizer::Tokenizer,
     EmbeddingsData,
 };
 
-use crate::utils::{estimate_token_length, format_option_value};
+use crate::function::FunctionDeclaration;
+use crate::utils::format_option_value;
 
 use anyhow::{bail, Result};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
 
 const PER_MESSAGES_TOKENS: usize = 5;
+const PER_FUNCTION_TOKENS: usize = 8;
 const BASIS_TOKENS: usize = 2;
+const DEFAULT_MAX_TOKENS: usize = 4096;
 
 #[derive(Debug, Clone)]
 pub struct Model {
@@ -109,7 +115,6 @@ impl Model {
             input_price,
             output_price,
             supports_vision,
-            supports_function_calling,
             ..
         } = &self.data;
         let max_input_tokens = format_option_value(max_input_tokens);
@@ -120,7 +125,7 @@ impl Model {
         if *supports_vision {
             capabilities.push('👁');
         };
-        if *supports_function_calling {
+        if self.supports_function_calling() {
             capabilities.push('⚒');
         };
         let capabilities: String = capabilities
@@ -142,12 +147,20 @@ impl Model {
         self.data.max_output_tokens
     }
 
+    pub fn context_strategy(&self) -> ContextStrategy {
+        self.data.context_strategy.unwrap_or_default()
+    }
+
     pub fn supports_vision(&self) -> bool {
         self.data.supports_vision
     }
 
     pub fn supports_function_calling(&self) -> bool {
-        self.data.supports_function_calling
+        self.data.supports_function_calling || self.data.emulate_function_calling
+    }
+
+    pub fn emulates_function_calling(&self) -> bool {
+        self.data.emulate_function_calling && !self.data.supports_function_calling
     }
 
     pub fn default_chunk_size(&self) -> usize {
@@ -158,6 +171,18 @@ impl Model {
         self.data.max_concurrent_chunks.unwrap_or(1)
     }
 
+    /// Cost in USD, with `input_price`/`output_price` given per million tokens.
+    pub fn cost(&self, input_tokens: Option<u64>, output_tokens: Option<u64>) -> Option<f64> {
+        let input_price = self.data.input_price?;
+        let output_price = self.data.output_price?;
+        if input_tokens.is_none() && output_tokens.is_none() {
+            return None;
+        }
+        let input_cost = input_tokens.unwrap_or_default() as f64 * input_price;
+        let output_cost = output_tokens.unwrap_or_default() as f64 * output_price;
+        Some((input_cost + output_cost) / 1_000_000.0)
+    }
+
     pub fn max_tokens_param(&self) -> Option<isize> {
         if self.data.require_max_tokens {
             self.data.max_output_tokens
@@ -166,6 +191,68 @@ impl Model {
         }
     }
 
+    /// Providers that reject requests without `max_tokens`.
+    pub fn requires_max_tokens(&self) -> bool {
+        self.data.require_max_tokens || self.data.name.contains("claude")
+    }
+
+    /// Output tokens left for a prompt: the smaller of `max_output_tokens` and the remaining context.
+    pub fn output_budget(&self, input_tokens: usize) -> Option<usize> {
+        let remaining = self
+            .data
+            .max_input_tokens
+            .map(|v| v.saturating_sub(input_tokens));
+        let max_output_tokens = self
+            .data
+            .max_output_tokens
+            .filter(|v| *v > 0)
+            .map(|v| v as usize);
+        match (remaining, max_output_tokens) {
+            (Some(remaining), Some(max_output_tokens)) => Some(remaining.min(max_output_tokens)),
+            (remaining, max_output_tokens) => remaining.or(max_output_tokens),
+        }
+    }
+
+    /// Sets the `max_tokens` sent upstream from the requested value and the output budget.
+    ///
+    /// Requests over budget are clamped or rejected per `policy`. Providers that require `max_tokens`
+    /// get the whole budget when none is requested.
+    pub fn resolve_max_tokens(
+        &mut self,
+        requested: Option<isize>,
+        input_tokens: usize,
+        policy: MaxTokensPolicy,
+    ) -> Result<Option<usize>> {
+        let budget = self.output_budget(input_tokens);
+        if budget == Some(0) {
+            bail!("Exceed max_input_tokens limit, no tokens left for the output");
+        }
+        let max_tokens = match (requested.filter(|v| *v > 0), budget) {
+            (Some(requested), Some(budget)) if requested as usize > budget => match policy {
+                MaxTokensPolicy::Clamp => {
+                    debug!("Clamp max_tokens {requested} to {budget}");
+                    Some(budget)
+                }
+                MaxTokensPolicy::Reject => {
+                    bail!("max_tokens {requested} exceeds the {budget} output tokens available")
+                }
+            },
+            (Some(requested), _) => Some(requested as usize),
+            (None, budget) if self.requires_max_tokens() => {
+                let cap = self
+                    .data
+                    .max_output_tokens
+                    .filter(|v| *v > 0)
+                    .map_or(DEFAULT_MAX_TOKENS, |v| v as usize);
+                Some(budget.map_or(cap, |v| v.min(cap)))
+            }
+            (None, _) => None,
+        };
+        let require_max_tokens = max_tokens.is_some();
+        self.set_max_tokens(max_tokens.map(|v| v as isize), require_max_tokens);
+        Ok(max_tokens)
+    }
+
     pub fn set_max_tokens(
         &mut self,
         max_output_tokens: Option<isize>,
@@ -179,17 +266,68 @@ impl Model {
         self
     }
 
+    pub fn tokenizer(&self) -> Arc<Tokenizer> {
+        Tokenizer::get(self.data.tokenizer.as_deref(), &self.data.name)
+    }
+
     pub fn messages_tokens(&self, messages: &[Message]) -> usize {
+        let tokenizer = self.tokenizer();
         messages
             .iter()
             .map(|v| match &v.content {
-                MessageContent::Text(text) => estimate_token_length(text),
-                MessageContent::Array(_) => 0,
-                MessageContent::ToolResults(_) => 0,
+                MessageContent::Text(text) => tokenizer.count(text),
+                MessageContent::Array(parts) => parts
+                    .iter()
+                    .map(|part| match part {
+                        MessageContentPart::Text { text } => tokenizer.count(text),
+                        MessageContentPart::ImageUrl { .. } => self.image_tokens(),
+                    })
+                    .sum(),
+                MessageContent::ToolResults((results, text)) => {
+                    let results: usize = results
+                        .iter()
+                        .map(|v| {
+                            PER_MESSAGES_TOKENS
+                                + tokenizer.count(&v.call.name)
+                                + tokenizer.count(&v.call.arguments.to_string())
+                                + tokenizer.count(&v.output.to_string())
+                        })
+                        .sum();
+                    results + tokenizer.count(text)
+                }
             })
             .sum()
     }
 
+    /// Tokens the tool schemas add to the prompt.
+    pub fn functions_tokens(&self, functions: &[FunctionDeclaration]) -> usize {
+        let tokenizer = self.tokenizer();
+        functions
+            .iter()
+            .map(|v| {
+                PER_FUNCTION_TOKENS + tokenizer.count(&serde_json::to_string(v).unwrap_or_default())
+            })
+            .sum()
+    }
+
+    /// Estimated tokens per image, since the image size is not known up front.
+    ///
+    /// Claude bills about `width * height / 750` capped near 1600, Gemini a flat 258, and OpenAI
+    /// 85 plus 170 per 512px tile (765 for a 1024px square).
+    pub fn image_tokens(&self) -> usize {
+        if let Some(tokens) = self.data.image_tokens {
+            return tokens;
+        }
+        let name = self.data.name.to_lowercase();
+        if name.contains("claude") {
+            1600
+        } else if name.contains("gemini") {
+            258
+        } else {
+            765
+        }
+    }
+
     pub fn total_tokens(&self, messages: &[Message]) -> usize {
         if messages.is_empty() {
             return 0;
@@ -203,8 +341,24 @@ impl Model {
         }
     }
 
-    pub fn guard_max_input_tokens(&self, messages: &[Message]) -> Result<()> {
-        let total_tokens = self.total_tokens(messages) + BASIS_TOKENS;
+    /// Prompt tokens of a request, as checked against `max_input_tokens`.
+    pub fn input_tokens(
+        &self,
+        messages: &[Message],
+        functions: Option<&[FunctionDeclaration]>,
+    ) -> usize {
+        let functions_tokens = functions
+            .map(|v| self.functions_tokens(v))
+            .unwrap_or_default();
+        self.total_tokens(messages) + functions_tokens + BASIS_TOKENS
+    }
+
+    pub fn guard_max_input_tokens(
+        &self,
+        messages: &[Message],
+        functions: Option<&[FunctionDeclaration]>,
+    ) -> Result<()> {
+        let total_tokens = self.input_tokens(messages, functions);
         if let Some(max_input_tokens) = self.data.max_input_tokens {
             if total_tokens >= max_input_tokens {
                 bail!("Exceed max_input_tokens limit")
@@ -221,6 +375,15 @@ impl Model {
     }
 }
 
+/// What to do when `max_tokens` exceeds the output budget of a model.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MaxTokensPolicy {
+    #[default]
+    Clamp,
+    Reject,
+}
+
 #[derive(Debug, Clone, Default, Deserialize)]
 pub struct ModelData {
     pub name: String,
@@ -229,6 +392,7 @@ pub struct ModelData {
     pub max_input_tokens: Option<usize>,
     pub input_price: Option<f64>,
     pub output_price: Option<f64>,
+    pub tokenizer: Option<String>,
 
     // chat-only properties
     pub max_output_tokens: Option<isize>,
@@ -238,6 +402,11 @@ pub struct ModelData {
     pub supports_vision: bool,
     #[serde(default)]
     pub supports_function_calling: bool,
+    /// Describe tools in the prompt and parse calls out of the text, for models without native support.
+    #[serde(default)]
+    pub emulate_function_calling: bool,
+    pub image_tokens: Option<usize>,
+    pub context_strategy: Option<ContextStrategy>,
 
     // embedding-only properties
     pub default_chunk_size: Option<usize>,
@@ -262,3 +431,42 @@ pub struct BuiltinModels {
 fn default_model_mode() -> String {
     "chat".into()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_max_tokens() {
+        let mut model = Model::new("claude", "claude-3-haiku");
+        model.data_mut().max_input_tokens = Some(1000);
+        model.data_mut().max_output_tokens = Some(500);
+        assert_eq!(
+            model
+                .clone()
+                .resolve_max_tokens(Some(800), 600, MaxTokensPolicy::Clamp)
+                .unwrap(),
+            Some(400)
+        );
+        assert!(model
+            .clone()
+            .resolve_max_tokens(Some(800), 600, MaxTokensPolicy::Reject)
+            .is_err());
+        assert_eq!(
+            model
+                .resolve_max_tokens(None, 100, MaxTokensPolicy::Clamp)
+                .unwrap(),
+            Some(500)
+        );
+        assert_eq!(model.max_tokens_param(), Some(500));
+
+        let mut model = Model::new("openai", "gpt-4o");
+        assert_eq!(
+            model
+                .resolve_max_tokens(None, 100, MaxTokensPolicy::Clamp)
+                .unwrap(),
+            None
+        );
+        assert_eq!(model.max_tokens_param(), None);
+    }
+}
@@ -34,7 +34,11 @@ impl OpenAIClient {
         let api_key = self.get_api_key()?;
         let api_base = self.get_api_base().unwrap_or_else(|_| API_BASE.to_string());
 
+        let stream = data.stream;
         let mut body = openai_build_chat_completions_body(data, &self.model);
+        if stream {
+            body["stream_options"] = json!({ "include_usage": true });
+        }
         self.patch_chat_completions_body(&mut body);
 
         let url = format!("{api_base}/chat/completions");
@@ -86,52 +90,30 @@ pub async fn openai_chat_completions_streaming(
     builder: RequestBuilder,
     handler: &mut SseHandler,
 ) -> Result<()> {
-    let mut function_index = 0;
-    let mut function_name = String::new();
-    let mut function_arguments = String::new();
-    let mut function_id = String::new();
     let handle = |message: SseMmessage| -> Result<bool> {
         if message.data == "[DONE]" {
-            if !function_name.is_empty() {
-                handler.tool_call(ToolCall::new(
-                    function_name.clone(),
-                    json!(function_arguments),
-                    Some(function_id.clone()),
-                ))?;
-            }
             return Ok(true);
         }
         let data: Value = serde_json::from_str(&message.data)?;
         debug!("stream-data: {data}");
-        if let Some(text) = data["choices"][0]["delta"]["content"].as_str() {
+        if data["usage"].is_object() {
+            handler.usage(
+                data["usage"]["prompt_tokens"].as_u64(),
+                data["usage"]["completion_tokens"].as_u64(),
+            );
+        }
+        let delta = &data["choices"][0]["delta"];
+        if let Some(text) = delta["content"].as_str() {
             handler.text(text)?;
-        } else if let (Some(function), index, id) = (
-            data["choices"][0]["delta"]["tool_calls"][0]["function"].as_object(),
-            data["choices"][0]["delta"]["tool_calls"][0]["index"].as_u64(),
-            data["choices"][0]["delta"]["tool_calls"][0]["id"].as_str(),
-        ) {
-            let index = index.unwrap_or_default();
-            if index != function_index {
-                if !function_name.is_empty() {
-                    handler.tool_call(ToolCall::new(
-                        function_name.clone(),
-                        json!(function_arguments),
-                        Some(function_id.clone()),
-                    ))?;
-                }
-                function_name.clear();
-                function_arguments.clear();
-                function_id.clear();
-                function_index = index;
-            }
-            if let Some(name) = function.get("name").and_then(|v| v.as_str()) {
-                function_name = name.to_string();
-            }
-            if let Some(arguments) = function.get("arguments").and_then(|v| v.as_str()) {
-                function_arguments.push_str(arguments);
-            }
-            if let Some(id) = id {
-                function_id = id.to_string();
+        }
+        if let Some(tool_calls) = delta["tool_calls"].as_array() {
+            for (i, call) in tool_calls.iter().enumerate() {
+                handler.tool_call_delta(
+                    call["index"].as_u64().unwrap_or(i as u64),
+                    call["id"].as_str(),
+                    call["function"]["name"].as_str(),
+                    call["function"]["arguments"].as_str().unwrap_or_default(),
+                )?;
             }
         }
         Ok(false)
@@ -184,7 +166,7 @@ pub fn openai_build_chat_completions_body(data: ChatCompletionsData, model: &Mod
                             "type": "function",
                             "function": {
                                 "name": tool_call_result.call.name,
-                                "arguments": tool_call_result.call.arguments,
+                                "arguments": tool_call_result.call.arguments_text(),
                             },
                         })
                     }).collect();
@@ -264,7 +246,7 @@ pub fn openai_extract_chat_completions(data: &Value) -> Result<ChatCompletionsOu
                 ) {
                     Some(ToolCall::new(
                         name.to_string(),
-                        json!(arguments),
+                        ToolCall::parse_arguments(arguments),
                         Some(id.to_string()),
                     ))
                 } else {
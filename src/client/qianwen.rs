@@ -110,6 +110,7 @@ impl Client for QianwenClient {
         let api_key = self.get_api_key()?;
         patch_messages(self.model.name(), &api_key, &mut data.messages).await?;
         let builder = self.chat_completions_builder(client, data)?;
+        crate::trace::capture_upstream_request(&builder);
         chat_completions(builder, &self.model).await
     }
 
@@ -122,6 +123,7 @@ impl Client for QianwenClient {
         let api_key = self.get_api_key()?;
         patch_messages(self.model.name(), &api_key, &mut data.messages).await?;
         let builder = self.chat_completions_builder(client, data)?;
+        crate::trace::capture_upstream_request(&builder);
         chat_completions_streaming(builder, handler, &self.model).await
     }
 
@@ -148,21 +150,33 @@ async fn chat_completions_streaming(
     handler: &mut SseHandler,
     model: &Model,
 ) -> Result<()> {
-    let model_name = model.name();
     let handle = |message: SseMmessage| -> Result<bool> {
         let data: Value = serde_json::from_str(&message.data)?;
         maybe_catch_error(&data)?;
         debug!("stream-data: {data}");
-        if model_name == "qwen-long" {
-            if let Some(text) = data["output"]["choices"][0]["message"]["content"].as_str() {
-                handler.text(text)?;
-            }
-        } else if model.supports_vision() {
+        let choice_message = &data["output"]["choices"][0]["message"];
+        if model.supports_vision() {
             if let Some(text) =
                 data["output"]["choices"][0]["message"]["content"][0]["text"].as_str()
             {
                 handler.text(text)?;
             }
+        } else if choice_message.is_object() {
+            if let Some(text) = choice_message["content"].as_str() {
+                if !text.is_empty() {
+                    handler.text(text)?;
+                }
+            }
+            if let Some(tool_calls) = choice_message["tool_calls"].as_array() {
+                for (i, call) in tool_calls.iter().enumerate() {
+                    handler.tool_call_delta(
+                        call["index"].as_u64().unwrap_or(i as u64),
+                        call["id"].as_str().filter(|v| !v.is_empty()),
+                        call["function"]["name"].as_str().filter(|v| !v.is_empty()),
+                        call["function"]["arguments"].as_str().unwrap_or_default(),
+                    )?;
+                }
+            }
         } else if let Some(text) = data["output"]["text"].as_str() {
             handler.text(text)?;
         }
@@ -177,12 +191,12 @@ fn build_chat_completions_body(data: ChatCompletionsData, model: &Model) -> Resu
         messages,
         temperature,
         top_p,
-        functions: _,
+        functions,
         stream,
     } = data;
 
     let mut has_upload = false;
-    let mut is_tool_call = false;
+    let mut is_tool_call = functions.is_some();
     let input = if model.supports_vision() {
         let messages: Vec<Value> = messages
             .into_iter()
@@ -213,19 +227,70 @@ fn build_chat_completions_body(data: ChatCompletionsData, model: &Model) -> Resu
             })
             .collect();
 
+        if is_tool_call {
+            bail!("The client does not support function calling with vision models");
+        }
         json!({
             "messages": messages,
         })
     } else {
+        let messages: Vec<Value> = messages
+            .into_iter()
+            .flat_map(|message| match message.content {
+                MessageContent::ToolResults((tool_call_results, text)) => {
+                    is_tool_call = true;
+                    let tool_calls: Vec<Value> = tool_call_results
+                        .iter()
+                        .map(|tool_call_result| {
+                            json!({
+                                "id": tool_call_result.call.id,
+                                "type": "function",
+                                "function": {
+                                    "name": tool_call_result.call.name,
+                                    "arguments": tool_call_result.call.arguments_text(),
+                                },
+                            })
+                        })
+                        .collect();
+                    let mut messages = vec![json!({
+                        "role": MessageRole::Assistant,
+                        "content": text,
+                        "tool_calls": tool_calls,
+                    })];
+                    for tool_call_result in tool_call_results {
+                        messages.push(json!({
+                            "role": "tool",
+                            "content": tool_call_result.output.to_string(),
+                            "name": tool_call_result.call.name,
+                            "tool_call_id": tool_call_result.call.id,
+                        }));
+                    }
+                    messages
+                }
+                content => vec![json!({ "role": message.role, "content": content })],
+            })
+            .collect();
         json!({
             "messages": messages,
         })
     };
-    if is_tool_call {
-        bail!("The client does not support function calling",);
-    }
 
     let mut parameters = json!({});
+    // Tool calls only come back in the `message` result format.
+    if is_tool_call {
+        parameters["result_format"] = "message".into();
+    }
+    if let Some(functions) = functions {
+        parameters["tools"] = functions
+            .iter()
+            .map(|v| {
+                json!({
+                    "type": "function",
+                    "function": v,
+                })
+            })
+            .collect();
+    }
     if stream {
         parameters["incremental_output"] = true.into();
     }
@@ -280,20 +345,36 @@ struct EmbeddingsResBodyOutputEmbedding {
 
 fn extract_chat_completions_text(data: &Value, model: &Model) -> Result<ChatCompletionsOutput> {
     let err = || anyhow!("Invalid response data: {data}");
-    let text = if model.name() == "qwen-long" {
-        data["output"]["choices"][0]["message"]["content"]
-            .as_str()
-            .ok_or_else(err)?
-    } else if model.supports_vision() {
-        data["output"]["choices"][0]["message"]["content"][0]["text"]
-            .as_str()
-            .ok_or_else(err)?
+    let message = &data["output"]["choices"][0]["message"];
+    let mut tool_calls = vec![];
+    let text = if model.supports_vision() {
+        message["content"][0]["text"].as_str().ok_or_else(err)?
+    } else if message.is_object() {
+        if let Some(calls) = message["tool_calls"].as_array() {
+            tool_calls = calls
+                .iter()
+                .filter_map(|call| {
+                    let name = call["function"]["name"].as_str()?;
+                    let arguments = call["function"]["arguments"].as_str().unwrap_or("{}");
+                    Some(ToolCall::new(
+                        name.to_string(),
+                        ToolCall::parse_arguments(arguments),
+                        call["id"].as_str().map(|v| v.to_string()),
+                    ))
+                })
+                .collect();
+        }
+        let text = message["content"].as_str().unwrap_or_default();
+        if text.is_empty() && tool_calls.is_empty() {
+            return Err(err());
+        }
+        text
     } else {
         data["output"]["text"].as_str().ok_or_else(err)?
     };
     let output = ChatCompletionsOutput {
         text: text.to_string(),
-        tool_calls: vec![],
+        tool_calls,
         id: data["request_id"].as_str().map(|v| v.to_string()),
         input_tokens: data["usage"]["input_tokens"].as_u64(),
         output_tokens: data["usage"]["output_tokens"].as_u64(),
@@ -395,3 +476,79 @@ async fn upload(model: &str, api_key: &str, url: &str) -> Result<String> {
     }
     Ok(format!("oss://{key}"))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::function::ToolCallResult;
+
+    #[test]
+    fn test_tool_calls() {
+        let model = Model::new("qianwen", "qwen-max");
+        let call = ToolCall::new(
+            "get_time".into(),
+            json!({ "tz": "UTC" }),
+            Some("call_1".into()),
+        );
+        let data = ChatCompletionsData {
+            messages: vec![
+                Message::new(MessageRole::User, MessageContent::Text("Time?".into())),
+                Message::new(
+                    MessageRole::Assistant,
+                    MessageContent::ToolResults((
+                        vec![ToolCallResult::new(call, json!("12:00"))],
+                        String::new(),
+                    )),
+                ),
+            ],
+            temperature: None,
+            top_p: None,
+            functions: Some(vec![serde_json::from_value(json!({
+                "name": "get_time",
+                "description": "Current time",
+                "parameters": { "type": "object", "properties": {} },
+            }))
+            .unwrap()]),
+            stream: false,
+        };
+        let (body, _) = build_chat_completions_body(data, &model).unwrap();
+        assert_eq!(body["parameters"]["result_format"], "message");
+        assert_eq!(
+            body["parameters"]["tools"][0]["function"]["name"],
+            "get_time"
+        );
+        let messages = body["input"]["messages"].as_array().unwrap();
+        assert_eq!(
+            messages[1]["tool_calls"][0]["function"]["arguments"],
+            r#"{"tz":"UTC"}"#
+        );
+        assert_eq!(messages[2]["role"], "tool");
+        assert_eq!(messages[2]["tool_call_id"], "call_1");
+
+        let output = extract_chat_completions_text(
+            &json!({
+                "output": {
+                    "choices": [{
+                        "finish_reason": "tool_calls",
+                        "message": {
+                            "role": "assistant",
+                            "content": "",
+                            "tool_calls": [{
+                                "id": "call_2",
+                                "type": "function",
+                                "function": { "name": "get_time", "arguments": "{\"tz\":\"CET\"}" },
+                            }],
+                        },
+                    }],
+                },
+                "usage": { "input_tokens": 20, "output_tokens": 5 },
+            }),
+            &model,
+        )
+        .unwrap();
+        assert_eq!(output.tool_calls.len(), 1);
+        assert_eq!(output.tool_calls[0].arguments, json!({ "tz": "CET" }));
+        assert_eq!(output.tool_calls[0].id.as_deref(), Some("call_2"));
+    }
+}
@@ -56,6 +56,7 @@ impl Client for ReplicateClient {
     ) -> Result<ChatCompletionsOutput> {
         let api_key = self.get_api_key()?;
         let builder = self.chat_completions_builder(client, data, &api_key)?;
+        crate::trace::capture_upstream_request(&builder);
         chat_completions(client, builder, &api_key).await
     }
 
@@ -67,6 +68,7 @@ impl Client for ReplicateClient {
     ) -> Result<()> {
         let api_key = self.get_api_key()?;
         let builder = self.chat_completions_builder(client, data, &api_key)?;
+        crate::trace::capture_upstream_request(&builder);
         chat_completions_streaming(client, builder, handler).await
     }
 }
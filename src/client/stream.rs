@@ -1,18 +1,21 @@
-use super::{catch_error, ToolCall};
-use crate::utils::AbortSignal;
+use super::{catch_error, ToolCall, ToolCallParser, ToolPromptPiece};
+use crate::utils::{random_hex, AbortSignal};
 
 use anyhow::{anyhow, bail, Context, Result};
 use futures_util::{Stream, StreamExt};
 use reqwest::RequestBuilder;
 use reqwest_eventsource::{Error as EventSourceError, Event, RequestBuilderExt};
-use serde_json::Value;
+use serde_json::{json, Value};
 use tokio::sync::mpsc::UnboundedSender;
 
 pub struct SseHandler {
     sender: UnboundedSender<SseEvent>,
     abort: AbortSignal,
     buffer: String,
-    tool_calls: Vec<ToolCall>,
+    tool_calls: Vec<StreamedToolCall>,
+    tool_call_parser: Option<ToolCallParser>,
+    input_tokens: Option<u64>,
+    output_tokens: Option<u64>,
 }
 
 impl SseHandler {
@@ -22,6 +25,25 @@ impl SseHandler {
             abort,
             buffer: String::new(),
             tool_calls: Vec::new(),
+            tool_call_parser: None,
+            input_tokens: None,
+            output_tokens: None,
+        }
+    }
+
+    /// Parses `<tool_call>` blocks out of the streamed text, see `render_tool_prompt`.
+    pub fn parse_tool_calls(&mut self) {
+        self.tool_call_parser = Some(ToolCallParser::default());
+    }
+
+    /// Flushes text held back while looking for `<tool_call>` blocks.
+    pub fn finish_tool_calls(&mut self) -> Result<()> {
+        match self.tool_call_parser.as_mut() {
+            Some(parser) => {
+                let pieces = parser.finish();
+                self.send_pieces(pieces)
+            }
+            None => Ok(()),
         }
     }
 
@@ -30,6 +52,10 @@ impl SseHandler {
         if text.is_empty() {
             return Ok(());
         }
+        if let Some(parser) = self.tool_call_parser.as_mut() {
+            let pieces = parser.push(text);
+            return self.send_pieces(pieces);
+        }
         self.buffer.push_str(text);
         let ret = self
             .sender
@@ -49,10 +75,71 @@ impl SseHandler {
         Ok(())
     }
 
+    /// A complete tool call, for providers that do not stream arguments.
     pub fn tool_call(&mut self, call: ToolCall) -> Result<()> {
         // debug!("HandleCall: {:?}", call);
-        self.tool_calls.push(call);
-        Ok(())
+        let delta = ToolCallDelta {
+            index: self.tool_calls.len(),
+            id: Some(call.id.clone().unwrap_or_else(generate_tool_call_id)),
+            name: Some(call.name.clone()),
+            arguments: call.arguments_text(),
+        };
+        self.tool_calls.push(StreamedToolCall {
+            key: None,
+            id: delta.id.clone().unwrap_or_default(),
+            name: call.name,
+            arguments: delta.arguments.clone(),
+        });
+        self.send_tool_call(delta)
+    }
+
+    /// A piece of a tool call, keyed by the provider's own index for the call.
+    pub fn tool_call_delta(
+        &mut self,
+        key: u64,
+        id: Option<&str>,
+        name: Option<&str>,
+        arguments: &str,
+    ) -> Result<()> {
+        let (index, is_new) = match self.tool_calls.iter().position(|v| v.key == Some(key)) {
+            Some(index) => (index, false),
+            None => {
+                self.tool_calls.push(StreamedToolCall {
+                    key: Some(key),
+                    id: id
+                        .map(|v| v.to_string())
+                        .unwrap_or_else(generate_tool_call_id),
+                    ..Default::default()
+                });
+                (self.tool_calls.len() - 1, true)
+            }
+        };
+        let call = &mut self.tool_calls[index];
+        if let Some(name) = name {
+            call.name.push_str(name);
+        }
+        call.arguments.push_str(arguments);
+        let delta = ToolCallDelta {
+            index,
+            id: is_new.then(|| call.id.clone()),
+            name: name.map(|v| v.to_string()),
+            arguments: arguments.to_string(),
+        };
+        self.send_tool_call(delta)
+    }
+
+    /// Token usage reported mid-stream; later reports override earlier ones.
+    pub fn usage(&mut self, input_tokens: Option<u64>, output_tokens: Option<u64>) {
+        if input_tokens.is_some() {
+            self.input_tokens = input_tokens;
+        }
+        if output_tokens.is_some() {
+            self.output_tokens = output_tokens;
+        }
+    }
+
+    pub fn get_usage(&self) -> (Option<u64>, Option<u64>) {
+        (self.input_tokens, self.output_tokens)
     }
 
     pub fn get_abort(&self) -> AbortSignal {
@@ -63,9 +150,32 @@ impl SseHandler {
         let Self {
             buffer, tool_calls, ..
         } = self;
+        let tool_calls = tool_calls
+            .into_iter()
+            .filter(|v| !v.name.is_empty())
+            .map(|v| ToolCall::new(v.name, ToolCall::parse_arguments(&v.arguments), Some(v.id)))
+            .collect();
         (buffer, tool_calls)
     }
 
+    fn send_pieces(&mut self, pieces: Vec<ToolPromptPiece>) -> Result<()> {
+        let parser = self.tool_call_parser.take();
+        let ret = pieces.into_iter().try_for_each(|piece| match piece {
+            ToolPromptPiece::Text(text) => self.text(&text),
+            ToolPromptPiece::ToolCall(call) => self.tool_call(call),
+        });
+        self.tool_call_parser = parser;
+        ret
+    }
+
+    fn send_tool_call(&self, delta: ToolCallDelta) -> Result<()> {
+        let ret = self
+            .sender
+            .send(SseEvent::ToolCall(delta))
+            .with_context(|| "Failed to send ReplyEvent::ToolCall");
+        self.safe_ret(ret)
+    }
+
     fn safe_ret(&self, ret: Result<()>) -> Result<()> {
         if ret.is_err() && self.abort.aborted() {
             return Ok(());
@@ -77,9 +187,47 @@ impl SseHandler {
 #[derive(Debug)]
 pub enum SseEvent {
     Text(String),
+    ToolCall(ToolCallDelta),
     Done,
 }
 
+/// An incremental tool call; `index` is stable across providers, `id` comes with the first piece.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToolCallDelta {
+    pub index: usize,
+    pub id: Option<String>,
+    pub name: Option<String>,
+    pub arguments: String,
+}
+
+impl ToolCallDelta {
+    /// An entry of the OpenAI `delta.tool_calls` array.
+    pub fn to_value(&self) -> Value {
+        let mut function = json!({ "arguments": self.arguments });
+        if let Some(name) = &self.name {
+            function["name"] = name.clone().into();
+        }
+        let mut value = json!({ "index": self.index, "function": function });
+        if let Some(id) = &self.id {
+            value["id"] = id.clone().into();
+            value["type"] = "function".into();
+        }
+        value
+    }
+}
+
+#[derive(Debug, Default)]
+struct StreamedToolCall {
+    key: Option<u64>,
+    id: String,
+    name: String,
+    arguments: String,
+}
+
+pub fn generate_tool_call_id() -> String {
+    format!("call_{}", random_hex(12))
+}
+
 #[derive(Debug)]
 pub struct SseMmessage {
     pub event: String,
@@ -289,4 +437,38 @@ mod tests {
 {"key": "value3"}"#;
         assert_json_stream!(input, output);
     }
+
+    #[test]
+    fn test_tool_call_deltas() {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut handler = SseHandler::new(tx, crate::utils::create_abort_signal());
+        handler
+            .tool_call_delta(3, Some("call_a"), Some("get_time"), "")
+            .unwrap();
+        handler
+            .tool_call_delta(7, Some("call_b"), Some("get_weather"), "{\"city\":")
+            .unwrap();
+        handler.tool_call_delta(3, None, None, "{}").unwrap();
+        handler
+            .tool_call_delta(7, None, None, "\"Paris\"}")
+            .unwrap();
+        let mut indexes = vec![];
+        while let Ok(SseEvent::ToolCall(delta)) = rx.try_recv() {
+            indexes.push((delta.index, delta.id));
+        }
+        assert_eq!(
+            indexes,
+            [
+                (0, Some("call_a".into())),
+                (1, Some("call_b".into())),
+                (0, None),
+                (1, None)
+            ]
+        );
+        let (_, calls) = handler.take();
+        assert_eq!(calls[0].id.as_deref(), Some("call_a"));
+        assert_eq!(calls[0].arguments, json!({}));
+        assert_eq!(calls[1].name, "get_weather");
+        assert_eq!(calls[1].arguments, json!({ "city": "Paris" }));
+    }
 }
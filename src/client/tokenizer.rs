@@ -0,0 +1,143 @@
+use crate::config::Config;
+
+use anyhow::{anyhow, Result};
+use indexmap::IndexMap;
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use std::{path::PathBuf, sync::Arc};
+use tiktoken_rs::CoreBPE;
+use tokenizers::Tokenizer as HfTokenizer;
+
+const TOKENIZERS_DIR_NAME: &str = "tokenizers";
+const CL100K_BASE: &str = "cl100k_base";
+const O200K_BASE: &str = "o200k_base";
+
+lazy_static! {
+    static ref TOKENIZERS: Mutex<IndexMap<String, Arc<Tokenizer>>> = Mutex::new(IndexMap::new());
+}
+
+/// A BPE vocabulary, either bundled (tiktoken) or loaded from a `tokenizer.json`.
+pub enum Tokenizer {
+    Bpe(CoreBPE),
+    HuggingFace(Box<HfTokenizer>),
+}
+
+impl std::fmt::Debug for Tokenizer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Tokenizer::Bpe(_) => f.write_str("Tokenizer::Bpe"),
+            Tokenizer::HuggingFace(_) => f.write_str("Tokenizer::HuggingFace"),
+        }
+    }
+}
+
+impl Tokenizer {
+    /// Resolves the `tokenizer` of a model, loading it once per name.
+    ///
+    /// `cl100k_base` and `o200k_base` are bundled. Any other name is a `tokenizer.json`, either a path
+    /// or `<config-dir>/tokenizers/<name>.json`; when that file is missing, `cl100k_base` is used instead
+    /// and a warning names the file to provide. The `mistral`, `llama3` and `qwen2` vocabularies set in
+    /// `models.yaml` are not bundled.
+    pub fn get(name: Option<&str>, model_name: &str) -> Arc<Self> {
+        let name = name.unwrap_or_else(|| default_tokenizer(model_name));
+        let mut tokenizers = TOKENIZERS.lock();
+        if let Some(tokenizer) = tokenizers.get(name) {
+            return tokenizer.clone();
+        }
+        let tokenizer = match Self::load(name) {
+            Ok(tokenizer) => Arc::new(tokenizer),
+            Err(err) => {
+                warn_fallback(&format!(
+                    "Failed to load tokenizer '{name}' for model '{model_name}', token counts fall back to {CL100K_BASE}: {err:#}"
+                ));
+                match tokenizers.get(CL100K_BASE) {
+                    Some(tokenizer) => tokenizer.clone(),
+                    None => Arc::new(Self::load(CL100K_BASE).expect("bundled vocabulary")),
+                }
+            }
+        };
+        tokenizers.insert(name.to_string(), tokenizer.clone());
+        tokenizer
+    }
+
+    fn load(name: &str) -> Result<Self> {
+        match name {
+            CL100K_BASE | "cl100k" => Ok(Self::Bpe(tiktoken_rs::cl100k_base()?)),
+            O200K_BASE | "o200k" => Ok(Self::Bpe(tiktoken_rs::o200k_base()?)),
+            _ => {
+                let path = tokenizer_file(name)?;
+                let tokenizer = HfTokenizer::from_file(&path)
+                    .map_err(|err| anyhow!("{}: {err}", path.display()))?;
+                Ok(Self::HuggingFace(Box::new(tokenizer)))
+            }
+        }
+    }
+
+    pub fn encode(&self, text: &str) -> Vec<u32> {
+        match self {
+            Tokenizer::Bpe(bpe) => bpe
+                .encode_with_special_tokens(text)
+                .into_iter()
+                .map(|v| v as u32)
+                .collect(),
+            Tokenizer::HuggingFace(tokenizer) => tokenizer
+                .encode(text, false)
+                .map(|v| v.get_ids().to_vec())
+                .unwrap_or_default(),
+        }
+    }
+
+    pub fn count(&self, text: &str) -> usize {
+        if text.is_empty() {
+            return 0;
+        }
+        match self {
+            Tokenizer::Bpe(bpe) => bpe.encode_with_special_tokens(text).len(),
+            Tokenizer::HuggingFace(tokenizer) => tokenizer
+                .encode(text, false)
+                .map(|v| v.len())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Logs the warning, or prints it when no logger runs (outside serve mode in release builds).
+fn warn_fallback(message: &str) {
+    if log::max_level() >= log::LevelFilter::Warn {
+        warn!("{message}");
+    } else {
+        eprintln!("Warning: {message}");
+    }
+}
+
+fn tokenizer_file(name: &str) -> Result<PathBuf> {
+    if name.ends_with(".json") {
+        let path = PathBuf::from(name);
+        if path.is_absolute() {
+            return Ok(path);
+        }
+        return Config::local_path(name);
+    }
+    Ok(Config::local_path(TOKENIZERS_DIR_NAME)?.join(format!("{name}.json")))
+}
+
+fn default_tokenizer(model_name: &str) -> &'static str {
+    if model_name.starts_with("gpt-4o") || model_name.starts_with("o1") {
+        O200K_BASE
+    } else {
+        CL100K_BASE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bundled_tokenizers() {
+        let text = "Hello, world! How are you today?";
+        assert_eq!(Tokenizer::get(Some(CL100K_BASE), "").count(text), 9);
+        assert_eq!(Tokenizer::get(None, "gpt-4o").count(text), 9);
+        assert_eq!(Tokenizer::get(Some("missing-vocab"), "").count(""), 0);
+    }
+}
@@ -0,0 +1,212 @@
+//! Tool calling for models without native support: tools are described in the system prompt and
+//! calls are parsed out of `<tool_call>` blocks in the completion.
+
+use super::{ChatCompletionsData, Message, MessageContent, MessageRole, ToolCall};
+
+use crate::function::repair_json;
+
+use serde_json::{json, Value};
+
+const TOOL_CALL_START: &str = "<tool_call>";
+const TOOL_CALL_END: &str = "</tool_call>";
+
+const TOOL_PROMPT: &str = r#"You can call the tools listed below. To call a tool, reply with one block per call, in exactly this form:
+<tool_call>
+{"name": "<tool name>", "arguments": {<arguments as a JSON object>}}
+</tool_call>
+Stop after the blocks; the results come back in <tool_result> blocks. Answer directly when no tool is needed.
+
+Tools:
+"#;
+
+/// Moves `data.functions` into the system prompt and rewrites tool results as plain messages.
+pub fn render_tool_prompt(data: &mut ChatCompletionsData) {
+    let Some(functions) = data.functions.take() else {
+        return;
+    };
+    let tools: Vec<String> = functions
+        .iter()
+        .map(|v| serde_json::to_string(v).unwrap_or_default())
+        .collect();
+    let prompt = format!("{TOOL_PROMPT}{}", tools.join("\n"));
+    match data.messages.first_mut() {
+        Some(Message {
+            role: MessageRole::System,
+            content: MessageContent::Text(system),
+        }) => {
+            *system = format!("{system}\n\n{prompt}");
+        }
+        _ => data.messages.insert(
+            0,
+            Message::new(MessageRole::System, MessageContent::Text(prompt)),
+        ),
+    }
+    let messages = std::mem::take(&mut data.messages);
+    for message in messages {
+        let MessageContent::ToolResults((results, text)) = message.content else {
+            data.messages.push(message);
+            continue;
+        };
+        let mut calls = vec![text];
+        let mut outputs = vec![];
+        for result in results {
+            let call =
+                json!({ "name": result.call.name, "arguments": result.call.arguments_object() });
+            calls.push(format!("{TOOL_CALL_START}\n{call}\n{TOOL_CALL_END}"));
+            outputs.push(format!(
+                "<tool_result name=\"{}\">\n{}\n</tool_result>",
+                result.call.name, result.output
+            ));
+        }
+        let calls: Vec<String> = calls.into_iter().filter(|v| !v.is_empty()).collect();
+        data.messages.push(Message::new(
+            MessageRole::Assistant,
+            MessageContent::Text(calls.join("\n")),
+        ));
+        data.messages.push(Message::new(
+            MessageRole::User,
+            MessageContent::Text(outputs.join("\n")),
+        ));
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ToolPromptPiece {
+    Text(String),
+    ToolCall(ToolCall),
+}
+
+/// Splits completion text into plain text and tool calls, holding back anything that may still
+/// turn into a `<tool_call>` block.
+#[derive(Debug, Default)]
+pub struct ToolCallParser {
+    pending: String,
+    in_block: bool,
+}
+
+impl ToolCallParser {
+    pub fn push(&mut self, text: &str) -> Vec<ToolPromptPiece> {
+        self.pending.push_str(text);
+        let mut pieces = vec![];
+        loop {
+            if self.in_block {
+                let Some(end) = self.pending.find(TOOL_CALL_END) else {
+                    break;
+                };
+                let block: String = self.pending.drain(..end + TOOL_CALL_END.len()).collect();
+                pieces.push(parse_block(&block[..end]));
+                self.in_block = false;
+            } else if let Some(start) = self.pending.find(TOOL_CALL_START) {
+                let text: String = self.pending.drain(..start).collect();
+                push_text(&mut pieces, text);
+                self.pending.drain(..TOOL_CALL_START.len());
+                self.in_block = true;
+            } else {
+                let keep = partial_marker_len(&self.pending);
+                let text: String = self.pending.drain(..self.pending.len() - keep).collect();
+                push_text(&mut pieces, text);
+                break;
+            }
+        }
+        pieces
+    }
+
+    /// Flushes the held-back text; an unclosed block still counts as a call when it parses.
+    pub fn finish(&mut self) -> Vec<ToolPromptPiece> {
+        let pending = std::mem::take(&mut self.pending);
+        let mut pieces = vec![];
+        if std::mem::take(&mut self.in_block) {
+            match parse_block(&pending) {
+                ToolPromptPiece::Text(_) => {
+                    push_text(&mut pieces, format!("{TOOL_CALL_START}{pending}"))
+                }
+                call => pieces.push(call),
+            }
+        } else {
+            push_text(&mut pieces, pending);
+        }
+        pieces
+    }
+}
+
+/// Parses the tool calls out of a whole completion, returning the remaining text.
+pub fn extract_tool_calls(text: &str) -> (String, Vec<ToolCall>) {
+    let mut parser = ToolCallParser::default();
+    let mut pieces = parser.push(text);
+    pieces.extend(parser.finish());
+    let (mut text, mut calls) = (String::new(), vec![]);
+    for piece in pieces {
+        match piece {
+            ToolPromptPiece::Text(v) => text.push_str(&v),
+            ToolPromptPiece::ToolCall(call) => calls.push(call),
+        }
+    }
+    (text.trim().to_string(), calls)
+}
+
+fn parse_block(block: &str) -> ToolPromptPiece {
+    let value = serde_json::from_str::<Value>(block.trim())
+        .ok()
+        .or_else(|| repair_json(block));
+    match value {
+        Some(value) if value["name"].is_string() => {
+            let name = value["name"].as_str().unwrap_or_default().to_string();
+            let arguments = match &value["arguments"] {
+                Value::String(raw) => ToolCall::parse_arguments(raw),
+                Value::Null => json!({}),
+                arguments => arguments.clone(),
+            };
+            ToolPromptPiece::ToolCall(ToolCall::new(name, arguments, None))
+        }
+        _ => ToolPromptPiece::Text(format!("{TOOL_CALL_START}{block}{TOOL_CALL_END}")),
+    }
+}
+
+fn push_text(pieces: &mut Vec<ToolPromptPiece>, text: String) {
+    if !text.is_empty() {
+        pieces.push(ToolPromptPiece::Text(text));
+    }
+}
+
+/// Length of the longest suffix of `text` that is a prefix of the start marker.
+fn partial_marker_len(text: &str) -> usize {
+    (1..TOOL_CALL_START.len())
+        .rev()
+        .find(|&n| text.ends_with(&TOOL_CALL_START[..n]))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tool_call_parser() {
+        let mut parser = ToolCallParser::default();
+        let mut pieces = vec![];
+        for chunk in [
+            "Let me check.\n<tool",
+            "_call>\n{\"name\": \"get_time\", \"arguments\": {\"tz\": ",
+            "\"UTC\"}}\n</tool_call>\n<tool_call>{\"name\": \"now\"",
+        ] {
+            pieces.extend(parser.push(chunk));
+        }
+        pieces.extend(parser.finish());
+        assert_eq!(
+            pieces,
+            [
+                ToolPromptPiece::Text("Let me check.\n".into()),
+                ToolPromptPiece::ToolCall(ToolCall::new(
+                    "get_time".into(),
+                    json!({ "tz": "UTC" }),
+                    None
+                )),
+                ToolPromptPiece::Text("\n".into()),
+                ToolPromptPiece::ToolCall(ToolCall::new("now".into(), json!({}), None)),
+            ]
+        );
+        let (text, calls) = extract_tool_calls("a < b <tool_call>oops</tool_call>");
+        assert_eq!(text, "a < b <tool_call>oops</tool_call>");
+        assert!(calls.is_empty());
+    }
+}
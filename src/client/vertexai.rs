@@ -1,13 +1,22 @@
 use super::access_token::*;
 use super::*;
 
+use crate::function::{FunctionDeclaration, JsonSchema, JsonSchemaType};
+use crate::utils::sign_jwt;
+
 use anyhow::{anyhow, bail, Context, Result};
 use async_trait::async_trait;
-use chrono::{Duration, Utc};
+use chrono::{DateTime, Duration, Utc};
 use reqwest::{Client as ReqwestClient, RequestBuilder};
 use serde::Deserialize;
 use serde_json::{json, Value};
-use std::path::PathBuf;
+use std::{env, path::PathBuf};
+
+const GOOGLE_TOKEN_URL: &str = "https://oauth2.googleapis.com/token";
+const GOOGLE_STS_URL: &str = "https://sts.googleapis.com/v1/token";
+const GCE_METADATA_HOST: &str = "metadata.google.internal";
+const CLOUD_PLATFORM_SCOPE: &str = "https://www.googleapis.com/auth/cloud-platform";
+const TOKEN_REFRESH_MARGIN: i64 = 300;
 
 #[derive(Debug, Clone, Deserialize, Default)]
 pub struct VertexAIConfig {
@@ -101,6 +110,7 @@ impl Client for VertexAIClient {
     ) -> Result<ChatCompletionsOutput> {
         prepare_gcloud_access_token(client, self.name(), &self.config.adc_file).await?;
         let builder = self.chat_completions_builder(client, data)?;
+        crate::trace::capture_upstream_request(&builder);
         gemini_chat_completions(builder).await
     }
 
@@ -112,6 +122,7 @@ impl Client for VertexAIClient {
     ) -> Result<()> {
         prepare_gcloud_access_token(client, self.name(), &self.config.adc_file).await?;
         let builder = self.chat_completions_builder(client, data)?;
+        crate::trace::capture_upstream_request(&builder);
         gemini_chat_completions_streaming(builder, handler).await
     }
 
@@ -150,24 +161,28 @@ pub async fn gemini_chat_completions_streaming(
         let handle = |value: &str| -> Result<()> {
             let data: Value = serde_json::from_str(value)?;
             debug!("stream-data: {data}");
-            if let Some(text) = data["candidates"][0]["content"]["parts"][0]["text"].as_str() {
-                if !text.is_empty() {
-                    handler.text(text)?;
-                }
-            } else if let Some("SAFETY") = data["promptFeedback"]["blockReason"]
-                .as_str()
-                .or_else(|| data["candidates"][0]["finishReason"].as_str())
-            {
-                bail!("Content Blocked")
-            } else if let Some(parts) = data["candidates"][0]["content"]["parts"].as_array() {
+            if data["usageMetadata"].is_object() {
+                handler.usage(
+                    data["usageMetadata"]["promptTokenCount"].as_u64(),
+                    data["usageMetadata"]["candidatesTokenCount"].as_u64(),
+                );
+            }
+            if let Some(parts) = data["candidates"][0]["content"]["parts"].as_array() {
                 for part in parts {
-                    if let (Some(name), Some(args)) = (
+                    if let Some(text) = part["text"].as_str() {
+                        handler.text(text)?;
+                    } else if let (Some(name), Some(args)) = (
                         part["functionCall"]["name"].as_str(),
                         part["functionCall"]["args"].as_object(),
                     ) {
                         handler.tool_call(ToolCall::new(name.to_string(), json!(args), None))?;
                     }
                 }
+            } else if let Some("SAFETY") = data["promptFeedback"]["blockReason"]
+                .as_str()
+                .or_else(|| data["candidates"][0]["finishReason"].as_str())
+            {
+                bail!("Content Blocked")
             }
 
             Ok(())
@@ -300,7 +315,7 @@ pub fn gemini_build_chat_completions_body(
                             json!({
                                 "functionCall": {
                                     "name": tool_call_result.call.name,
-                                    "args": tool_call_result.call.arguments,
+                                    "args": tool_call_result.call.arguments_object(),
                                 }
                             })
                         }).collect();
@@ -344,75 +359,393 @@ pub fn gemini_build_chat_completions_body(
     }
 
     if let Some(functions) = functions {
-        body["tools"] = json!([{ "functionDeclarations": *functions }]);
+        let declarations: Vec<Value> = functions.iter().map(gemini_function).collect();
+        body["tools"] = json!([{ "functionDeclarations": declarations }]);
     }
 
     Ok(body)
 }
 
+/// Gemini takes an OpenAPI subset of JSON Schema: no `$ref`, one type plus `nullable`, string enums.
+fn gemini_function(function: &FunctionDeclaration) -> Value {
+    let (parameters, mut dropped) = function.parameters.inline_refs();
+    let parameters = gemini_schema(parameters, "parameters", &mut dropped);
+    function.warn_lossy("Gemini", &dropped);
+    json!({
+        "name": function.name,
+        "description": function.description,
+        "parameters": parameters,
+    })
+}
+
+const GEMINI_SCHEMA_KEYWORDS: [&str; 14] = [
+    "format",
+    "nullable",
+    "title",
+    "example",
+    "minItems",
+    "maxItems",
+    "minProperties",
+    "maxProperties",
+    "minLength",
+    "maxLength",
+    "pattern",
+    "minimum",
+    "maximum",
+    "propertyOrdering",
+];
+
+fn gemini_schema(mut schema: JsonSchema, path: &str, dropped: &mut Vec<String>) -> JsonSchema {
+    for variant in schema.all_of.take().into_iter().flatten() {
+        let required = schema.required.get_or_insert_with(Vec::new);
+        required.extend(variant.required.clone().unwrap_or_default());
+        if let Some(properties) = variant.properties.clone() {
+            schema
+                .properties
+                .get_or_insert_with(Default::default)
+                .extend(properties);
+        }
+        schema.type_value = schema.type_value.or(variant.type_value);
+        schema.description = schema.description.or(variant.description);
+    }
+    if let Some(one_of) = schema.one_of.take() {
+        dropped.push(format!("{path}.oneOf (sent as anyOf)"));
+        schema.any_of.get_or_insert_with(Vec::new).extend(one_of);
+    }
+    let mut nullable = false;
+    if let Some(mut any_of) = schema.any_of.take() {
+        any_of.retain(|v| {
+            let is_null = v.types() == ["null"];
+            nullable |= is_null;
+            !is_null
+        });
+        if any_of.len() == 1 {
+            let mut variant = any_of.remove(0);
+            variant.description = schema.description.take().or(variant.description);
+            variant.default = schema.default.take().or(variant.default);
+            for (key, value) in std::mem::take(&mut schema.extra) {
+                variant.extra.entry(key).or_insert(value);
+            }
+            schema = variant;
+        } else if !any_of.is_empty() {
+            schema.any_of = Some(any_of);
+        }
+    }
+    nullable |= schema.types().contains(&"null");
+    if let Some(JsonSchemaType::Multiple(types)) = &schema.type_value {
+        let mut types = types.iter().filter(|v| *v != "null");
+        let first = types.next().cloned();
+        if types.next().is_some() {
+            dropped.push(format!("{path}.type"));
+        }
+        schema.type_value = first.map(JsonSchemaType::Single);
+    }
+    if let Some(value) = schema.extra.shift_remove("const") {
+        schema.enum_value = Some(vec![value]);
+    }
+    if let Some(values) = &schema.enum_value {
+        if !values.iter().all(|v| v.is_string()) {
+            dropped.push(format!("{path}.enum"));
+            schema.enum_value = None;
+        }
+    }
+    if let Some(format) = schema.extra.shift_remove("format") {
+        let supported = match schema.types().first() {
+            Some(&"string") => ["enum", "date-time"].as_slice(),
+            Some(&"number") => &["float", "double"],
+            Some(&"integer") => &["int32", "int64"],
+            _ => &[],
+        };
+        if format.as_str().is_some_and(|v| supported.contains(&v)) {
+            schema.extra.insert("format".into(), format);
+        } else {
+            dropped.push(format!("{path}.format"));
+        }
+    }
+    if let Some(value) = schema.additional_properties.take() {
+        if value != Value::Bool(false) {
+            dropped.push(format!("{path}.additionalProperties"));
+        }
+    }
+    schema.extra.retain(|key, _| {
+        let keep = GEMINI_SCHEMA_KEYWORDS.contains(&key.as_str());
+        if !keep && !key.starts_with('$') {
+            dropped.push(format!("{path}.{key}"));
+        }
+        keep
+    });
+    if nullable {
+        schema.extra.insert("nullable".into(), true.into());
+    }
+    schema.for_each_child_mut(|segment, child| {
+        *child = gemini_schema(std::mem::take(child), &format!("{path}.{segment}"), dropped);
+    });
+    schema
+}
+
+/// Fetches an access token, cached until shortly before it expires.
 pub async fn prepare_gcloud_access_token(
     client: &reqwest::Client,
     client_name: &str,
     adc_file: &Option<String>,
 ) -> Result<()> {
-    if !is_valid_access_token(client_name) {
-        let (token, expires_in) = fetch_access_token(client, adc_file)
-            .await
-            .with_context(|| "Failed to fetch access token")?;
-        let expires_at = Utc::now()
-            + Duration::try_seconds(expires_in)
-                .ok_or_else(|| anyhow!("Failed to parse expires_in of access_token"))?;
-        set_access_token(client_name, token, expires_at.timestamp())
+    if is_valid_access_token(client_name) {
+        return Ok(());
+    }
+    let lock = refresh_lock(client_name);
+    let _guard = lock.lock().await;
+    // Refreshed by another request while waiting.
+    if is_valid_access_token(client_name) {
+        return Ok(());
     }
+    let (token, expires_in) = fetch_access_token(client, adc_file)
+        .await
+        .with_context(|| "Failed to fetch access token")?;
+    let expires_at = Utc::now()
+        + Duration::try_seconds(expires_in)
+            .ok_or_else(|| anyhow!("Failed to parse expires_in of access_token"))?;
+    set_access_token(
+        client_name,
+        token,
+        expires_at.timestamp() - TOKEN_REFRESH_MARGIN,
+    );
     Ok(())
 }
 
+/// Picks the token flow from the `type` of the credentials file; without one, asks the GCE
+/// metadata server.
 async fn fetch_access_token(
     client: &reqwest::Client,
     file: &Option<String>,
 ) -> Result<(String, i64)> {
-    let credentials = load_adc(file).await?;
+    let Some(credentials) = load_adc(file).await? else {
+        return fetch_metadata_access_token(client)
+            .await
+            .with_context(|| "No application_default_credentials.json and no GCE metadata server");
+    };
+    match credentials["type"].as_str().unwrap_or("authorized_user") {
+        "authorized_user" => fetch_user_access_token(client, &credentials).await,
+        "service_account" => fetch_service_account_access_token(client, &credentials).await,
+        "external_account" => fetch_external_account_access_token(client, &credentials).await,
+        kind => bail!("Unsupported credentials type '{kind}'"),
+    }
+}
+
+async fn fetch_user_access_token(
+    client: &reqwest::Client,
+    credentials: &Value,
+) -> Result<(String, i64)> {
+    let (Some(client_id), Some(client_secret), Some(refresh_token)) = (
+        credentials["client_id"].as_str(),
+        credentials["client_secret"].as_str(),
+        credentials["refresh_token"].as_str(),
+    ) else {
+        bail!("Invalid application_default_credentials.json")
+    };
     let value: Value = client
-        .post("https://oauth2.googleapis.com/token")
-        .json(&credentials)
+        .post(GOOGLE_TOKEN_URL)
+        .json(&json!({
+            "client_id": client_id,
+            "client_secret": client_secret,
+            "refresh_token": refresh_token,
+            "grant_type": "refresh_token",
+        }))
         .send()
         .await?
         .json()
         .await?;
+    parse_token_response(&value)
+}
 
+async fn fetch_service_account_access_token(
+    client: &reqwest::Client,
+    credentials: &Value,
+) -> Result<(String, i64)> {
+    let (Some(client_email), Some(private_key)) = (
+        credentials["client_email"].as_str(),
+        credentials["private_key"].as_str(),
+    ) else {
+        bail!("Invalid service_account credentials")
+    };
+    let token_uri = credentials["token_uri"]
+        .as_str()
+        .unwrap_or(GOOGLE_TOKEN_URL);
+    let now = Utc::now().timestamp();
+    let mut header = json!({ "alg": "RS256", "typ": "JWT" });
+    if let Some(key_id) = credentials["private_key_id"].as_str() {
+        header["kid"] = key_id.into();
+    }
+    let claims = json!({
+        "iss": client_email,
+        "scope": CLOUD_PLATFORM_SCOPE,
+        "aud": token_uri,
+        "iat": now,
+        "exp": now + 3600,
+    });
+    let assertion = sign_jwt(&header, &claims, private_key)?;
+    let value: Value = client
+        .post(token_uri)
+        .form(&[
+            ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+            ("assertion", &assertion),
+        ])
+        .send()
+        .await?
+        .json()
+        .await?;
+    parse_token_response(&value)
+}
+
+/// Exchanges a workload identity federation subject token at STS, then impersonates the service
+/// account when one is configured.
+async fn fetch_external_account_access_token(
+    client: &reqwest::Client,
+    credentials: &Value,
+) -> Result<(String, i64)> {
+    let Some(audience) = credentials["audience"].as_str() else {
+        bail!("Invalid external_account credentials")
+    };
+    let subject_token = read_subject_token(client, &credentials["credential_source"])
+        .await
+        .with_context(|| "Failed to read the subject token of external_account credentials")?;
+    let subject_token_type = credentials["subject_token_type"]
+        .as_str()
+        .unwrap_or("urn:ietf:params:oauth:token-type:jwt");
+    let mut form = vec![
+        (
+            "grant_type",
+            "urn:ietf:params:oauth:grant-type:token-exchange".to_string(),
+        ),
+        ("audience", audience.to_string()),
+        ("scope", CLOUD_PLATFORM_SCOPE.to_string()),
+        (
+            "requested_token_type",
+            "urn:ietf:params:oauth:token-type:access_token".to_string(),
+        ),
+        ("subject_token_type", subject_token_type.to_string()),
+        ("subject_token", subject_token),
+    ];
+    if let Some(project) = credentials["workforce_pool_user_project"].as_str() {
+        form.push(("options", json!({ "userProject": project }).to_string()));
+    }
+    let token_url = credentials["token_url"].as_str().unwrap_or(GOOGLE_STS_URL);
+    let value: Value = client
+        .post(token_url)
+        .form(&form)
+        .send()
+        .await?
+        .json()
+        .await?;
+    let (token, expires_in) = parse_token_response(&value)?;
+
+    let Some(url) = credentials["service_account_impersonation_url"].as_str() else {
+        return Ok((token, expires_in));
+    };
+    let value: Value = client
+        .post(url)
+        .bearer_auth(token)
+        .json(&json!({ "scope": [CLOUD_PLATFORM_SCOPE] }))
+        .send()
+        .await?
+        .json()
+        .await?;
+    if let (Some(access_token), Some(expire_time)) =
+        (value["accessToken"].as_str(), value["expireTime"].as_str())
+    {
+        let expires_at = DateTime::parse_from_rfc3339(expire_time)?.timestamp();
+        Ok((
+            access_token.to_string(),
+            expires_at - Utc::now().timestamp(),
+        ))
+    } else {
+        parse_token_response(&value)
+    }
+}
+
+async fn fetch_metadata_access_token(client: &reqwest::Client) -> Result<(String, i64)> {
+    let host = env::var("GCE_METADATA_HOST").unwrap_or_else(|_| GCE_METADATA_HOST.into());
+    let value: Value = client
+        .get(format!(
+            "http://{host}/computeMetadata/v1/instance/service-accounts/default/token"
+        ))
+        .header("Metadata-Flavor", "Google")
+        .timeout(std::time::Duration::from_secs(3))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    parse_token_response(&value)
+}
+
+async fn read_subject_token(client: &reqwest::Client, source: &Value) -> Result<String> {
+    let data = if let Some(file) = source["file"].as_str() {
+        tokio::fs::read_to_string(file)
+            .await
+            .with_context(|| format!("Failed to read '{file}'"))?
+    } else if let Some(url) = source["url"].as_str() {
+        let mut builder = client.get(url);
+        if let Some(headers) = source["headers"].as_object() {
+            for (key, value) in headers {
+                if let Some(value) = value.as_str() {
+                    builder = builder.header(key, value);
+                }
+            }
+        }
+        builder.send().await?.error_for_status()?.text().await?
+    } else {
+        bail!("Unsupported credential_source, only file and url sources are supported")
+    };
+    parse_subject_token(&data, &source["format"])
+}
+
+fn parse_subject_token(data: &str, format: &Value) -> Result<String> {
+    if format["type"].as_str() != Some("json") {
+        return Ok(data.trim().to_string());
+    }
+    let field = format["subject_token_field_name"]
+        .as_str()
+        .ok_or_else(|| anyhow!("Missing subject_token_field_name"))?;
+    let data: Value = serde_json::from_str(data)?;
+    data[field]
+        .as_str()
+        .map(|v| v.to_string())
+        .ok_or_else(|| anyhow!("No '{field}' in the subject token"))
+}
+
+fn parse_token_response(value: &Value) -> Result<(String, i64)> {
     if let (Some(access_token), Some(expires_in)) =
         (value["access_token"].as_str(), value["expires_in"].as_i64())
     {
         Ok((access_token.to_string(), expires_in))
     } else if let Some(err_msg) = value["error_description"].as_str() {
         bail!("{err_msg}")
+    } else if let Some(err_msg) = value["error"]["message"].as_str() {
+        bail!("{err_msg}")
     } else {
         bail!("Invalid response data: {value}")
     }
 }
 
-async fn load_adc(file: &Option<String>) -> Result<Value> {
-    let adc_file = file
-        .as_ref()
-        .map(PathBuf::from)
-        .or_else(default_adc_file)
-        .ok_or_else(|| anyhow!("No application_default_credentials.json"))?;
-    let data = tokio::fs::read_to_string(adc_file).await?;
-    let data: Value = serde_json::from_str(&data)?;
-    if let (Some(client_id), Some(client_secret), Some(refresh_token)) = (
-        data["client_id"].as_str(),
-        data["client_secret"].as_str(),
-        data["refresh_token"].as_str(),
-    ) {
-        Ok(json!({
-            "client_id": client_id,
-            "client_secret": client_secret,
-            "refresh_token": refresh_token,
-            "grant_type": "refresh_token",
-        }))
-    } else {
-        bail!("Invalid application_default_credentials.json")
-    }
+/// Reads `adc_file`, then GOOGLE_APPLICATION_CREDENTIALS, then the gcloud default file.
+async fn load_adc(file: &Option<String>) -> Result<Option<Value>> {
+    let adc_file = match file.clone().or_else(|| {
+        env::var("GOOGLE_APPLICATION_CREDENTIALS")
+            .ok()
+            .filter(|v| !v.is_empty())
+    }) {
+        Some(file) => PathBuf::from(file),
+        None => match default_adc_file().filter(|v| v.exists()) {
+            Some(file) => file,
+            None => return Ok(None),
+        },
+    };
+    let data = tokio::fs::read_to_string(&adc_file)
+        .await
+        .with_context(|| format!("Failed to read '{}'", adc_file.display()))?;
+    let data = serde_json::from_str(&data)
+        .with_context(|| format!("Invalid credentials file '{}'", adc_file.display()))?;
+    Ok(Some(data))
 }
 
 #[cfg(not(windows))]
@@ -431,3 +764,58 @@ fn default_adc_file() -> Option<PathBuf> {
     path.push("application_default_credentials.json");
     Some(path)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gemini_schema() {
+        let schema: JsonSchema = serde_json::from_value(json!({
+            "type": "object",
+            "properties": {
+                "when": { "type": ["string", "null"], "format": "date-time" },
+                "mode": { "anyOf": [{ "type": "string", "const": "car" }, { "type": "null" }], "default": null },
+                "count": { "type": "integer", "exclusiveMinimum": 0, "maximum": 9 },
+                "tags": { "type": "object", "additionalProperties": { "type": "string" } },
+            },
+            "additionalProperties": false,
+        }))
+        .unwrap();
+        let mut dropped = vec![];
+        let schema = gemini_schema(schema, "parameters", &mut dropped);
+        assert_eq!(
+            serde_json::to_value(&schema).unwrap(),
+            json!({
+                "type": "object",
+                "properties": {
+                    "when": { "type": "string", "format": "date-time", "nullable": true },
+                    "mode": { "type": "string", "enum": ["car"], "default": null, "nullable": true },
+                    "count": { "type": "integer", "maximum": 9 },
+                    "tags": { "type": "object" },
+                },
+            })
+        );
+        assert_eq!(
+            dropped,
+            vec![
+                "parameters.properties.count.exclusiveMinimum",
+                "parameters.properties.tags.additionalProperties",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_subject_token() {
+        assert_eq!(
+            parse_subject_token(" eyJ.a.b\n", &Value::Null).unwrap(),
+            "eyJ.a.b"
+        );
+        let format = json!({ "type": "json", "subject_token_field_name": "id_token" });
+        assert_eq!(
+            parse_subject_token(r#"{"id_token":"eyJ.c.d"}"#, &format).unwrap(),
+            "eyJ.c.d"
+        );
+        assert!(parse_subject_token(r#"{"token":"x"}"#, &format).is_err());
+    }
+}
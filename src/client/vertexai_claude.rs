@@ -70,6 +70,7 @@ impl Client for VertexAIClaudeClient {
     ) -> Result<ChatCompletionsOutput> {
         prepare_gcloud_access_token(client, self.name(), &self.config.adc_file).await?;
         let builder = self.chat_completions_builder(client, data)?;
+        crate::trace::capture_upstream_request(&builder);
         claude_chat_completions(builder).await
     }
 
@@ -81,6 +82,7 @@ impl Client for VertexAIClaudeClient {
     ) -> Result<()> {
         prepare_gcloud_access_token(client, self.name(), &self.config.adc_file).await?;
         let builder = self.chat_completions_builder(client, data)?;
+        crate::trace::capture_upstream_request(&builder);
         claude_chat_completions_streaming(builder, handler).await
     }
 }
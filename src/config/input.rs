@@ -167,7 +167,6 @@ impl Input {
             bail!("The current model does not support vision. Is the model configured with `supports_vision: true`?");
         }
         let messages = self.build_messages()?;
-        self.config.read().model.guard_max_input_tokens(&messages)?;
         let (temperature, top_p) = if let Some(session) = self.session(&self.config.read().session)
         {
             (session.temperature(), session.top_p())
@@ -190,6 +189,7 @@ impl Input {
                 }
             }
         };
+        model.guard_max_input_tokens(&messages, functions.as_deref())?;
         Ok(ChatCompletionsData {
             messages,
             temperature,
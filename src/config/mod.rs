@@ -2,15 +2,18 @@ mod input;
 mod session;
 
 pub use self::input::{Input, InputContext};
-use self::session::{Session, TEMP_SESSION_NAME};
+pub use self::session::Session;
+use self::session::TEMP_SESSION_NAME;
 
 use crate::client::{
-    create_client_config, list_chat_models, list_client_types, ClientConfig, Model,
-    OPENAI_COMPATIBLE_PLATFORMS,
+    create_client_config, list_chat_models, list_client_types, ClientConfig, MaxTokensPolicy,
+    Model, OPENAI_COMPATIBLE_PLATFORMS,
 };
-use crate::function::{Function, ToolCallResult};
+use crate::function::{Function, FunctionExecution, ToolCallResult, ToolCallValidation};
+use crate::mcp::McpServerConfig;
+use crate::trace::TraceConfig;
 use crate::utils::{
-    format_option_value, get_env_name, now, 
+    format_option_value, get_env_name, now, resolve_secret_refs,
     set_text, 
 };
 
@@ -32,14 +35,17 @@ const CONFIG_FILE_NAME: &str = "config.yaml";
 const MESSAGES_FILE_NAME: &str = "messages.md";
 const SESSIONS_DIR_NAME: &str = "sessions";
 const FUNCTIONS_DIR_NAME: &str = "functions";
+const RAGS_DIR_NAME: &str = "rags";
+const FILES_DIR_NAME: &str = "files";
+const VECTOR_STORES_DIR_NAME: &str = "vector_stores";
 
 const CLIENTS_FIELD: &str = "clients";
 
-const SUMMARIZE_PROMPT: &str =
+pub const SUMMARIZE_PROMPT: &str =
     "Summarize the discussion briefly in 200 words or less to use as a prompt for future context.";
-const SUMMARY_PROMPT: &str = "This is a summary of the chat history as a recap: ";
+pub const SUMMARY_PROMPT: &str = "This is a summary of the chat history as a recap: ";
 
-const RAG_TEMPLATE: &str = r#"Answer the following question based only on the provided context:
+pub const RAG_TEMPLATE: &str = r#"Answer the following question based only on the provided context:
 <context>
 __CONTEXT__
 </context>
@@ -61,7 +67,13 @@ pub struct Config {
     pub save: bool,
     pub save_session: Option<bool>,
     pub function_calling: bool,
+    pub max_tokens_policy: MaxTokensPolicy,
+    pub function_execution: FunctionExecution,
+    pub tool_call_validation: ToolCallValidation,
+    pub mcp_servers: Vec<McpServerConfig>,
+    pub documents_dir: Option<String>,
     pub clients: Vec<ClientConfig>,
+    pub trace: TraceConfig,
     #[serde(skip)]
     pub session: Option<Session>,
     #[serde(skip)]
@@ -81,7 +93,13 @@ impl Default for Config {
             save: false,
             save_session: None,
             function_calling: false,
+            max_tokens_policy: Default::default(),
+            function_execution: Default::default(),
+            tool_call_validation: Default::default(),
+            mcp_servers: vec![],
+            documents_dir: None,
             clients: vec![],
+            trace: Default::default(),
             session: None,
             model: Default::default(),
             function: Default::default(),
@@ -196,6 +214,33 @@ impl Config {
         Ok(path)
     }
 
+    pub fn rags_dir() -> Result<PathBuf> {
+        match env::var(get_env_name("rags_dir")) {
+            Ok(value) => Ok(PathBuf::from(value)),
+            Err(_) => Self::local_path(RAGS_DIR_NAME),
+        }
+    }
+
+    pub fn rag_file(name: &str) -> Result<PathBuf> {
+        let mut path = Self::rags_dir()?;
+        path.push(format!("{name}.bin"));
+        Ok(path)
+    }
+
+    pub fn files_dir() -> Result<PathBuf> {
+        match env::var(get_env_name("files_dir")) {
+            Ok(value) => Ok(PathBuf::from(value)),
+            Err(_) => Self::local_path(FILES_DIR_NAME),
+        }
+    }
+
+    pub fn vector_stores_dir() -> Result<PathBuf> {
+        match env::var(get_env_name("vector_stores_dir")) {
+            Ok(value) => Ok(PathBuf::from(value)),
+            Err(_) => Self::local_path(VECTOR_STORES_DIR_NAME),
+        }
+    }
+
     pub fn state(&self) -> StateFlags {
         let mut flags = StateFlags::empty();
         if let Some(session) = &self.session {
@@ -280,6 +325,8 @@ impl Config {
             ("messages_file", display_path(&Self::messages_file()?)),
             ("sessions_dir", display_path(&Self::sessions_dir()?)),
             ("functions_dir", display_path(&Self::functions_dir()?)),
+            ("trace_file", display_path(&self.trace.trace_file()?)),
+            ("trace_store_file", display_path(&self.trace.store_file()?)),
         ];
         let output = items
             .iter()
@@ -500,7 +547,10 @@ impl Config {
     fn load_config_file(config_path: &Path) -> Result<Self> {
         let content = read_to_string(config_path)
             .with_context(|| format!("Failed to load config at {}", config_path.display()))?;
-        let config: Self = serde_yaml::from_str(&content).map_err(|err| {
+        let mut value: serde_yaml::Value =
+            serde_yaml::from_str(&content).map_err(|err| anyhow!("{err}"))?;
+        resolve_secret_refs(&mut value)?;
+        let config: Self = serde_yaml::from_value(value).map_err(|err| {
             let err_msg = err.to_string();
             let err_msg = if err_msg.starts_with(&format!("{}: ", CLIENTS_FIELD)) {
                 // location is incorrect, get rid of it
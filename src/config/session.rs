@@ -111,6 +111,10 @@ impl Session {
         self.save_session
     }
 
+    pub fn messages(&self) -> &[Message] {
+        &self.messages
+    }
+
     pub fn need_compress(&self, current_compress_threshold: usize) -> bool {
         let threshold = self
             .compress_threshold
@@ -355,17 +359,29 @@ impl Session {
     }
 
     pub fn add_message(&mut self, input: &Input, output: &str) -> Result<()> {
-        let mut need_add_msg = true;
-        if need_add_msg {
-            self.messages
-                .push(Message::new(MessageRole::User, input.message_content()));
-        }
         self.data_urls.extend(input.data_urls());
+        self.add_turn(input.message_content(), output);
+        Ok(())
+    }
+
+    /// Records a user turn and the assistant's reply.
+    pub fn add_turn(&mut self, content: MessageContent, output: &str) {
+        self.messages.push(Message::new(MessageRole::User, content));
         self.messages.push(Message::new(
             MessageRole::Assistant,
             MessageContent::Text(output.to_string()),
         ));
         self.dirty = true;
+    }
+
+    /// Starts an empty session with a system prompt.
+    pub fn set_system_prompt(&mut self, prompt: &str) -> Result<()> {
+        self.guard_empty()?;
+        self.messages.push(Message::new(
+            MessageRole::System,
+            MessageContent::Text(prompt.to_string()),
+        ));
+        self.dirty = true;
         Ok(())
     }
 
@@ -382,17 +398,35 @@ impl Session {
     }
 
     pub fn build_messages(&self, input: &Input) -> Vec<Message> {
+        self.build_turn_messages(input.message_content())
+    }
+
+    /// The history sent along with a new user turn.
+    pub fn build_turn_messages(&self, content: MessageContent) -> Vec<Message> {
         let mut messages = self.messages.clone();
-        let mut need_add_msg = true;
         let len = messages.len();
         // TODO: Review the change
         if len == 0 && self.compressed_messages.len() >= 2 {
             messages
                 .extend(self.compressed_messages[self.compressed_messages.len() - 2..].to_vec());
         }
-        if need_add_msg {
-            messages.push(Message::new(MessageRole::User, input.message_content()));
-        }
+        messages.push(Message::new(MessageRole::User, content));
         messages
     }
+
+    /// The session as returned by the HTTP API.
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "id": self.name,
+            "object": "session",
+            "model": self.model_id,
+            "temperature": self.temperature,
+            "top_p": self.top_p,
+            "compress_threshold": self.compress_threshold,
+            "total_tokens": self.tokens(),
+            "max_input_tokens": self.model.max_input_tokens(),
+            "compressed_messages": self.compressed_messages.len(),
+            "messages": self.messages,
+        })
+    }
 }
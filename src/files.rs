@@ -0,0 +1,185 @@
+use crate::config::{ensure_parent_exists, Config};
+use crate::utils::random_hex;
+
+use anyhow::{anyhow, bail, Context, Result};
+use bstr::ByteSlice;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    fs::{self, read_dir, remove_file},
+    path::PathBuf,
+};
+
+/// An uploaded file, stored as `<config-dir>/files/<id>` with its metadata in `<id>.json`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileObject {
+    pub id: String,
+    pub bytes: u64,
+    pub created_at: i64,
+    pub filename: String,
+    pub purpose: String,
+}
+
+impl FileObject {
+    pub fn create(filename: &str, purpose: &str, data: &[u8]) -> Result<Self> {
+        let file = Self {
+            id: format!("file-{}", random_hex(12)),
+            bytes: data.len() as u64,
+            created_at: Utc::now().timestamp(),
+            filename: filename.to_string(),
+            purpose: purpose.to_string(),
+        };
+        let (data_path, meta_path) = Self::paths(&file.id)?;
+        ensure_parent_exists(&data_path)?;
+        fs::write(&data_path, data)
+            .with_context(|| format!("Failed to save file '{}'", file.filename))?;
+        fs::write(&meta_path, serde_json::to_string(&file)?)
+            .with_context(|| format!("Failed to save file '{}'", file.filename))?;
+        Ok(file)
+    }
+
+    pub fn load(id: &str) -> Result<Self> {
+        let (_, meta_path) = Self::paths(id)?;
+        let content = fs::read_to_string(&meta_path).map_err(|_| anyhow!("No file '{id}'"))?;
+        serde_json::from_str(&content).map_err(|err| anyhow!("Invalid file '{id}', {err}"))
+    }
+
+    pub fn content(&self) -> Result<Vec<u8>> {
+        let (data_path, _) = Self::paths(&self.id)?;
+        fs::read(&data_path).with_context(|| format!("Failed to read file '{}'", self.id))
+    }
+
+    /// All files, newest first.
+    pub fn list() -> Vec<Self> {
+        let Ok(rd) = Config::files_dir().and_then(|v| Ok(read_dir(v)?)) else {
+            return vec![];
+        };
+        let mut files: Vec<Self> = rd
+            .flatten()
+            .filter_map(|entry| {
+                let name = entry.file_name();
+                let id = name.to_string_lossy();
+                Self::load(id.strip_suffix(".json")?).ok()
+            })
+            .collect();
+        files.sort_by_key(|v| std::cmp::Reverse(v.created_at));
+        files
+    }
+
+    pub fn remove(id: &str) -> Result<bool> {
+        let (data_path, meta_path) = Self::paths(id)?;
+        if !meta_path.exists() {
+            return Ok(false);
+        }
+        let _ = remove_file(&data_path);
+        remove_file(&meta_path).with_context(|| format!("Failed to delete file '{id}'"))?;
+        Ok(true)
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "id": self.id,
+            "object": "file",
+            "bytes": self.bytes,
+            "created_at": self.created_at,
+            "filename": self.filename,
+            "purpose": self.purpose,
+            "status": "processed",
+        })
+    }
+
+    fn paths(id: &str) -> Result<(PathBuf, PathBuf)> {
+        if id.is_empty()
+            || !id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            bail!("Invalid file id '{id}'");
+        }
+        let dir = Config::files_dir()?;
+        Ok((dir.join(id), dir.join(format!("{id}.json"))))
+    }
+}
+
+/// One field of a `multipart/form-data` body.
+#[derive(Debug)]
+pub struct FormPart {
+    pub name: String,
+    pub filename: Option<String>,
+    pub data: Vec<u8>,
+}
+
+impl FormPart {
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.data).to_string()
+    }
+}
+
+pub fn parse_multipart(content_type: &str, body: &[u8]) -> Result<Vec<FormPart>> {
+    let boundary = content_type
+        .split(';')
+        .find_map(|v| v.trim().strip_prefix("boundary="))
+        .map(|v| v.trim_matches('"'))
+        .filter(|v| !v.is_empty())
+        .ok_or_else(|| anyhow!("Invalid multipart body, missing boundary"))?;
+    let delimiter = format!("--{boundary}");
+    let mut parts = vec![];
+    let mut rest = match body.find(&delimiter) {
+        Some(index) => &body[index + delimiter.len()..],
+        None => bail!("Invalid multipart body, missing boundary"),
+    };
+    let delimiter = format!("\r\n{delimiter}");
+    while !rest.starts_with(b"--") {
+        let rest_part = rest
+            .strip_prefix(b"\r\n")
+            .ok_or_else(|| anyhow!("Invalid multipart body"))?;
+        let end = rest_part
+            .find(&delimiter)
+            .ok_or_else(|| anyhow!("Invalid multipart body, unterminated part"))?;
+        let part = &rest_part[..end];
+        rest = &rest_part[end + delimiter.len()..];
+        let header_end = part
+            .find(b"\r\n\r\n")
+            .ok_or_else(|| anyhow!("Invalid multipart body, missing part headers"))?;
+        let headers = part[..header_end].to_str_lossy();
+        let data = part[header_end + 4..].to_vec();
+        let disposition = headers
+            .lines()
+            .find_map(|v| {
+                let (name, value) = v.split_once(':')?;
+                name.trim()
+                    .eq_ignore_ascii_case("content-disposition")
+                    .then(|| value.to_string())
+            })
+            .ok_or_else(|| anyhow!("Invalid multipart body, missing Content-Disposition"))?;
+        let param = |key: &str| {
+            disposition.split(';').find_map(|v| {
+                let (k, v) = v.trim().split_once('=')?;
+                (k == key).then(|| v.trim_matches('"').to_string())
+            })
+        };
+        parts.push(FormPart {
+            name: param("name").unwrap_or_default(),
+            filename: param("filename"),
+            data,
+        });
+    }
+    Ok(parts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_multipart() {
+        let body = b"--XyZ\r\nContent-Disposition: form-data; name=\"purpose\"\r\n\r\nassistants\r\n--XyZ\r\nContent-Disposition: form-data; name=\"file\"; filename=\"notes.md\"\r\nContent-Type: text/markdown\r\n\r\n# Notes\r\n\r\nline\r\n--XyZ--\r\n";
+        let parts = parse_multipart("multipart/form-data; boundary=XyZ", body).unwrap();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].name, "purpose");
+        assert_eq!(parts[0].text(), "assistants");
+        assert_eq!(parts[1].filename.as_deref(), Some("notes.md"));
+        assert_eq!(parts[1].text(), "# Notes\r\n\r\nline");
+    }
+}
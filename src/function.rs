@@ -3,14 +3,21 @@ use fancy_regex::Regex;
 use indexmap::{IndexMap, IndexSet};
 use inquire::{validator::Validation, Text};
 use lazy_static::lazy_static;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::json;
 use serde_json::Value;
 use std::{
     collections::HashSet,
-    fs,
-    path::Path,
+    env, fs,
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::Duration,
 };
 use threadpool::ThreadPool;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    process::Command,
+};
 
 const BIN_DIR_NAME: &str = "bin";
 const DECLARATIONS_FILE_PATH: &str = "functions.json";
@@ -41,8 +48,7 @@ impl ToolCallResult {
 pub struct Function {
     names: IndexSet<String>,
     declarations: Vec<FunctionDeclaration>,
-    #[cfg(windows)]
-    bin_dir: std::path::PathBuf,
+    bin_dir: PathBuf,
     env_path: Option<String>,
 }
 
@@ -75,12 +81,95 @@ impl Function {
         Ok(Self {
             names: func_names,
             declarations,
-            #[cfg(windows)]
             bin_dir,
             env_path,
         })
     }
 
+    pub fn contains(&self, name: &str) -> bool {
+        self.names.contains(name)
+    }
+
+    /// Runs the executable behind a declared function with the call's arguments, never failing:
+    /// errors, timeouts and non-zero exits become an `{"error": ...}` output for the model to see.
+    pub async fn run(&self, call: &ToolCall, options: &FunctionExecution) -> FunctionRun {
+        if !self.contains(&call.name) {
+            return FunctionRun::failed(format!("Unknown function '{}'", call.name));
+        }
+        let timeout = Duration::from_secs(options.timeout);
+        match tokio::time::timeout(timeout, self.spawn(call, options)).await {
+            Ok(Ok(run)) => run,
+            Ok(Err(err)) => FunctionRun::failed(format!("{err:#}")),
+            Err(_) => FunctionRun {
+                timed_out: true,
+                ..FunctionRun::failed(format!("Timed out after {}s", options.timeout))
+            },
+        }
+    }
+
+    async fn spawn(&self, call: &ToolCall, options: &FunctionExecution) -> Result<FunctionRun> {
+        let arguments = call.arguments_text();
+        #[cfg(windows)]
+        let program = polyfill_cmd_name(&call.name, &self.bin_dir);
+        #[cfg(not(windows))]
+        let program = self.bin_dir.join(&call.name);
+        let mut command = Command::new(program);
+        command.env_clear();
+        if let Some(path) = self.env_path.clone().or_else(|| env::var("PATH").ok()) {
+            command.env("PATH", path);
+        }
+        for key in &options.env {
+            if let Ok(value) = env::var(key) {
+                command.env(key, value);
+            }
+        }
+        if options.arguments == ArgumentsMode::Argv {
+            command.arg(&arguments);
+        }
+        command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        let mut child = command
+            .spawn()
+            .with_context(|| format!("Failed to run function '{}'", call.name))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            if options.arguments == ArgumentsMode::Stdin {
+                // A function that exits without reading its input is not an error.
+                let _ = stdin.write_all(arguments.as_bytes()).await;
+            }
+        }
+        let stdout = child.stdout.take();
+        let stderr = child.stderr.take();
+        let ((stdout, truncated), (stderr, _)) = tokio::join!(
+            read_capped(stdout, options.max_output_bytes),
+            read_capped(stderr, options.max_output_bytes),
+        );
+        let status = child.wait().await?;
+        let stdout = if truncated {
+            format!("{stdout}\n...[truncated]")
+        } else {
+            stdout
+        };
+        let output = if status.success() {
+            serde_json::from_str(&stdout).unwrap_or_else(|_| Value::String(stdout))
+        } else {
+            json!({
+                "error": format!("Function '{}' exited with {status}", call.name),
+                "stdout": stdout,
+                "stderr": stderr,
+            })
+        };
+        Ok(FunctionRun {
+            output,
+            exit_code: status.code(),
+            truncated,
+            timed_out: false,
+            error: (!status.success()).then(|| stderr.trim().to_string()),
+        })
+    }
+
     pub fn select(&self, matcher: &str) -> Option<Vec<FunctionDeclaration>> {
         let regex = Regex::new(&format!("^({matcher})$")).ok()?;
         let output: Vec<FunctionDeclaration> = self
@@ -97,6 +186,62 @@ impl Function {
     }
 }
 
+/// Server-side execution of tool calls that name functions declared in `functions.json`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct FunctionExecution {
+    pub enabled: bool,
+    pub max_steps: usize,
+    pub timeout: u64,
+    pub max_output_bytes: usize,
+    pub arguments: ArgumentsMode,
+    pub env: Vec<String>,
+}
+
+impl Default for FunctionExecution {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_steps: 8,
+            timeout: 30,
+            max_output_bytes: 64 * 1024,
+            arguments: Default::default(),
+            env: vec![],
+        }
+    }
+}
+
+/// How the JSON arguments are handed to a function.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArgumentsMode {
+    #[default]
+    Stdin,
+    Argv,
+}
+
+/// The outcome of one function execution.
+#[derive(Debug, Clone)]
+pub struct FunctionRun {
+    pub output: Value,
+    pub exit_code: Option<i32>,
+    pub truncated: bool,
+    pub timed_out: bool,
+    pub error: Option<String>,
+}
+
+impl FunctionRun {
+    pub fn failed(error: String) -> Self {
+        Self {
+            output: json!({ "error": error }),
+            exit_code: None,
+            truncated: false,
+            timed_out: false,
+            error: Some(error),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct FunctionConfig {
     pub enable: bool,
@@ -111,21 +256,306 @@ pub struct FunctionDeclaration {
     pub parameters: JsonSchema,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl FunctionDeclaration {
+    /// Logs the schema keywords `provider` can't take for this function.
+    pub fn warn_lossy(&self, provider: &str, dropped: &[String]) {
+        if !dropped.is_empty() {
+            warn!(
+                "Function '{}' loses {} for {provider}",
+                self.name,
+                dropped.join(", ")
+            );
+        }
+    }
+}
+
+/// A JSON Schema. Keywords without a field of their own are kept in `extra`, so nothing is lost
+/// on the way to providers that accept full JSON Schema.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct JsonSchema {
-    #[serde(rename = "type")]
-    pub type_value: String,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub type_value: Option<JsonSchemaType>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub description: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub properties: Option<IndexMap<String, JsonSchema>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub items: Option<Box<JsonSchema>>,
     #[serde(rename = "enum", skip_serializing_if = "Option::is_none")]
-    pub enum_value: Option<Vec<String>>,
+    pub enum_value: Option<Vec<Value>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub required: Option<Vec<String>>,
+    #[serde(rename = "anyOf", skip_serializing_if = "Option::is_none")]
+    pub any_of: Option<Vec<JsonSchema>>,
+    #[serde(rename = "oneOf", skip_serializing_if = "Option::is_none")]
+    pub one_of: Option<Vec<JsonSchema>>,
+    #[serde(rename = "allOf", skip_serializing_if = "Option::is_none")]
+    pub all_of: Option<Vec<JsonSchema>>,
+    #[serde(rename = "$ref", skip_serializing_if = "Option::is_none")]
+    pub reference: Option<String>,
+    #[serde(rename = "$defs", skip_serializing_if = "Option::is_none")]
+    pub defs: Option<IndexMap<String, JsonSchema>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub definitions: Option<IndexMap<String, JsonSchema>>,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_present",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub default: Option<Value>,
+    /// A boolean or a schema.
+    #[serde(
+        rename = "additionalProperties",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub additional_properties: Option<Value>,
+    #[serde(flatten)]
+    pub extra: IndexMap<String, Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum JsonSchemaType {
+    Single(String),
+    Multiple(Vec<String>),
 }
 
-#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+impl JsonSchema {
+    pub fn object() -> Self {
+        Self {
+            type_value: Some(JsonSchemaType::Single("object".into())),
+            properties: Some(Default::default()),
+            ..Default::default()
+        }
+    }
+
+    /// The types this schema allows, `null` included.
+    pub fn types(&self) -> Vec<&str> {
+        match &self.type_value {
+            Some(JsonSchemaType::Single(v)) => vec![v.as_str()],
+            Some(JsonSchemaType::Multiple(v)) => v.iter().map(|v| v.as_str()).collect(),
+            None => vec![],
+        }
+    }
+
+    /// Whether this is just a type and maybe a description, with nothing nested.
+    pub fn is_simple(&self) -> bool {
+        *self
+            == Self {
+                type_value: self.type_value.clone(),
+                description: self.description.clone(),
+                ..Default::default()
+            }
+    }
+
+    /// Calls `f` with each directly nested schema and its path segment, such as `properties.city` or `anyOf[1]`.
+    pub fn for_each_child_mut(&mut self, mut f: impl FnMut(String, &mut JsonSchema)) {
+        if let Some(properties) = &mut self.properties {
+            for (key, value) in properties.iter_mut() {
+                f(format!("properties.{key}"), value);
+            }
+        }
+        if let Some(items) = &mut self.items {
+            f("items".into(), items);
+        }
+        for (name, list) in [
+            ("anyOf", &mut self.any_of),
+            ("oneOf", &mut self.one_of),
+            ("allOf", &mut self.all_of),
+        ] {
+            for (i, value) in list.iter_mut().flatten().enumerate() {
+                f(format!("{name}[{i}]"), value);
+            }
+        }
+    }
+
+    /// Where `value` breaks this schema, one message per violation. `$ref`s are expected to be inlined.
+    pub fn validate(&self, value: &Value) -> Vec<String> {
+        let mut errors = vec![];
+        self.validate_at(value, "$", &mut errors);
+        errors
+    }
+
+    fn validate_at(&self, value: &Value, path: &str, errors: &mut Vec<String>) {
+        let types = self.types();
+        if !types.is_empty() && !types.iter().any(|v| matches_type(v, value)) {
+            errors.push(format!("{path}: expected {}", types.join(" or ")));
+            return;
+        }
+        if let Some(values) = &self.enum_value {
+            if !values.contains(value) {
+                errors.push(format!("{path}: expected one of {}", json!(values)));
+            }
+        }
+        if let Some(expected) = self.extra.get("const") {
+            if expected != value {
+                errors.push(format!("{path}: expected {expected}"));
+            }
+        }
+        let bound = |key: &str| self.extra.get(key).and_then(|v| v.as_f64());
+        match value {
+            Value::Object(map) => {
+                for key in self.required.iter().flatten() {
+                    if !map.contains_key(key) {
+                        errors.push(format!("{path}: missing required property '{key}'"));
+                    }
+                }
+                for (key, value) in map {
+                    let path = format!("{path}.{key}");
+                    match self.properties.as_ref().and_then(|v| v.get(key)) {
+                        Some(schema) => schema.validate_at(value, &path, errors),
+                        None => match &self.additional_properties {
+                            Some(Value::Bool(false)) => {
+                                errors.push(format!("{path}: unexpected property"))
+                            }
+                            Some(schema @ Value::Object(_)) => {
+                                if let Ok(schema) = Self::deserialize(schema) {
+                                    schema.validate_at(value, &path, errors);
+                                }
+                            }
+                            _ => {}
+                        },
+                    }
+                }
+            }
+            Value::Array(items) => {
+                if let Some(schema) = &self.items {
+                    for (i, item) in items.iter().enumerate() {
+                        schema.validate_at(item, &format!("{path}[{i}]"), errors);
+                    }
+                }
+                let len = items.len() as f64;
+                if bound("minItems").is_some_and(|v| len < v) {
+                    errors.push(format!("{path}: too few items"));
+                }
+                if bound("maxItems").is_some_and(|v| len > v) {
+                    errors.push(format!("{path}: too many items"));
+                }
+            }
+            Value::String(text) => {
+                let len = text.chars().count() as f64;
+                if bound("minLength").is_some_and(|v| len < v) {
+                    errors.push(format!("{path}: too short"));
+                }
+                if bound("maxLength").is_some_and(|v| len > v) {
+                    errors.push(format!("{path}: too long"));
+                }
+            }
+            Value::Number(number) => {
+                let number = number.as_f64().unwrap_or_default();
+                if bound("minimum").is_some_and(|v| number < v)
+                    || bound("exclusiveMinimum").is_some_and(|v| number <= v)
+                {
+                    errors.push(format!("{path}: below the minimum"));
+                }
+                if bound("maximum").is_some_and(|v| number > v)
+                    || bound("exclusiveMaximum").is_some_and(|v| number >= v)
+                {
+                    errors.push(format!("{path}: above the maximum"));
+                }
+            }
+            _ => {}
+        }
+        for schema in self.all_of.iter().flatten() {
+            schema.validate_at(value, path, errors);
+        }
+        let matches =
+            |list: &Vec<JsonSchema>| list.iter().filter(|v| v.validate(value).is_empty()).count();
+        if self.any_of.as_ref().is_some_and(|v| matches(v) == 0) {
+            errors.push(format!("{path}: matches none of anyOf"));
+        }
+        if self.one_of.as_ref().is_some_and(|v| matches(v) != 1) {
+            errors.push(format!("{path}: must match exactly one of oneOf"));
+        }
+    }
+
+    /// This schema with every local `$ref` replaced by its definition and the definitions dropped.
+    ///
+    /// Recursive or unresolvable references become an unconstrained object; their paths are returned.
+    pub fn inline_refs(&self) -> (Self, Vec<String>) {
+        let mut defs = IndexMap::new();
+        for (prefix, map) in [
+            ("#/$defs/", &self.defs),
+            ("#/definitions/", &self.definitions),
+        ] {
+            for (key, value) in map.iter().flatten() {
+                defs.insert(format!("{prefix}{key}"), value.clone());
+            }
+        }
+        let mut schema = self.clone();
+        schema.defs = None;
+        schema.definitions = None;
+        let mut unresolved = vec![];
+        inline_refs_at(
+            &mut schema,
+            &defs,
+            &mut vec![],
+            "parameters",
+            &mut unresolved,
+        );
+        (schema, unresolved)
+    }
+}
+
+fn matches_type(type_value: &str, value: &Value) -> bool {
+    match type_value {
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => {
+            value.is_i64() || value.is_u64() || value.as_f64().is_some_and(|v| v.fract() == 0.0)
+        }
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        _ => true,
+    }
+}
+
+/// Keeps an explicit `null` as `Some(Value::Null)`.
+fn deserialize_present<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Value>, D::Error> {
+    Value::deserialize(deserializer).map(Some)
+}
+
+fn inline_refs_at(
+    schema: &mut JsonSchema,
+    defs: &IndexMap<String, JsonSchema>,
+    stack: &mut Vec<String>,
+    path: &str,
+    unresolved: &mut Vec<String>,
+) {
+    let mut pushed = false;
+    if let Some(reference) = schema.reference.take() {
+        match defs.get(&reference) {
+            Some(def) if !stack.contains(&reference) => {
+                let description = schema.description.take();
+                *schema = JsonSchema {
+                    description: description.or_else(|| def.description.clone()),
+                    ..def.clone()
+                };
+                stack.push(reference);
+                pushed = true;
+            }
+            _ => {
+                unresolved.push(format!("{path}.$ref"));
+                *schema = JsonSchema {
+                    description: schema.description.take(),
+                    ..JsonSchema::object()
+                };
+            }
+        }
+    }
+    schema.for_each_child_mut(|segment, child| {
+        inline_refs_at(child, defs, stack, &format!("{path}.{segment}"), unresolved)
+    });
+    if pushed {
+        stack.pop();
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
 pub struct ToolCall {
     pub name: String,
     pub arguments: Value,
@@ -159,6 +589,133 @@ impl ToolCall {
             id,
         }
     }
+
+    /// Parses arguments streamed as JSON text, keeping the text as a string when it is not valid JSON.
+    pub fn parse_arguments(raw: &str) -> Value {
+        if raw.trim().is_empty() {
+            return json!({});
+        }
+        match serde_json::from_str(raw) {
+            // Some models encode the arguments twice.
+            Ok(Value::String(inner)) => serde_json::from_str::<Value>(&inner)
+                .ok()
+                .filter(|v| v.is_object())
+                .unwrap_or(Value::String(inner)),
+            Ok(value) => value,
+            Err(_) => Value::String(raw.to_string()),
+        }
+    }
+
+    /// The arguments as JSON text, for APIs that take them that way.
+    pub fn arguments_text(&self) -> String {
+        match &self.arguments {
+            Value::String(value) => value.clone(),
+            Value::Null => "{}".into(),
+            value => value.to_string(),
+        }
+    }
+
+    /// The arguments as a JSON object, for APIs that take them that way; unparsed ones become `{}`.
+    pub fn arguments_object(&self) -> Value {
+        match &self.arguments {
+            Value::Object(_) => self.arguments.clone(),
+            _ => json!({}),
+        }
+    }
+}
+
+/// Checks applied to the tool calls a model returns, before the gateway runs them.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ToolCallValidation {
+    pub enabled: bool,
+    pub repair: bool,
+    pub reask: bool,
+}
+
+impl Default for ToolCallValidation {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            repair: true,
+            reask: true,
+        }
+    }
+}
+
+/// Parses near-valid JSON from models: code fences, surrounding prose, trailing commas and unclosed brackets.
+pub fn repair_json(raw: &str) -> Option<Value> {
+    let mut text = raw.trim();
+    if let Some(rest) = text.strip_prefix("```") {
+        let rest = rest.trim_start_matches(|c: char| c.is_ascii_alphanumeric());
+        text = rest.trim().strip_suffix("```").unwrap_or(rest).trim();
+    }
+    text = &text[text.find(['{', '['])?..];
+    let trim_comma = |output: &mut String| {
+        let len = output.trim_end().len();
+        if output[..len].ends_with(',') {
+            output.truncate(len - 1);
+        }
+    };
+    let mut output = String::with_capacity(text.len());
+    let mut closers = vec![];
+    let (mut in_string, mut escaped) = (false, false);
+    for c in text.chars() {
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+            }
+            output.push(c);
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '{' => closers.push('}'),
+            '[' => closers.push(']'),
+            '}' | ']' => {
+                trim_comma(&mut output);
+                if closers.last() == Some(&c) {
+                    closers.pop();
+                }
+            }
+            _ => {}
+        }
+        output.push(c);
+        if closers.is_empty() {
+            break;
+        }
+    }
+    if in_string {
+        output.push('"');
+    }
+    while let Some(c) = closers.pop() {
+        trim_comma(&mut output);
+        output.push(c);
+    }
+    serde_json::from_str(&output).ok()
+}
+
+/// Reads up to `max_bytes`, draining the rest so the child never blocks on a full pipe.
+async fn read_capped<R: AsyncRead + Unpin>(reader: Option<R>, max_bytes: usize) -> (String, bool) {
+    let Some(mut reader) = reader else {
+        return (String::new(), false);
+    };
+    let mut output = vec![];
+    let mut truncated = false;
+    let mut buf = [0u8; 8192];
+    while let Ok(n) = reader.read(&mut buf).await {
+        if n == 0 {
+            break;
+        }
+        let take = n.min(max_bytes.saturating_sub(output.len()));
+        output.extend_from_slice(&buf[..take]);
+        truncated |= take < n;
+    }
+    (String::from_utf8_lossy(&output).to_string(), truncated)
 }
 
 fn prepend_env_path(bin_dir: &Path) -> Result<String> {
@@ -186,3 +743,89 @@ fn polyfill_cmd_name(name: &str, bin_dir: &std::path::Path) -> String {
     }
     name
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inline_refs() {
+        let value = json!({
+            "type": "object",
+            "properties": {
+                "stops": { "type": "array", "items": { "$ref": "#/$defs/Stop" }, "minItems": 1 },
+                "mode": { "anyOf": [{ "type": "string", "enum": ["car", "bike"] }, { "type": "null" }], "default": null },
+            },
+            "required": ["stops"],
+            "additionalProperties": false,
+            "$defs": {
+                "Stop": {
+                    "type": "object",
+                    "properties": { "city": { "type": "string" }, "next": { "$ref": "#/$defs/Stop" } },
+                },
+            },
+        });
+        let schema: JsonSchema = serde_json::from_value(value.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&schema).unwrap(), value);
+
+        let (schema, unresolved) = schema.inline_refs();
+        let value = serde_json::to_value(&schema).unwrap();
+        assert!(value.get("$defs").is_none());
+        assert_eq!(
+            value["properties"]["stops"]["items"]["properties"]["city"],
+            json!({ "type": "string" })
+        );
+        assert_eq!(
+            unresolved,
+            vec!["parameters.properties.stops.items.properties.next.$ref"]
+        );
+    }
+
+    #[test]
+    fn test_repair_json() {
+        let expected = json!({ "city": "Paris", "days": [1, 2] });
+        for raw in [
+            "```json\n{\"city\": \"Paris\", \"days\": [1, 2],}\n```",
+            "Sure: {\"city\": \"Paris\", \"days\": [1, 2]} hope that helps",
+            "{\"city\": \"Paris\", \"days\": [1, 2,",
+        ] {
+            assert_eq!(repair_json(raw), Some(expected.clone()), "{raw}");
+        }
+        assert_eq!(
+            repair_json("{\"a\": \"x}, \""),
+            Some(json!({ "a": "x}, " }))
+        );
+        assert_eq!(repair_json("no json here"), None);
+        assert_eq!(
+            ToolCall::parse_arguments("\"{\\\"a\\\": 1}\""),
+            json!({ "a": 1 })
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let schema: JsonSchema = serde_json::from_value(json!({
+            "type": "object",
+            "properties": {
+                "city": { "type": "string", "minLength": 2 },
+                "days": { "type": "integer", "minimum": 1 },
+                "mode": { "anyOf": [{ "enum": ["car", "bike"] }, { "type": "null" }] },
+            },
+            "required": ["city"],
+            "additionalProperties": false,
+        }))
+        .unwrap();
+        assert!(schema
+            .validate(&json!({ "city": "Paris", "days": 2, "mode": null }))
+            .is_empty());
+        assert_eq!(
+            schema.validate(&json!({ "days": 0.5, "mode": "boat", "x": 1 })),
+            vec![
+                "$: missing required property 'city'",
+                "$.days: expected integer",
+                "$.mode: matches none of anyOf",
+                "$.x: unexpected property",
+            ]
+        );
+    }
+}
@@ -1,11 +1,13 @@
+use crate::utils::redact_secrets;
+
 use anyhow::Result;
-use log::LevelFilter;
-use simplelog::{format_description, Config as LogConfig, ConfigBuilder};
+use log::{LevelFilter, Log, Metadata, Record};
+use simplelog::{format_description, Config as LogConfig, ConfigBuilder, SimpleLogger};
 
 #[cfg(debug_assertions)]
 pub fn setup_logger() -> Result<()> {
     let config = build_config();
-        simplelog::SimpleLogger::init(LevelFilter::Debug, config)?;
+    init_logger(LevelFilter::Debug, config)?;
     Ok(())
 }
 
@@ -13,7 +15,7 @@ pub fn setup_logger() -> Result<()> {
 pub fn setup_logger(working_mode: WorkingMode) -> Result<()> {
     let config = build_config();
     if working_mode == WorkingMode::Serve {
-        simplelog::SimpleLogger::init(log::LevelFilter::Info, config)?;
+        init_logger(LevelFilter::Info, config)?;
     }
     Ok(())
 }
@@ -26,3 +28,38 @@ fn build_config() -> LogConfig {
         .set_thread_level(LevelFilter::Off)
         .build()
 }
+
+fn init_logger(level: LevelFilter, config: LogConfig) -> Result<()> {
+    log::set_max_level(level);
+    log::set_boxed_logger(Box::new(RedactLogger(SimpleLogger::new(level, config))))?;
+    Ok(())
+}
+
+/// Masks the secrets resolved from the config before a record is written.
+struct RedactLogger(Box<SimpleLogger>);
+
+impl Log for RedactLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let args = record.args().to_string();
+        self.0.log(
+            &Record::builder()
+                .metadata(record.metadata().clone())
+                .args(format_args!("{}", redact_secrets(&args)))
+                .module_path(record.module_path())
+                .file(record.file())
+                .line(record.line())
+                .build(),
+        );
+    }
+
+    fn flush(&self) {
+        self.0.flush()
+    }
+}
@@ -1,8 +1,13 @@
 mod client;
 mod config;
+mod files;
 mod function;
 mod logger;
+mod mcp;
+mod rag;
+mod responses;
 mod serve;
+mod trace;
 #[macro_use]
 mod utils;
 
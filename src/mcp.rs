@@ -0,0 +1,518 @@
+use crate::function::{FunctionDeclaration, JsonSchema, ToolCall};
+
+use anyhow::{anyhow, bail, Context, Result};
+use indexmap::IndexMap;
+use parking_lot::Mutex;
+use reqwest::Client as ReqwestClient;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    process::Stdio,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    process::{Child, ChildStderr, ChildStdin, ChildStdout, Command},
+    sync::Mutex as AsyncMutex,
+};
+
+const PROTOCOL_VERSION: &str = "2025-03-26";
+const SESSION_ID_HEADER: &str = "mcp-session-id";
+/// Separates the server name from the tool name in the functions offered to models.
+pub const TOOL_NAME_SEPARATOR: &str = "__";
+
+/// An MCP server, launched over stdio (`command`) or reached over streamable HTTP (`url`).
+#[derive(Debug, Clone, Deserialize)]
+pub struct McpServerConfig {
+    pub name: String,
+    pub command: Option<String>,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub env: IndexMap<String, String>,
+    pub url: Option<String>,
+    #[serde(default)]
+    pub headers: IndexMap<String, String>,
+}
+
+/// The configured MCP servers, connected on first use and reconnected after a failure.
+///
+/// Connecting, up to the listing of tools, is bounded by `connect_timeout`.
+#[derive(Default)]
+pub struct McpHub {
+    configs: Vec<McpServerConfig>,
+    connect_timeout: Duration,
+    servers: Mutex<HashMap<String, Arc<McpServer>>>,
+    connect_locks: Mutex<HashMap<String, Arc<AsyncMutex<()>>>>,
+}
+
+impl McpHub {
+    pub fn new(configs: Vec<McpServerConfig>, connect_timeout: Duration) -> Self {
+        Self {
+            configs,
+            connect_timeout,
+            ..Default::default()
+        }
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.configs.iter().map(|v| v.name.as_str()).collect()
+    }
+
+    /// The tools of the named servers, as functions named `<server>__<tool>`.
+    pub async fn functions(&self, names: &[String]) -> Result<Vec<FunctionDeclaration>> {
+        let mut functions = vec![];
+        for name in names {
+            let server = self.server(name).await?;
+            functions.extend(server.functions());
+        }
+        Ok(functions)
+    }
+
+    /// Whether `name` is the function of a tool on one of the configured servers.
+    ///
+    /// Checked against the listed tools once the server is connected; `call` checks again after
+    /// reconnecting.
+    pub fn contains(&self, name: &str) -> bool {
+        let Some((server_name, tool)) = self.split_name(name) else {
+            return false;
+        };
+        match self.servers.lock().get(server_name) {
+            Some(server) => server.has_tool(tool),
+            None => true,
+        }
+    }
+
+    /// Calls the tool behind an MCP function, turning its content into the output fed back to the model.
+    pub async fn call(&self, call: &ToolCall) -> Result<Value> {
+        let (server_name, tool) = self
+            .split_name(&call.name)
+            .ok_or_else(|| anyhow!("Unknown MCP tool '{}'", call.name))?;
+        let server = self.server(server_name).await?;
+        if !server.has_tool(tool) {
+            bail!("Unknown MCP tool '{}'", call.name);
+        }
+        let arguments = match &call.arguments {
+            Value::String(value) if value.trim().is_empty() => json!({}),
+            Value::String(value) => serde_json::from_str(value)
+                .with_context(|| format!("Invalid arguments for '{}'", call.name))?,
+            Value::Null => json!({}),
+            value => value.clone(),
+        };
+        let params = json!({ "name": tool, "arguments": arguments });
+        match server.request("tools/call", params).await {
+            Ok(result) => Ok(tool_output(result)),
+            Err(err) => {
+                if server.transport.is_closed() {
+                    self.servers.lock().remove(server_name);
+                }
+                Err(err)
+            }
+        }
+    }
+
+    pub async fn server(&self, name: &str) -> Result<Arc<McpServer>> {
+        if let Some(server) = self.cached(name) {
+            return Ok(server);
+        }
+        let config = self
+            .configs
+            .iter()
+            .find(|v| v.name == name)
+            .ok_or_else(|| anyhow!("No MCP server '{name}'"))?;
+        // Per server, so a slow server does not hold up the others.
+        let lock = self
+            .connect_locks
+            .lock()
+            .entry(name.to_string())
+            .or_default()
+            .clone();
+        let _guard = lock.lock().await;
+        if let Some(server) = self.cached(name) {
+            return Ok(server);
+        }
+        let server =
+            match tokio::time::timeout(self.connect_timeout, McpServer::connect(config)).await {
+                Ok(Ok(server)) => Arc::new(server),
+                Ok(Err(err)) => bail!("Failed to connect to MCP server '{name}', {err:#}"),
+                Err(_) => bail!(
+                    "Failed to connect to MCP server '{name}', timed out after {}s",
+                    self.connect_timeout.as_secs()
+                ),
+            };
+        debug!("MCP server '{name}': {} tools", server.tools.len());
+        self.servers.lock().insert(name.to_string(), server.clone());
+        Ok(server)
+    }
+
+    fn cached(&self, name: &str) -> Option<Arc<McpServer>> {
+        let mut servers = self.servers.lock();
+        match servers.get(name) {
+            Some(server) if server.transport.is_closed() => {
+                servers.remove(name);
+                None
+            }
+            server => server.cloned(),
+        }
+    }
+
+    fn split_name<'a>(&self, name: &'a str) -> Option<(&'a str, &'a str)> {
+        let (server, tool) = name.split_once(TOOL_NAME_SEPARATOR)?;
+        self.configs
+            .iter()
+            .any(|v| v.name == server)
+            .then_some((server, tool))
+    }
+}
+
+/// A connected MCP server and the tools it listed at initialization.
+pub struct McpServer {
+    name: String,
+    transport: Transport,
+    tools: Vec<McpTool>,
+    next_id: AtomicU64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct McpTool {
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub input_schema: Value,
+}
+
+impl McpServer {
+    async fn connect(config: &McpServerConfig) -> Result<Self> {
+        let transport = match (&config.command, &config.url) {
+            (Some(command), None) => Transport::Stdio(AsyncMutex::new(StdioConn::spawn(
+                &config.name,
+                command,
+                &config.args,
+                &config.env,
+            )?)),
+            (None, Some(url)) => Transport::Http(HttpConn::new(url, &config.headers)?),
+            _ => bail!("Expected either 'command' or 'url'"),
+        };
+        let mut server = Self {
+            name: config.name.clone(),
+            transport,
+            tools: vec![],
+            next_id: AtomicU64::new(1),
+        };
+        let params = json!({
+            "protocolVersion": PROTOCOL_VERSION,
+            "capabilities": {},
+            "clientInfo": { "name": env!("CARGO_CRATE_NAME"), "version": env!("CARGO_PKG_VERSION") },
+        });
+        server.request("initialize", params).await?;
+        server.notify("notifications/initialized").await?;
+        let mut cursor: Option<String> = None;
+        loop {
+            let params = match &cursor {
+                Some(cursor) => json!({ "cursor": cursor }),
+                None => json!({}),
+            };
+            let mut result = server.request("tools/list", params).await?;
+            let tools: Vec<McpTool> = serde_json::from_value(result["tools"].take())
+                .context("Invalid tools/list result")?;
+            server.tools.extend(tools);
+            match result["nextCursor"].as_str() {
+                Some(next) if !next.is_empty() => cursor = Some(next.to_string()),
+                _ => break,
+            }
+        }
+        Ok(server)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn has_tool(&self, name: &str) -> bool {
+        self.tools.iter().any(|v| v.name == name)
+    }
+
+    pub fn functions(&self) -> Vec<FunctionDeclaration> {
+        self.tools
+            .iter()
+            .map(|tool| {
+                let parameters: JsonSchema = serde_json::from_value(tool.input_schema.clone())
+                    .unwrap_or_else(|err| {
+                        warn!(
+                            "MCP tool '{}' of '{}' has an unsupported input schema, {err}",
+                            tool.name, self.name
+                        );
+                        JsonSchema::object()
+                    });
+                FunctionDeclaration {
+                    name: format!("{}{TOOL_NAME_SEPARATOR}{}", self.name, tool.name),
+                    description: tool.description.clone().unwrap_or_default(),
+                    parameters,
+                }
+            })
+            .collect()
+    }
+
+    async fn request(&self, method: &str, params: Value) -> Result<Value> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let message = json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params });
+        let response = match &self.transport {
+            Transport::Stdio(conn) => conn.lock().await.request(id, &message).await?,
+            Transport::Http(conn) => conn.request(id, &message).await?,
+        };
+        if let Some(error) = response.get("error") {
+            bail!(
+                "MCP server '{}' failed {method}, {}",
+                self.name,
+                error["message"].as_str().unwrap_or("unknown error")
+            );
+        }
+        Ok(response.get("result").cloned().unwrap_or_default())
+    }
+
+    async fn notify(&self, method: &str) -> Result<()> {
+        let message = json!({ "jsonrpc": "2.0", "method": method });
+        match &self.transport {
+            Transport::Stdio(conn) => conn.lock().await.send(&message).await,
+            Transport::Http(conn) => conn.notify(&message).await,
+        }
+    }
+}
+
+enum Transport {
+    Stdio(AsyncMutex<StdioConn>),
+    Http(HttpConn),
+}
+
+impl Transport {
+    fn is_closed(&self) -> bool {
+        match self {
+            Transport::Stdio(conn) => conn.try_lock().map(|v| v.closed).unwrap_or_default(),
+            Transport::Http(_) => false,
+        }
+    }
+}
+
+/// Newline-delimited JSON-RPC over the stdin/stdout of a child process, one request at a time.
+struct StdioConn {
+    _child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    closed: bool,
+}
+
+impl StdioConn {
+    fn spawn(
+        name: &str,
+        command: &str,
+        args: &[String],
+        env: &IndexMap<String, String>,
+    ) -> Result<Self> {
+        let mut child = Command::new(command)
+            .args(args)
+            .envs(env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("Failed to run '{command}'"))?;
+        let stdin = child.stdin.take().ok_or_else(|| anyhow!("No stdin"))?;
+        let stdout = child.stdout.take().ok_or_else(|| anyhow!("No stdout"))?;
+        if let Some(stderr) = child.stderr.take() {
+            tokio::spawn(log_stderr(name.to_string(), stderr));
+        }
+        Ok(Self {
+            _child: child,
+            stdin,
+            stdout: BufReader::new(stdout),
+            closed: false,
+        })
+    }
+
+    async fn send(&mut self, message: &Value) -> Result<()> {
+        let line = format!("{message}\n");
+        if let Err(err) = self.stdin.write_all(line.as_bytes()).await {
+            self.closed = true;
+            bail!("MCP server closed its input, {err}");
+        }
+        self.stdin.flush().await?;
+        Ok(())
+    }
+
+    /// Sends a request and reads messages until its response, answering pings on the way.
+    /// Responses to earlier, abandoned requests are skipped.
+    async fn request(&mut self, id: u64, message: &Value) -> Result<Value> {
+        self.send(message).await?;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.stdout.read_line(&mut line).await? == 0 {
+                self.closed = true;
+                bail!("MCP server exited");
+            }
+            let Ok(message) = serde_json::from_str::<Value>(&line) else {
+                continue;
+            };
+            match (message.get("id"), message.get("method")) {
+                (Some(message_id), None) if message_id.as_u64() == Some(id) => return Ok(message),
+                (Some(message_id), Some(method)) => {
+                    let reply = server_request_reply(message_id, method);
+                    self.send(&reply).await?;
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Logs what a stdio server writes to stderr, until it exits.
+async fn log_stderr(name: String, stderr: ChildStderr) {
+    let mut lines = BufReader::new(stderr).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        info!("MCP server '{name}': {line}");
+    }
+}
+
+/// JSON-RPC over HTTP POSTs, answered with either a JSON body or an SSE stream.
+struct HttpConn {
+    url: String,
+    headers: IndexMap<String, String>,
+    http_client: ReqwestClient,
+    session_id: Mutex<Option<String>>,
+}
+
+impl HttpConn {
+    fn new(url: &str, headers: &IndexMap<String, String>) -> Result<Self> {
+        Ok(Self {
+            url: url.to_string(),
+            headers: headers.clone(),
+            http_client: ReqwestClient::builder().build()?,
+            session_id: Default::default(),
+        })
+    }
+
+    async fn post(&self, message: &Value) -> Result<reqwest::Response> {
+        let mut builder = self
+            .http_client
+            .post(&self.url)
+            .header("Accept", "application/json, text/event-stream")
+            .json(message);
+        for (key, value) in &self.headers {
+            builder = builder.header(key, value);
+        }
+        if let Some(session_id) = self.session_id.lock().clone() {
+            builder = builder.header(SESSION_ID_HEADER, session_id);
+        }
+        let res = builder.send().await?;
+        let status = res.status();
+        if !status.is_success() {
+            let text = res.text().await.unwrap_or_default();
+            bail!("MCP server returned {status}, {text}");
+        }
+        if let Some(session_id) = res.headers().get(SESSION_ID_HEADER) {
+            *self.session_id.lock() = session_id.to_str().ok().map(|v| v.to_string());
+        }
+        Ok(res)
+    }
+
+    async fn request(&self, id: u64, message: &Value) -> Result<Value> {
+        let res = self.post(message).await?;
+        let is_sse = res
+            .headers()
+            .get("content-type")
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.starts_with("text/event-stream"));
+        let text = res.text().await?;
+        if !is_sse {
+            return serde_json::from_str(&text).context("Invalid MCP response");
+        }
+        parse_sse_messages(&text)
+            .into_iter()
+            .find(|v| v["id"].as_u64() == Some(id) && v.get("method").is_none())
+            .ok_or_else(|| anyhow!("No response in the MCP event stream"))
+    }
+
+    async fn notify(&self, message: &Value) -> Result<()> {
+        self.post(message).await?;
+        Ok(())
+    }
+}
+
+/// The JSON-RPC messages in the `data` of an SSE body.
+fn parse_sse_messages(text: &str) -> Vec<Value> {
+    text.replace("\r\n", "\n")
+        .split("\n\n")
+        .filter_map(|event| {
+            let data: Vec<&str> = event
+                .lines()
+                .filter_map(|v| v.strip_prefix("data:"))
+                .map(|v| v.strip_prefix(' ').unwrap_or(v))
+                .collect();
+            if data.is_empty() {
+                return None;
+            }
+            serde_json::from_str(&data.join("\n")).ok()
+        })
+        .collect()
+}
+
+fn server_request_reply(id: &Value, method: &Value) -> Value {
+    if method == "ping" {
+        json!({ "jsonrpc": "2.0", "id": id, "result": {} })
+    } else {
+        json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": -32601, "message": format!("Method {method} not supported") },
+        })
+    }
+}
+
+/// Structured content when the tool returns it, otherwise its text content; errors become `{"error": ...}`.
+fn tool_output(mut result: Value) -> Value {
+    let is_error = result["isError"].as_bool().unwrap_or_default();
+    let structured = result["structuredContent"].take();
+    let content = match result["content"].take() {
+        Value::Array(parts) => parts,
+        _ => vec![],
+    };
+    let texts: Vec<&str> = content.iter().filter_map(|v| v["text"].as_str()).collect();
+    let output = if !structured.is_null() {
+        structured
+    } else if texts.len() == content.len() {
+        let text = texts.join("\n");
+        serde_json::from_str(&text).unwrap_or(Value::String(text))
+    } else {
+        Value::Array(content)
+    };
+    if is_error {
+        json!({ "error": output })
+    } else {
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tool_output() {
+        let result = json!({ "content": [{ "type": "text", "text": "{\"temp\": 21}" }] });
+        assert_eq!(tool_output(result), json!({ "temp": 21 }));
+        let result = json!({ "content": [{ "type": "text", "text": "boom" }], "isError": true });
+        assert_eq!(tool_output(result), json!({ "error": "boom" }));
+        let messages = parse_sse_messages(
+            "event: message\ndata: {\"jsonrpc\":\"2.0\",\"id\":3,\"result\":{}}\n\n",
+        );
+        assert_eq!(messages[0]["id"], 3);
+    }
+}
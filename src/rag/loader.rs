@@ -0,0 +1,106 @@
+use anyhow::{anyhow, bail, Context, Result};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+pub const SUPPORTED_EXTENSIONS: &[&str] = &["txt", "md", "markdown", "pdf"];
+
+/// Lowercased extension of a file name, or an empty string.
+pub fn extension(name: &str) -> String {
+    Path::new(name)
+        .extension()
+        .map(|v| v.to_string_lossy().to_lowercase())
+        .unwrap_or_default()
+}
+
+/// Text of a text, markdown or PDF document, picked by the extension of `name`.
+pub fn extract_text(name: &str, bytes: &[u8]) -> Result<String> {
+    match extension(name).as_str() {
+        "pdf" => pdf_extract::extract_text_from_mem(bytes)
+            .map_err(|err| anyhow!("Failed to extract text from '{name}', {err}")),
+        "txt" | "md" | "markdown" => String::from_utf8(bytes.to_vec())
+            .map_err(|_| anyhow!("Failed to read '{name}', not valid UTF-8")),
+        _ => bail!(
+            "Unsupported document '{name}', expected one of: {}",
+            SUPPORTED_EXTENSIONS.join(", ")
+        ),
+    }
+}
+
+pub fn load_file(path: &Path) -> Result<String> {
+    let bytes = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    extract_text(&path.to_string_lossy(), &bytes)
+}
+
+/// Files behind `paths`, walking directories for the supported extensions.
+///
+/// Relative paths start at `root`; anything resolving outside it, or any symlink, is rejected.
+pub fn expand_paths(paths: &[String], root: &Path) -> Result<Vec<PathBuf>> {
+    let root = root
+        .canonicalize()
+        .with_context(|| format!("Failed to read {}", root.display()))?;
+    let mut files = vec![];
+    for name in paths {
+        let path = root.join(name);
+        let metadata = fs::symlink_metadata(&path)
+            .map_err(|_| anyhow!("No such file or directory '{name}'"))?;
+        if metadata.file_type().is_symlink() {
+            bail!("'{name}' is a symlink");
+        }
+        let Some(path) = path.canonicalize().ok().filter(|v| v.starts_with(&root)) else {
+            bail!("'{name}' is outside of the documents directory");
+        };
+        if metadata.is_dir() {
+            walk_dir(&path, &mut files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+fn walk_dir(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    let mut entries: Vec<(PathBuf, fs::FileType)> = fs::read_dir(dir)
+        .with_context(|| format!("Failed to read {}", dir.display()))?
+        .flatten()
+        .filter_map(|v| Some((v.path(), v.file_type().ok()?)))
+        .collect();
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    for (path, file_type) in entries {
+        if file_type.is_dir() {
+            walk_dir(&path, files)?;
+        } else if file_type.is_file()
+            && SUPPORTED_EXTENSIONS.contains(&extension(&path.to_string_lossy()).as_str())
+        {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand_paths() {
+        let dir = std::env::temp_dir().join(format!("expand_paths_{}", std::process::id()));
+        let root = dir.join("docs");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("a.md"), "a").unwrap();
+        fs::write(root.join("sub/b.txt"), "b").unwrap();
+        fs::write(root.join("sub/c.bin"), "c").unwrap();
+        fs::write(dir.join("secret.txt"), "s").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(dir.join("secret.txt"), root.join("sub/link.txt")).unwrap();
+
+        let root = root.canonicalize().unwrap();
+        let files = expand_paths(&[".".into()], &root).unwrap();
+        assert_eq!(files, vec![root.join("a.md"), root.join("sub/b.txt")]);
+        for path in ["../secret.txt", "/etc/hostname", "sub/link.txt"] {
+            assert!(expand_paths(&[path.into()], &root).is_err(), "{path}");
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
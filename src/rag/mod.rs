@@ -0,0 +1,485 @@
+mod loader;
+mod splitter;
+mod vector_store;
+
+pub use loader::{expand_paths, extract_text, load_file};
+pub use vector_store::*;
+
+use self::loader::extension;
+use self::splitter::{separators_for, split_text};
+
+use crate::client::{Client, EmbeddingsData, Message, MessageContent, MessageContentPart, Model};
+use crate::config::{ensure_parent_exists, Config, RAG_TEMPLATE};
+
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::Utc;
+use hnsw_rs::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    fs::{self, read_dir, remove_file},
+    path::{Path, PathBuf},
+};
+
+pub const DEFAULT_TOP_K: usize = 4;
+
+const HNSW_MAX_NB_CONNECTION: usize = 32;
+const HNSW_MAX_LAYER: usize = 16;
+const HNSW_EF_CONSTRUCTION: usize = 200;
+const HNSW_EF_SEARCH: usize = 64;
+
+/// A named knowledge base: chunked documents, their embeddings and an HNSW index over them.
+///
+/// The chunks and vectors are stored at `<config-dir>/rags/<name>.bin`, the HNSW graph beside them as
+/// `<name>.hnsw.graph` and `<name>.hnsw.data`. The graph is rebuilt from the vectors when that dump is
+/// missing or stale.
+pub struct Rag {
+    name: String,
+    path: PathBuf,
+    data: RagData,
+    hnsw: Hnsw<'static, f32, DistCosine>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RagData {
+    embedding_model: String,
+    chunk_size: usize,
+    chunk_overlap: usize,
+    created_at: i64,
+    documents: Vec<RagDocument>,
+    chunks: Vec<RagChunk>,
+    vectors: Vec<Vec<f32>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RagDocument {
+    source: String,
+    chunks: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RagChunk {
+    document: usize,
+    index: usize,
+    text: String,
+}
+
+/// A retrieved chunk, cited back to the caller.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RagHit {
+    pub source: String,
+    pub chunk: usize,
+    pub score: f32,
+    pub text: String,
+}
+
+impl RagHit {
+    pub fn to_json(&self, index: usize) -> Value {
+        json!({
+            "index": index,
+            "source": self.source,
+            "chunk": self.chunk,
+            "score": self.score,
+            "text": self.text,
+        })
+    }
+}
+
+impl Rag {
+    /// An empty knowledge base embedding with `embedding_model`, chunked at its `default_chunk_size` unless overridden.
+    pub fn create(
+        name: &str,
+        embedding_model: &Model,
+        chunk_size: Option<usize>,
+        chunk_overlap: Option<usize>,
+    ) -> Result<Self> {
+        guard_name(name)?;
+        let path = Config::rag_file(name)?;
+        if path.exists() {
+            bail!("Knowledge base '{name}' already exists");
+        }
+        let chunk_size = chunk_size.unwrap_or_else(|| embedding_model.default_chunk_size());
+        let chunk_overlap = chunk_overlap.unwrap_or(chunk_size / 20);
+        if chunk_size == 0 || chunk_overlap >= chunk_size {
+            bail!("Invalid chunk size {chunk_size} with overlap {chunk_overlap}");
+        }
+        let data = RagData {
+            embedding_model: embedding_model.id(),
+            chunk_size,
+            chunk_overlap,
+            created_at: Utc::now().timestamp(),
+            documents: vec![],
+            chunks: vec![],
+            vectors: vec![],
+        };
+        Ok(Self::build(name, path, data))
+    }
+
+    pub fn load(name: &str) -> Result<Self> {
+        guard_name(name)?;
+        let path = Config::rag_file(name)?;
+        if !path.exists() {
+            bail!("No knowledge base '{name}'");
+        }
+        let bytes =
+            fs::read(&path).with_context(|| format!("Failed to load knowledge base '{name}'"))?;
+        let data: RagData = bincode::deserialize(&bytes)
+            .map_err(|err| anyhow!("Invalid knowledge base '{name}', {err}"))?;
+        let Some(hnsw) = load_index(&path, data.vectors.len()) else {
+            debug!("Knowledge base '{name}': rebuilding the index");
+            return Ok(Self::build(name, path, data));
+        };
+        Ok(Self {
+            name: name.to_string(),
+            path,
+            data,
+            hnsw,
+        })
+    }
+
+    pub fn save(&self) -> Result<()> {
+        ensure_parent_exists(&self.path)?;
+        let bytes = bincode::serialize(&self.data)?;
+        fs::write(&self.path, bytes)
+            .with_context(|| format!("Failed to save knowledge base '{}'", self.name))?;
+        dump_index(&self.hnsw, &self.path)
+            .with_context(|| format!("Failed to save the index of knowledge base '{}'", self.name))
+    }
+
+    pub fn list() -> Vec<String> {
+        let Ok(rd) = Config::rags_dir().and_then(|v| Ok(read_dir(v)?)) else {
+            return vec![];
+        };
+        let mut names: Vec<String> = rd
+            .flatten()
+            .filter_map(|entry| {
+                let name = entry.file_name();
+                name.to_string_lossy()
+                    .strip_suffix(".bin")
+                    .map(|v| v.to_string())
+            })
+            .collect();
+        names.sort_unstable();
+        names
+    }
+
+    pub fn remove(name: &str) -> Result<bool> {
+        guard_name(name)?;
+        let path = Config::rag_file(name)?;
+        if !path.exists() {
+            return Ok(false);
+        }
+        remove_file(&path).with_context(|| format!("Failed to delete knowledge base '{name}'"))?;
+        for file in index_files(&path) {
+            let _ = remove_file(file);
+        }
+        Ok(true)
+    }
+
+    pub fn embedding_model(&self) -> &str {
+        &self.data.embedding_model
+    }
+
+    /// Chunks, embeds and indexes `(source, text)` documents, returning the number of chunks added.
+    pub async fn add_documents(
+        &mut self,
+        client: &dyn Client,
+        documents: Vec<(String, String)>,
+    ) -> Result<usize> {
+        let mut chunks = vec![];
+        let mut new_documents = vec![];
+        for (source, text) in documents {
+            let separators = separators_for(&extension(&source));
+            let texts = split_text(
+                &text,
+                self.data.chunk_size,
+                self.data.chunk_overlap,
+                separators,
+            );
+            let document = self.data.documents.len() + new_documents.len();
+            new_documents.push(RagDocument {
+                source,
+                chunks: texts.len(),
+            });
+            chunks.extend(texts.into_iter().enumerate().map(|(index, text)| RagChunk {
+                document,
+                index,
+                text,
+            }));
+        }
+        let batch_size = client.model().max_concurrent_chunks().max(1);
+        let mut vectors = Vec::with_capacity(chunks.len());
+        for batch in chunks.chunks(batch_size) {
+            let data = EmbeddingsData {
+                texts: batch.iter().map(|v| v.text.clone()).collect(),
+                query: false,
+            };
+            let output = client.embeddings(data).await?;
+            if output.len() != batch.len() {
+                bail!("Expected {} embeddings, got {}", batch.len(), output.len());
+            }
+            vectors.extend(output);
+        }
+        let offset = self.data.chunks.len();
+        let points: Vec<(&Vec<f32>, usize)> = vectors
+            .iter()
+            .enumerate()
+            .map(|(i, v)| (v, offset + i))
+            .collect();
+        self.hnsw.parallel_insert(&points);
+        let added = chunks.len();
+        debug!(
+            "Knowledge base '{}': added {} documents, {added} chunks",
+            self.name,
+            new_documents.len()
+        );
+        self.data.documents.extend(new_documents);
+        self.data.chunks.extend(chunks);
+        self.data.vectors.extend(vectors);
+        Ok(added)
+    }
+
+    /// Drops the chunks of the document from `source` and rebuilds the index, returning whether there were any.
+    pub fn remove_document(&mut self, source: &str) -> bool {
+        let Some(document) = self.data.documents.iter().position(|v| v.source == source) else {
+            return false;
+        };
+        self.data.documents.remove(document);
+        let chunks = std::mem::take(&mut self.data.chunks);
+        let vectors = std::mem::take(&mut self.data.vectors);
+        for (mut chunk, vector) in chunks.into_iter().zip(vectors) {
+            if chunk.document == document {
+                continue;
+            }
+            if chunk.document > document {
+                chunk.document -= 1;
+            }
+            self.data.chunks.push(chunk);
+            self.data.vectors.push(vector);
+        }
+        self.hnsw = build_index(&self.data.vectors);
+        true
+    }
+
+    /// The `top_k` chunks closest to `query`, best first.
+    pub async fn search(
+        &self,
+        client: &dyn Client,
+        query: &str,
+        top_k: usize,
+    ) -> Result<Vec<RagHit>> {
+        if self.data.chunks.is_empty() || top_k == 0 {
+            return Ok(vec![]);
+        }
+        let data = EmbeddingsData {
+            texts: vec![query.to_string()],
+            query: true,
+        };
+        let vector = client
+            .embeddings(data)
+            .await?
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("No embedding returned for the query"))?;
+        let neighbours = self.hnsw.search(&vector, top_k, HNSW_EF_SEARCH.max(top_k));
+        let hits = neighbours
+            .into_iter()
+            .filter_map(|v| {
+                let chunk = self.data.chunks.get(v.d_id)?;
+                let document = self.data.documents.get(chunk.document)?;
+                Some(RagHit {
+                    source: document.source.clone(),
+                    chunk: chunk.index,
+                    score: 1.0 - v.distance,
+                    text: chunk.text.clone(),
+                })
+            })
+            .collect();
+        Ok(hits)
+    }
+
+    pub fn to_json(&self) -> Value {
+        let documents: Vec<Value> = self
+            .data
+            .documents
+            .iter()
+            .map(|v| json!({ "source": v.source, "chunks": v.chunks }))
+            .collect();
+        json!({
+            "id": self.name,
+            "object": "knowledge_base",
+            "created_at": self.data.created_at,
+            "embedding_model": self.data.embedding_model,
+            "chunk_size": self.data.chunk_size,
+            "chunk_overlap": self.data.chunk_overlap,
+            "chunks": self.data.chunks.len(),
+            "documents": documents,
+        })
+    }
+
+    fn build(name: &str, path: PathBuf, data: RagData) -> Self {
+        let hnsw = build_index(&data.vectors);
+        Self {
+            name: name.to_string(),
+            path,
+            data,
+            hnsw,
+        }
+    }
+}
+
+fn build_index(vectors: &[Vec<f32>]) -> Hnsw<'static, f32, DistCosine> {
+    let hnsw = Hnsw::new(
+        HNSW_MAX_NB_CONNECTION,
+        vectors.len().max(1000),
+        HNSW_MAX_LAYER,
+        HNSW_EF_CONSTRUCTION,
+        DistCosine {},
+    );
+    let points: Vec<(&Vec<f32>, usize)> = vectors.iter().enumerate().map(|(i, v)| (v, i)).collect();
+    hnsw.parallel_insert(&points);
+    hnsw
+}
+
+/// The dump of the index of the knowledge base saved at `path`: its graph and its points.
+fn index_files(path: &Path) -> [PathBuf; 2] {
+    ["hnsw.graph", "hnsw.data"].map(|ext| path.with_extension(ext))
+}
+
+/// Dumps under a temporary name and renames, so a failed save never leaves a half-written dump.
+fn dump_index(hnsw: &Hnsw<'static, f32, DistCosine>, path: &Path) -> Result<()> {
+    if hnsw.get_nb_point() == 0 {
+        // hnsw_rs cannot dump an empty graph, and building one is free.
+        for file in index_files(path) {
+            let _ = remove_file(file);
+        }
+        return Ok(());
+    }
+    // `file_dump` writes `<basename>.hnsw.graph` and `<basename>.hnsw.data`, relative to the
+    // working directory unless the basename is absolute.
+    let basename = hnsw.file_dump(&path.with_extension("tmp").to_string_lossy().into_owned())?;
+    for (ext, file) in ["hnsw.graph", "hnsw.data"]
+        .into_iter()
+        .zip(index_files(path))
+    {
+        fs::rename(format!("{basename}.{ext}"), file)?;
+    }
+    Ok(())
+}
+
+/// The dumped index, unless it is missing, older than the data saved at `path` or of another size.
+fn load_index(path: &Path, points: usize) -> Option<Hnsw<'static, f32, DistCosine>> {
+    if points == 0 {
+        return None;
+    }
+    let saved_at = fs::metadata(path).and_then(|v| v.modified()).ok()?;
+    for file in index_files(path) {
+        if fs::metadata(file).and_then(|v| v.modified()).ok()? < saved_at {
+            return None;
+        }
+    }
+    let dir = path.parent()?.to_path_buf();
+    let basename = path.file_stem()?.to_string_lossy().into_owned();
+    // The reloaded graph borrows its loader for good; a loader is a path and a few options.
+    let io = Box::leak(Box::new(HnswIo::new(dir, basename)));
+    let hnsw = io.load_hnsw::<f32, DistCosine>().ok()?;
+    (hnsw.get_nb_point() == points).then_some(hnsw)
+}
+
+/// Fills `RAG_TEMPLATE` with the retrieved chunks and the last user message, in place of that message's text.
+pub fn augment_messages(messages: &mut [Message], hits: &[RagHit]) -> Result<()> {
+    let Some(message) = messages.iter_mut().rev().find(|v| v.role.is_user()) else {
+        bail!("No user message to answer from the knowledge base");
+    };
+    let context = hits
+        .iter()
+        .enumerate()
+        .map(|(i, v)| format!("[{}] {}\n{}", i + 1, v.source, v.text))
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    let prompt = RAG_TEMPLATE
+        .replace("__CONTEXT__", &context)
+        .replace("__INPUT__", &message.content.to_text());
+    message.content = match &message.content {
+        MessageContent::Array(parts) => {
+            let mut new_parts = vec![MessageContentPart::Text { text: prompt }];
+            new_parts.extend(
+                parts
+                    .iter()
+                    .filter(|v| !matches!(v, MessageContentPart::Text { .. }))
+                    .cloned(),
+            );
+            MessageContent::Array(new_parts)
+        }
+        _ => MessageContent::Text(prompt),
+    };
+    Ok(())
+}
+
+/// Names become file names, so only a conservative charset is accepted.
+fn guard_name(name: &str) -> Result<()> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        bail!("Invalid knowledge base name '{name}'");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::MessageRole;
+
+    #[test]
+    fn test_augment_messages() {
+        let mut messages = vec![
+            Message::new(
+                MessageRole::System,
+                MessageContent::Text("Be terse.".into()),
+            ),
+            Message::new(
+                MessageRole::User,
+                MessageContent::Text("Which pets purr?".into()),
+            ),
+        ];
+        let hits = vec![RagHit {
+            source: "pets.md".into(),
+            chunk: 0,
+            score: 0.9,
+            text: "Cats purr.".into(),
+        }];
+        augment_messages(&mut messages, &hits).unwrap();
+        let text = messages[1].content.to_text();
+        assert!(text.contains("[1] pets.md\nCats purr."));
+        assert!(text.contains("Question: Which pets purr?"));
+        assert_eq!(messages[0].content.to_text(), "Be terse.");
+    }
+
+    #[test]
+    fn test_index_dump() {
+        let dir = std::env::temp_dir().join(format!("rag_index_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("pets.bin");
+        let vectors: Vec<Vec<f32>> = (0..50)
+            .map(|i| vec![1.0, i as f32, (i % 7) as f32])
+            .collect();
+        fs::write(&path, "data").unwrap();
+        dump_index(&build_index(&vectors), &path).unwrap();
+        assert!(!dir.join("pets.tmp.hnsw.graph").exists());
+
+        let hnsw = load_index(&path, vectors.len()).unwrap();
+        let hits = hnsw.search(&vectors[20], 1, HNSW_EF_SEARCH);
+        assert_eq!(hits[0].d_id, 20);
+        assert!(load_index(&path, vectors.len() + 1).is_none());
+
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        fs::write(&path, "newer data").unwrap();
+        assert!(load_index(&path, vectors.len()).is_none());
+        dump_index(&build_index(&[]), &path).unwrap();
+        assert!(!index_files(&path)[0].exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,119 @@
+const DEFAULT_SEPARATORS: &[&str] = &["\n\n", "\n", " ", ""];
+const MARKDOWN_SEPARATORS: &[&str] = &[
+    "\n# ", "\n## ", "\n### ", "\n#### ", "\n```\n", "\n\n", "\n", " ", "",
+];
+
+/// Separators to split on, coarsest first, for a document with the given extension.
+pub fn separators_for(extension: &str) -> &'static [&'static str] {
+    match extension {
+        "md" | "markdown" => MARKDOWN_SEPARATORS,
+        _ => DEFAULT_SEPARATORS,
+    }
+}
+
+/// Splits `text` into chunks of at most `chunk_size` chars, consecutive chunks sharing up to `chunk_overlap` chars.
+pub fn split_text(
+    text: &str,
+    chunk_size: usize,
+    chunk_overlap: usize,
+    separators: &[&str],
+) -> Vec<String> {
+    split_recursive(text, chunk_size.max(1), chunk_overlap, separators)
+        .into_iter()
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
+fn split_recursive(
+    text: &str,
+    chunk_size: usize,
+    chunk_overlap: usize,
+    separators: &[&str],
+) -> Vec<String> {
+    let index = separators
+        .iter()
+        .position(|v| v.is_empty() || text.contains(v))
+        .unwrap_or(separators.len().saturating_sub(1));
+    let separator = separators.get(index).copied().unwrap_or_default();
+    let rest = separators.get(index + 1..).unwrap_or_default();
+
+    // Each separator stays at the start of the piece it precedes, so merging is plain concatenation.
+    let pieces: Vec<String> = if separator.is_empty() {
+        text.chars().map(String::from).collect()
+    } else {
+        text.split(separator)
+            .enumerate()
+            .map(|(i, v)| {
+                if i == 0 {
+                    v.to_string()
+                } else {
+                    format!("{separator}{v}")
+                }
+            })
+            .filter(|v| !v.is_empty())
+            .collect()
+    };
+
+    let mut chunks = vec![];
+    let mut fitting = vec![];
+    for piece in pieces {
+        if piece.chars().count() <= chunk_size {
+            fitting.push(piece);
+            continue;
+        }
+        if !fitting.is_empty() {
+            chunks.extend(merge_pieces(&fitting, chunk_size, chunk_overlap));
+            fitting.clear();
+        }
+        if rest.is_empty() {
+            chunks.push(piece);
+        } else {
+            chunks.extend(split_recursive(&piece, chunk_size, chunk_overlap, rest));
+        }
+    }
+    if !fitting.is_empty() {
+        chunks.extend(merge_pieces(&fitting, chunk_size, chunk_overlap));
+    }
+    chunks
+}
+
+fn merge_pieces(pieces: &[String], chunk_size: usize, chunk_overlap: usize) -> Vec<String> {
+    let mut chunks = vec![];
+    let mut current: Vec<(&str, usize)> = vec![];
+    let mut total = 0;
+    for piece in pieces {
+        let len = piece.chars().count();
+        if total + len > chunk_size && !current.is_empty() {
+            chunks.push(current.iter().map(|(v, _)| *v).collect::<String>());
+            while total > chunk_overlap || (total + len > chunk_size && total > 0) {
+                let (_, first_len) = current.remove(0);
+                total -= first_len;
+            }
+        }
+        current.push((piece, len));
+        total += len;
+    }
+    if !current.is_empty() {
+        chunks.push(current.iter().map(|(v, _)| *v).collect::<String>());
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_text() {
+        let text = "# Title\n\nFirst paragraph about cats.\n\n## Section\n\nSecond paragraph about dogs, which runs a little longer than the first.";
+        let chunks = split_text(text, 60, 10, separators_for("md"));
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|v| v.chars().count() <= 60));
+        assert!(chunks[0].starts_with("# Title"));
+        assert!(chunks.iter().any(|v| v.starts_with("## Section")));
+
+        let chunks = split_text(&"x".repeat(25), 10, 0, separators_for("txt"));
+        assert_eq!(chunks, vec!["x".repeat(10), "x".repeat(10), "x".repeat(5)]);
+    }
+}
@@ -0,0 +1,145 @@
+use super::guard_name;
+
+use crate::config::{ensure_parent_exists, Config};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    fs::{self, read_dir, remove_file},
+    path::PathBuf,
+};
+
+/// Prefix of vector store ids, which keeps their knowledge bases apart from named ones.
+pub const VECTOR_STORE_PREFIX: &str = "vs_";
+
+/// An OpenAI-style vector store. Its chunks live in the knowledge base of the same id.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VectorStore {
+    pub id: String,
+    pub name: Option<String>,
+    pub created_at: i64,
+    #[serde(default)]
+    pub metadata: Value,
+    pub embedding_model: String,
+    #[serde(default)]
+    pub files: Vec<VectorStoreFile>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VectorStoreFile {
+    pub id: String,
+    pub created_at: i64,
+    pub status: VectorStoreFileStatus,
+    pub last_error: Option<String>,
+    pub usage_bytes: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VectorStoreFileStatus {
+    InProgress,
+    Completed,
+    Failed,
+}
+
+impl VectorStore {
+    pub fn load(id: &str) -> Result<Self> {
+        let path = Self::path(id)?;
+        let content = fs::read_to_string(&path).map_err(|_| anyhow!("No vector store '{id}'"))?;
+        serde_json::from_str(&content).map_err(|err| anyhow!("Invalid vector store '{id}', {err}"))
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = Self::path(&self.id)?;
+        ensure_parent_exists(&path)?;
+        fs::write(&path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("Failed to save vector store '{}'", self.id))
+    }
+
+    /// All vector stores, newest first.
+    pub fn list() -> Vec<Self> {
+        let Ok(rd) = Config::vector_stores_dir().and_then(|v| Ok(read_dir(v)?)) else {
+            return vec![];
+        };
+        let mut stores: Vec<Self> = rd
+            .flatten()
+            .filter_map(|entry| {
+                let name = entry.file_name();
+                let id = name.to_string_lossy();
+                Self::load(id.strip_suffix(".json")?).ok()
+            })
+            .collect();
+        stores.sort_by_key(|v| std::cmp::Reverse(v.created_at));
+        stores
+    }
+
+    pub fn remove(id: &str) -> Result<bool> {
+        let path = Self::path(id)?;
+        if !path.exists() {
+            return Ok(false);
+        }
+        remove_file(&path).with_context(|| format!("Failed to delete vector store '{id}'"))?;
+        Ok(true)
+    }
+
+    pub fn file(&self, file_id: &str) -> Option<&VectorStoreFile> {
+        self.files.iter().find(|v| v.id == file_id)
+    }
+
+    pub fn file_mut(&mut self, file_id: &str) -> Option<&mut VectorStoreFile> {
+        self.files.iter_mut().find(|v| v.id == file_id)
+    }
+
+    pub fn to_json(&self) -> Value {
+        let count = |status: VectorStoreFileStatus| {
+            self.files.iter().filter(|v| v.status == status).count()
+        };
+        let in_progress = count(VectorStoreFileStatus::InProgress);
+        let status = if in_progress > 0 {
+            "in_progress"
+        } else {
+            "completed"
+        };
+        json!({
+            "id": self.id,
+            "object": "vector_store",
+            "created_at": self.created_at,
+            "name": self.name,
+            "usage_bytes": self.files.iter().map(|v| v.usage_bytes).sum::<u64>(),
+            "file_counts": {
+                "in_progress": in_progress,
+                "completed": count(VectorStoreFileStatus::Completed),
+                "failed": count(VectorStoreFileStatus::Failed),
+                "cancelled": 0,
+                "total": self.files.len(),
+            },
+            "status": status,
+            "metadata": self.metadata,
+            "embedding_model": self.embedding_model,
+        })
+    }
+
+    pub fn file_json(&self, file: &VectorStoreFile) -> Value {
+        let last_error = file
+            .last_error
+            .as_ref()
+            .map(|v| json!({ "code": "server_error", "message": v }));
+        json!({
+            "id": file.id,
+            "object": "vector_store.file",
+            "created_at": file.created_at,
+            "vector_store_id": self.id,
+            "status": file.status,
+            "last_error": last_error,
+            "usage_bytes": file.usage_bytes,
+        })
+    }
+
+    fn path(id: &str) -> Result<PathBuf> {
+        guard_name(id)?;
+        let mut path = Config::vector_stores_dir()?;
+        path.push(format!("{id}.json"));
+        Ok(path)
+    }
+}
@@ -0,0 +1,322 @@
+mod store;
+
+pub use store::ResponseStore;
+
+use crate::client::{
+    ContextStrategy, ImageUrl, Message, MessageContent, MessageContentPart, MessageRole,
+};
+
+use anyhow::{anyhow, bail, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+/// Body of `POST /v1/responses`.
+#[derive(Debug, Deserialize)]
+pub struct ResponsesReqBody {
+    pub model: String,
+    pub input: ResponseInput,
+    pub instructions: Option<String>,
+    pub previous_response_id: Option<String>,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub max_output_tokens: Option<isize>,
+    #[serde(default)]
+    pub stream: bool,
+    pub store: Option<bool>,
+    pub metadata: Option<Value>,
+    pub context_strategy: Option<ContextStrategy>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum ResponseInput {
+    Text(String),
+    Items(Vec<Value>),
+}
+
+impl ResponseInput {
+    /// Converts input items to chat messages. Only message items are supported.
+    pub fn to_messages(&self) -> Result<Vec<Message>> {
+        let items = match self {
+            ResponseInput::Text(text) => {
+                return Ok(vec![Message::new(
+                    MessageRole::User,
+                    MessageContent::Text(text.clone()),
+                )])
+            }
+            ResponseInput::Items(items) => items,
+        };
+        let mut messages = vec![];
+        for item in items {
+            let kind = item["type"].as_str().unwrap_or("message");
+            if kind != "message" {
+                bail!("Unsupported input item type '{kind}'");
+            }
+            let role = match item["role"].as_str() {
+                Some("user") => MessageRole::User,
+                Some("assistant") => MessageRole::Assistant,
+                Some("system") | Some("developer") => MessageRole::System,
+                _ => bail!("Invalid input item, missing or unknown role"),
+            };
+            let content = match &item["content"] {
+                Value::String(text) => MessageContent::Text(text.clone()),
+                Value::Array(parts) => {
+                    MessageContent::Array(parts.iter().map(content_part).collect::<Result<_>>()?)
+                }
+                _ => bail!("Invalid input item, missing content"),
+            };
+            messages.push(Message::new(role, content));
+        }
+        Ok(messages)
+    }
+}
+
+fn content_part(part: &Value) -> Result<MessageContentPart> {
+    match part["type"].as_str().unwrap_or_default() {
+        "input_text" | "output_text" | "text" => Ok(MessageContentPart::Text {
+            text: part["text"].as_str().unwrap_or_default().to_string(),
+        }),
+        "input_image" => {
+            let url = part["image_url"]
+                .as_str()
+                .ok_or_else(|| anyhow!("Invalid input_image, missing image_url"))?;
+            Ok(MessageContentPart::ImageUrl {
+                image_url: ImageUrl {
+                    url: url.to_string(),
+                },
+            })
+        }
+        kind => bail!("Unsupported content part type '{kind}'"),
+    }
+}
+
+/// A stored response, with the conversation it ended so chained requests can continue it.
+#[derive(Debug, Clone)]
+pub struct StoredResponse {
+    pub id: String,
+    pub messages: Vec<Message>,
+    pub response: Value,
+}
+
+/// The fields of a response object that are known before the model answers.
+#[derive(Debug, Clone)]
+pub struct ResponseObject {
+    pub id: String,
+    pub message_id: String,
+    pub created_at: i64,
+    pub model: String,
+    pub instructions: Option<String>,
+    pub previous_response_id: Option<String>,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub max_output_tokens: Option<isize>,
+    pub store: bool,
+    pub metadata: Value,
+}
+
+impl ResponseObject {
+    pub fn in_progress(&self) -> Value {
+        self.build("in_progress", vec![], None, None)
+    }
+
+    pub fn completed(&self, text: &str, input_tokens: u64, output_tokens: u64) -> Value {
+        let usage = json!({
+            "input_tokens": input_tokens,
+            "output_tokens": output_tokens,
+            "total_tokens": input_tokens + output_tokens,
+        });
+        self.build(
+            "completed",
+            vec![self.message(text, "completed")],
+            Some(usage),
+            None,
+        )
+    }
+
+    pub fn failed(&self, text: &str, error: &str) -> Value {
+        let output = if text.is_empty() {
+            vec![]
+        } else {
+            vec![self.message(text, "incomplete")]
+        };
+        let error = json!({ "code": "server_error", "message": error });
+        self.build("failed", output, None, Some(error))
+    }
+
+    fn message(&self, text: &str, status: &str) -> Value {
+        json!({
+            "type": "message",
+            "id": self.message_id,
+            "status": status,
+            "role": "assistant",
+            "content": [output_text(text)],
+        })
+    }
+
+    fn build(
+        &self,
+        status: &str,
+        output: Vec<Value>,
+        usage: Option<Value>,
+        error: Option<Value>,
+    ) -> Value {
+        json!({
+            "id": self.id,
+            "object": "response",
+            "created_at": self.created_at,
+            "status": status,
+            "error": error,
+            "model": self.model,
+            "instructions": self.instructions,
+            "previous_response_id": self.previous_response_id,
+            "temperature": self.temperature,
+            "top_p": self.top_p,
+            "max_output_tokens": self.max_output_tokens,
+            "store": self.store,
+            "metadata": self.metadata,
+            "output": output,
+            "usage": usage,
+        })
+    }
+}
+
+fn output_text(text: &str) -> Value {
+    json!({ "type": "output_text", "text": text, "annotations": [] })
+}
+
+/// Typed streaming events for one response with a single text message.
+#[derive(Debug)]
+pub struct ResponseEvents {
+    object: ResponseObject,
+    sequence_number: u64,
+    text: String,
+}
+
+impl ResponseEvents {
+    pub fn new(object: ResponseObject) -> Self {
+        Self {
+            object,
+            sequence_number: 0,
+            text: String::new(),
+        }
+    }
+
+    pub fn start(&mut self) -> Vec<String> {
+        let response = self.object.in_progress();
+        let item = json!({
+            "type": "message",
+            "id": self.object.message_id,
+            "status": "in_progress",
+            "role": "assistant",
+            "content": [],
+        });
+        vec![
+            self.event("response.created", json!({ "response": response })),
+            self.event("response.in_progress", json!({ "response": response })),
+            self.event(
+                "response.output_item.added",
+                json!({ "output_index": 0, "item": item }),
+            ),
+            self.event(
+                "response.content_part.added",
+                json!({
+                    "item_id": self.object.message_id,
+                    "output_index": 0,
+                    "content_index": 0,
+                    "part": output_text(""),
+                }),
+            ),
+        ]
+    }
+
+    pub fn delta(&mut self, text: &str) -> String {
+        self.text.push_str(text);
+        self.event(
+            "response.output_text.delta",
+            json!({
+                "item_id": self.object.message_id,
+                "output_index": 0,
+                "content_index": 0,
+                "delta": text,
+            }),
+        )
+    }
+
+    /// Closes the message and ends with `response.completed` or `response.failed`, as per `response`.
+    pub fn finish(&mut self, response: Value) -> Vec<String> {
+        if response["status"] == "failed" {
+            return vec![self.event("response.failed", json!({ "response": response }))];
+        }
+        let text = self.text.clone();
+        let item = response["output"][0].clone();
+        vec![
+            self.event(
+                "response.output_text.done",
+                json!({
+                    "item_id": self.object.message_id,
+                    "output_index": 0,
+                    "content_index": 0,
+                    "text": text,
+                }),
+            ),
+            self.event(
+                "response.content_part.done",
+                json!({
+                    "item_id": self.object.message_id,
+                    "output_index": 0,
+                    "content_index": 0,
+                    "part": output_text(&text),
+                }),
+            ),
+            self.event(
+                "response.output_item.done",
+                json!({ "output_index": 0, "item": item }),
+            ),
+            self.event("response.completed", json!({ "response": response })),
+        ]
+    }
+
+    fn event(&mut self, kind: &str, mut data: Value) -> String {
+        data["type"] = kind.into();
+        data["sequence_number"] = self.sequence_number.into();
+        self.sequence_number += 1;
+        format!("event: {kind}\ndata: {data}\n\n")
+    }
+}
+
+pub fn generate_response_id() -> String {
+    format!("resp_{}", crate::utils::random_hex(24))
+}
+
+pub fn generate_message_id() -> String {
+    format!("msg_{}", crate::utils::random_hex(24))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_input_items() {
+        let input: ResponseInput = serde_json::from_value(json!([
+            { "role": "developer", "content": "Be terse." },
+            {
+                "type": "message",
+                "role": "user",
+                "content": [
+                    { "type": "input_text", "text": "What is this?" },
+                    { "type": "input_image", "image_url": "https://example.com/cat.png" },
+                ],
+            },
+        ]))
+        .unwrap();
+        let messages = input.to_messages().unwrap();
+        assert!(messages[0].role.is_system());
+        assert_eq!(messages[1].content.to_text(), "What is this?");
+
+        let input: ResponseInput =
+            serde_json::from_value(json!([{ "type": "function_call_output", "output": "{}" }]))
+                .unwrap();
+        assert!(input.to_messages().is_err());
+    }
+}
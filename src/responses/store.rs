@@ -0,0 +1,93 @@
+use super::StoredResponse;
+
+use crate::config::Config;
+use crate::utils::get_env_name;
+
+use anyhow::{anyhow, Context, Result};
+use parking_lot::Mutex;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::{env, path::PathBuf};
+
+const RESPONSES_STORE_FILE_NAME: &str = "responses.db";
+
+const SCHEMA: &str = r#"
+CREATE TABLE IF NOT EXISTS responses (
+    id TEXT PRIMARY KEY,
+    created_at INTEGER NOT NULL,
+    previous_response_id TEXT,
+    messages TEXT NOT NULL,
+    response TEXT NOT NULL
+);
+"#;
+
+/// SQLite file holding stored responses, so `previous_response_id` can be resolved.
+#[derive(Debug)]
+pub struct ResponseStore {
+    conn: Mutex<Connection>,
+}
+
+impl ResponseStore {
+    /// `<config-dir>/responses.db`, unless `RESPONSES_STORE_FILE` is set.
+    pub fn path() -> Result<PathBuf> {
+        match env::var(get_env_name("responses_store_file")) {
+            Ok(value) => Ok(PathBuf::from(value)),
+            Err(_) => Config::local_path(RESPONSES_STORE_FILE_NAME),
+        }
+    }
+
+    pub fn open() -> Result<Self> {
+        let path = Self::path()?;
+        crate::config::ensure_parent_exists(&path)?;
+        let conn = Connection::open(&path)
+            .with_context(|| format!("Failed to open responses store {}", path.display()))?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.execute_batch(SCHEMA)
+            .with_context(|| format!("Failed to init responses store {}", path.display()))?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    pub fn insert(&self, response: &StoredResponse) -> Result<()> {
+        self.conn.lock().execute(
+            "INSERT OR REPLACE INTO responses (id, created_at, previous_response_id, messages, response) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                response.id,
+                response.response["created_at"].as_i64().unwrap_or_default(),
+                response.response["previous_response_id"].as_str(),
+                serde_json::to_string(&response.messages)?,
+                response.response.to_string(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn get(&self, id: &str) -> Result<Option<StoredResponse>> {
+        let row: Option<(String, String)> = self
+            .conn
+            .lock()
+            .query_row(
+                "SELECT messages, response FROM responses WHERE id = ?1",
+                [id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        let Some((messages, response)) = row else {
+            return Ok(None);
+        };
+        let invalid = |err: serde_json::Error| anyhow!("Invalid response '{id}', {err}");
+        Ok(Some(StoredResponse {
+            id: id.to_string(),
+            messages: serde_json::from_str(&messages).map_err(invalid)?,
+            response: serde_json::from_str(&response).map_err(invalid)?,
+        }))
+    }
+
+    pub fn delete(&self, id: &str) -> Result<bool> {
+        let count = self
+            .conn
+            .lock()
+            .execute("DELETE FROM responses WHERE id = ?1", [id])?;
+        Ok(count > 0)
+    }
+}
@@ -1,9 +1,22 @@
-use crate::{client::*, config::*, utils::*};
+use crate::{
+    client::*,
+    config::*,
+    files::*,
+    function::{
+        repair_json, Function, FunctionDeclaration, FunctionExecution, FunctionRun, ToolCallResult,
+        ToolCallValidation,
+    },
+    mcp::McpHub,
+    rag::*,
+    responses::*,
+    trace::*,
+    utils::*,
+};
 
 use anyhow::{anyhow, bail, Result};
 use bytes::Bytes;
-use chrono::{Timelike, Utc};
-use futures_util::StreamExt;
+use chrono::{SecondsFormat, Timelike, Utc};
+use futures_util::{future::join_all, StreamExt};
 use http::{Method, Response, StatusCode};
 use http_body_util::{combinators::BoxBody, BodyExt, Full, StreamBody};
 use hyper::{
@@ -11,15 +24,23 @@ use hyper::{
     service::service_fn,
 };
 use hyper_util::rt::{TokioExecutor, TokioIo};
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
+use reqwest::Client as ReqwestClient;
 use serde::Deserialize;
 use serde_json::{json, Value};
-use std::{convert::Infallible, net::IpAddr, sync::Arc};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use tokio::{
     net::TcpListener,
     sync::{
-        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
-        oneshot,
+        mpsc::{unbounded_channel, UnboundedSender},
+        oneshot, Mutex as AsyncMutex,
     },
 };
 use tokio_graceful::Shutdown;
@@ -27,19 +48,29 @@ use tokio_stream::wrappers::UnboundedReceiverStream;
 
 const DEFAULT_ADDRESS: &str = "127.0.0.1:8000";
 const DEFAULT_MODEL_NAME: &str = "default";
+const REQUEST_ID_HEADER: &str = "x-request-id";
+const COST_HEADER: &str = "x-gateway-cost-usd";
+const MODEL_RESOLVED_HEADER: &str = "x-gateway-model-resolved";
+const CLIENT_HEADER: &str = "x-gateway-client";
+const LATENCY_HEADER: &str = "x-gateway-latency-ms";
+const TTFT_HEADER: &str = "x-gateway-ttft-ms";
+const CONTEXT_TRIMMED_HEADER: &str = "x-gateway-context-trimmed";
+const GATEWAY_STATS_COMMENT: &str = "x-gateway-stats";
+const DEFAULT_MAX_NUM_RESULTS: usize = 10;
+const CHARS_PER_TOKEN: usize = 4;
 
 type AppResponse = Response<BoxBody<Bytes, Infallible>>;
 
 pub async fn run(config: GlobalConfig, addr: Option<u16>) -> Result<()> {
     let addr = match addr {
-        Some(port) =>   format!("127.0.0.1:{port}"),
+        Some(port) => format!("127.0.0.1:{port}"),
         None => DEFAULT_ADDRESS.to_string(),
     };
-    let server = Arc::new(Server::new(&config));
+    let server = Arc::new(Server::new(&config)?);
     let listener = TcpListener::bind(&addr).await?;
     let stop_server = server.run(listener).await?;
     info!("Chat Completions API: http://{addr}/v1/chat/completions");
-    
+
     shutdown_signal().await;
     let _ = stop_server.send(());
     Ok(())
@@ -49,13 +80,50 @@ struct Server {
     clients: Vec<ClientConfig>,
     model: Model,
     models: Vec<Value>,
+    max_tokens_policy: MaxTokensPolicy,
+    tools: ToolRunner,
+    tracer: Tracer,
+    responses: Option<Arc<ResponseStore>>,
+    session_locks: Mutex<HashMap<String, Arc<AsyncMutex<()>>>>,
+    knowledge_bases: Mutex<HashMap<String, Arc<Rag>>>,
+    documents_dir: Option<PathBuf>,
+    knowledge_base_locks: Mutex<HashMap<String, Arc<AsyncMutex<()>>>>,
+    vector_store_lock: Mutex<()>,
 }
 
 impl Server {
-    fn new(config: &GlobalConfig) -> Self {
+    fn new(config: &GlobalConfig) -> Result<Self> {
         let config = config.read();
+        let tracer = Tracer::init(&config.trace)?;
+        let responses = match ResponseStore::open() {
+            Ok(store) => Some(Arc::new(store)),
+            Err(err) => {
+                warn!("Storing responses is disabled: {err:#}");
+                None
+            }
+        };
         let clients = config.clients.clone();
         let model = config.model.clone();
+        let max_tokens_policy = config.max_tokens_policy;
+        let documents_dir = match config.documents_dir.as_deref().map(Path::new) {
+            Some(dir) => match dir.canonicalize() {
+                Ok(dir) => Some(dir),
+                Err(err) => {
+                    warn!("Reading paths is disabled, '{}': {err}", dir.display());
+                    None
+                }
+            },
+            None => None,
+        };
+        let tools = ToolRunner {
+            function: config.function.clone(),
+            mcp: Arc::new(McpHub::new(
+                config.mcp_servers.clone(),
+                Duration::from_secs(config.function_execution.timeout),
+            )),
+            options: config.function_execution.clone(),
+            validation: config.tool_call_validation.clone(),
+        };
         let mut models = list_chat_models(&config);
         let mut default_model = model.clone();
         default_model.data_mut().name = DEFAULT_MODEL_NAME.into();
@@ -76,7 +144,7 @@ impl Server {
                     input_price,
                     output_price,
                     supports_vision,
-                    supports_function_calling,
+                    emulate_function_calling,
                     ..
                 } = model.data();
                 json!({
@@ -87,15 +155,25 @@ impl Server {
                     "input_price": input_price,
                     "output_price": output_price,
                     "supports_vision": supports_vision,
-                    "supports_function_calling": supports_function_calling,
+                    "supports_function_calling": model.supports_function_calling(),
+                    "emulate_function_calling": emulate_function_calling,
                 })
             })
             .collect();
-        Self {
+        Ok(Self {
             clients,
             model,
             models,
-        }
+            max_tokens_policy,
+            tools,
+            tracer,
+            responses,
+            session_locks: Default::default(),
+            knowledge_bases: Default::default(),
+            documents_dir,
+            knowledge_base_locks: Default::default(),
+            vector_store_lock: Default::default(),
+        })
     }
     async fn run(self: Arc<Self>, listener: TcpListener) -> Result<oneshot::Sender<()>> {
         let (tx, rx) = oneshot::channel();
@@ -150,6 +228,101 @@ impl Server {
             self.chat_completion(req).await
         } else if path == "/v1/models" {
             self.list_models()
+        } else if path == "/v1/tokenize" {
+            self.tokenize(req).await
+        } else if path == "/v1/count_tokens" {
+            self.count_tokens(req).await
+        } else if path == "/metrics" {
+            self.metrics()
+        } else if path == "/v1/traces" {
+            self.list_traces(uri.query().unwrap_or_default())
+        } else if let Some(id) = path.strip_prefix("/v1/traces/") {
+            self.get_trace(id)
+        } else if let Some(trace_id) = path.strip_prefix("/v1/runs/") {
+            self.get_run(trace_id)
+        } else if path == "/v1/responses" {
+            self.create_response(req).await
+        } else if let Some(id) = path.strip_prefix("/v1/responses/") {
+            match method {
+                Method::DELETE => self.delete_response(id),
+                _ => self.get_response(id),
+            }
+        } else if path == "/v1/sessions" {
+            match method {
+                Method::POST => self.create_session(req).await,
+                _ => self.list_sessions(),
+            }
+        } else if let Some(rest) = path.strip_prefix("/v1/sessions/") {
+            match (method.clone(), rest.split_once('/')) {
+                (Method::GET, None) => self.get_session(rest),
+                (Method::DELETE, None) => self.delete_session(rest).await,
+                (Method::POST, Some((id, "messages"))) => self.session_message(id, req).await,
+                (Method::POST, Some((id, "compress"))) => self.compress_session(id, req).await,
+                _ => {
+                    status = StatusCode::NOT_FOUND;
+                    Err(anyhow!("The requested endpoint was not found."))
+                }
+            }
+        } else if path == "/v1/knowledge_bases" {
+            match method {
+                Method::POST => self.create_knowledge_base(req).await,
+                _ => self.list_knowledge_bases(),
+            }
+        } else if let Some(rest) = path.strip_prefix("/v1/knowledge_bases/") {
+            match (method.clone(), rest.split_once('/')) {
+                (Method::GET, None) => self.get_knowledge_base(rest),
+                (Method::DELETE, None) => self.delete_knowledge_base(rest).await,
+                (Method::POST, Some((name, "documents"))) => {
+                    self.add_knowledge_base_documents(name, req).await
+                }
+                (Method::POST, Some((name, "search"))) => {
+                    self.search_knowledge_base(name, req).await
+                }
+                _ => {
+                    status = StatusCode::NOT_FOUND;
+                    Err(anyhow!("The requested endpoint was not found."))
+                }
+            }
+        } else if path == "/v1/mcp_servers" {
+            self.list_mcp_servers().await
+        } else if path == "/v1/files" {
+            match method {
+                Method::POST => self.upload_file(req).await,
+                _ => self.list_files(),
+            }
+        } else if let Some(rest) = path.strip_prefix("/v1/files/") {
+            match (method.clone(), rest.split_once('/')) {
+                (Method::GET, None) => self.get_file(rest),
+                (Method::DELETE, None) => self.delete_file(rest).await,
+                (Method::GET, Some((id, "content"))) => self.file_content(id),
+                _ => {
+                    status = StatusCode::NOT_FOUND;
+                    Err(anyhow!("The requested endpoint was not found."))
+                }
+            }
+        } else if path == "/v1/vector_stores" {
+            match method {
+                Method::POST => self.create_vector_store(req).await,
+                _ => self.list_vector_stores(),
+            }
+        } else if let Some(rest) = path.strip_prefix("/v1/vector_stores/") {
+            let segments: Vec<&str> = rest.split('/').collect();
+            match (method.clone(), segments.as_slice()) {
+                (Method::GET, [id]) => self.get_vector_store(id),
+                (Method::POST, [id]) => self.update_vector_store(id, req).await,
+                (Method::DELETE, [id]) => self.delete_vector_store(id).await,
+                (Method::GET, [id, "files"]) => self.list_vector_store_files(id),
+                (Method::POST, [id, "files"]) => self.attach_vector_store_file(id, req).await,
+                (Method::GET, [id, "files", file_id]) => self.get_vector_store_file(id, file_id),
+                (Method::DELETE, [id, "files", file_id]) => {
+                    self.detach_vector_store_file(id, file_id).await
+                }
+                (Method::POST, [id, "search"]) => self.search_vector_store(id, req).await,
+                _ => {
+                    status = StatusCode::NOT_FOUND;
+                    Err(anyhow!("The requested endpoint was not found."))
+                }
+            }
         } else {
             status = StatusCode::NOT_FOUND;
             Err(anyhow!("The requested endpoint was not found."))
@@ -160,7 +333,11 @@ impl Server {
                 res
             }
             Err(err) => {
-                status = StatusCode::BAD_REQUEST;
+                status = if err.is::<NotFound>() {
+                    StatusCode::NOT_FOUND
+                } else {
+                    StatusCode::BAD_REQUEST
+                };
                 error!("{method} {uri} {} {err}", status.as_u16());
                 ret_err(err)
             }
@@ -178,267 +355,2641 @@ impl Server {
         Ok(res)
     }
 
-    async fn chat_completion(&self, req: hyper::Request<Incoming>) -> Result<AppResponse> {
-        let req_body = req.collect().await?.to_bytes();
-        let req_body: ChatCompletionReqBody = serde_json::from_slice(&req_body)
-            .map_err(|err| anyhow!("Invalid request body, {err}"))?;
-
-        let ChatCompletionReqBody {
-            model,
-            messages,
-            temperature,
-            top_p,
-            max_tokens,
-            stream,
-        } = req_body;
+    fn metrics(&self) -> Result<AppResponse> {
+        let res = Response::builder()
+            .header("Content-Type", "text/plain; version=0.0.4; charset=utf-8")
+            .body(Full::new(Bytes::from(self.tracer.metrics().render())).boxed())?;
+        Ok(res)
+    }
 
-        log::debug!(
-            "Chat completion request: model={model}, messages={messages:?}, temperature={temperature:?}, top_p={top_p:?}, max_tokens={max_tokens:?}, stream={stream}"
-        );
-        let config = Config {
-            clients: self.clients.to_vec(),
-            model: self.model.clone(),
-            ..Default::default()
-        };
-        let config = Arc::new(RwLock::new(config));
+    fn list_traces(&self, query: &str) -> Result<AppResponse> {
+        let query = TraceQuery::from_query_str(query)?;
+        let data = json!(self.tracer.list(&query)?);
+        let res = Response::builder()
+            .header("Content-Type", "application/json; charset=utf-8")
+            .body(Full::new(Bytes::from(data.to_string())).boxed())?;
+        Ok(res)
+    }
 
-        let (model_name, change) = if model == DEFAULT_MODEL_NAME {
-            (self.model.id(), true)
-        } else if self.model.id() == model {
-            (model, false)
-        } else {
-            (model, true)
+    fn get_trace(&self, id: &str) -> Result<AppResponse> {
+        let Some(record) = self.tracer.get(id)? else {
+            return Err(NotFound(format!("No trace '{id}'")).into());
         };
+        let data = json!(record);
+        let res = Response::builder()
+            .header("Content-Type", "application/json; charset=utf-8")
+            .body(Full::new(Bytes::from(data.to_string())).boxed())?;
+        Ok(res)
+    }
 
-        log::debug!("Model name: {}", model_name);
-        if change {
-            config.write().set_model(&model_name)?;
+    fn get_run(&self, trace_id: &str) -> Result<AppResponse> {
+        let records = self.tracer.find_run(trace_id)?;
+        if records.is_empty() {
+            return Err(NotFound(format!("No run '{trace_id}'")).into());
         }
+        let data = json!(build_run_tree(trace_id, records));
+        let res = Response::builder()
+            .header("Content-Type", "application/json; charset=utf-8")
+            .body(Full::new(Bytes::from(data.to_string())).boxed())?;
+        Ok(res)
+    }
 
-        let mut client = init_client(&config, None)?;
-        if max_tokens.is_some() {
-            client.model_mut().set_max_tokens(max_tokens, true);
+    async fn create_response(&self, req: hyper::Request<Incoming>) -> Result<AppResponse> {
+        let request_id = generate_request_id();
+        let mut record = TraceRecord::new(&request_id, caller_key(&req).as_deref());
+        let started = Instant::now();
+        self.tracer.start();
+        let ret = self
+            .create_response_inner(req, &request_id, &mut record, started)
+            .await;
+        if let Err(err) = &ret {
+            if !record.is_taken() {
+                record.latency_ms = started.elapsed().as_millis() as u64;
+                record.set_error(StatusCode::BAD_REQUEST.as_u16(), err);
+                self.tracer.record(record);
+            }
         }
-        let abort = create_abort_signal();
-        let http_client = client.build_client()?;
+        ret
+    }
 
-        let completion_id = generate_completion_id();
-        let created = Utc::now().timestamp();
+    /// Resolves `previous_response_id` into the full history, then answers with any configured client.
+    async fn create_response_inner(
+        &self,
+        req: hyper::Request<Incoming>,
+        request_id: &str,
+        record: &mut TraceRecord,
+        started: Instant,
+    ) -> Result<AppResponse> {
+        let headers = req.headers().clone();
+        let req_body = req.collect().await?.to_bytes();
+        let req_body: Value = serde_json::from_slice(&req_body)
+            .map_err(|err| anyhow!("Invalid request body, {err}"))?;
+        let run = RunContext::from_request(&headers, &req_body["metadata"]);
+        run.apply(record);
+        record.model = req_body["model"].as_str().unwrap_or_default().to_string();
+        record.request = Some(req_body.clone());
+        let req_body: ResponsesReqBody = serde_json::from_value(req_body)
+            .map_err(|err| anyhow!("Invalid request body, {err}"))?;
+        let stream = req_body.stream;
+        let store = req_body.store.unwrap_or(true);
+        record.stream = stream;
+
+        let mut history = match &req_body.previous_response_id {
+            Some(id) => self.stored_response(id)?.messages,
+            None => vec![],
+        };
+        history.extend(req_body.input.to_messages()?);
+        let mut messages = vec![];
+        if let Some(instructions) = &req_body.instructions {
+            messages.push(Message::new(
+                MessageRole::System,
+                MessageContent::Text(instructions.clone()),
+            ));
+        }
+        messages.extend(history.iter().cloned());
 
-        let data: ChatCompletionsData = ChatCompletionsData {
+        let (config, model_name) = self.init_config(req_body.model)?;
+        let mut client = init_client(&config, None)?;
+        record.set_model(client.model());
+        let http_client = client.build_client()?;
+        let mut data = ChatCompletionsData {
             messages,
-            temperature,
-            top_p,
+            temperature: req_body.temperature,
+            top_p: req_body.top_p,
             functions: None,
             stream,
         };
+        prepare_data(
+            record,
+            &mut client,
+            &http_client,
+            &mut data,
+            req_body.context_strategy,
+            req_body.max_output_tokens,
+            self.max_tokens_policy,
+        )
+        .await?;
+
+        let object = ResponseObject {
+            id: generate_response_id(),
+            message_id: generate_message_id(),
+            created_at: Utc::now().timestamp(),
+            model: model_name,
+            instructions: req_body.instructions,
+            previous_response_id: req_body.previous_response_id,
+            temperature: req_body.temperature,
+            top_p: req_body.top_p,
+            max_output_tokens: req_body.max_output_tokens,
+            store,
+            metadata: req_body.metadata.unwrap_or_else(|| json!({})),
+        };
+        let responses = self.responses.clone().filter(|_| store);
 
         if stream {
+            let model_resolved = client.model().id();
+            let client_name = client.model().client_name().to_string();
+            let abort = create_abort_signal();
             let (tx, mut rx) = unbounded_channel();
+            let tracer = self.tracer.clone();
+            let mut record = std::mem::take(record);
+            let task_object = object.clone();
             tokio::spawn(async move {
                 let mut is_first = true;
-                let (tx2, rx2) = unbounded_channel();
-                let mut handler = SseHandler::new(tx2, abort);
-                async fn map_event(
-                    mut rx: UnboundedReceiver<SseEvent>,
-                    tx: &UnboundedSender<ResEvent>,
-                    is_first: &mut bool,
-                ) {
-                    while let Some(reply_event) = rx.recv().await {
-                        if *is_first {
-                            let _ = tx.send(ResEvent::First(None));
-                            *is_first = false;
-                        }
-                        match reply_event {
-                            SseEvent::Text(text) => {
-                                let _ = tx.send(ResEvent::Text(text));
-                            }
-                            SseEvent::Done => {
-                                let _ = tx.send(ResEvent::Done);
-                            }
+                let text = stream_completion(
+                    client.as_ref(),
+                    &http_client,
+                    data,
+                    abort,
+                    &tx,
+                    &mut record,
+                    started,
+                    &mut is_first,
+                )
+                .await;
+                let response = match &record.error {
+                    Some(err) => {
+                        // Before any delta the request fails; afterwards the failure is the response.
+                        if is_first {
+                            send_error_event(&tx, err, &mut is_first);
                         }
+                        task_object.failed(&text, err)
                     }
-                }
-                tokio::select! {
-                    _ = map_event(rx2, &tx, &mut is_first) => {}
-                    ret = client.chat_completions_streaming_inner(&http_client, &mut handler, data) => {
-                        if let Err(err) = ret {
-                            send_first_event(&tx, Some(format!("{err:?}")), &mut is_first)
-                        }
-                        let _ = tx.send(ResEvent::Done);
+                    None => {
+                        let response = task_object.completed(
+                            &text,
+                            record.input_tokens.unwrap_or_default(),
+                            record.output_tokens.unwrap_or_default(),
+                        );
+                        save_response(responses.as_deref(), history, &text, &response);
+                        response
                     }
-                }
+                };
+                let _ = tx.send(ResEvent::Response(response));
+                let _ = tx.send(ResEvent::Done);
+                tracer.record(record);
             });
 
-            let first_event = rx.recv().await;
-
-            if let Some(ResEvent::First(Some(err))) = first_event {
+            if let Some(ResEvent::First(Some(err))) = rx.recv().await {
                 bail!("{err}");
             }
 
-            let shared: Arc<(String, String, i64)> = Arc::new((completion_id, model_name, created));
-            let stream = UnboundedReceiverStream::new(rx);
-            let stream = stream.filter_map(move |res_event| {
-                let shared = shared.clone();
-                async move {
-                    let (completion_id, model, created) = shared.as_ref();
-                    match res_event {
-                        ResEvent::Text(text) => Some(Ok(create_frame(
-                            completion_id,
-                            model,
-                            *created,
-                            &text,
-                            false,
-                        ))),
-                        ResEvent::Done => {
-                            Some(Ok(create_frame(completion_id, model, *created, "", true)))
-                        }
-                        _ => None,
-                    }
-                }
-            });
+            let mut events = ResponseEvents::new(object);
+            let start = events.start();
+            let stream = UnboundedReceiverStream::new(rx)
+                .map(move |res_event| {
+                    let frames = match res_event {
+                        ResEvent::Text(text) => vec![events.delta(&text)],
+                        ResEvent::Response(response) => events.finish(response),
+                        _ => vec![],
+                    };
+                    futures_util::stream::iter(frames)
+                })
+                .flatten();
+            let stream = futures_util::stream::iter(start)
+                .chain(stream)
+                .map(|frame| Ok(Frame::data(Bytes::from(frame))));
             let res = Response::builder()
                 .status(StatusCode::OK)
                 .header("Content-Type", "text/event-stream")
                 .header("Cache-Control", "no-cache")
                 .header("Connection", "keep-alive")
+                .header(REQUEST_ID_HEADER, request_id)
+                .header(TRACE_ID_HEADER, &run.trace_id)
+                .header(SPAN_ID_HEADER, &run.span_id)
+                .header(MODEL_RESOLVED_HEADER, &model_resolved)
+                .header(CLIENT_HEADER, &client_name)
                 .body(BodyExt::boxed(StreamBody::new(stream)))?;
             Ok(res)
         } else {
-            let output = client.chat_completions_inner(&http_client, data).await?;
-            let res = Response::builder()
+            let (output, upstream_request) =
+                capture_upstream(client.chat_completions_inner(&http_client, data)).await;
+            record.upstream_request = upstream_request;
+            let output = output?;
+            record.latency_ms = started.elapsed().as_millis() as u64;
+            record.set_output(client.model(), &output);
+            let response = object.completed(
+                &output.text,
+                output.input_tokens.unwrap_or_default(),
+                output.output_tokens.unwrap_or_default(),
+            );
+            save_response(responses.as_deref(), history, &output.text, &response);
+            let mut builder = Response::builder()
                 .header("Content-Type", "application/json")
-                .body(
-                    Full::new(ret_non_stream(
-                        &completion_id,
-                        &model_name,
-                        created,
-                        &output,
-                    ))
-                    .boxed(),
-                )?;
+                .header(REQUEST_ID_HEADER, request_id)
+                .header(TRACE_ID_HEADER, &run.trace_id)
+                .header(SPAN_ID_HEADER, &run.span_id)
+                .header(TTFT_HEADER, record.latency_ms.to_string());
+            for (key, value) in gateway_headers(record) {
+                builder = builder.header(key, value);
+            }
+            self.tracer.record(std::mem::take(record));
+            let res = builder.body(Full::new(Bytes::from(response.to_string())).boxed())?;
             Ok(res)
         }
     }
-}
 
-#[derive(Debug, Deserialize)]
-struct ChatCompletionReqBody {
-    model: String,
-    messages: Vec<Message>,
-    temperature: Option<f64>,
-    top_p: Option<f64>,
-    max_tokens: Option<isize>,
-    #[serde(default)]
-    stream: bool,
-}
-
-#[derive(Debug)]
-enum ResEvent {
-    First(Option<String>),
-    Text(String),
-    Done,
-}
-
-fn send_first_event(tx: &UnboundedSender<ResEvent>, data: Option<String>, is_first: &mut bool) {
-    if *is_first {
-        let _ = tx.send(ResEvent::First(data));
-        *is_first = false;
+    fn get_response(&self, id: &str) -> Result<AppResponse> {
+        let data = self.stored_response(id)?.response;
+        let res = Response::builder()
+            .header("Content-Type", "application/json; charset=utf-8")
+            .body(Full::new(Bytes::from(data.to_string())).boxed())?;
+        Ok(res)
     }
-}
 
-async fn shutdown_signal() {
-    tokio::signal::ctrl_c()
-        .await
-        .expect("Failed to install CTRL+C signal handler")
-}
+    fn delete_response(&self, id: &str) -> Result<AppResponse> {
+        let Some(responses) = &self.responses else {
+            bail!("Storing responses is disabled");
+        };
+        if !responses.delete(id)? {
+            return Err(NotFound(format!("No response '{id}'")).into());
+        }
+        let data = json!({ "id": id, "object": "response", "deleted": true });
+        let res = Response::builder()
+            .header("Content-Type", "application/json; charset=utf-8")
+            .body(Full::new(Bytes::from(data.to_string())).boxed())?;
+        Ok(res)
+    }
 
-fn generate_completion_id() -> String {
-    let random_id = chrono::Utc::now().nanosecond();
-    format!("chatcmpl-{}", random_id)
-}
+    fn stored_response(&self, id: &str) -> Result<StoredResponse> {
+        let Some(responses) = &self.responses else {
+            bail!("Storing responses is disabled");
+        };
+        responses
+            .get(id)?
+            .ok_or_else(|| NotFound(format!("No response '{id}'")).into())
+    }
 
-fn set_cors_header(res: &mut AppResponse) {
-    res.headers_mut().insert(
-        hyper::header::ACCESS_CONTROL_ALLOW_ORIGIN,
-        hyper::header::HeaderValue::from_static("*"),
-    );
-    res.headers_mut().insert(
-        hyper::header::ACCESS_CONTROL_ALLOW_METHODS,
-        hyper::header::HeaderValue::from_static("GET,POST,PUT,PATCH,DELETE"),
-    );
-    res.headers_mut().insert(
-        hyper::header::ACCESS_CONTROL_ALLOW_HEADERS,
-        hyper::header::HeaderValue::from_static("Content-Type,Authorization"),
-    );
-}
+    fn list_sessions(&self) -> Result<AppResponse> {
+        let config = Config::default();
+        let data: Vec<Value> = config
+            .list_sessions()
+            .into_iter()
+            .filter_map(|id| {
+                let session = Session::load(&id, &Config::session_file(&id).ok()?).ok()?;
+                Some(json!({
+                    "id": id,
+                    "object": "session",
+                    "model": session.model_id(),
+                    "messages": session.messages().len(),
+                }))
+            })
+            .collect();
+        let data = json!({ "object": "list", "data": data });
+        let res = Response::builder()
+            .header("Content-Type", "application/json; charset=utf-8")
+            .body(Full::new(Bytes::from(data.to_string())).boxed())?;
+        Ok(res)
+    }
 
-fn create_frame(id: &str, model: &str, created: i64, content: &str, done: bool) -> Frame<Bytes> {
-    let (delta, finish_reason) = if done {
-        (json!({}), "stop".into())
-    } else {
-        let delta = if content.is_empty() {
-            json!({ "role": "assistant", "content": content })
+    async fn create_session(&self, req: hyper::Request<Incoming>) -> Result<AppResponse> {
+        let req_body = req.collect().await?.to_bytes();
+        let req_body: CreateSessionReqBody = if req_body.is_empty() {
+            Default::default()
         } else {
-            json!({ "content": content })
+            serde_json::from_slice(&req_body)
+                .map_err(|err| anyhow!("Invalid request body, {err}"))?
         };
-        (delta, Value::Null)
-    };
-    let value = json!({
-        "id": id,
-        "object": "chat.completion.chunk",
-        "created": created,
-        "model": model,
-        "choices": [
-            {
-                "index": 0,
-                "delta": delta,
-                "finish_reason": finish_reason,
-            },
-        ],
-    });
-    let output = if done {
-        format!("data: {value}\n\ndata: [DONE]\n\n")
-    } else {
-        format!("data: {value}\n\n")
-    };
-    Frame::data(Bytes::from(output))
-}
+        let id = match req_body.name {
+            Some(name) => {
+                guard_session_id(&name)?;
+                name
+            }
+            None => format!("sess_{}", random_hex(12)),
+        };
+        if Config::session_file(&id)?.exists() {
+            bail!("Session '{id}' already exists");
+        }
+        let model = req_body.model.unwrap_or_else(|| DEFAULT_MODEL_NAME.into());
+        let (config, _) = self.init_config(model)?;
+        let mut session = Session::new(&config.read(), &id);
+        session.set_temperature(req_body.temperature);
+        session.set_top_p(req_body.top_p);
+        session.set_compress_threshold(req_body.compress_threshold);
+        if let Some(system) = &req_body.system {
+            session.set_system_prompt(system)?;
+        }
+        session.save(&Config::sessions_dir()?)?;
+        let data = session.to_json();
+        let res = Response::builder()
+            .header("Content-Type", "application/json; charset=utf-8")
+            .body(Full::new(Bytes::from(data.to_string())).boxed())?;
+        Ok(res)
+    }
 
-fn ret_non_stream(id: &str, model: &str, created: i64, output: &ChatCompletionsOutput) -> Bytes {
-    let id = output.id.as_deref().unwrap_or(id);
-    let input_tokens = output.input_tokens.unwrap_or_default();
-    let output_tokens = output.output_tokens.unwrap_or_default();
-    let total_tokens = input_tokens + output_tokens;
-    let res_body = json!({
-        "id": id,
-        "object": "chat.completion",
-        "created": created,
-        "model": model,
-        "choices": [
-            {
-                "index": 0,
-                "message": {
-                    "role": "assistant",
-                    "content": output.text,
-                },
-                "logprobs": null,
-                "finish_reason": "stop",
-            },
-        ],
-        "usage": {
-            "prompt_tokens": input_tokens,
-            "completion_tokens": output_tokens,
-            "total_tokens": total_tokens,
-        },
-    });
-    Bytes::from(res_body.to_string())
-}
+    fn get_session(&self, id: &str) -> Result<AppResponse> {
+        let (session, _) = self.load_session(id)?;
+        let data = session.to_json();
+        let res = Response::builder()
+            .header("Content-Type", "application/json; charset=utf-8")
+            .body(Full::new(Bytes::from(data.to_string())).boxed())?;
+        Ok(res)
+    }
+
+    async fn delete_session(&self, id: &str) -> Result<AppResponse> {
+        guard_session_id(id)?;
+        // Waits for a running turn, which would otherwise save the session again.
+        let lock = self.session_lock(id);
+        let _guard = lock.lock().await;
+        let path = Config::session_file(id)?;
+        if !path.exists() {
+            bail!("No session '{id}'");
+        }
+        std::fs::remove_file(&path)
+            .map_err(|err| anyhow!("Failed to delete session '{id}', {err}"))?;
+        self.session_locks.lock().remove(id);
+        let data = json!({ "id": id, "object": "session.deleted", "deleted": true });
+        let res = Response::builder()
+            .header("Content-Type", "application/json; charset=utf-8")
+            .body(Full::new(Bytes::from(data.to_string())).boxed())?;
+        Ok(res)
+    }
+
+    /// Appends a user turn, answers it with the session's model and settings, and saves the reply.
+    async fn session_message(
+        &self,
+        id: &str,
+        req: hyper::Request<Incoming>,
+    ) -> Result<AppResponse> {
+        let mut record = TraceRecord::new(&generate_request_id(), caller_key(&req).as_deref());
+        let started = Instant::now();
+        self.tracer.start();
+        let ret = self.session_message_inner(id, req, &mut record).await;
+        record.latency_ms = started.elapsed().as_millis() as u64;
+        if let Err(err) = &ret {
+            record.set_error(StatusCode::BAD_REQUEST.as_u16(), err);
+        }
+        self.tracer.record(record);
+        ret
+    }
+
+    async fn session_message_inner(
+        &self,
+        id: &str,
+        req: hyper::Request<Incoming>,
+        record: &mut TraceRecord,
+    ) -> Result<AppResponse> {
+        let req_body = req.collect().await?.to_bytes();
+        let req_body: Value = serde_json::from_slice(&req_body)
+            .map_err(|err| anyhow!("Invalid request body, {err}"))?;
+        record.request = Some(req_body.clone());
+        let SessionMessageReqBody {
+            content,
+            max_tokens,
+            context_strategy,
+        } = serde_json::from_value(req_body)
+            .map_err(|err| anyhow!("Invalid request body, {err}"))?;
+
+        let lock = self.session_lock(id);
+        let _guard = lock.lock().await;
+        let (mut session, config) = self.load_session(id)?;
+        record.model = session.model_id().to_string();
+        let mut client = init_client(&config, None)?;
+        let http_client = client.build_client()?;
+        let mut data = ChatCompletionsData {
+            messages: session.build_turn_messages(content.clone()),
+            temperature: session.temperature(),
+            top_p: session.top_p(),
+            functions: None,
+            stream: false,
+        };
+        prepare_data(
+            record,
+            &mut client,
+            &http_client,
+            &mut data,
+            context_strategy,
+            max_tokens,
+            self.max_tokens_policy,
+        )
+        .await?;
+
+        record.set_model(client.model());
+        let (output, upstream_request) =
+            capture_upstream(client.chat_completions_inner(&http_client, data)).await;
+        record.upstream_request = upstream_request;
+        let output = output?;
+        record.set_output(client.model(), &output);
+        session.add_turn(content, &output.text);
+        let sessions_dir = Config::sessions_dir()?;
+        session.save(&sessions_dir)?;
+        // The turn is already stored, so a failed compression is retried on the next turn.
+        let mut compressed = false;
+        if session.need_compress(0) {
+            let started = Instant::now();
+            let timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+            let ret = summarize_session(client.as_ref(), &http_client, &mut session).await;
+            record
+                .spans
+                .push(summary_span(client.model(), started, timestamp, &ret));
+            match ret {
+                Ok(_) => {
+                    session.save(&sessions_dir)?;
+                    compressed = true;
+                }
+                Err(err) => log::warn!("Failed to compress session '{id}', {err:#}"),
+            }
+        }
+
+        let data = json!({
+            "id": record.id,
+            "object": "session.message",
+            "session_id": id,
+            "model": session.model_id(),
+            "message": {
+                "role": "assistant",
+                "content": output.text,
+            },
+            "usage": {
+                "prompt_tokens": output.input_tokens.unwrap_or_default(),
+                "completion_tokens": output.output_tokens.unwrap_or_default(),
+            },
+            "session_tokens": session.tokens(),
+            "compressed": compressed,
+        });
+        let mut builder = Response::builder()
+            .header("Content-Type", "application/json; charset=utf-8")
+            .header(REQUEST_ID_HEADER, &record.id);
+        for (key, value) in gateway_headers(record) {
+            builder = builder.header(key, value);
+        }
+        let res = builder.body(Full::new(Bytes::from(data.to_string())).boxed())?;
+        Ok(res)
+    }
+
+    async fn compress_session(
+        &self,
+        id: &str,
+        req: hyper::Request<Incoming>,
+    ) -> Result<AppResponse> {
+        let lock = self.session_lock(id);
+        let _guard = lock.lock().await;
+        let (mut session, config) = self.load_session(id)?;
+        if session.messages().iter().all(|v| v.role.is_system()) {
+            bail!("Session '{id}' has nothing to compress");
+        }
+        let mut record = TraceRecord::new(&generate_request_id(), caller_key(&req).as_deref());
+        record.model = session.model_id().to_string();
+        let client = init_client(&config, None)?;
+        let http_client = client.build_client()?;
+        let started = Instant::now();
+        self.tracer.start();
+        record.set_model(client.model());
+        let (ret, upstream_request) = capture_upstream(summarize_session(
+            client.as_ref(),
+            &http_client,
+            &mut session,
+        ))
+        .await;
+        record.upstream_request = upstream_request;
+        record.latency_ms = started.elapsed().as_millis() as u64;
+        match &ret {
+            Ok(output) => record.set_output(client.model(), output),
+            Err(err) => record.set_error(StatusCode::BAD_REQUEST.as_u16(), err),
+        }
+        self.tracer.record(record);
+        ret?;
+        session.save(&Config::sessions_dir()?)?;
+        let data = session.to_json();
+        let res = Response::builder()
+            .header("Content-Type", "application/json; charset=utf-8")
+            .body(Full::new(Bytes::from(data.to_string())).boxed())?;
+        Ok(res)
+    }
+
+    /// A stored session, with its model resolved against the configured clients.
+    fn load_session(&self, id: &str) -> Result<(Session, GlobalConfig)> {
+        guard_session_id(id)?;
+        let path = Config::session_file(id)?;
+        if !path.exists() {
+            bail!("No session '{id}'");
+        }
+        let mut session = Session::load(id, &path)?;
+        let (config, _) = self.init_config(session.model_id().to_string())?;
+        session.set_model(&config.read().model);
+        Ok((session, config))
+    }
+
+    fn session_lock(&self, id: &str) -> Arc<AsyncMutex<()>> {
+        self.session_locks
+            .lock()
+            .entry(id.to_string())
+            .or_default()
+            .clone()
+    }
+
+    fn list_knowledge_bases(&self) -> Result<AppResponse> {
+        let data: Vec<Value> = Rag::list()
+            .into_iter()
+            .filter(|name| !name.starts_with(VECTOR_STORE_PREFIX))
+            .filter_map(|name| Some(self.knowledge_base(&name).ok()?.to_json()))
+            .collect();
+        let data = json!({ "object": "list", "data": data });
+        let res = Response::builder()
+            .header("Content-Type", "application/json; charset=utf-8")
+            .body(Full::new(Bytes::from(data.to_string())).boxed())?;
+        Ok(res)
+    }
+
+    async fn create_knowledge_base(&self, req: hyper::Request<Incoming>) -> Result<AppResponse> {
+        let req_body = req.collect().await?.to_bytes();
+        let req_body: CreateKnowledgeBaseReqBody = serde_json::from_slice(&req_body)
+            .map_err(|err| anyhow!("Invalid request body, {err}"))?;
+        if req_body.name.starts_with(VECTOR_STORE_PREFIX) {
+            bail!(
+                "Invalid knowledge base name '{}', '{VECTOR_STORE_PREFIX}' is reserved for vector stores",
+                req_body.name
+            );
+        }
+        let lock = self.knowledge_base_lock(&req_body.name);
+        let _guard = lock.lock().await;
+        let client = self.init_embedding_client(req_body.embedding_model.as_deref())?;
+        let mut rag = Rag::create(
+            &req_body.name,
+            client.model(),
+            req_body.chunk_size,
+            req_body.chunk_overlap,
+        )?;
+        let documents = collect_documents(req_body.documents, self.documents_dir.as_deref())?;
+        rag.add_documents(client.as_ref(), documents).await?;
+        rag.save()?;
+        let data = rag.to_json();
+        self.knowledge_bases
+            .lock()
+            .insert(req_body.name, Arc::new(rag));
+        let res = Response::builder()
+            .header("Content-Type", "application/json; charset=utf-8")
+            .body(Full::new(Bytes::from(data.to_string())).boxed())?;
+        Ok(res)
+    }
+
+    fn get_knowledge_base(&self, name: &str) -> Result<AppResponse> {
+        let data = self.knowledge_base(name)?.to_json();
+        let res = Response::builder()
+            .header("Content-Type", "application/json; charset=utf-8")
+            .body(Full::new(Bytes::from(data.to_string())).boxed())?;
+        Ok(res)
+    }
+
+    async fn delete_knowledge_base(&self, name: &str) -> Result<AppResponse> {
+        if name.starts_with(VECTOR_STORE_PREFIX) {
+            bail!("'{name}' is a vector store, delete it through /v1/vector_stores");
+        }
+        // Waits for running ingests, which would otherwise save the knowledge base again.
+        let lock = self.knowledge_base_lock(name);
+        let _guard = lock.lock().await;
+        if !Rag::remove(name)? {
+            bail!("No knowledge base '{name}'");
+        }
+        self.knowledge_bases.lock().remove(name);
+        let data = json!({ "id": name, "object": "knowledge_base.deleted", "deleted": true });
+        let res = Response::builder()
+            .header("Content-Type", "application/json; charset=utf-8")
+            .body(Full::new(Bytes::from(data.to_string())).boxed())?;
+        Ok(res)
+    }
+
+    async fn add_knowledge_base_documents(
+        &self,
+        name: &str,
+        req: hyper::Request<Incoming>,
+    ) -> Result<AppResponse> {
+        let req_body = req.collect().await?.to_bytes();
+        let req_body: KnowledgeBaseDocumentsReqBody = serde_json::from_slice(&req_body)
+            .map_err(|err| anyhow!("Invalid request body, {err}"))?;
+        let lock = self.knowledge_base_lock(name);
+        let _guard = lock.lock().await;
+        // Reloaded rather than taken from the cache, which searches keep reading meanwhile.
+        let mut rag = Rag::load(name)?;
+        let client = self.init_embedding_client(Some(rag.embedding_model()))?;
+        let documents = collect_documents(req_body, self.documents_dir.as_deref())?;
+        rag.add_documents(client.as_ref(), documents).await?;
+        rag.save()?;
+        let data = rag.to_json();
+        self.knowledge_bases
+            .lock()
+            .insert(name.to_string(), Arc::new(rag));
+        let res = Response::builder()
+            .header("Content-Type", "application/json; charset=utf-8")
+            .body(Full::new(Bytes::from(data.to_string())).boxed())?;
+        Ok(res)
+    }
+
+    async fn search_knowledge_base(
+        &self,
+        name: &str,
+        req: hyper::Request<Incoming>,
+    ) -> Result<AppResponse> {
+        let req_body = req.collect().await?.to_bytes();
+        let req_body: SearchKnowledgeBaseReqBody = serde_json::from_slice(&req_body)
+            .map_err(|err| anyhow!("Invalid request body, {err}"))?;
+        let rag = self.knowledge_base(name)?;
+        let client = self.init_embedding_client(Some(rag.embedding_model()))?;
+        let hits = rag
+            .search(
+                client.as_ref(),
+                &req_body.query,
+                req_body.top_k.unwrap_or(DEFAULT_TOP_K),
+            )
+            .await?;
+        let data: Vec<Value> = hits
+            .iter()
+            .enumerate()
+            .map(|(i, v)| v.to_json(i + 1))
+            .collect();
+        let data = json!({ "object": "list", "data": data });
+        let res = Response::builder()
+            .header("Content-Type", "application/json; charset=utf-8")
+            .body(Full::new(Bytes::from(data.to_string())).boxed())?;
+        Ok(res)
+    }
+
+    async fn upload_file(&self, req: hyper::Request<Incoming>) -> Result<AppResponse> {
+        let content_type = req
+            .headers()
+            .get(hyper::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        let req_body = req.collect().await?.to_bytes();
+        let parts = parse_multipart(&content_type, &req_body)?;
+        let file = parts
+            .iter()
+            .find(|v| v.name == "file")
+            .ok_or_else(|| anyhow!("Invalid request body, missing 'file'"))?;
+        let purpose = parts
+            .iter()
+            .find(|v| v.name == "purpose")
+            .map(|v| v.text())
+            .unwrap_or_else(|| "assistants".into());
+        let filename = file.filename.as_deref().unwrap_or("file");
+        let data = FileObject::create(filename, &purpose, &file.data)?.to_json();
+        let res = Response::builder()
+            .header("Content-Type", "application/json; charset=utf-8")
+            .body(Full::new(Bytes::from(data.to_string())).boxed())?;
+        Ok(res)
+    }
+
+    fn list_files(&self) -> Result<AppResponse> {
+        let data: Vec<Value> = FileObject::list().iter().map(|v| v.to_json()).collect();
+        let data = json!({ "object": "list", "data": data, "has_more": false });
+        let res = Response::builder()
+            .header("Content-Type", "application/json; charset=utf-8")
+            .body(Full::new(Bytes::from(data.to_string())).boxed())?;
+        Ok(res)
+    }
+
+    fn get_file(&self, id: &str) -> Result<AppResponse> {
+        let data = FileObject::load(id)?.to_json();
+        let res = Response::builder()
+            .header("Content-Type", "application/json; charset=utf-8")
+            .body(Full::new(Bytes::from(data.to_string())).boxed())?;
+        Ok(res)
+    }
+
+    async fn delete_file(&self, id: &str) -> Result<AppResponse> {
+        if !FileObject::remove(id)? {
+            bail!("No file '{id}'");
+        }
+        // Detached from every vector store too, so searches no longer cite it.
+        let store_ids: Vec<String> = {
+            let _guard = self.vector_store_lock.lock();
+            let mut store_ids = vec![];
+            for mut store in VectorStore::list() {
+                if store.file(id).is_some() {
+                    store.files.retain(|v| v.id != id);
+                    store.save()?;
+                    store_ids.push(store.id);
+                }
+            }
+            store_ids
+        };
+        for store_id in store_ids {
+            self.remove_vector_store_document(&store_id, id).await?;
+        }
+        let data = json!({ "id": id, "object": "file", "deleted": true });
+        let res = Response::builder()
+            .header("Content-Type", "application/json; charset=utf-8")
+            .body(Full::new(Bytes::from(data.to_string())).boxed())?;
+        Ok(res)
+    }
+
+    fn file_content(&self, id: &str) -> Result<AppResponse> {
+        let file = FileObject::load(id)?;
+        let mime = mime_guess::from_path(&file.filename).first_or_octet_stream();
+        let res = Response::builder()
+            .header("Content-Type", mime.as_ref())
+            .body(Full::new(Bytes::from(file.content()?)).boxed())?;
+        Ok(res)
+    }
+
+    async fn create_vector_store(
+        self: &Arc<Self>,
+        req: hyper::Request<Incoming>,
+    ) -> Result<AppResponse> {
+        let req_body = req.collect().await?.to_bytes();
+        let req_body: CreateVectorStoreReqBody = if req_body.is_empty() {
+            Default::default()
+        } else {
+            serde_json::from_slice(&req_body)
+                .map_err(|err| anyhow!("Invalid request body, {err}"))?
+        };
+        let files = req_body
+            .file_ids
+            .iter()
+            .map(|id| FileObject::load(id))
+            .collect::<Result<Vec<_>>>()?;
+        let client = self.init_embedding_client(req_body.embedding_model.as_deref())?;
+        let (chunk_size, chunk_overlap) = match req_body.chunking_strategy {
+            Some(ChunkingStrategy::Static { r#static }) => (
+                Some(r#static.max_chunk_size_tokens * CHARS_PER_TOKEN),
+                Some(r#static.chunk_overlap_tokens * CHARS_PER_TOKEN),
+            ),
+            _ => (None, None),
+        };
+        let id = format!("{VECTOR_STORE_PREFIX}{}", random_hex(12));
+        Rag::create(&id, client.model(), chunk_size, chunk_overlap)?.save()?;
+        let created_at = Utc::now().timestamp();
+        let store = VectorStore {
+            id,
+            name: req_body.name,
+            created_at,
+            metadata: req_body.metadata.unwrap_or_else(|| json!({})),
+            embedding_model: client.model().id(),
+            files: files
+                .iter()
+                .map(|v| VectorStoreFile {
+                    id: v.id.clone(),
+                    created_at,
+                    status: VectorStoreFileStatus::InProgress,
+                    last_error: None,
+                    usage_bytes: 0,
+                })
+                .collect(),
+        };
+        store.save()?;
+        for file in &files {
+            self.spawn_ingest(&store.id, &file.id);
+        }
+        let data = store.to_json();
+        let res = Response::builder()
+            .header("Content-Type", "application/json; charset=utf-8")
+            .body(Full::new(Bytes::from(data.to_string())).boxed())?;
+        Ok(res)
+    }
+
+    fn list_vector_stores(&self) -> Result<AppResponse> {
+        let data: Vec<Value> = VectorStore::list().iter().map(|v| v.to_json()).collect();
+        let data = json!({ "object": "list", "data": data, "has_more": false });
+        let res = Response::builder()
+            .header("Content-Type", "application/json; charset=utf-8")
+            .body(Full::new(Bytes::from(data.to_string())).boxed())?;
+        Ok(res)
+    }
+
+    fn get_vector_store(&self, id: &str) -> Result<AppResponse> {
+        let data = VectorStore::load(id)?.to_json();
+        let res = Response::builder()
+            .header("Content-Type", "application/json; charset=utf-8")
+            .body(Full::new(Bytes::from(data.to_string())).boxed())?;
+        Ok(res)
+    }
+
+    async fn update_vector_store(
+        &self,
+        id: &str,
+        req: hyper::Request<Incoming>,
+    ) -> Result<AppResponse> {
+        let req_body = req.collect().await?.to_bytes();
+        let req_body: UpdateVectorStoreReqBody = serde_json::from_slice(&req_body)
+            .map_err(|err| anyhow!("Invalid request body, {err}"))?;
+        let _guard = self.vector_store_lock.lock();
+        let mut store = VectorStore::load(id)?;
+        if let Some(name) = req_body.name {
+            store.name = Some(name);
+        }
+        if let Some(metadata) = req_body.metadata {
+            store.metadata = metadata;
+        }
+        store.save()?;
+        let data = store.to_json();
+        let res = Response::builder()
+            .header("Content-Type", "application/json; charset=utf-8")
+            .body(Full::new(Bytes::from(data.to_string())).boxed())?;
+        Ok(res)
+    }
+
+    async fn delete_vector_store(&self, id: &str) -> Result<AppResponse> {
+        // Waits for running ingests, which would otherwise save the knowledge base again.
+        let lock = self.knowledge_base_lock(id);
+        let _kb_guard = lock.lock().await;
+        {
+            let _guard = self.vector_store_lock.lock();
+            if !VectorStore::remove(id)? {
+                bail!("No vector store '{id}'");
+            }
+        }
+        Rag::remove(id)?;
+        self.knowledge_bases.lock().remove(id);
+        let data = json!({ "id": id, "object": "vector_store.deleted", "deleted": true });
+        let res = Response::builder()
+            .header("Content-Type", "application/json; charset=utf-8")
+            .body(Full::new(Bytes::from(data.to_string())).boxed())?;
+        Ok(res)
+    }
+
+    fn list_vector_store_files(&self, id: &str) -> Result<AppResponse> {
+        let store = VectorStore::load(id)?;
+        let data: Vec<Value> = store.files.iter().map(|v| store.file_json(v)).collect();
+        let data = json!({
+            "object": "list",
+            "data": data,
+            "first_id": store.files.first().map(|v| &v.id),
+            "last_id": store.files.last().map(|v| &v.id),
+            "has_more": false,
+        });
+        let res = Response::builder()
+            .header("Content-Type", "application/json; charset=utf-8")
+            .body(Full::new(Bytes::from(data.to_string())).boxed())?;
+        Ok(res)
+    }
+
+    async fn attach_vector_store_file(
+        self: &Arc<Self>,
+        id: &str,
+        req: hyper::Request<Incoming>,
+    ) -> Result<AppResponse> {
+        let req_body = req.collect().await?.to_bytes();
+        let req_body: AttachVectorStoreFileReqBody = serde_json::from_slice(&req_body)
+            .map_err(|err| anyhow!("Invalid request body, {err}"))?;
+        let file = FileObject::load(&req_body.file_id)?;
+        let data = {
+            let _guard = self.vector_store_lock.lock();
+            let mut store = VectorStore::load(id)?;
+            if let Some(attached) = store.file(&file.id) {
+                store.file_json(attached)
+            } else {
+                let attached = VectorStoreFile {
+                    id: file.id.clone(),
+                    created_at: Utc::now().timestamp(),
+                    status: VectorStoreFileStatus::InProgress,
+                    last_error: None,
+                    usage_bytes: 0,
+                };
+                let data = store.file_json(&attached);
+                store.files.push(attached);
+                store.save()?;
+                self.spawn_ingest(id, &file.id);
+                data
+            }
+        };
+        let res = Response::builder()
+            .header("Content-Type", "application/json; charset=utf-8")
+            .body(Full::new(Bytes::from(data.to_string())).boxed())?;
+        Ok(res)
+    }
+
+    fn get_vector_store_file(&self, id: &str, file_id: &str) -> Result<AppResponse> {
+        let store = VectorStore::load(id)?;
+        let file = store
+            .file(file_id)
+            .ok_or_else(|| anyhow!("No file '{file_id}' in vector store '{id}'"))?;
+        let data = store.file_json(file);
+        let res = Response::builder()
+            .header("Content-Type", "application/json; charset=utf-8")
+            .body(Full::new(Bytes::from(data.to_string())).boxed())?;
+        Ok(res)
+    }
+
+    async fn detach_vector_store_file(&self, id: &str, file_id: &str) -> Result<AppResponse> {
+        {
+            let _guard = self.vector_store_lock.lock();
+            let mut store = VectorStore::load(id)?;
+            let Some(index) = store.files.iter().position(|v| v.id == file_id) else {
+                bail!("No file '{file_id}' in vector store '{id}'");
+            };
+            store.files.remove(index);
+            store.save()?;
+        }
+        self.remove_vector_store_document(id, file_id).await?;
+        let data = json!({ "id": file_id, "object": "vector_store.file.deleted", "deleted": true });
+        let res = Response::builder()
+            .header("Content-Type", "application/json; charset=utf-8")
+            .body(Full::new(Bytes::from(data.to_string())).boxed())?;
+        Ok(res)
+    }
+
+    async fn search_vector_store(
+        &self,
+        id: &str,
+        req: hyper::Request<Incoming>,
+    ) -> Result<AppResponse> {
+        let req_body = req.collect().await?.to_bytes();
+        let req_body: SearchVectorStoreReqBody = serde_json::from_slice(&req_body)
+            .map_err(|err| anyhow!("Invalid request body, {err}"))?;
+        let query = match &req_body.query {
+            Value::String(query) => query.clone(),
+            Value::Array(queries) => queries
+                .iter()
+                .filter_map(|v| v.as_str())
+                .collect::<Vec<_>>()
+                .join("\n"),
+            _ => bail!("Invalid request body, 'query' must be a string or an array of strings"),
+        };
+        VectorStore::load(id)?;
+        let rag = self.knowledge_base(id)?;
+        let client = self.init_embedding_client(Some(rag.embedding_model()))?;
+        let hits = rag
+            .search(
+                client.as_ref(),
+                &query,
+                req_body.max_num_results.unwrap_or(DEFAULT_MAX_NUM_RESULTS),
+            )
+            .await?;
+        let data: Vec<Value> = hits
+            .iter()
+            .map(|v| {
+                let filename = FileObject::load(&v.source)
+                    .map(|file| file.filename)
+                    .unwrap_or_default();
+                json!({
+                    "file_id": v.source,
+                    "filename": filename,
+                    "score": v.score,
+                    "attributes": {},
+                    "content": [{ "type": "text", "text": v.text }],
+                })
+            })
+            .collect();
+        let data = json!({
+            "object": "vector_store.search_results.page",
+            "search_query": query,
+            "data": data,
+            "has_more": false,
+            "next_page": null,
+        });
+        let res = Response::builder()
+            .header("Content-Type", "application/json; charset=utf-8")
+            .body(Full::new(Bytes::from(data.to_string())).boxed())?;
+        Ok(res)
+    }
+
+    /// Drops the chunks of a detached file from the vector store's knowledge base.
+    async fn remove_vector_store_document(&self, id: &str, file_id: &str) -> Result<()> {
+        let lock = self.knowledge_base_lock(id);
+        let _guard = lock.lock().await;
+        let mut rag = Rag::load(id)?;
+        if rag.remove_document(file_id) {
+            rag.save()?;
+            self.knowledge_bases
+                .lock()
+                .insert(id.to_string(), Arc::new(rag));
+        }
+        Ok(())
+    }
+
+    /// Ingests a file into a vector store in the background, then records its status.
+    fn spawn_ingest(self: &Arc<Self>, store_id: &str, file_id: &str) {
+        let server = self.clone();
+        let store_id = store_id.to_string();
+        let file_id = file_id.to_string();
+        tokio::spawn(async move {
+            let ret = server.ingest_file(&store_id, &file_id).await;
+            if let Err(err) = &ret {
+                warn!("Failed to ingest '{file_id}' into '{store_id}': {err:#}");
+            }
+            let _guard = server.vector_store_lock.lock();
+            let saved = VectorStore::load(&store_id).and_then(|mut store| {
+                let Some(file) = store.file_mut(&file_id) else {
+                    return Ok(());
+                };
+                match ret {
+                    Ok(usage_bytes) => {
+                        file.status = VectorStoreFileStatus::Completed;
+                        file.usage_bytes = usage_bytes;
+                    }
+                    Err(err) => {
+                        file.status = VectorStoreFileStatus::Failed;
+                        file.last_error = Some(format!("{err:#}"));
+                    }
+                }
+                store.save()
+            });
+            if let Err(err) = saved {
+                warn!("Failed to update vector store '{store_id}': {err:#}");
+            }
+        });
+    }
+
+    async fn ingest_file(&self, store_id: &str, file_id: &str) -> Result<u64> {
+        let lock = self.knowledge_base_lock(store_id);
+        let _guard = lock.lock().await;
+        // Detached while waiting for earlier files.
+        if VectorStore::load(store_id)?.file(file_id).is_none() {
+            bail!("File '{file_id}' is no longer in vector store '{store_id}'");
+        }
+        let file = FileObject::load(file_id)?;
+        let text = extract_text(&file.filename, &file.content()?)?;
+        let mut rag = Rag::load(store_id)?;
+        let client = self.init_embedding_client(Some(rag.embedding_model()))?;
+        rag.add_documents(client.as_ref(), vec![(file.id.clone(), text)])
+            .await?;
+        rag.save()?;
+        self.knowledge_bases
+            .lock()
+            .insert(store_id.to_string(), Arc::new(rag));
+        Ok(file.bytes)
+    }
+
+    fn knowledge_base(&self, name: &str) -> Result<Arc<Rag>> {
+        if let Some(rag) = self.knowledge_bases.lock().get(name) {
+            return Ok(rag.clone());
+        }
+        let rag = Arc::new(Rag::load(name)?);
+        self.knowledge_bases
+            .lock()
+            .insert(name.to_string(), rag.clone());
+        Ok(rag)
+    }
+
+    fn knowledge_base_lock(&self, name: &str) -> Arc<AsyncMutex<()>> {
+        self.knowledge_base_locks
+            .lock()
+            .entry(name.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// Retrieves chunks for the last user message and rewrites it with `RAG_TEMPLATE`, returning the citations.
+    async fn retrieve(
+        &self,
+        record: &mut TraceRecord,
+        name: &str,
+        top_k: Option<usize>,
+        messages: &mut [Message],
+    ) -> Result<Value> {
+        let query = messages
+            .iter()
+            .rev()
+            .find(|v| v.role.is_user())
+            .map(|v| v.content.to_text())
+            .ok_or_else(|| anyhow!("No user message to answer from the knowledge base"))?;
+        let rag = self.knowledge_base(name)?;
+        let client = self.init_embedding_client(Some(rag.embedding_model()))?;
+        let top_k = top_k.unwrap_or(DEFAULT_TOP_K);
+        let started = Instant::now();
+        let timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+        let hits = rag.search(client.as_ref(), &query, top_k).await?;
+        augment_messages(messages, &hits)?;
+        log::debug!("Retrieved {} chunks from '{name}'", hits.len());
+        let sources: Vec<&str> = hits.iter().map(|v| v.source.as_str()).collect();
+        record.spans.push(TraceSpan {
+            name: format!("retrieve {name}"),
+            span_id: Some(generate_span_id()),
+            timestamp,
+            latency_ms: started.elapsed().as_millis() as u64,
+            attributes: [
+                ("gateway.rag.knowledge_base", json!(name)),
+                ("gateway.rag.embedding_model", json!(rag.embedding_model())),
+                ("gateway.rag.top_k", json!(top_k)),
+                ("gateway.rag.hits", json!(hits.len())),
+                ("gateway.rag.sources", json!(sources)),
+            ]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect(),
+            ..Default::default()
+        });
+        let citations: Vec<Value> = hits
+            .iter()
+            .enumerate()
+            .map(|(i, v)| v.to_json(i + 1))
+            .collect();
+        Ok(citations.into())
+    }
+
+    /// The declared functions matching `matcher` and the tools of the named MCP servers,
+    /// offered to the model and run by the gateway.
+    async fn select_functions(
+        &self,
+        matcher: Option<&str>,
+        mcp_servers: &[String],
+    ) -> Result<Option<Vec<FunctionDeclaration>>> {
+        let mut functions = vec![];
+        if let Some(matcher) = matcher {
+            if !self.tools.options.enabled {
+                bail!("Function execution is disabled");
+            }
+            let declared = self
+                .tools
+                .function
+                .select(matcher)
+                .ok_or_else(|| anyhow!("No declared functions match '{matcher}'"))?;
+            functions.extend(declared);
+        }
+        functions.extend(self.tools.mcp.functions(mcp_servers).await?);
+        Ok((!functions.is_empty()).then_some(functions))
+    }
+
+    async fn list_mcp_servers(&self) -> Result<AppResponse> {
+        let mut data = vec![];
+        for name in self.tools.mcp.names() {
+            let value = match self.tools.mcp.server(name).await {
+                Ok(server) => json!({
+                    "name": server.name(),
+                    "object": "mcp_server",
+                    "tools": server.functions(),
+                }),
+                Err(err) => json!({
+                    "name": name,
+                    "object": "mcp_server",
+                    "error": format!("{err:#}"),
+                }),
+            };
+            data.push(value);
+        }
+        let data = json!({ "object": "list", "data": data });
+        let res = Response::builder()
+            .header("Content-Type", "application/json; charset=utf-8")
+            .body(Full::new(Bytes::from(data.to_string())).boxed())?;
+        Ok(res)
+    }
+
+    /// A client for an embedding model, or for the first configured one.
+    fn init_embedding_client(&self, model: Option<&str>) -> Result<Box<dyn Client>> {
+        let config = Config {
+            clients: self.clients.to_vec(),
+            ..Default::default()
+        };
+        let models = list_embedding_models(&config);
+        let model = match model {
+            Some(model) => Model::find(&models, model)
+                .ok_or_else(|| anyhow!("No embedding model '{model}'"))?,
+            None => models
+                .first()
+                .map(|v| (*v).clone())
+                .ok_or_else(|| anyhow!("No embedding model configured"))?,
+        };
+        init_client(&Arc::new(RwLock::new(config)), Some(model))
+    }
+
+    /// One non-streaming completion, recorded like `/v1/chat/completions`.
+    /// A per-request config with `model` selected, plus the resolved model name.
+    fn init_config(&self, model: String) -> Result<(GlobalConfig, String)> {
+        let config = Config {
+            clients: self.clients.to_vec(),
+            model: self.model.clone(),
+            max_tokens_policy: self.max_tokens_policy,
+            ..Default::default()
+        };
+        let config = Arc::new(RwLock::new(config));
+
+        let (model_name, change) = if model == DEFAULT_MODEL_NAME {
+            (self.model.id(), true)
+        } else if self.model.id() == model {
+            (model, false)
+        } else {
+            (model, true)
+        };
+
+        log::debug!("Model name: {}", model_name);
+        if change {
+            config.write().set_model(&model_name)?;
+        }
+        Ok((config, model_name))
+    }
+
+    async fn tokenize(&self, req: hyper::Request<Incoming>) -> Result<AppResponse> {
+        let req_body = req.collect().await?.to_bytes();
+        let req_body: TokenizeReqBody = serde_json::from_slice(&req_body)
+            .map_err(|err| anyhow!("Invalid request body, {err}"))?;
+        let (config, _) = self.init_config(req_body.model)?;
+        let model = config.read().model.clone();
+        let tokens = model.tokenizer().encode(&req_body.text);
+        let mut data = token_count_data(&model, tokens.len());
+        data["tokens"] = json!(tokens);
+        let res = Response::builder()
+            .header("Content-Type", "application/json; charset=utf-8")
+            .body(Full::new(Bytes::from(data.to_string())).boxed())?;
+        Ok(res)
+    }
+
+    async fn count_tokens(&self, req: hyper::Request<Incoming>) -> Result<AppResponse> {
+        let req_body = req.collect().await?.to_bytes();
+        let req_body: CountTokensReqBody = serde_json::from_slice(&req_body)
+            .map_err(|err| anyhow!("Invalid request body, {err}"))?;
+        let (config, _) = self.init_config(req_body.model)?;
+        let model = config.read().model.clone();
+        let functions = parse_tools(req_body.tools)?;
+        let input_tokens = model.input_tokens(&req_body.messages, functions.as_deref());
+        let data = token_count_data(&model, input_tokens);
+        let res = Response::builder()
+            .header("Content-Type", "application/json; charset=utf-8")
+            .body(Full::new(Bytes::from(data.to_string())).boxed())?;
+        Ok(res)
+    }
+
+    async fn chat_completion(&self, req: hyper::Request<Incoming>) -> Result<AppResponse> {
+        let request_id = generate_request_id();
+        let mut record = TraceRecord::new(&request_id, caller_key(&req).as_deref());
+        let started = Instant::now();
+        self.tracer.start();
+        let ret = self
+            .chat_completion_inner(req, &request_id, &mut record, started)
+            .await;
+        if let Err(err) = &ret {
+            if !record.is_taken() {
+                record.latency_ms = started.elapsed().as_millis() as u64;
+                record.set_error(StatusCode::BAD_REQUEST.as_u16(), err);
+                self.tracer.record(record);
+            }
+        }
+        ret
+    }
+
+    async fn chat_completion_inner(
+        &self,
+        req: hyper::Request<Incoming>,
+        request_id: &str,
+        record: &mut TraceRecord,
+        started: Instant,
+    ) -> Result<AppResponse> {
+        let headers = req.headers().clone();
+        let req_body = req.collect().await?.to_bytes();
+        let req_body: Value = serde_json::from_slice(&req_body)
+            .map_err(|err| anyhow!("Invalid request body, {err}"))?;
+        let run = RunContext::from_request(&headers, &req_body["metadata"]);
+        run.apply(record);
+        record.model = req_body["model"].as_str().unwrap_or_default().to_string();
+        record.request = Some(req_body.clone());
+        let req_body: ChatCompletionReqBody = serde_json::from_value(req_body)
+            .map_err(|err| anyhow!("Invalid request body, {err}"))?;
+
+        let ChatCompletionReqBody {
+            model,
+            messages,
+            temperature,
+            top_p,
+            max_tokens,
+            stream,
+            context_strategy,
+            knowledge_base,
+            top_k,
+            functions,
+            mcp_servers,
+            tools,
+        } = req_body;
+        record.stream = stream;
+        let mut messages = parse_messages(messages)?;
+
+        log::debug!(
+            "Chat completion request: model={model}, messages={messages:?}, temperature={temperature:?}, top_p={top_p:?}, max_tokens={max_tokens:?}, stream={stream}"
+        );
+        let (config, model_name) = self.init_config(model)?;
+
+        let max_tokens_policy = config.read().max_tokens_policy;
+        let budget = TokenBudget {
+            max_tokens,
+            policy: max_tokens_policy,
+        };
+        let mut client = init_client(&config, None)?;
+        record.set_model(client.model());
+        let abort = create_abort_signal();
+        let http_client = client.build_client()?;
+
+        let completion_id = generate_completion_id();
+        let created = Utc::now().timestamp();
+
+        let citations = match &knowledge_base {
+            Some(name) => Some(self.retrieve(record, name, top_k, &mut messages).await?),
+            None => None,
+        };
+
+        let mut functions = self
+            .select_functions(functions.as_deref(), &mcp_servers)
+            .await?;
+        let mut client_tools = vec![];
+        for tool in parse_tools(tools)?.unwrap_or_default() {
+            let functions = functions.get_or_insert_with(Vec::new);
+            if functions.iter().any(|v| v.name == tool.name) {
+                bail!("Tool '{}' is also run by the gateway", tool.name);
+            }
+            client_tools.push(tool.name.clone());
+            functions.push(tool);
+        }
+        let mut data: ChatCompletionsData = ChatCompletionsData {
+            messages,
+            temperature,
+            top_p,
+            functions,
+            stream,
+        };
+        prepare_data(
+            record,
+            &mut client,
+            &http_client,
+            &mut data,
+            context_strategy,
+            max_tokens,
+            max_tokens_policy,
+        )
+        .await?;
+
+        if stream {
+            let model_resolved = client.model().id();
+            let client_name = client.model().client_name().to_string();
+            let context_trimmed = record.context_trimmed.clone();
+            let (tx, mut rx) = unbounded_channel();
+            let tracer = self.tracer.clone();
+            let tools = self.tools.clone();
+            let mut record = std::mem::take(record);
+            tokio::spawn(async move {
+                let mut is_first = true;
+                if data.functions.is_some() {
+                    stream_functions(
+                        &tools,
+                        client.as_mut(),
+                        &http_client,
+                        data,
+                        budget,
+                        &client_tools,
+                        abort,
+                        &tx,
+                        &mut record,
+                        started,
+                        &mut is_first,
+                    )
+                    .await;
+                } else {
+                    stream_completion(
+                        client.as_ref(),
+                        &http_client,
+                        data,
+                        abort,
+                        &tx,
+                        &mut record,
+                        started,
+                        &mut is_first,
+                    )
+                    .await;
+                }
+                match &record.error {
+                    Some(err) => send_error_event(&tx, err, &mut is_first),
+                    None => {
+                        let stats: serde_json::Map<String, Value> = gateway_headers(&record)
+                            .into_iter()
+                            .map(|(k, v)| (k.to_string(), v.into()))
+                            .collect();
+                        let _ = tx.send(ResEvent::Stats(stats.into()));
+                        let _ = tx.send(ResEvent::Finish(finish_reason(&record.tool_calls)));
+                    }
+                }
+                tracer.record(record);
+            });
+
+            let first_event = rx.recv().await;
+
+            if let Some(ResEvent::First(Some(err))) = first_event {
+                bail!("{err}");
+            }
+
+            let shared: Arc<(String, String, i64, Option<Value>)> =
+                Arc::new((completion_id, model_name, created, citations));
+            let stream = UnboundedReceiverStream::new(rx);
+            let stream = stream.filter_map(move |res_event| {
+                let shared = shared.clone();
+                async move {
+                    let (completion_id, model, created, citations) = shared.as_ref();
+                    match res_event {
+                        ResEvent::Text(text) => Some(Ok(create_frame(
+                            completion_id,
+                            model,
+                            *created,
+                            &text,
+                            None,
+                            None,
+                            None,
+                        ))),
+                        ResEvent::ToolCall(delta) => Some(Ok(create_frame(
+                            completion_id,
+                            model,
+                            *created,
+                            "",
+                            Some(&delta),
+                            None,
+                            None,
+                        ))),
+                        ResEvent::Stats(stats) => Some(Ok(Frame::data(Bytes::from(format!(
+                            ": {GATEWAY_STATS_COMMENT} {stats}\n\n"
+                        ))))),
+                        ResEvent::Error(err) => Some(Ok(create_error_frame(&err))),
+                        ResEvent::Finish(finish_reason) => Some(Ok(create_frame(
+                            completion_id,
+                            model,
+                            *created,
+                            "",
+                            None,
+                            Some(finish_reason),
+                            citations.as_ref(),
+                        ))),
+                        _ => None,
+                    }
+                }
+            });
+            let res = Response::builder()
+                .status(StatusCode::OK)
+                .header("Content-Type", "text/event-stream")
+                .header("Cache-Control", "no-cache")
+                .header("Connection", "keep-alive")
+                .header(REQUEST_ID_HEADER, request_id)
+                .header(TRACE_ID_HEADER, &run.trace_id)
+                .header(SPAN_ID_HEADER, &run.span_id)
+                .header(MODEL_RESOLVED_HEADER, &model_resolved)
+                .header(CLIENT_HEADER, &client_name);
+            let res = match &context_trimmed {
+                Some(value) => res.header(CONTEXT_TRIMMED_HEADER, value),
+                None => res,
+            }
+            .body(BodyExt::boxed(StreamBody::new(stream)))?;
+            Ok(res)
+        } else {
+            let output = if data.functions.is_some() {
+                complete_functions(
+                    &self.tools,
+                    client.as_mut(),
+                    &http_client,
+                    data,
+                    budget,
+                    &client_tools,
+                    record,
+                )
+                .await?
+            } else {
+                let (output, upstream_request) =
+                    capture_upstream(client.chat_completions_inner(&http_client, data)).await;
+                record.upstream_request = upstream_request;
+                output?
+            };
+            record.latency_ms = started.elapsed().as_millis() as u64;
+            record.set_output(client.model(), &output);
+            let mut builder = Response::builder()
+                .header("Content-Type", "application/json")
+                .header(REQUEST_ID_HEADER, request_id)
+                .header(TRACE_ID_HEADER, &run.trace_id)
+                .header(SPAN_ID_HEADER, &run.span_id)
+                // The whole answer arrives at once, so the first token comes with the last.
+                .header(TTFT_HEADER, record.latency_ms.to_string());
+            for (key, value) in gateway_headers(record) {
+                builder = builder.header(key, value);
+            }
+            self.tracer.record(std::mem::take(record));
+            let res = builder.body(
+                Full::new(ret_non_stream(
+                    &completion_id,
+                    &model_name,
+                    created,
+                    &output,
+                    citations.as_ref(),
+                ))
+                .boxed(),
+            )?;
+            Ok(res)
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatCompletionReqBody {
+    model: String,
+    messages: Vec<Value>,
+    temperature: Option<f64>,
+    top_p: Option<f64>,
+    max_tokens: Option<isize>,
+    #[serde(default)]
+    stream: bool,
+    context_strategy: Option<ContextStrategy>,
+    knowledge_base: Option<String>,
+    top_k: Option<usize>,
+    /// Regex over declared function names, run on the gateway when `function_execution` is enabled.
+    functions: Option<String>,
+    /// MCP servers whose tools are offered to the model and run by the gateway.
+    #[serde(default)]
+    mcp_servers: Vec<String>,
+    /// OpenAI tools run by the client: offered alongside the gateway's, their calls are handed back.
+    tools: Option<Vec<Value>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct CreateSessionReqBody {
+    name: Option<String>,
+    model: Option<String>,
+    system: Option<String>,
+    temperature: Option<f64>,
+    top_p: Option<f64>,
+    compress_threshold: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SessionMessageReqBody {
+    content: MessageContent,
+    max_tokens: Option<isize>,
+    context_strategy: Option<ContextStrategy>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateKnowledgeBaseReqBody {
+    name: String,
+    embedding_model: Option<String>,
+    chunk_size: Option<usize>,
+    chunk_overlap: Option<usize>,
+    #[serde(flatten)]
+    documents: KnowledgeBaseDocumentsReqBody,
+}
+
+/// Documents to ingest: files or directories under `documents_dir` on the gateway host, and inline documents.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct KnowledgeBaseDocumentsReqBody {
+    paths: Vec<String>,
+    documents: Vec<InlineDocument>,
+}
+
+#[derive(Debug, Deserialize)]
+struct InlineDocument {
+    name: String,
+    content: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct SearchKnowledgeBaseReqBody {
+    query: String,
+    top_k: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct CreateVectorStoreReqBody {
+    name: Option<String>,
+    file_ids: Vec<String>,
+    metadata: Option<Value>,
+    chunking_strategy: Option<ChunkingStrategy>,
+    /// Not part of the OpenAI API; defaults to the first configured embedding model.
+    embedding_model: Option<String>,
+}
+
+/// Token sizes are converted to chars, which is what the splitter counts.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ChunkingStrategy {
+    Auto,
+    Static { r#static: StaticChunkingStrategy },
+}
+
+#[derive(Debug, Deserialize)]
+struct StaticChunkingStrategy {
+    max_chunk_size_tokens: usize,
+    chunk_overlap_tokens: usize,
+}
+
+#[derive(Debug, Deserialize)]
+struct UpdateVectorStoreReqBody {
+    name: Option<String>,
+    metadata: Option<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AttachVectorStoreFileReqBody {
+    file_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct SearchVectorStoreReqBody {
+    query: Value,
+    max_num_results: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenizeReqBody {
+    model: String,
+    text: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct CountTokensReqBody {
+    model: String,
+    messages: Vec<Message>,
+    tools: Option<Vec<Value>>,
+}
+
+/// Accepts OpenAI `tools` entries as well as bare function declarations.
+fn parse_tools(tools: Option<Vec<Value>>) -> Result<Option<Vec<FunctionDeclaration>>> {
+    let Some(tools) = tools else {
+        return Ok(None);
+    };
+    let functions = tools
+        .into_iter()
+        .map(|mut tool| {
+            let tool = match tool.get_mut("function") {
+                Some(function) => function.take(),
+                None => tool,
+            };
+            serde_json::from_value(tool).map_err(|err| anyhow!("Invalid tool, {err}"))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(Some(functions))
+}
+
+/// Accepts OpenAI messages, folding an assistant message with `tool_calls` and the `tool` messages
+/// answering it into one message with the tool results.
+fn parse_messages(messages: Vec<Value>) -> Result<Vec<Message>> {
+    let mut output = vec![];
+    let mut messages = messages.into_iter().peekable();
+    while let Some(message) = messages.next() {
+        let Some(calls) = message["tool_calls"].as_array().filter(|v| !v.is_empty()) else {
+            let message =
+                serde_json::from_value(message).map_err(|err| anyhow!("Invalid message, {err}"))?;
+            output.push(message);
+            continue;
+        };
+        let mut outputs = HashMap::new();
+        while let Some(tool) = messages.next_if(|v| v["role"] == "tool") {
+            let id = tool["tool_call_id"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string();
+            let content = match &tool["content"] {
+                Value::String(text) => serde_json::from_str(text).unwrap_or_else(|_| json!(text)),
+                content => content.clone(),
+            };
+            outputs.insert(id, content);
+        }
+        let results = calls
+            .iter()
+            .map(|call| {
+                let id = call["id"].as_str().unwrap_or_default();
+                let output = outputs
+                    .remove(id)
+                    .ok_or_else(|| anyhow!("No tool message answers tool call '{id}'"))?;
+                let call = ToolCall::new(
+                    call["function"]["name"].as_str().unwrap_or_default().into(),
+                    ToolCall::parse_arguments(
+                        call["function"]["arguments"].as_str().unwrap_or("{}"),
+                    ),
+                    Some(id.to_string()),
+                );
+                Ok(ToolCallResult::new(call, output))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let text = message["content"].as_str().unwrap_or_default().to_string();
+        output.push(Message::new(
+            MessageRole::Assistant,
+            MessageContent::ToolResults((results, text)),
+        ));
+    }
+    Ok(output)
+}
+
+fn token_count_data(model: &Model, input_tokens: usize) -> Value {
+    let max_input_tokens = model.max_input_tokens();
+    json!({
+        "object": "token_count",
+        "model": model.id(),
+        "input_tokens": input_tokens,
+        "max_input_tokens": max_input_tokens,
+        "remaining_tokens": max_input_tokens.map(|v| v.saturating_sub(input_tokens)),
+        "input_cost_usd": model.cost(Some(input_tokens as u64), None),
+    })
+}
+
+/// Fits the messages into the context window and settles `max_tokens`, noting any trimming in `record`.
+#[allow(clippy::too_many_arguments)]
+async fn prepare_data(
+    record: &mut TraceRecord,
+    client: &mut Box<dyn Client>,
+    http_client: &ReqwestClient,
+    data: &mut ChatCompletionsData,
+    context_strategy: Option<ContextStrategy>,
+    max_tokens: Option<isize>,
+    max_tokens_policy: MaxTokensPolicy,
+) -> Result<()> {
+    let strategy = context_strategy.unwrap_or_else(|| client.model().context_strategy());
+    let started = Instant::now();
+    let timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+    let result = fit_context(client.as_ref(), http_client, data, strategy).await;
+    if let Err(err) = &result {
+        if strategy == ContextStrategy::Summarize {
+            record.spans.push(TraceSpan {
+                name: format!("fit_context {}", strategy.as_str()),
+                span_id: Some(generate_span_id()),
+                timestamp: timestamp.clone(),
+                latency_ms: started.elapsed().as_millis() as u64,
+                error: Some(redact_secrets(&format!("{err:#}")).into_owned()),
+                ..Default::default()
+            });
+        }
+    }
+    if let Some(report) = result? {
+        log::debug!("Context trimmed: {}", report.header_value());
+        record.spans.push(TraceSpan {
+            name: format!("fit_context {}", strategy.as_str()),
+            span_id: Some(generate_span_id()),
+            timestamp,
+            latency_ms: started.elapsed().as_millis() as u64,
+            attributes: [
+                (
+                    "gateway.context.dropped_messages",
+                    json!(report.dropped_messages),
+                ),
+                (
+                    "gateway.context.truncated_messages",
+                    json!(report.truncated_messages),
+                ),
+                (
+                    "gateway.context.summarized_messages",
+                    json!(report.summarized_messages),
+                ),
+                ("gateway.context.tokens_before", json!(report.tokens_before)),
+                ("gateway.context.tokens_after", json!(report.tokens_after)),
+                (
+                    "gen_ai.usage.input_tokens",
+                    json!(report.summary_input_tokens),
+                ),
+                (
+                    "gen_ai.usage.output_tokens",
+                    json!(report.summary_output_tokens),
+                ),
+                (
+                    "gateway.cost",
+                    json!(client
+                        .model()
+                        .cost(report.summary_input_tokens, report.summary_output_tokens)),
+                ),
+            ]
+            .into_iter()
+            .filter(|(_, v)| !v.is_null())
+            .map(|(k, v)| (k.to_string(), v))
+            .collect(),
+            ..Default::default()
+        });
+        record.context_trimmed = Some(report.header_value());
+    }
+    TokenBudget {
+        max_tokens,
+        policy: max_tokens_policy,
+    }
+    .apply(client.as_mut(), data)
+}
+
+/// The requested `max_tokens`, settled again before every step of a tool loop since tool
+/// results grow the input.
+#[derive(Debug, Clone, Copy)]
+struct TokenBudget {
+    max_tokens: Option<isize>,
+    policy: MaxTokensPolicy,
+}
+
+impl TokenBudget {
+    fn apply(&self, client: &mut dyn Client, data: &ChatCompletionsData) -> Result<()> {
+        let input_tokens = client
+            .model()
+            .input_tokens(&data.messages, data.functions.as_deref());
+        client
+            .model_mut()
+            .resolve_max_tokens(self.max_tokens, input_tokens, self.policy)?;
+        Ok(())
+    }
+}
+
+/// Streams one completion into `tx` and fills `record` with its outcome, returning the streamed text.
+///
+/// Tool calls are not streamed: the loop streams those it hands back once they are checked.
+/// A failure is only recorded; the caller ends the stream with `send_error_event`.
+#[allow(clippy::too_many_arguments)]
+async fn stream_completion(
+    client: &dyn Client,
+    http_client: &ReqwestClient,
+    data: ChatCompletionsData,
+    abort: AbortSignal,
+    tx: &UnboundedSender<ResEvent>,
+    record: &mut TraceRecord,
+    started: Instant,
+    is_first: &mut bool,
+) -> String {
+    let mut ttft = None;
+    let (tx2, mut rx2) = unbounded_channel();
+    let mut handler = SseHandler::new(tx2, abort);
+    let forward = |reply_event: SseEvent, is_first: &mut bool, ttft: &mut Option<u64>| {
+        if !matches!(reply_event, SseEvent::ToolCall(_)) {
+            forward_event(reply_event, tx, is_first, ttft, started);
+        }
+    };
+    let (ret, upstream_request) = capture_upstream(async {
+        tokio::select! {
+            _ = async {
+                while let Some(reply_event) = rx2.recv().await {
+                    forward(reply_event, is_first, &mut ttft);
+                }
+            } => Ok(()),
+            ret = client.chat_completions_streaming_with_tools(http_client, &mut handler, data) => ret,
+        }
+    })
+    .await;
+    // Events still queued when the upstream stream ended.
+    while let Ok(reply_event) = rx2.try_recv() {
+        forward(reply_event, is_first, &mut ttft);
+    }
+    if let Err(err) = ret {
+        record.set_error(StatusCode::BAD_REQUEST.as_u16(), &err);
+    }
+    let (input_tokens, output_tokens) = handler.get_usage();
+    let (text, tool_calls) = handler.take();
+    record.upstream_request = upstream_request;
+    record.latency_ms = started.elapsed().as_millis() as u64;
+    record.ttft_ms = ttft;
+    record.input_tokens = input_tokens;
+    record.output_tokens = output_tokens;
+    record.cost = client.model().cost(input_tokens, output_tokens);
+    if record.error.is_none() {
+        record.finish_reason = Some(finish_reason(&tool_calls).into());
+    }
+    record.response_text = Some(text.clone());
+    record.tool_calls = tool_calls;
+    text
+}
+
+/// What the gateway runs for tool calls: declared functions and the tools of MCP servers.
+#[derive(Clone)]
+struct ToolRunner {
+    function: Function,
+    mcp: Arc<McpHub>,
+    options: FunctionExecution,
+    validation: ToolCallValidation,
+}
+
+impl ToolRunner {
+    async fn run(&self, call: &ToolCall) -> FunctionRun {
+        if !self.mcp.contains(&call.name) {
+            return self.function.run(call, &self.options).await;
+        }
+        let timeout = Duration::from_secs(self.options.timeout);
+        match tokio::time::timeout(timeout, self.mcp.call(call)).await {
+            Ok(Ok(output)) => FunctionRun {
+                error: output.get("error").map(|v| v.to_string()),
+                output,
+                exit_code: None,
+                truncated: false,
+                timed_out: false,
+            },
+            Ok(Err(err)) => FunctionRun::failed(format!("{err:#}")),
+            Err(_) => FunctionRun {
+                timed_out: true,
+                ..FunctionRun::failed(format!("Timed out after {}s", self.options.timeout))
+            },
+        }
+    }
+}
+
+/// Calls the model until it stops asking for tools, running each step's tool calls in between.
+///
+/// A step that calls `client_tools` ends the loop and hands its calls back.
+async fn complete_functions(
+    tools: &ToolRunner,
+    client: &mut dyn Client,
+    http_client: &ReqwestClient,
+    mut data: ChatCompletionsData,
+    budget: TokenBudget,
+    client_tools: &[String],
+    record: &mut TraceRecord,
+) -> Result<ChatCompletionsOutput> {
+    let (mut input_tokens, mut output_tokens, mut reasked) = (None, None, false);
+    for step in 1..=tools.options.max_steps {
+        if step > 1 {
+            budget.apply(client, &data)?;
+        }
+        let started = Instant::now();
+        let timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+        let (output, upstream_request) =
+            capture_upstream(client.chat_completions_with_tools(http_client, data.clone())).await;
+        record.upstream_request = upstream_request;
+        let mut output = output?;
+        input_tokens = sum_tokens(input_tokens, output.input_tokens);
+        output_tokens = sum_tokens(output_tokens, output.output_tokens);
+        record
+            .spans
+            .push(step_span(step, started, timestamp, &output.tool_calls));
+        let step = if output.tool_calls.is_empty() {
+            ToolStep::HandBack(vec![])
+        } else {
+            let offered = data.functions.as_deref().unwrap_or_default();
+            let calls = std::mem::take(&mut output.tool_calls);
+            run_tool_calls(tools, offered, client_tools, calls, &mut reasked, record).await?
+        };
+        let results = match step {
+            ToolStep::Results(results) => results,
+            ToolStep::HandBack(calls) => {
+                output.tool_calls = calls;
+                output.input_tokens = input_tokens;
+                output.output_tokens = output_tokens;
+                return Ok(output);
+            }
+        };
+        data.messages.push(Message::new(
+            MessageRole::Assistant,
+            MessageContent::ToolResults((results, output.text)),
+        ));
+    }
+    bail!(
+        "Exceed max_steps ({}) of function execution",
+        tools.options.max_steps
+    )
+}
+
+/// Like `complete_functions`, streaming the text of every step and the calls handed back.
+#[allow(clippy::too_many_arguments)]
+async fn stream_functions(
+    tools: &ToolRunner,
+    client: &mut dyn Client,
+    http_client: &ReqwestClient,
+    mut data: ChatCompletionsData,
+    budget: TokenBudget,
+    client_tools: &[String],
+    abort: AbortSignal,
+    tx: &UnboundedSender<ResEvent>,
+    record: &mut TraceRecord,
+    started: Instant,
+    is_first: &mut bool,
+) {
+    let (mut input_tokens, mut output_tokens, mut ttft_ms) = (None, None, None);
+    let mut reasked = false;
+    for step in 1..=tools.options.max_steps {
+        if step > 1 {
+            if let Err(err) = budget.apply(client, &data) {
+                record.set_error(StatusCode::BAD_REQUEST.as_u16(), &err);
+                break;
+            }
+        }
+        let step_started = Instant::now();
+        let timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+        let text = stream_completion(
+            client,
+            http_client,
+            data.clone(),
+            abort.clone(),
+            tx,
+            record,
+            started,
+            is_first,
+        )
+        .await;
+        input_tokens = sum_tokens(input_tokens, record.input_tokens);
+        output_tokens = sum_tokens(output_tokens, record.output_tokens);
+        ttft_ms = ttft_ms.or(record.ttft_ms);
+        record
+            .spans
+            .push(step_span(step, step_started, timestamp, &record.tool_calls));
+        if record.error.is_some() || record.tool_calls.is_empty() {
+            break;
+        }
+        let last_step = step == tools.options.max_steps;
+        let calls = std::mem::take(&mut record.tool_calls);
+        let step = if last_step && !calls.iter().any(|v| client_tools.contains(&v.name)) {
+            Err(anyhow!(
+                "Exceed max_steps ({}) of function execution",
+                tools.options.max_steps
+            ))
+        } else {
+            let offered = data.functions.as_deref().unwrap_or_default();
+            run_tool_calls(tools, offered, client_tools, calls, &mut reasked, record).await
+        };
+        let results = match step {
+            Ok(ToolStep::Results(_)) if last_step => {
+                let err = anyhow!(
+                    "Exceed max_steps ({}) of function execution",
+                    tools.options.max_steps
+                );
+                record.set_error(StatusCode::BAD_REQUEST.as_u16(), &err);
+                break;
+            }
+            Ok(ToolStep::Results(results)) => results,
+            // Streamed only now, as checking them may have repaired their arguments.
+            Ok(ToolStep::HandBack(calls)) => {
+                for (index, call) in calls.iter().enumerate() {
+                    let delta = ToolCallDelta {
+                        index,
+                        id: call.id.clone(),
+                        name: Some(call.name.clone()),
+                        arguments: call.arguments_text(),
+                    };
+                    forward_event(
+                        SseEvent::ToolCall(delta),
+                        tx,
+                        is_first,
+                        &mut ttft_ms,
+                        started,
+                    );
+                }
+                record.tool_calls = calls;
+                break;
+            }
+            Err(err) => {
+                record.set_error(StatusCode::BAD_REQUEST.as_u16(), &err);
+                break;
+            }
+        };
+        data.messages.push(Message::new(
+            MessageRole::Assistant,
+            MessageContent::ToolResults((results, text)),
+        ));
+    }
+    record.input_tokens = input_tokens;
+    record.output_tokens = output_tokens;
+    record.ttft_ms = ttft_ms;
+    record.cost = client.model().cost(input_tokens, output_tokens);
+}
+
+/// Whether a step's calls go back to the client. A step may not mix them with calls to the
+/// gateway's offered functions, which the client could not answer.
+fn hands_back(
+    calls: &[ToolCall],
+    offered: &[FunctionDeclaration],
+    client_tools: &[String],
+) -> Result<bool> {
+    let (client, gateway): (Vec<&ToolCall>, Vec<&ToolCall>) = calls
+        .iter()
+        .filter(|v| offered.iter().any(|f| f.name == v.name))
+        .partition(|v| client_tools.contains(&v.name));
+    match (client.first(), gateway.first()) {
+        (Some(client), Some(gateway)) => bail!(
+            "Tool calls to '{}' and '{}' in one step, run by the client and the gateway",
+            client.name,
+            gateway.name
+        ),
+        (client, _) => Ok(client.is_some()),
+    }
+}
+
+/// What became of a step's tool calls.
+enum ToolStep {
+    /// The results to send back to the model.
+    Results(Vec<ToolCallResult>),
+    /// Checked calls for the client to run.
+    HandBack(Vec<ToolCall>),
+}
+
+/// Runs tool calls concurrently, with a span per run, or hands calls to `client_tools` back.
+///
+/// Calls to functions not offered in this request, or with invalid arguments, are not run: their
+/// errors go back to the model once, and fail the request when they come back. A step handed back
+/// with a rejected call goes back to the model whole, none of its calls run.
+async fn run_tool_calls(
+    tools: &ToolRunner,
+    offered: &[FunctionDeclaration],
+    client_tools: &[String],
+    calls: Vec<ToolCall>,
+    reasked: &mut bool,
+    record: &mut TraceRecord,
+) -> Result<ToolStep> {
+    let mut calls = ToolCall::dedup(calls);
+    let handed_back = hands_back(&calls, offered, client_tools)?;
+    let rejections = check_tool_calls(&tools.validation, offered, &mut calls, record);
+    if let Some((call, err)) = calls
+        .iter()
+        .zip(&rejections)
+        .find_map(|(call, err)| Some((call, err.as_ref()?)))
+    {
+        if tools.validation.enabled {
+            if *reasked || !tools.validation.reask {
+                bail!("Tool call '{}' rejected, {err}", call.name);
+            }
+            *reasked = true;
+            record.retries += 1;
+            record.spans.push(TraceSpan {
+                name: "retry".into(),
+                span_id: Some(generate_span_id()),
+                timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+                attributes: [
+                    ("gateway.retry.reason", json!("rejected_tool_calls")),
+                    ("gateway.function.name", json!(call.name)),
+                ]
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v))
+                .collect(),
+                ..Default::default()
+            });
+        }
+    }
+    if handed_back && rejections.iter().all(Option::is_none) {
+        for call in calls.iter_mut() {
+            call.id.get_or_insert_with(generate_tool_call_id);
+        }
+        return Ok(ToolStep::HandBack(calls));
+    }
+    let timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+    let runs = join_all(calls.iter().zip(&rejections).map(|(call, err)| async move {
+        let started = Instant::now();
+        let run = match err {
+            Some(err) => FunctionRun::failed(err.clone()),
+            None if handed_back => {
+                FunctionRun::failed("Not run, another call in this step was rejected".into())
+            }
+            None => tools.run(call).await,
+        };
+        (run, started.elapsed().as_millis() as u64)
+    }))
+    .await;
+    let mut results = vec![];
+    for (call, (run, latency_ms)) in calls.into_iter().zip(runs) {
+        log::debug!("Function {} returned {}", call.name, run.output);
+        record.spans.push(TraceSpan {
+            name: format!("function {}", call.name),
+            span_id: Some(generate_span_id()),
+            timestamp: timestamp.clone(),
+            latency_ms,
+            attributes: [
+                ("gateway.function.name", json!(call.name)),
+                ("gateway.function.call_id", json!(call.id)),
+                ("gateway.function.exit_code", json!(run.exit_code)),
+                ("gateway.function.truncated", json!(run.truncated)),
+                ("gateway.function.timed_out", json!(run.timed_out)),
+            ]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect(),
+            error: run.error,
+        });
+        results.push(ToolCallResult::new(call, run.output));
+    }
+    Ok(ToolStep::Results(results))
+}
+
+/// Repairs and validates tool calls against the offered functions, with a span per call that needed
+/// either, returning why each call must not run.
+fn check_tool_calls(
+    validation: &ToolCallValidation,
+    offered: &[FunctionDeclaration],
+    calls: &mut [ToolCall],
+    record: &mut TraceRecord,
+) -> Vec<Option<String>> {
+    let timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+    let mut rejections = vec![];
+    for call in calls.iter_mut() {
+        let Some(function) = offered.iter().find(|v| v.name == call.name) else {
+            rejections.push(Some(format!("Function '{}' was not offered", call.name)));
+            continue;
+        };
+        if !validation.enabled {
+            rejections.push(None);
+            continue;
+        }
+        let mut repaired = false;
+        if let Value::String(raw) = &call.arguments {
+            if let Some(arguments) = validation.repair.then(|| repair_json(raw)).flatten() {
+                call.arguments = arguments;
+                repaired = true;
+            }
+        }
+        let errors = match &call.arguments {
+            Value::String(_) => vec!["arguments are not valid JSON".to_string()],
+            arguments => function.parameters.inline_refs().0.validate(arguments),
+        };
+        let rejection =
+            (!errors.is_empty()).then(|| format!("invalid arguments, {}", errors.join("; ")));
+        if repaired || rejection.is_some() {
+            log::debug!(
+                "Tool call {} repaired={repaired} errors={errors:?}",
+                call.name
+            );
+            record.spans.push(TraceSpan {
+                name: format!("check {}", call.name),
+                span_id: Some(generate_span_id()),
+                timestamp: timestamp.clone(),
+                attributes: [
+                    ("gateway.function.name", json!(call.name)),
+                    ("gateway.function.call_id", json!(call.id)),
+                    ("gateway.tool_call.repaired", json!(repaired)),
+                    ("gateway.tool_call.valid", json!(errors.is_empty())),
+                ]
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v))
+                .collect(),
+                error: (!errors.is_empty()).then(|| errors.join("; ")),
+                ..Default::default()
+            });
+        }
+        rejections.push(rejection);
+    }
+    rejections
+}
+
+fn step_span(
+    step: usize,
+    started: Instant,
+    timestamp: String,
+    tool_calls: &[ToolCall],
+) -> TraceSpan {
+    let names: Vec<&str> = tool_calls.iter().map(|v| v.name.as_str()).collect();
+    TraceSpan {
+        name: format!("chat step {step}"),
+        span_id: Some(generate_span_id()),
+        timestamp,
+        latency_ms: started.elapsed().as_millis() as u64,
+        attributes: [
+            ("gateway.step", json!(step)),
+            ("gateway.tool_calls", json!(names)),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect(),
+        ..Default::default()
+    }
+}
+
+fn sum_tokens(total: Option<u64>, tokens: Option<u64>) -> Option<u64> {
+    match (total, tokens) {
+        (None, None) => None,
+        (total, tokens) => Some(total.unwrap_or_default() + tokens.unwrap_or_default()),
+    }
+}
+
+#[derive(Debug)]
+enum ResEvent {
+    First(Option<String>),
+    /// A failure after the first event, when the response has already begun.
+    Error(String),
+    Text(String),
+    ToolCall(ToolCallDelta),
+    Stats(Value),
+    Response(Value),
+    /// The end of a chat completion, with its finish reason.
+    Finish(&'static str),
+    Done,
+}
+
+fn forward_event(
+    event: SseEvent,
+    tx: &UnboundedSender<ResEvent>,
+    is_first: &mut bool,
+    ttft: &mut Option<u64>,
+    started: Instant,
+) {
+    if *is_first {
+        *ttft = Some(started.elapsed().as_millis() as u64);
+        let _ = tx.send(ResEvent::First(None));
+        *is_first = false;
+    }
+    // The final frame is sent once the stream is fully drained.
+    match event {
+        SseEvent::Text(text) => {
+            let _ = tx.send(ResEvent::Text(text));
+        }
+        SseEvent::ToolCall(delta) => {
+            let _ = tx.send(ResEvent::ToolCall(delta));
+        }
+        SseEvent::Done => {}
+    }
+}
+
+/// Per-call cost and latency headers, also sent as the last SSE comment of streams.
+fn gateway_headers(record: &TraceRecord) -> Vec<(&'static str, String)> {
+    let mut headers = vec![];
+    if let Some(cost) = record.cost {
+        headers.push((COST_HEADER, cost.to_string()));
+    }
+    if let Some(model) = &record.resolved_model {
+        headers.push((MODEL_RESOLVED_HEADER, model.clone()));
+    }
+    if let Some(client) = &record.client {
+        headers.push((CLIENT_HEADER, client.clone()));
+    }
+    headers.push((LATENCY_HEADER, record.latency_ms.to_string()));
+    if let Some(ttft_ms) = record.ttft_ms {
+        headers.push((TTFT_HEADER, ttft_ms.to_string()));
+    }
+    if let Some(context_trimmed) = &record.context_trimmed {
+        headers.push((CONTEXT_TRIMMED_HEADER, context_trimmed.clone()));
+    }
+    headers
+}
+
+/// `(source, text)` pairs for every file and inline document in the request.
+///
+/// `paths` are only read below `documents_dir`, and cited relative to it.
+fn collect_documents(
+    req_body: KnowledgeBaseDocumentsReqBody,
+    documents_dir: Option<&Path>,
+) -> Result<Vec<(String, String)>> {
+    let mut documents = vec![];
+    if !req_body.paths.is_empty() {
+        let Some(root) = documents_dir else {
+            bail!("Reading 'paths' is disabled, set `documents_dir` to enable it");
+        };
+        for path in expand_paths(&req_body.paths, root)? {
+            let text = load_file(&path)?;
+            let source = path.strip_prefix(root).unwrap_or(&path);
+            documents.push((source.display().to_string(), text));
+        }
+    }
+    for document in req_body.documents {
+        documents.push((document.name, document.content));
+    }
+    if documents.is_empty() {
+        bail!("No documents to ingest");
+    }
+    Ok(documents)
+}
+
+/// Stores a completed response with its conversation, so later requests can chain from it.
+fn save_response(
+    responses: Option<&ResponseStore>,
+    mut messages: Vec<Message>,
+    text: &str,
+    response: &Value,
+) {
+    let Some(responses) = responses else {
+        return;
+    };
+    messages.push(Message::new(
+        MessageRole::Assistant,
+        MessageContent::Text(text.to_string()),
+    ));
+    let stored = StoredResponse {
+        id: response["id"].as_str().unwrap_or_default().to_string(),
+        messages,
+        response: response.clone(),
+    };
+    if let Err(err) = responses.insert(&stored) {
+        warn!("Failed to store response '{}': {err}", stored.id);
+    }
+}
+
+/// Session ids become file names, so only a conservative charset is accepted.
+fn guard_session_id(id: &str) -> Result<()> {
+    if id.is_empty()
+        || !id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        bail!("Invalid session id '{id}'");
+    }
+    Ok(())
+}
+
+/// Replaces the session history with a summary, keeping its system prompt.
+async fn summarize_session(
+    client: &dyn Client,
+    http_client: &ReqwestClient,
+    session: &mut Session,
+) -> Result<ChatCompletionsOutput> {
+    let mut messages = session.messages().to_vec();
+    messages.push(Message::new(
+        MessageRole::User,
+        MessageContent::Text(SUMMARIZE_PROMPT.into()),
+    ));
+    let data = ChatCompletionsData {
+        messages,
+        temperature: None,
+        top_p: None,
+        functions: None,
+        stream: false,
+    };
+    let output = client.chat_completions_inner(http_client, data).await?;
+    let mut prompt = format!("{SUMMARY_PROMPT}{}", output.text);
+    if let Some(Message {
+        role: MessageRole::System,
+        content: MessageContent::Text(system),
+    }) = session.messages().first()
+    {
+        prompt = format!("{system}\n\n{prompt}");
+    }
+    session.compress(prompt);
+    Ok(output)
+}
+
+/// The span of a session summary, with the usage of the model call that wrote it.
+fn summary_span(
+    model: &Model,
+    started: Instant,
+    timestamp: String,
+    ret: &Result<ChatCompletionsOutput>,
+) -> TraceSpan {
+    let (input_tokens, output_tokens) = match ret {
+        Ok(output) => (output.input_tokens, output.output_tokens),
+        Err(_) => (None, None),
+    };
+    TraceSpan {
+        name: "summarize_session".into(),
+        span_id: Some(generate_span_id()),
+        timestamp,
+        latency_ms: started.elapsed().as_millis() as u64,
+        attributes: [
+            ("gen_ai.usage.input_tokens", json!(input_tokens)),
+            ("gen_ai.usage.output_tokens", json!(output_tokens)),
+            (
+                "gateway.cost",
+                json!(model.cost(input_tokens, output_tokens)),
+            ),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect(),
+        error: ret
+            .as_ref()
+            .err()
+            .map(|err| redact_secrets(&format!("{err:#}")).into_owned()),
+    }
+}
+
+/// Fails the request before the first event; afterwards the stream ends with an error event.
+fn send_error_event(tx: &UnboundedSender<ResEvent>, err: &str, is_first: &mut bool) {
+    if *is_first {
+        let _ = tx.send(ResEvent::First(Some(err.to_string())));
+        *is_first = false;
+    } else {
+        let _ = tx.send(ResEvent::Error(err.to_string()));
+    }
+}
+
+async fn shutdown_signal() {
+    tokio::signal::ctrl_c()
+        .await
+        .expect("Failed to install CTRL+C signal handler")
+}
+
+fn caller_key(req: &hyper::Request<Incoming>) -> Option<String> {
+    let value = req
+        .headers()
+        .get(hyper::header::AUTHORIZATION)?
+        .to_str()
+        .ok()?;
+    let key = value.strip_prefix("Bearer ").unwrap_or(value).trim();
+    if key.is_empty() {
+        None
+    } else {
+        Some(key.to_string())
+    }
+}
+
+fn generate_completion_id() -> String {
+    let random_id = chrono::Utc::now().nanosecond();
+    format!("chatcmpl-{}", random_id)
+}
+
+fn set_cors_header(res: &mut AppResponse) {
+    res.headers_mut().insert(
+        hyper::header::ACCESS_CONTROL_ALLOW_ORIGIN,
+        hyper::header::HeaderValue::from_static("*"),
+    );
+    res.headers_mut().insert(
+        hyper::header::ACCESS_CONTROL_ALLOW_METHODS,
+        hyper::header::HeaderValue::from_static("GET,POST,PUT,PATCH,DELETE"),
+    );
+    res.headers_mut().insert(
+        hyper::header::ACCESS_CONTROL_ALLOW_HEADERS,
+        hyper::header::HeaderValue::from_static(
+            "Content-Type,Authorization,X-Trace-Id,X-Parent-Span-Id,X-Agent-Name,X-Step,Traceparent",
+        ),
+    );
+    res.headers_mut().insert(
+        hyper::header::ACCESS_CONTROL_EXPOSE_HEADERS,
+        hyper::header::HeaderValue::from_static(
+            "X-Request-Id,X-Trace-Id,X-Span-Id,X-Gateway-Cost-Usd,X-Gateway-Model-Resolved,X-Gateway-Client,X-Gateway-Latency-Ms,X-Gateway-Ttft-Ms,X-Gateway-Context-Trimmed",
+        ),
+    );
+}
+
+fn create_frame(
+    id: &str,
+    model: &str,
+    created: i64,
+    content: &str,
+    tool_call: Option<&ToolCallDelta>,
+    finish_reason: Option<&str>,
+    citations: Option<&Value>,
+) -> Frame<Bytes> {
+    let done = finish_reason.is_some();
+    let (delta, finish_reason) = if let Some(finish_reason) = finish_reason {
+        (json!({}), finish_reason.into())
+    } else if let Some(tool_call) = tool_call {
+        (json!({ "tool_calls": [tool_call.to_value()] }), Value::Null)
+    } else {
+        let delta = if content.is_empty() {
+            json!({ "role": "assistant", "content": content })
+        } else {
+            json!({ "content": content })
+        };
+        (delta, Value::Null)
+    };
+    let mut value = json!({
+        "id": id,
+        "object": "chat.completion.chunk",
+        "created": created,
+        "model": model,
+        "choices": [
+            {
+                "index": 0,
+                "delta": delta,
+                "finish_reason": finish_reason,
+            },
+        ],
+    });
+    if let Some(citations) = citations {
+        value["citations"] = citations.clone();
+    }
+    let output = if done {
+        format!("data: {value}\n\ndata: [DONE]\n\n")
+    } else {
+        format!("data: {value}\n\n")
+    };
+    Frame::data(Bytes::from(output))
+}
+
+/// An error chunk in the shape of an error response, ending the stream.
+fn create_error_frame(err: &str) -> Frame<Bytes> {
+    let value = json!({
+        "error": {
+            "message": redact_secrets(err),
+            "type": "api_error",
+        },
+    });
+    Frame::data(Bytes::from(format!("data: {value}\n\ndata: [DONE]\n\n")))
+}
+
+fn ret_non_stream(
+    id: &str,
+    model: &str,
+    created: i64,
+    output: &ChatCompletionsOutput,
+    citations: Option<&Value>,
+) -> Bytes {
+    let id = output.id.as_deref().unwrap_or(id);
+    let input_tokens = output.input_tokens.unwrap_or_default();
+    let output_tokens = output.output_tokens.unwrap_or_default();
+    let total_tokens = input_tokens + output_tokens;
+    let mut message = json!({
+        "role": "assistant",
+        "content": output.text,
+    });
+    if !output.tool_calls.is_empty() {
+        let tool_calls: Vec<Value> = output
+            .tool_calls
+            .iter()
+            .map(|call| {
+                json!({
+                    "id": call.id.clone().unwrap_or_else(generate_tool_call_id),
+                    "type": "function",
+                    "function": {
+                        "name": call.name,
+                        "arguments": call.arguments_text(),
+                    },
+                })
+            })
+            .collect();
+        if output.text.is_empty() {
+            message["content"] = Value::Null;
+        }
+        message["tool_calls"] = tool_calls.into();
+    }
+    let mut res_body = json!({
+        "id": id,
+        "object": "chat.completion",
+        "created": created,
+        "model": model,
+        "choices": [
+            {
+                "index": 0,
+                "message": message,
+                "logprobs": null,
+                "finish_reason": finish_reason(&output.tool_calls),
+            },
+        ],
+        "usage": {
+            "prompt_tokens": input_tokens,
+            "completion_tokens": output_tokens,
+            "total_tokens": total_tokens,
+        },
+    });
+    if let Some(citations) = citations {
+        res_body["citations"] = citations.clone();
+    }
+    Bytes::from(res_body.to_string())
+}
+
+/// A handler error answered with 404 instead of 400.
+#[derive(Debug)]
+struct NotFound(String);
+
+impl std::fmt::Display for NotFound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for NotFound {}
 
 fn ret_err<T: std::fmt::Display>(err: T) -> AppResponse {
     let data = json!({
         "error": {
-            "message": err.to_string(),
+            "message": redact_secrets(&err.to_string()),
             "type": "invalid_request_error",
         },
     });
@@ -447,3 +2998,417 @@ fn ret_err<T: std::fmt::Display>(err: T) -> AppResponse {
         .body(Full::new(Bytes::from(data.to_string())).boxed())
         .unwrap()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use async_trait::async_trait;
+    use lazy_static::lazy_static;
+
+    lazy_static! {
+        static ref GLOBAL_CONFIG: GlobalConfig = Default::default();
+    }
+
+    /// Asks for `echo` until it has `tool_steps` results, then answers with the last one.
+    struct LoopClient {
+        model: Model,
+        tool_steps: usize,
+    }
+
+    impl LoopClient {
+        fn reply(&self, data: &ChatCompletionsData) -> ChatCompletionsOutput {
+            let results: Vec<&ToolCallResult> = data
+                .messages
+                .iter()
+                .filter_map(|v| match &v.content {
+                    MessageContent::ToolResults((results, _)) => results.first(),
+                    _ => None,
+                })
+                .collect();
+            let step = results.len();
+            let mut output = ChatCompletionsOutput {
+                input_tokens: Some(10),
+                output_tokens: Some(2),
+                ..Default::default()
+            };
+            if step < self.tool_steps {
+                output.tool_calls = vec![ToolCall::new(
+                    "echo".into(),
+                    json!({ "q": format!("step {step}") }),
+                    Some(format!("call_{step}")),
+                )];
+            } else {
+                output.text = format!("done: {}", results[step - 1].output);
+            }
+            output
+        }
+    }
+
+    #[async_trait]
+    impl Client for LoopClient {
+        fn global_config(&self) -> &GlobalConfig {
+            &GLOBAL_CONFIG
+        }
+
+        fn extra_config(&self) -> Option<&ExtraConfig> {
+            None
+        }
+
+        fn patches_config(&self) -> Option<&ModelPatches> {
+            None
+        }
+
+        fn name(&self) -> &str {
+            "test"
+        }
+
+        fn model(&self) -> &Model {
+            &self.model
+        }
+
+        fn model_mut(&mut self) -> &mut Model {
+            &mut self.model
+        }
+
+        async fn chat_completions_inner(
+            &self,
+            _client: &ReqwestClient,
+            data: ChatCompletionsData,
+        ) -> Result<ChatCompletionsOutput> {
+            Ok(self.reply(&data))
+        }
+
+        async fn chat_completions_streaming_inner(
+            &self,
+            _client: &ReqwestClient,
+            handler: &mut SseHandler,
+            data: ChatCompletionsData,
+        ) -> Result<()> {
+            let output = self.reply(&data);
+            if !output.text.is_empty() {
+                handler.text(&output.text)?;
+            }
+            for call in output.tool_calls {
+                handler.tool_call(call)?;
+            }
+            handler.usage(output.input_tokens, output.output_tokens);
+            Ok(())
+        }
+
+        async fn embeddings_inner(
+            &self,
+            _client: &ReqwestClient,
+            _data: EmbeddingsData,
+        ) -> Result<EmbeddingsOutput> {
+            bail!("No embeddings api")
+        }
+    }
+
+    /// A runner with one `echo` function that prints its arguments back.
+    #[cfg(unix)]
+    fn echo_tools(dir: &Path, max_steps: usize) -> (ToolRunner, Vec<FunctionDeclaration>) {
+        use std::os::unix::fs::PermissionsExt;
+
+        let declarations = json!([{
+            "name": "echo",
+            "description": "Echo the arguments",
+            "parameters": { "type": "object", "properties": { "q": { "type": "string" } } },
+        }]);
+        std::fs::create_dir_all(dir.join("bin")).unwrap();
+        std::fs::write(dir.join("functions.json"), declarations.to_string()).unwrap();
+        let bin = dir.join("bin/echo");
+        std::fs::write(&bin, "#!/bin/sh\ncat\n").unwrap();
+        std::fs::set_permissions(&bin, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let tools = ToolRunner {
+            function: Function::init(dir).unwrap(),
+            mcp: Arc::new(McpHub::new(vec![], Duration::from_secs(30))),
+            options: FunctionExecution {
+                enabled: true,
+                max_steps,
+                ..Default::default()
+            },
+            validation: Default::default(),
+        };
+        let declarations = serde_json::from_value(declarations).unwrap();
+        (tools, declarations)
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_function_loop() {
+        let dir = std::env::temp_dir().join(format!("function_loop_{}", std::process::id()));
+        let (tools, declarations) = echo_tools(&dir, 3);
+        let mut client = LoopClient {
+            model: Model::new("test", "gpt-4"),
+            tool_steps: 1,
+        };
+        let http_client = ReqwestClient::new();
+        let data = ChatCompletionsData {
+            messages: vec![Message::new(
+                MessageRole::User,
+                MessageContent::Text("hi".into()),
+            )],
+            temperature: None,
+            top_p: None,
+            functions: Some(declarations),
+            stream: false,
+        };
+        let budget = TokenBudget {
+            max_tokens: None,
+            policy: Default::default(),
+        };
+
+        // One tool round-trip: the function output goes back to the model
+        let mut record = TraceRecord::new("req-1", None);
+        let output = complete_functions(
+            &tools,
+            &mut client,
+            &http_client,
+            data.clone(),
+            budget,
+            &[],
+            &mut record,
+        )
+        .await
+        .unwrap();
+        assert_eq!(output.text, r#"done: {"q":"step 0"}"#);
+        assert_eq!(
+            (output.input_tokens, output.output_tokens),
+            (Some(20), Some(4))
+        );
+        let spans: Vec<&str> = record.spans.iter().map(|v| v.name.as_str()).collect();
+        assert_eq!(spans, ["chat step 1", "function echo", "chat step 2"]);
+
+        let (tx, mut rx) = unbounded_channel();
+        let mut record = TraceRecord::new("req-2", None);
+        let mut is_first = true;
+        stream_functions(
+            &tools,
+            &mut client,
+            &http_client,
+            data.clone(),
+            budget,
+            &[],
+            create_abort_signal(),
+            &tx,
+            &mut record,
+            Instant::now(),
+            &mut is_first,
+        )
+        .await;
+        assert!(record.error.is_none());
+        let mut text = String::new();
+        let mut tool_calls = 0;
+        while let Ok(event) = rx.try_recv() {
+            match event {
+                ResEvent::Text(value) => text.push_str(&value),
+                ResEvent::ToolCall(_) => tool_calls += 1,
+                _ => {}
+            }
+        }
+        // The gateway ran the call, so it is not streamed
+        assert_eq!((text.as_str(), tool_calls), (r#"done: {"q":"step 0"}"#, 0));
+
+        // A client tool is handed back instead of run
+        let client_tools = ["echo".to_string()];
+        let mut record = TraceRecord::new("req-h1", None);
+        let output = complete_functions(
+            &tools,
+            &mut client,
+            &http_client,
+            data.clone(),
+            budget,
+            &client_tools,
+            &mut record,
+        )
+        .await
+        .unwrap();
+        assert_eq!(output.tool_calls.len(), 1);
+        assert_eq!(record.spans.len(), 1);
+
+        let (tx, mut rx) = unbounded_channel();
+        let mut record = TraceRecord::new("req-h2", None);
+        let mut is_first = true;
+        stream_functions(
+            &tools,
+            &mut client,
+            &http_client,
+            data.clone(),
+            budget,
+            &client_tools,
+            create_abort_signal(),
+            &tx,
+            &mut record,
+            Instant::now(),
+            &mut is_first,
+        )
+        .await;
+        assert_eq!(finish_reason(&record.tool_calls), "tool_calls");
+        let mut indices = vec![];
+        while let Ok(event) = rx.try_recv() {
+            if let ResEvent::ToolCall(delta) = event {
+                indices.push(delta.index);
+            }
+        }
+        assert_eq!(indices, [0]);
+
+        // A step may not mix the client's calls with the gateway's
+        let call = |name: &str| ToolCall::new(name.into(), json!({}), None);
+        let mut offered = data.functions.clone().unwrap();
+        assert!(!hands_back(&[call("echo")], &offered, &[]).unwrap());
+        assert!(hands_back(&[call("echo"), call("nope")], &offered, &client_tools).unwrap());
+        offered.push(FunctionDeclaration {
+            name: "get_time".into(),
+            ..offered[0].clone()
+        });
+        assert!(hands_back(&[call("echo"), call("get_time")], &offered, &client_tools).is_err());
+
+        // Never done within max_steps
+        client.tool_steps = 5;
+        let mut record = TraceRecord::new("req-3", None);
+        let err = complete_functions(
+            &tools,
+            &mut client,
+            &http_client,
+            data.clone(),
+            budget,
+            &[],
+            &mut record,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Exceed max_steps (3) of function execution"
+        );
+
+        let (tx, mut rx) = unbounded_channel();
+        let mut record = TraceRecord::new("req-4", None);
+        let mut is_first = true;
+        stream_functions(
+            &tools,
+            &mut client,
+            &http_client,
+            data,
+            budget,
+            &[],
+            create_abort_signal(),
+            &tx,
+            &mut record,
+            Instant::now(),
+            &mut is_first,
+        )
+        .await;
+        let err = record.error.clone().unwrap();
+        assert_eq!(err, "Exceed max_steps (3) of function execution");
+        // Nothing was streamed, so the failure still fails the request
+        send_error_event(&tx, &err, &mut is_first);
+        let mut last = None;
+        while let Ok(event) = rx.try_recv() {
+            last = Some(event);
+        }
+        assert!(matches!(last, Some(ResEvent::First(Some(value))) if value == err));
+
+        // Once the response has begun, it ends with an error event
+        send_error_event(&tx, &err, &mut is_first);
+        assert!(matches!(rx.try_recv(), Ok(ResEvent::Error(value)) if value == err));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// An OpenAI-compatible upstream that always answers "Hello!".
+    async fn mock_upstream() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((cnx, _)) = listener.accept().await {
+                let service = service_fn(|_: hyper::Request<Incoming>| async {
+                    let data = json!({
+                        "choices": [{
+                            "message": { "role": "assistant", "content": "Hello!" },
+                            "finish_reason": "stop",
+                        }],
+                        "usage": { "prompt_tokens": 5, "completion_tokens": 2 },
+                    });
+                    Ok::<_, Infallible>(Response::new(Full::new(Bytes::from(data.to_string()))))
+                });
+                tokio::spawn(async move {
+                    let _ = hyper_util::server::conn::auto::Builder::new(TokioExecutor::new())
+                        .serve_connection(TokioIo::new(cnx), service)
+                        .await;
+                });
+            }
+        });
+        format!("http://{addr}/v1")
+    }
+
+    #[tokio::test]
+    async fn test_session_endpoints() {
+        let dir = std::env::temp_dir().join(format!("session_endpoints_{}", std::process::id()));
+        std::env::set_var("CONFIG_DIR", &dir);
+        let clients = format!(
+            "[{{type: openai-compatible, name: mock, api_base: '{}', models: [{{name: echo}}]}}]",
+            mock_upstream().await
+        );
+        let mut config = Config {
+            clients: serde_yaml::from_str(&clients).unwrap(),
+            ..Default::default()
+        };
+        config.set_model("mock:echo").unwrap();
+        let server = Arc::new(Server::new(&Arc::new(RwLock::new(config))).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}/v1/sessions", listener.local_addr().unwrap());
+        let stop_server = server.run(listener).await.unwrap();
+        let http_client = ReqwestClient::builder().no_proxy().build().unwrap();
+
+        let res = http_client
+            .post(&base)
+            .json(&json!({ "name": "s1", "system": "Be terse." }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = http_client
+            .post(format!("{base}/s1/messages"))
+            .json(&json!({ "content": "Hi" }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let data: Value = res.json().await.unwrap();
+        assert_eq!(data["message"]["content"], "Hello!");
+        assert_eq!(data["usage"]["prompt_tokens"], 5);
+
+        let data: Value = http_client
+            .get(format!("{base}/s1"))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let contents: Vec<&Value> = data["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|v| &v["content"])
+            .collect();
+        assert_eq!(
+            contents,
+            [&json!("Be terse."), &json!("Hi"), &json!("Hello!")]
+        );
+
+        let res = http_client
+            .delete(format!("{base}/s1"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = http_client.get(format!("{base}/s1")).send().await.unwrap();
+        assert!(!res.status().is_success());
+
+        let _ = stop_server.send(());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,203 @@
+use super::TraceConfig;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// Append-only JSONL file that rotates itself by size and age.
+#[derive(Debug)]
+pub struct JsonlWriter {
+    path: PathBuf,
+    file: Option<File>,
+    size: u64,
+    opened_at: DateTime<Local>,
+    max_file_size: u64,
+    max_file_age: i64,
+    max_files: usize,
+    last_rotation: Option<(String, u32)>,
+}
+
+impl JsonlWriter {
+    pub fn new(path: &Path, config: &TraceConfig) -> Result<Self> {
+        crate::config::ensure_parent_exists(path)?;
+        let mut writer = Self {
+            path: path.to_path_buf(),
+            file: None,
+            size: 0,
+            opened_at: Local::now(),
+            max_file_size: config.max_file_size,
+            max_file_age: config.max_file_age as i64,
+            max_files: config.max_files,
+            last_rotation: None,
+        };
+        writer.open()?;
+        Ok(writer)
+    }
+
+    pub fn write_line(&mut self, line: &str) -> Result<()> {
+        if self.need_rotate(line.len() as u64 + 1) {
+            self.rotate()?;
+        }
+        if self.file.is_none() {
+            self.open()?;
+        }
+        let file = self.file.as_mut().expect("file opened above");
+        file.write_all(line.as_bytes())
+            .and_then(|_| file.write_all(b"\n"))
+            .with_context(|| format!("Failed to write to {}", self.path.display()))?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    fn open(&mut self) -> Result<()> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("Failed to create/append {}", self.path.display()))?;
+        let metadata = file.metadata()?;
+        self.size = metadata.len();
+        self.opened_at = metadata
+            .created()
+            .or_else(|_| metadata.modified())
+            .map(DateTime::<Local>::from)
+            .unwrap_or_else(|_| Local::now());
+        self.file = Some(file);
+        Ok(())
+    }
+
+    fn need_rotate(&self, incoming: u64) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+        if self.max_file_size > 0 && self.size + incoming > self.max_file_size {
+            return true;
+        }
+        self.max_file_age > 0 && (Local::now() - self.opened_at).num_seconds() >= self.max_file_age
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        self.file.take();
+        let timestamp = Local::now().format("%Y%m%dT%H%M%S%.3f").to_string();
+        // Rotations within the same millisecond take the next sequence number instead of
+        // overwriting each other; the fixed width keeps them in order when sorted.
+        let first_seq = match &self.last_rotation {
+            Some((last, seq)) if *last == timestamp => seq + 1,
+            _ => 0,
+        };
+        let (seq, rotated_path) = (first_seq..)
+            .map(|seq| (seq, self.rotated_path(&format!("{timestamp}-{seq:03}"))))
+            .find(|(_, path)| !path.exists())
+            .expect("unbounded sequence");
+        self.last_rotation = Some((timestamp, seq));
+        fs::rename(&self.path, &rotated_path).with_context(|| {
+            format!(
+                "Failed to rotate {} to {}",
+                self.path.display(),
+                rotated_path.display()
+            )
+        })?;
+        self.prune()?;
+        self.open()
+    }
+
+    fn prune(&self) -> Result<()> {
+        let mut rotated = self.list_rotated();
+        if rotated.len() <= self.max_files {
+            return Ok(());
+        }
+        rotated.sort();
+        let excess = rotated.len() - self.max_files;
+        for path in rotated.into_iter().take(excess) {
+            fs::remove_file(&path)
+                .with_context(|| format!("Failed to remove {}", path.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Rotated files, oldest first once sorted, since the suffix is a timestamp.
+    pub fn list_rotated(&self) -> Vec<PathBuf> {
+        let (dir, stem, ext) = self.parts();
+        let prefix = format!("{stem}-");
+        let suffix = format!(".{ext}");
+        match fs::read_dir(dir) {
+            Ok(rd) => rd
+                .flatten()
+                .filter(|entry| {
+                    let name = entry.file_name();
+                    let name = name.to_string_lossy();
+                    name.starts_with(&prefix) && name.ends_with(&suffix)
+                })
+                .map(|entry| entry.path())
+                .collect(),
+            Err(_) => vec![],
+        }
+    }
+
+    fn rotated_path(&self, tag: &str) -> PathBuf {
+        let (dir, stem, ext) = self.parts();
+        dir.join(format!("{stem}-{tag}.{ext}"))
+    }
+
+    fn parts(&self) -> (PathBuf, String, String) {
+        let dir = self
+            .path
+            .parent()
+            .map(|v| v.to_path_buf())
+            .unwrap_or_default();
+        let stem = self
+            .path
+            .file_stem()
+            .map(|v| v.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let ext = self
+            .path
+            .extension()
+            .map(|v| v.to_string_lossy().to_string())
+            .unwrap_or_else(|| "jsonl".into());
+        (dir, stem, ext)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "agent-panel-jsonl-{}-{}",
+            name,
+            rand::random::<u32>()
+        ));
+        dir.join("requests.jsonl")
+    }
+
+    #[test]
+    fn test_rotate_by_size() {
+        let path = temp_path("size");
+        let config = TraceConfig {
+            max_file_size: 64,
+            max_file_age: 0,
+            max_files: 2,
+            ..Default::default()
+        };
+        let mut writer = JsonlWriter::new(&path, &config).unwrap();
+        for i in 0..10 {
+            writer
+                .write_line(&format!(r#"{{"id":"req-{i}","pad":"xxxxxxxxxxxxxxxx"}}"#))
+                .unwrap();
+        }
+        assert!(fs::metadata(&path).unwrap().len() <= 64);
+        let mut rotated = writer.list_rotated();
+        rotated.sort();
+        assert_eq!(rotated.len(), 2);
+        // Back-to-back rotations in the same millisecond must not overwrite each other
+        let last = fs::read_to_string(&rotated[1]).unwrap();
+        assert!(last.contains(r#""id":"req-8""#));
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,327 @@
+use super::TraceRecord;
+
+use indexmap::IndexMap;
+use parking_lot::Mutex;
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicI64, Ordering},
+};
+
+const LATENCY_BUCKETS: &[f64] = &[0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0];
+const TTFT_BUCKETS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+const TOKENS_PER_SECOND_BUCKETS: &[f64] = &[1.0, 5.0, 10.0, 25.0, 50.0, 100.0, 200.0, 500.0];
+
+/// In-memory Prometheus metrics, fed by every recorded call.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    in_flight: AtomicI64,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    series: IndexMap<Labels, Series>,
+    errors: IndexMap<(Labels, String), u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Labels {
+    client: String,
+    model: String,
+    status: u16,
+}
+
+impl Labels {
+    fn render(&self, extra: Option<(&str, &str)>) -> String {
+        let mut output = format!(
+            r#"client="{}",model="{}",status="{}""#,
+            escape_label(&self.client),
+            escape_label(&self.model),
+            self.status
+        );
+        if let Some((name, value)) = extra {
+            let _ = write!(output, r#",{name}="{}""#, escape_label(value));
+        }
+        output
+    }
+}
+
+#[derive(Debug)]
+struct Series {
+    requests: u64,
+    input_tokens: u64,
+    output_tokens: u64,
+    cost: f64,
+    cache_hits: u64,
+    retries: u64,
+    fallbacks: u64,
+    latency: Histogram,
+    ttft: Histogram,
+    tokens_per_second: Histogram,
+}
+
+impl Default for Series {
+    fn default() -> Self {
+        Self {
+            requests: 0,
+            input_tokens: 0,
+            output_tokens: 0,
+            cost: 0.0,
+            cache_hits: 0,
+            retries: 0,
+            fallbacks: 0,
+            latency: Histogram::new(LATENCY_BUCKETS),
+            ttft: Histogram::new(TTFT_BUCKETS),
+            tokens_per_second: Histogram::new(TOKENS_PER_SECOND_BUCKETS),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Histogram {
+    buckets: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(buckets: &'static [f64]) -> Self {
+        Self {
+            buckets,
+            counts: vec![0; buckets.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (bucket, count) in self.buckets.iter().zip(self.counts.iter_mut()) {
+            if value <= *bucket {
+                *count += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+impl Metrics {
+    /// Marks a call as in flight until its record is observed.
+    pub fn start(&self) {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn observe(&self, record: &TraceRecord) {
+        self.in_flight.fetch_sub(1, Ordering::Relaxed);
+        let labels = Labels {
+            client: record.client.clone().unwrap_or_default(),
+            // Never the requested name, which any caller could vary without bound.
+            model: record
+                .resolved_model
+                .clone()
+                .unwrap_or_else(|| "unknown".into()),
+            status: record.status,
+        };
+        let mut state = self.state.lock();
+        if let Some(class) = &record.error_class {
+            *state
+                .errors
+                .entry((labels.clone(), class.clone()))
+                .or_default() += 1;
+        }
+        let series = state.series.entry(labels).or_default();
+        series.requests += 1;
+        series.input_tokens += record.input_tokens.unwrap_or_default();
+        series.output_tokens += record.output_tokens.unwrap_or_default();
+        series.cost += record.cost.unwrap_or_default();
+        if record.cache_hit {
+            series.cache_hits += 1;
+        }
+        series.retries += record.retries as u64;
+        series.fallbacks += record.fallbacks as u64;
+        series.latency.observe(record.latency_ms as f64 / 1000.0);
+        if let Some(ttft_ms) = record.ttft_ms {
+            series.ttft.observe(ttft_ms as f64 / 1000.0);
+        }
+        if let Some(output_tokens) = record.output_tokens.filter(|v| *v > 0) {
+            // Generation time only, so slow first tokens don't skew the rate of streams.
+            let generation_ms = record
+                .latency_ms
+                .saturating_sub(record.ttft_ms.unwrap_or_default());
+            if generation_ms > 0 {
+                series
+                    .tokens_per_second
+                    .observe(output_tokens as f64 * 1000.0 / generation_ms as f64);
+            }
+        }
+    }
+
+    /// Text exposition format, as served on `/metrics`.
+    pub fn render(&self) -> String {
+        let state = self.state.lock();
+        let mut output = String::new();
+
+        type Counter = fn(&Series) -> f64;
+        let counters: [(&str, &str, Counter); 7] = [
+            (
+                "gateway_requests_total",
+                "Completed chat completion requests.",
+                |v| v.requests as f64,
+            ),
+            (
+                "gateway_input_tokens_total",
+                "Prompt tokens reported by providers.",
+                |v| v.input_tokens as f64,
+            ),
+            (
+                "gateway_output_tokens_total",
+                "Completion tokens reported by providers.",
+                |v| v.output_tokens as f64,
+            ),
+            ("gateway_cost_usd_total", "Estimated spend in USD.", |v| {
+                v.cost
+            }),
+            (
+                "gateway_cache_hits_total",
+                "Requests served from cache.",
+                |v| v.cache_hits as f64,
+            ),
+            (
+                "gateway_retries_total",
+                "Model calls retried, such as re-asks after rejected tool calls.",
+                |v| v.retries as f64,
+            ),
+            (
+                "gateway_fallbacks_total",
+                "Fallbacks to another model.",
+                |v| v.fallbacks as f64,
+            ),
+        ];
+        for (name, help, get) in counters {
+            let _ = writeln!(output, "# HELP {name} {help}\n# TYPE {name} counter");
+            for (labels, series) in state.series.iter() {
+                let _ = writeln!(output, "{name}{{{}}} {}", labels.render(None), get(series));
+            }
+        }
+
+        let name = "gateway_errors_total";
+        let _ = writeln!(
+            output,
+            "# HELP {name} Failed requests by error class.\n# TYPE {name} counter"
+        );
+        for ((labels, class), count) in state.errors.iter() {
+            let _ = writeln!(
+                output,
+                "{name}{{{}}} {count}",
+                labels.render(Some(("class", class)))
+            );
+        }
+
+        type Histo = fn(&Series) -> &Histogram;
+        let histograms: [(&str, &str, Histo); 3] = [
+            (
+                "gateway_request_duration_seconds",
+                "End-to-end request latency.",
+                |v| &v.latency,
+            ),
+            (
+                "gateway_time_to_first_token_seconds",
+                "Time to the first streamed token.",
+                |v| &v.ttft,
+            ),
+            (
+                "gateway_output_tokens_per_second",
+                "Output tokens per second of generation.",
+                |v| &v.tokens_per_second,
+            ),
+        ];
+        for (name, help, get) in histograms {
+            let _ = writeln!(output, "# HELP {name} {help}\n# TYPE {name} histogram");
+            for (labels, series) in state.series.iter() {
+                let histogram = get(series);
+                if histogram.count == 0 {
+                    continue;
+                }
+                for (bucket, count) in histogram.buckets.iter().zip(histogram.counts.iter()) {
+                    let le = bucket.to_string();
+                    let _ = writeln!(
+                        output,
+                        "{name}_bucket{{{}}} {count}",
+                        labels.render(Some(("le", &le)))
+                    );
+                }
+                let labels_text = labels.render(None);
+                let _ = writeln!(
+                    output,
+                    "{name}_bucket{{{}}} {}",
+                    labels.render(Some(("le", "+Inf"))),
+                    histogram.count
+                );
+                let _ = writeln!(output, "{name}_sum{{{labels_text}}} {}", histogram.sum);
+                let _ = writeln!(output, "{name}_count{{{labels_text}}} {}", histogram.count);
+            }
+        }
+
+        let name = "gateway_in_flight_requests";
+        let _ = writeln!(
+            output,
+            "# HELP {name} Requests currently being served.\n# TYPE {name} gauge\n{name} {}",
+            self.in_flight.load(Ordering::Relaxed)
+        );
+        output
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_metrics() {
+        let metrics = Metrics::default();
+        metrics.start();
+        metrics.start();
+        metrics.observe(&TraceRecord {
+            model: "gpt-4o".into(),
+            resolved_model: Some("openai:gpt-4o".into()),
+            client: Some("openai".into()),
+            status: 200,
+            input_tokens: Some(12),
+            output_tokens: Some(40),
+            latency_ms: 1500,
+            ttft_ms: Some(300),
+            ..Default::default()
+        });
+        let output = metrics.render();
+        let labels = r#"client="openai",model="openai:gpt-4o",status="200""#;
+        assert!(output.contains(&format!("gateway_requests_total{{{labels}}} 1")));
+        assert!(output.contains(&format!("gateway_output_tokens_total{{{labels}}} 40")));
+        assert!(output.contains(&format!(
+            r#"gateway_request_duration_seconds_bucket{{{labels},le="2.5"}} 1"#
+        )));
+        assert!(output.contains(&format!(
+            r#"gateway_time_to_first_token_seconds_bucket{{{labels},le="0.25"}} 0"#
+        )));
+        assert!(output.contains("gateway_in_flight_requests 1"));
+
+        // An unresolved model name stays out of the labels
+        metrics.observe(&TraceRecord {
+            model: "made-up-1234".into(),
+            status: 400,
+            ..Default::default()
+        });
+        let output = metrics.render();
+        assert!(
+            output.contains(r#"gateway_requests_total{client="",model="unknown",status="400"} 1"#)
+        );
+        assert!(!output.contains("made-up-1234"));
+    }
+}
@@ -0,0 +1,379 @@
+mod jsonl;
+mod metrics;
+mod otlp;
+mod run;
+mod store;
+
+pub use self::metrics::Metrics;
+pub use self::run::*;
+pub use self::store::{TracePage, TraceQuery};
+
+use self::jsonl::JsonlWriter;
+use self::otlp::{OtlpConfig, OtlpExporter};
+use self::store::TraceStore;
+
+use crate::client::{ChatCompletionsOutput, Model, UpstreamError};
+use crate::config::Config;
+use crate::function::ToolCall;
+use crate::utils::{get_env_name, random_hex, redact_secrets, sha256};
+
+use anyhow::{bail, Result};
+use indexmap::IndexMap;
+use parking_lot::Mutex;
+use reqwest::RequestBuilder;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{cell::RefCell, env, future::Future, path::PathBuf, sync::Arc};
+
+const TRACE_FILE_NAME: &str = "requests.jsonl";
+const TRACE_STORE_FILE_NAME: &str = "traces.db";
+
+tokio::task_local! {
+    static UPSTREAM_REQUEST: RefCell<Option<Value>>;
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TraceConfig {
+    pub enabled: bool,
+    pub file: Option<String>,
+    pub store_file: Option<String>,
+    pub max_file_size: u64,
+    pub max_file_age: u64,
+    pub max_files: usize,
+    pub max_store_age: u64,
+    pub max_store_rows: usize,
+    pub omit_bodies: bool,
+    pub otlp: OtlpConfig,
+}
+
+impl Default for TraceConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            file: None,
+            store_file: None,
+            max_file_size: 10 * 1024 * 1024,
+            max_file_age: 24 * 60 * 60,
+            max_files: 10,
+            max_store_age: 30 * 24 * 60 * 60,
+            max_store_rows: 100_000,
+            omit_bodies: false,
+            otlp: Default::default(),
+        }
+    }
+}
+
+impl TraceConfig {
+    pub fn trace_file(&self) -> Result<PathBuf> {
+        if let Ok(value) = env::var(get_env_name("trace_file")) {
+            return Ok(PathBuf::from(value));
+        }
+        match &self.file {
+            Some(file) => Ok(PathBuf::from(file)),
+            None => Config::local_path(TRACE_FILE_NAME),
+        }
+    }
+
+    pub fn store_file(&self) -> Result<PathBuf> {
+        if let Ok(value) = env::var(get_env_name("trace_store_file")) {
+            return Ok(PathBuf::from(value));
+        }
+        match &self.store_file {
+            Some(file) => Ok(PathBuf::from(file)),
+            None => Config::local_path(TRACE_STORE_FILE_NAME),
+        }
+    }
+}
+
+/// One gateway call, as written to the trace file.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TraceRecord {
+    pub id: String,
+    pub timestamp: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub caller_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trace_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub span_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_span_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub agent_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub step: Option<u64>,
+    pub model: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resolved_model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client: Option<String>,
+    pub stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upstream_request: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_text: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub input_tokens: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output_tokens: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cost: Option<f64>,
+    pub latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ttft_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finish_reason: Option<String>,
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_class: Option<String>,
+    /// Never set yet: the gateway has no response cache.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub cache_hit: bool,
+    /// Model calls made again, such as the re-ask after rejected tool calls.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub retries: u32,
+    /// Never set yet: the gateway has no fallback to another model.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub fallbacks: u32,
+    /// What a context strategy trimmed to fit `max_input_tokens`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context_trimmed: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub spans: Vec<TraceSpan>,
+}
+
+/// A step inside a call: a retrieval, a context fit, a tool check or execution, or a chat step.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TraceSpan {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub span_id: Option<String>,
+    pub timestamp: String,
+    pub latency_ms: u64,
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub attributes: IndexMap<String, Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl TraceRecord {
+    pub fn new(id: &str, caller_key: Option<&str>) -> Self {
+        Self {
+            id: id.to_string(),
+            timestamp: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            caller_key: caller_key.map(hash_caller_key),
+            status: 200,
+            ..Default::default()
+        }
+    }
+
+    /// Whether the record was already moved out with `std::mem::take`, to a stream task or to
+    /// the tracer, so it must not be recorded again.
+    pub fn is_taken(&self) -> bool {
+        self.id.is_empty()
+    }
+
+    pub fn set_model(&mut self, model: &Model) {
+        self.resolved_model = Some(model.id());
+        self.client = Some(model.client_name().to_string());
+    }
+
+    pub fn set_output(&mut self, model: &Model, output: &ChatCompletionsOutput) {
+        self.response_text = Some(output.text.clone());
+        self.tool_calls = output.tool_calls.clone();
+        self.input_tokens = output.input_tokens;
+        self.output_tokens = output.output_tokens;
+        self.cost = model.cost(output.input_tokens, output.output_tokens);
+        self.finish_reason = Some(finish_reason(&output.tool_calls).into());
+    }
+
+    pub fn set_error(&mut self, status: u16, err: &anyhow::Error) {
+        self.status = status;
+        self.error = Some(redact_secrets(&format!("{err:#}")).into_owned());
+        self.error_class = Some(classify_error(err).into());
+    }
+
+    fn strip_bodies(&mut self) {
+        self.request = None;
+        self.upstream_request = None;
+        self.response_text = None;
+        for call in self.tool_calls.iter_mut() {
+            call.arguments = Value::Null;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Tracer {
+    inner: Option<Arc<TracerInner>>,
+    metrics: Arc<Metrics>,
+    exporter: Option<OtlpExporter>,
+}
+
+#[derive(Debug)]
+struct TracerInner {
+    writer: Mutex<JsonlWriter>,
+    store: TraceStore,
+    omit_bodies: bool,
+}
+
+impl Tracer {
+    pub fn init(config: &TraceConfig) -> Result<Self> {
+        let exporter = OtlpExporter::init(&config.otlp);
+        if !config.enabled {
+            return Ok(Self {
+                exporter,
+                ..Default::default()
+            });
+        }
+        let path = config.trace_file()?;
+        let writer = JsonlWriter::new(&path, config)?;
+        info!("Trace file: {}", path.display());
+        let store_path = config.store_file()?;
+        let store = TraceStore::open(&store_path, config)?;
+        info!("Trace store: {}", store_path.display());
+        Ok(Self {
+            inner: Some(Arc::new(TracerInner {
+                writer: Mutex::new(writer),
+                store,
+                omit_bodies: config.omit_bodies,
+            })),
+            metrics: Default::default(),
+            exporter,
+        })
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Call once per request before its record is built; `record` closes it.
+    pub fn start(&self) {
+        self.metrics.start();
+    }
+
+    pub fn record(&self, mut record: TraceRecord) {
+        self.metrics.observe(&record);
+        if let Some(exporter) = &self.exporter {
+            exporter.export(&record);
+        }
+        let Some(inner) = &self.inner else {
+            return;
+        };
+        if inner.omit_bodies {
+            record.strip_bodies();
+        }
+        let ret = serde_json::to_string(&record)
+            .map_err(anyhow::Error::from)
+            .and_then(|line| inner.writer.lock().write_line(&line))
+            .and_then(|_| inner.store.insert(&record));
+        if let Err(err) = ret {
+            warn!("Failed to record trace {}: {err}", record.id);
+        }
+    }
+
+    pub fn list(&self, query: &TraceQuery) -> Result<TracePage> {
+        self.store()?.list(query)
+    }
+
+    pub fn get(&self, id: &str) -> Result<Option<TraceRecord>> {
+        self.store()?.get(id)
+    }
+
+    /// Every recorded call of a run.
+    pub fn find_run(&self, trace_id: &str) -> Result<Vec<TraceRecord>> {
+        self.store()?.find_run(trace_id)
+    }
+
+    fn store(&self) -> Result<&TraceStore> {
+        match &self.inner {
+            Some(inner) => Ok(&inner.store),
+            None => bail!("Tracing is disabled"),
+        }
+    }
+}
+
+/// Runs `f` with upstream body capture enabled and returns the last captured body.
+pub async fn capture_upstream<F: Future>(f: F) -> (F::Output, Option<Value>) {
+    UPSTREAM_REQUEST
+        .scope(RefCell::new(None), async move {
+            let output = f.await;
+            let body = UPSTREAM_REQUEST.with(|v| v.borrow_mut().take());
+            (output, body)
+        })
+        .await
+}
+
+/// Remembers the JSON body of a request about to be sent upstream, if a capture is active.
+pub fn capture_upstream_request(builder: &RequestBuilder) {
+    let _ = UPSTREAM_REQUEST.try_with(|cell| {
+        let body = builder
+            .try_clone()
+            .and_then(|v| v.build().ok())
+            .and_then(|req| {
+                req.body()
+                    .and_then(|v| v.as_bytes())
+                    .and_then(|v| serde_json::from_slice::<Value>(v).ok())
+            });
+        if body.is_some() {
+            *cell.borrow_mut() = body;
+        }
+    });
+}
+
+pub fn generate_request_id() -> String {
+    format!("req-{}", random_hex(12))
+}
+
+pub fn finish_reason(tool_calls: &[ToolCall]) -> &'static str {
+    if tool_calls.is_empty() {
+        "stop"
+    } else {
+        "tool_calls"
+    }
+}
+
+/// Coarse error class used for metrics, from the typed error where one is available.
+pub fn classify_error(err: &anyhow::Error) -> &'static str {
+    for cause in err.chain() {
+        if let Some(err) = cause.downcast_ref::<UpstreamError>() {
+            return match err.status {
+                401 | 403 => "auth",
+                408 | 504 => "timeout",
+                429 => "rate_limit",
+                400..=499 => "upstream_client",
+                _ => "upstream_server",
+            };
+        }
+        if let Some(err) = cause.downcast_ref::<reqwest::Error>() {
+            return if err.is_timeout() {
+                "timeout"
+            } else if err.is_connect() {
+                "connection"
+            } else {
+                "network"
+            };
+        }
+    }
+    "invalid_request"
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+fn is_zero(value: &u32) -> bool {
+    *value == 0
+}
+
+/// Caller keys are never stored verbatim, only a short stable digest.
+pub fn hash_caller_key(key: &str) -> String {
+    sha256(key)[..16].to_string()
+}
@@ -0,0 +1,330 @@
+use super::{TraceRecord, TraceSpan};
+
+use crate::utils::{get_env_name, sha256};
+
+use chrono::DateTime;
+use indexmap::IndexMap;
+use reqwest::Client as ReqwestClient;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{env, time::Duration};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+const DEFAULT_SERVICE_NAME: &str = "agent-panel";
+const MAX_BATCH_SIZE: usize = 128;
+const EXPORT_TIMEOUT: u64 = 10;
+
+const SPAN_KIND_INTERNAL: u64 = 1;
+const SPAN_KIND_CLIENT: u64 = 3;
+const STATUS_CODE_OK: u64 = 1;
+const STATUS_CODE_ERROR: u64 = 2;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct OtlpConfig {
+    pub endpoint: Option<String>,
+    pub headers: IndexMap<String, String>,
+    pub service_name: Option<String>,
+}
+
+impl OtlpConfig {
+    /// Standard OTel env vars take precedence, then the gateway's own.
+    pub fn endpoint(&self) -> Option<String> {
+        let endpoint = env::var("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT")
+            .ok()
+            .or_else(|| env::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok())
+            .or_else(|| env::var(get_env_name("otlp_endpoint")).ok())
+            .or_else(|| self.endpoint.clone())
+            .filter(|v| !v.is_empty())?;
+        let endpoint = endpoint.trim_end_matches('/');
+        if endpoint.ends_with("/v1/traces") {
+            Some(endpoint.to_string())
+        } else {
+            Some(format!("{endpoint}/v1/traces"))
+        }
+    }
+}
+
+/// Ships one span per record to an OTLP/HTTP collector, in the background.
+#[derive(Debug, Clone)]
+pub struct OtlpExporter {
+    tx: UnboundedSender<TraceRecord>,
+}
+
+impl OtlpExporter {
+    pub fn init(config: &OtlpConfig) -> Option<Self> {
+        let endpoint = config.endpoint()?;
+        let service_name = config
+            .service_name
+            .clone()
+            .unwrap_or_else(|| DEFAULT_SERVICE_NAME.into());
+        let (tx, rx) = unbounded_channel();
+        info!("OTLP endpoint: {endpoint}");
+        tokio::spawn(export_loop(
+            rx,
+            endpoint,
+            config.headers.clone(),
+            service_name,
+        ));
+        Some(Self { tx })
+    }
+
+    pub fn export(&self, record: &TraceRecord) {
+        let _ = self.tx.send(record.clone());
+    }
+}
+
+async fn export_loop(
+    mut rx: UnboundedReceiver<TraceRecord>,
+    endpoint: String,
+    headers: IndexMap<String, String>,
+    service_name: String,
+) {
+    let client = ReqwestClient::builder()
+        .timeout(Duration::from_secs(EXPORT_TIMEOUT))
+        .build()
+        .unwrap_or_default();
+    while let Some(record) = rx.recv().await {
+        let mut records = vec![record];
+        while records.len() < MAX_BATCH_SIZE {
+            match rx.try_recv() {
+                Ok(record) => records.push(record),
+                Err(_) => break,
+            }
+        }
+        let body = build_export_request(&service_name, &records);
+        let mut builder = client.post(&endpoint).json(&body);
+        for (key, value) in &headers {
+            builder = builder.header(key, value);
+        }
+        match builder.send().await {
+            Ok(res) if !res.status().is_success() => {
+                warn!("Failed to export {} spans: {}", records.len(), res.status())
+            }
+            Err(err) => warn!("Failed to export {} spans: {err}", records.len()),
+            _ => debug!("Exported {} spans", records.len()),
+        }
+    }
+}
+
+fn build_export_request(service_name: &str, records: &[TraceRecord]) -> Value {
+    let spans: Vec<Value> = records.iter().flat_map(record_spans).collect();
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": attributes([("service.name", json!(service_name))]),
+            },
+            "scopeSpans": [{
+                "scope": {
+                    "name": env!("CARGO_CRATE_NAME"),
+                    "version": env!("CARGO_PKG_VERSION"),
+                },
+                "spans": spans,
+            }],
+        }],
+    })
+}
+
+/// The request span, followed by a child span per recorded step, including a `retry` span for
+/// each re-ask of the model. A fallback would be recorded the same way; the gateway has none yet.
+fn record_spans(record: &TraceRecord) -> Vec<Value> {
+    let trace_id = otlp_trace_id(record.trace_id.as_deref().unwrap_or(&record.id));
+    let span_id = record
+        .span_id
+        .clone()
+        .filter(|v| is_hex(v, 16))
+        .unwrap_or_else(|| sha256(&record.id)[..16].to_string());
+    let parent_span_id = record
+        .parent_span_id
+        .clone()
+        .filter(|v| is_hex(v, 16))
+        .unwrap_or_default();
+    let start = unix_nanos(&record.timestamp);
+    let end = start + record.latency_ms * 1_000_000;
+
+    let request_model = record.model.clone();
+    let response_model = record
+        .resolved_model
+        .as_deref()
+        .and_then(|v| v.split_once(':').map(|(_, name)| name.to_string()))
+        .unwrap_or_else(|| request_model.clone());
+    let mut attrs = vec![
+        ("gen_ai.operation.name", json!("chat")),
+        (
+            "gen_ai.system",
+            json!(gen_ai_system(record.client.as_deref())),
+        ),
+        ("gen_ai.request.model", json!(request_model)),
+        ("gen_ai.response.model", json!(response_model)),
+        ("gen_ai.response.id", json!(record.id)),
+        ("http.response.status_code", json!(record.status)),
+        ("gateway.stream", json!(record.stream)),
+    ];
+    let optional = [
+        (
+            "gen_ai.usage.input_tokens",
+            record.input_tokens.map(|v| json!(v)),
+        ),
+        (
+            "gen_ai.usage.output_tokens",
+            record.output_tokens.map(|v| json!(v)),
+        ),
+        (
+            "gen_ai.response.finish_reasons",
+            record.finish_reason.as_ref().map(|v| json!([v])),
+        ),
+        ("gateway.client", record.client.as_ref().map(|v| json!(v))),
+        ("gateway.cost_usd", record.cost.map(|v| json!(v))),
+        ("gateway.ttft_ms", record.ttft_ms.map(|v| json!(v))),
+        (
+            "gateway.agent_name",
+            record.agent_name.as_ref().map(|v| json!(v)),
+        ),
+        ("gateway.step", record.step.map(|v| json!(v))),
+        (
+            "gateway.error_class",
+            record.error_class.as_ref().map(|v| json!(v)),
+        ),
+    ];
+    attrs.extend(optional.into_iter().filter_map(|(k, v)| v.map(|v| (k, v))));
+
+    let mut spans = vec![json!({
+        "traceId": trace_id,
+        "spanId": span_id,
+        "parentSpanId": parent_span_id,
+        "name": format!("chat {request_model}"),
+        "kind": SPAN_KIND_CLIENT,
+        "startTimeUnixNano": start.to_string(),
+        "endTimeUnixNano": end.to_string(),
+        "attributes": attributes(attrs),
+        "status": status(record.error.as_deref()),
+    })];
+    spans.extend(
+        record
+            .spans
+            .iter()
+            .map(|child| child_span(child, &trace_id, &span_id)),
+    );
+    spans
+}
+
+fn child_span(span: &TraceSpan, trace_id: &str, parent_span_id: &str) -> Value {
+    let start = unix_nanos(&span.timestamp);
+    let end = start + span.latency_ms * 1_000_000;
+    let span_id = span
+        .span_id
+        .clone()
+        .filter(|v| is_hex(v, 16))
+        .unwrap_or_else(|| {
+            sha256(&format!("{parent_span_id}{}{}", span.name, span.timestamp))[..16].to_string()
+        });
+    json!({
+        "traceId": trace_id,
+        "spanId": span_id,
+        "parentSpanId": parent_span_id,
+        "name": span.name,
+        "kind": SPAN_KIND_INTERNAL,
+        "startTimeUnixNano": start.to_string(),
+        "endTimeUnixNano": end.to_string(),
+        "attributes": attributes(span.attributes.iter().map(|(k, v)| (k.as_str(), v.clone()))),
+        "status": status(span.error.as_deref()),
+    })
+}
+
+fn status(error: Option<&str>) -> Value {
+    match error {
+        Some(message) => json!({ "code": STATUS_CODE_ERROR, "message": message }),
+        None => json!({ "code": STATUS_CODE_OK }),
+    }
+}
+
+fn attributes<'a>(attrs: impl IntoIterator<Item = (&'a str, Value)>) -> Vec<Value> {
+    attrs
+        .into_iter()
+        .map(|(key, value)| json!({ "key": key, "value": any_value(&value) }))
+        .collect()
+}
+
+fn any_value(value: &Value) -> Value {
+    match value {
+        Value::Bool(v) => json!({ "boolValue": v }),
+        Value::Number(v) => match v.as_i64() {
+            Some(v) => json!({ "intValue": v.to_string() }),
+            None => json!({ "doubleValue": v.as_f64() }),
+        },
+        Value::String(v) => json!({ "stringValue": v }),
+        Value::Array(v) => {
+            json!({ "arrayValue": { "values": v.iter().map(any_value).collect::<Vec<_>>() } })
+        }
+        _ => json!({ "stringValue": value.to_string() }),
+    }
+}
+
+/// Run trace ids are W3C-shaped already unless an agent sent its own; those are hashed to fit.
+fn otlp_trace_id(trace_id: &str) -> String {
+    if is_hex(trace_id, 32) {
+        trace_id.to_lowercase()
+    } else {
+        sha256(trace_id)[..32].to_string()
+    }
+}
+
+fn gen_ai_system(client: Option<&str>) -> &str {
+    match client.unwrap_or_default() {
+        "claude" => "anthropic",
+        "bedrock" => "aws.bedrock",
+        "azure-openai" => "az.ai.openai",
+        "gemini" => "gcp.gemini",
+        "vertexai" | "vertexai-claude" => "gcp.vertex_ai",
+        "mistral" => "mistral_ai",
+        "qianwen" => "dashscope",
+        v => v,
+    }
+}
+
+fn unix_nanos(timestamp: &str) -> u64 {
+    DateTime::parse_from_rfc3339(timestamp)
+        .ok()
+        .and_then(|v| v.timestamp_nanos_opt())
+        .unwrap_or_default() as u64
+}
+
+pub(super) fn is_hex(value: &str, len: usize) -> bool {
+    value.len() == len && value.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_spans() {
+        let record = TraceRecord {
+            id: "req-1".into(),
+            timestamp: "2024-06-01T00:00:00.000Z".into(),
+            trace_id: Some("4bf92f3577b34da6a3ce929d0e0e4736".into()),
+            span_id: Some("00f067aa0ba902b7".into()),
+            model: "openai:gpt-4o".into(),
+            resolved_model: Some("openai:gpt-4o".into()),
+            client: Some("openai".into()),
+            input_tokens: Some(12),
+            latency_ms: 250,
+            spans: vec![TraceSpan {
+                name: "execute_tool get_weather".into(),
+                timestamp: "2024-06-01T00:00:00.100Z".into(),
+                latency_ms: 50,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let spans = record_spans(&record);
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[0]["endTimeUnixNano"], "1717200000250000000");
+        assert!(spans[0]["attributes"]
+            .as_array()
+            .unwrap()
+            .contains(&json!({"key": "gen_ai.usage.input_tokens", "value": {"intValue": "12"}})));
+        assert_eq!(spans[1]["parentSpanId"], "00f067aa0ba902b7");
+        assert_eq!(spans[1]["traceId"], spans[0]["traceId"]);
+    }
+}
@@ -0,0 +1,296 @@
+use super::{otlp::is_hex, TraceRecord};
+
+use crate::utils::random_hex;
+
+use http::HeaderMap;
+use indexmap::IndexMap;
+use serde::Serialize;
+use serde_json::Value;
+
+pub const TRACE_ID_HEADER: &str = "x-trace-id";
+pub const SPAN_ID_HEADER: &str = "x-span-id";
+pub const PARENT_SPAN_ID_HEADER: &str = "x-parent-span-id";
+pub const AGENT_NAME_HEADER: &str = "x-agent-name";
+pub const STEP_HEADER: &str = "x-step";
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+
+/// Where a call sits in a multi-agent run.
+#[derive(Debug, Clone, Default)]
+pub struct RunContext {
+    pub trace_id: String,
+    pub span_id: String,
+    pub parent_span_id: Option<String>,
+    pub agent_name: Option<String>,
+    pub step: Option<u64>,
+}
+
+impl RunContext {
+    /// Reads the run headers, falling back to the same keys in the OpenAI `metadata` object,
+    /// then to a W3C `traceparent`.
+    pub fn from_request(headers: &HeaderMap, metadata: &Value) -> Self {
+        let get = |header: &str, key: &str| -> Option<String> {
+            headers
+                .get(header)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.trim().to_string())
+                .or_else(|| match &metadata[key] {
+                    Value::String(v) => Some(v.trim().to_string()),
+                    Value::Number(v) => Some(v.to_string()),
+                    _ => None,
+                })
+                .filter(|v| !v.is_empty())
+        };
+        let traceparent = headers
+            .get(TRACEPARENT_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(parse_traceparent);
+        let (parent_trace_id, parent_span_id) = traceparent.unzip();
+        Self {
+            trace_id: get(TRACE_ID_HEADER, "trace_id")
+                .or(parent_trace_id)
+                .unwrap_or_else(generate_trace_id),
+            span_id: generate_span_id(),
+            parent_span_id: get(PARENT_SPAN_ID_HEADER, "parent_span_id").or(parent_span_id),
+            agent_name: get(AGENT_NAME_HEADER, "agent_name"),
+            step: get(STEP_HEADER, "step").and_then(|v| v.parse().ok()),
+        }
+    }
+
+    pub fn apply(&self, record: &mut TraceRecord) {
+        record.trace_id = Some(self.trace_id.clone());
+        record.span_id = Some(self.span_id.clone());
+        record.parent_span_id = self.parent_span_id.clone();
+        record.agent_name = self.agent_name.clone();
+        record.step = self.step;
+    }
+}
+
+/// `version-traceid-parentid-flags`, as in https://www.w3.org/TR/trace-context/#traceparent-header
+fn parse_traceparent(value: &str) -> Option<(String, String)> {
+    let parts: Vec<&str> = value.trim().split('-').collect();
+    match parts.as_slice() {
+        [version, trace_id, span_id, flags, ..]
+            if is_hex(version, 2)
+                && *version != "ff"
+                && is_hex(trace_id, 32)
+                && is_hex(span_id, 16)
+                && is_hex(flags, 2)
+                && trace_id.chars().any(|c| c != '0')
+                && span_id.chars().any(|c| c != '0') =>
+        {
+            Some((trace_id.to_lowercase(), span_id.to_lowercase()))
+        }
+        _ => None,
+    }
+}
+
+pub fn generate_trace_id() -> String {
+    random_hex(16)
+}
+
+pub fn generate_span_id() -> String {
+    random_hex(8)
+}
+
+#[derive(Debug, Serialize)]
+pub struct RunTree {
+    pub trace_id: String,
+    pub total: Rollup,
+    pub agents: IndexMap<String, Rollup>,
+    pub critical_path: Vec<String>,
+    pub critical_path_latency_ms: u64,
+    pub roots: Vec<RunNode>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RunNode {
+    pub id: String,
+    pub span_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_span_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub agent_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub step: Option<u64>,
+    pub timestamp: String,
+    pub model: String,
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub input_tokens: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output_tokens: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cost: Option<f64>,
+    pub latency_ms: u64,
+    pub children: Vec<RunNode>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Rollup {
+    pub calls: u64,
+    pub errors: u64,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub cost: f64,
+    pub latency_ms: u64,
+}
+
+impl Rollup {
+    fn add(&mut self, record: &TraceRecord) {
+        self.calls += 1;
+        if record.error.is_some() {
+            self.errors += 1;
+        }
+        self.input_tokens += record.input_tokens.unwrap_or_default();
+        self.output_tokens += record.output_tokens.unwrap_or_default();
+        self.cost += record.cost.unwrap_or_default();
+        self.latency_ms += record.latency_ms;
+    }
+}
+
+/// Rebuilds the call tree of one run from its records.
+///
+/// Calls whose parent span was not recorded by the gateway (e.g. an agent's own span) become roots.
+pub fn build_run_tree(trace_id: &str, mut records: Vec<TraceRecord>) -> RunTree {
+    records.sort_by(|a, b| (a.step, &a.timestamp).cmp(&(b.step, &b.timestamp)));
+
+    let mut total = Rollup::default();
+    let mut agents: IndexMap<String, Rollup> = IndexMap::new();
+    for record in &records {
+        total.add(record);
+        let agent = record.agent_name.clone().unwrap_or_else(|| "-".into());
+        agents.entry(agent).or_default().add(record);
+    }
+
+    let span_ids: Vec<String> = records
+        .iter()
+        .map(|v| v.span_id.clone().unwrap_or_else(|| v.id.clone()))
+        .collect();
+    let mut children: IndexMap<Option<String>, Vec<usize>> = IndexMap::new();
+    for (i, record) in records.iter().enumerate() {
+        let parent = record
+            .parent_span_id
+            .clone()
+            .filter(|parent| span_ids.contains(parent));
+        children.entry(parent).or_default().push(i);
+    }
+
+    let roots = build_nodes(&records, &span_ids, &children, None);
+    let (critical_path_latency_ms, critical_path) = roots
+        .iter()
+        .map(critical_path)
+        .max_by_key(|(latency, _)| *latency)
+        .unwrap_or_default();
+
+    RunTree {
+        trace_id: trace_id.to_string(),
+        total,
+        agents,
+        critical_path,
+        critical_path_latency_ms,
+        roots,
+    }
+}
+
+fn build_nodes(
+    records: &[TraceRecord],
+    span_ids: &[String],
+    children: &IndexMap<Option<String>, Vec<usize>>,
+    parent: Option<String>,
+) -> Vec<RunNode> {
+    let Some(indexes) = children.get(&parent) else {
+        return vec![];
+    };
+    indexes
+        .iter()
+        .map(|&i| {
+            let record = &records[i];
+            let span_id = span_ids[i].clone();
+            RunNode {
+                id: record.id.clone(),
+                span_id: span_id.clone(),
+                parent_span_id: record.parent_span_id.clone(),
+                agent_name: record.agent_name.clone(),
+                step: record.step,
+                timestamp: record.timestamp.clone(),
+                model: record
+                    .resolved_model
+                    .clone()
+                    .unwrap_or_else(|| record.model.clone()),
+                status: record.status,
+                input_tokens: record.input_tokens,
+                output_tokens: record.output_tokens,
+                cost: record.cost,
+                latency_ms: record.latency_ms,
+                children: build_nodes(records, span_ids, children, Some(span_id)),
+            }
+        })
+        .collect()
+}
+
+/// The root-to-leaf chain with the largest summed latency.
+fn critical_path(node: &RunNode) -> (u64, Vec<String>) {
+    let (latency, mut path) = node
+        .children
+        .iter()
+        .map(critical_path)
+        .max_by_key(|(latency, _)| *latency)
+        .unwrap_or_default();
+    path.insert(0, node.span_id.clone());
+    (latency + node.latency_ms, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(span: &str, parent: Option<&str>, agent: &str, latency_ms: u64) -> TraceRecord {
+        TraceRecord {
+            id: format!("req-{span}"),
+            span_id: Some(span.into()),
+            parent_span_id: parent.map(|v| v.into()),
+            agent_name: Some(agent.into()),
+            latency_ms,
+            input_tokens: Some(10),
+            output_tokens: Some(5),
+            cost: Some(0.5),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_traceparent() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            TRACEPARENT_HEADER,
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+                .parse()
+                .unwrap(),
+        );
+        let ctx = RunContext::from_request(&headers, &Value::Null);
+        assert_eq!(ctx.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(ctx.parent_span_id.as_deref(), Some("00f067aa0ba902b7"));
+
+        headers.insert(TRACE_ID_HEADER, "run-1".parse().unwrap());
+        let ctx = RunContext::from_request(&headers, &Value::Null);
+        assert_eq!(ctx.trace_id, "run-1");
+    }
+
+    #[test]
+    fn test_build_run_tree() {
+        let records = vec![
+            record("a", Some("external"), "planner", 100),
+            record("b", Some("a"), "search", 300),
+            record("c", Some("a"), "coder", 200),
+            record("d", Some("c"), "coder", 150),
+        ];
+        let tree = build_run_tree("t1", records);
+        assert_eq!(tree.roots.len(), 1);
+        assert_eq!(tree.roots[0].children.len(), 2);
+        assert_eq!(tree.critical_path, vec!["a", "c", "d"]);
+        assert_eq!(tree.critical_path_latency_ms, 450);
+        assert_eq!(tree.total.calls, 4);
+        assert_eq!(tree.agents["coder"].input_tokens, 20);
+        assert_eq!(tree.agents["coder"].latency_ms, 350);
+    }
+}
@@ -0,0 +1,407 @@
+use super::{hash_caller_key, TraceConfig, TraceRecord};
+
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, SecondsFormat, TimeZone, Utc};
+use parking_lot::Mutex;
+use rusqlite::{params, params_from_iter, types::Value as SqlValue, Connection, OptionalExtension};
+use serde::Serialize;
+use std::{
+    path::Path,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 1000;
+const PRUNE_EVERY_INSERTS: usize = 1000;
+
+const SCHEMA: &str = r#"
+CREATE TABLE IF NOT EXISTS traces (
+    id TEXT PRIMARY KEY,
+    timestamp TEXT NOT NULL,
+    caller_key TEXT,
+    trace_id TEXT,
+    agent_name TEXT,
+    model TEXT NOT NULL,
+    resolved_model TEXT,
+    client TEXT,
+    status INTEGER NOT NULL,
+    latency_ms INTEGER NOT NULL,
+    cost REAL,
+    record TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS traces_timestamp ON traces (timestamp);
+CREATE INDEX IF NOT EXISTS traces_trace_id ON traces (trace_id);
+"#;
+
+/// SQLite file holding every recorded call, so traces can be queried without an external database.
+#[derive(Debug)]
+pub struct TraceStore {
+    conn: Mutex<Connection>,
+    max_age: u64,
+    max_rows: usize,
+    inserts: AtomicUsize,
+}
+
+impl TraceStore {
+    pub fn open(path: &Path, config: &TraceConfig) -> Result<Self> {
+        crate::config::ensure_parent_exists(path)?;
+        let conn = Connection::open(path)
+            .with_context(|| format!("Failed to open trace store {}", path.display()))?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.execute_batch(SCHEMA)
+            .with_context(|| format!("Failed to init trace store {}", path.display()))?;
+        let store = Self {
+            conn: Mutex::new(conn),
+            max_age: config.max_store_age,
+            max_rows: config.max_store_rows,
+            inserts: AtomicUsize::new(0),
+        };
+        store.prune()?;
+        Ok(store)
+    }
+
+    pub fn insert(&self, record: &TraceRecord) -> Result<()> {
+        let data = serde_json::to_string(record)?;
+        self.conn.lock().execute(
+            "INSERT OR REPLACE INTO traces (id, timestamp, caller_key, trace_id, agent_name, model, resolved_model, client, status, latency_ms, cost, record) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+            params![
+                record.id,
+                record.timestamp,
+                record.caller_key,
+                record.trace_id,
+                record.agent_name,
+                record.model,
+                record.resolved_model,
+                record.client,
+                record.status,
+                record.latency_ms as i64,
+                record.cost,
+                data,
+            ],
+        )?;
+        if self.inserts.fetch_add(1, Ordering::Relaxed) % PRUNE_EVERY_INSERTS
+            == PRUNE_EVERY_INSERTS - 1
+        {
+            self.prune()?;
+        }
+        Ok(())
+    }
+
+    /// Drops calls older than `max_store_age` seconds, then all but the newest `max_store_rows`.
+    pub fn prune(&self) -> Result<()> {
+        let conn = self.conn.lock();
+        if self.max_age > 0 {
+            let cutoff = Utc::now() - chrono::Duration::seconds(self.max_age as i64);
+            conn.execute(
+                "DELETE FROM traces WHERE timestamp < ?1",
+                [cutoff.to_rfc3339_opts(SecondsFormat::Millis, true)],
+            )?;
+        }
+        if self.max_rows > 0 {
+            conn.execute(
+                "DELETE FROM traces WHERE id IN (SELECT id FROM traces ORDER BY timestamp DESC, id DESC LIMIT -1 OFFSET ?1)",
+                [self.max_rows as i64],
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, id: &str) -> Result<Option<TraceRecord>> {
+        let data: Option<String> = self
+            .conn
+            .lock()
+            .query_row("SELECT record FROM traces WHERE id = ?1", [id], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        data.map(|v| serde_json::from_str(&v).map_err(|err| anyhow!("Invalid trace '{id}', {err}")))
+            .transpose()
+    }
+
+    pub fn find_run(&self, trace_id: &str) -> Result<Vec<TraceRecord>> {
+        self.select(
+            "SELECT record FROM traces WHERE trace_id = ?1 ORDER BY timestamp",
+            vec![SqlValue::Text(trace_id.to_string())],
+        )
+    }
+
+    /// One page of matching calls, newest first, without request/response bodies.
+    pub fn list(&self, query: &TraceQuery) -> Result<TracePage> {
+        let mut conditions = vec![];
+        let mut values = vec![];
+        let mut push = |condition: &str, args: Vec<SqlValue>| {
+            conditions.push(condition.to_string());
+            values.extend(args);
+        };
+        if let Some(since) = &query.since {
+            push("timestamp >= ?", vec![SqlValue::Text(since.clone())]);
+        }
+        if let Some(until) = &query.until {
+            push("timestamp < ?", vec![SqlValue::Text(until.clone())]);
+        }
+        if let Some(model) = &query.model {
+            push(
+                "(model = ? OR resolved_model = ?)",
+                vec![SqlValue::Text(model.clone()), SqlValue::Text(model.clone())],
+            );
+        }
+        if let Some(client) = &query.client {
+            push("client = ?", vec![SqlValue::Text(client.clone())]);
+        }
+        if let Some(agent) = &query.agent {
+            push("agent_name = ?", vec![SqlValue::Text(agent.clone())]);
+        }
+        if let Some(key) = &query.key {
+            // Accepts either the raw key or the digest shown in records.
+            push(
+                "caller_key IN (?, ?)",
+                vec![
+                    SqlValue::Text(key.clone()),
+                    SqlValue::Text(hash_caller_key(key)),
+                ],
+            );
+        }
+        if let Some(status) = query.status {
+            push("status = ?", vec![SqlValue::Integer(status as i64)]);
+        }
+        if let Some(min_latency_ms) = query.min_latency_ms {
+            push(
+                "latency_ms >= ?",
+                vec![SqlValue::Integer(min_latency_ms as i64)],
+            );
+        }
+        if let Some(min_cost) = query.min_cost {
+            push("cost >= ?", vec![SqlValue::Real(min_cost)]);
+        }
+        if let Some(q) = &query.q {
+            let pattern = format!(
+                "%{}%",
+                q.replace('\\', "\\\\")
+                    .replace('%', "\\%")
+                    .replace('_', "\\_")
+            );
+            push("record LIKE ? ESCAPE '\\'", vec![SqlValue::Text(pattern)]);
+        }
+
+        let mut sql = "SELECT record FROM traces".to_string();
+        if !conditions.is_empty() {
+            sql.push_str(" WHERE ");
+            sql.push_str(&conditions.join(" AND "));
+        }
+        sql.push_str(" ORDER BY timestamp DESC, id DESC LIMIT ? OFFSET ?");
+        values.push(SqlValue::Integer(query.limit as i64 + 1));
+        values.push(SqlValue::Integer(query.offset as i64));
+
+        let mut data = self.select(&sql, values)?;
+        let has_more = data.len() > query.limit;
+        data.truncate(query.limit);
+        for record in data.iter_mut() {
+            record.strip_bodies();
+        }
+        let next_offset = if has_more {
+            Some(query.offset + data.len())
+        } else {
+            None
+        };
+        Ok(TracePage {
+            object: "list",
+            data,
+            has_more,
+            next_offset,
+        })
+    }
+
+    fn select(&self, sql: &str, values: Vec<SqlValue>) -> Result<Vec<TraceRecord>> {
+        let conn = self.conn.lock();
+        let mut stmt = conn.prepare(sql)?;
+        let rows = stmt.query_map(params_from_iter(values), |row| row.get::<_, String>(0))?;
+        let mut records = vec![];
+        for row in rows {
+            if let Ok(record) = serde_json::from_str(&row?) {
+                records.push(record);
+            }
+        }
+        Ok(records)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceQuery {
+    pub since: Option<String>,
+    pub until: Option<String>,
+    pub model: Option<String>,
+    pub client: Option<String>,
+    pub agent: Option<String>,
+    pub key: Option<String>,
+    pub status: Option<u16>,
+    pub min_latency_ms: Option<u64>,
+    pub min_cost: Option<f64>,
+    pub q: Option<String>,
+    pub limit: usize,
+    pub offset: usize,
+}
+
+impl Default for TraceQuery {
+    fn default() -> Self {
+        Self {
+            since: None,
+            until: None,
+            model: None,
+            client: None,
+            agent: None,
+            key: None,
+            status: None,
+            min_latency_ms: None,
+            min_cost: None,
+            q: None,
+            limit: DEFAULT_PAGE_SIZE,
+            offset: 0,
+        }
+    }
+}
+
+impl TraceQuery {
+    /// Parses the query string of `GET /v1/traces`.
+    pub fn from_query_str(query: &str) -> Result<Self> {
+        let mut output = Self::default();
+        for pair in query.split('&').filter(|v| !v.is_empty()) {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let value = urlencoding::decode(&value.replace('+', " "))
+                .map_err(|_| anyhow!("Invalid query parameter '{name}'"))?
+                .into_owned();
+            if value.is_empty() {
+                continue;
+            }
+            let invalid = || anyhow!("Invalid query parameter '{name}'");
+            match name {
+                "since" => output.since = Some(parse_time(&value).ok_or_else(invalid)?),
+                "until" => output.until = Some(parse_time(&value).ok_or_else(invalid)?),
+                "model" => output.model = Some(value),
+                "client" => output.client = Some(value),
+                "agent" => output.agent = Some(value),
+                "key" => output.key = Some(value),
+                "status" => output.status = Some(value.parse().map_err(|_| invalid())?),
+                "min_latency_ms" => {
+                    output.min_latency_ms = Some(value.parse().map_err(|_| invalid())?)
+                }
+                "min_cost" => output.min_cost = Some(value.parse().map_err(|_| invalid())?),
+                "q" => output.q = Some(value),
+                "limit" => {
+                    let limit: usize = value.parse().map_err(|_| invalid())?;
+                    if limit == 0 || limit > MAX_PAGE_SIZE {
+                        bail!("Query parameter 'limit' must be between 1 and {MAX_PAGE_SIZE}");
+                    }
+                    output.limit = limit;
+                }
+                "offset" => output.offset = value.parse().map_err(|_| invalid())?,
+                _ => bail!("Unknown query parameter '{name}'"),
+            }
+        }
+        Ok(output)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct TracePage {
+    pub object: &'static str,
+    pub data: Vec<TraceRecord>,
+    pub has_more: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_offset: Option<usize>,
+}
+
+/// Accepts RFC 3339 or unix seconds, normalized to the format records are stored with.
+fn parse_time(value: &str) -> Option<String> {
+    let time = match value.parse::<i64>() {
+        Ok(secs) => Utc.timestamp_opt(secs, 0).single()?,
+        Err(_) => DateTime::parse_from_rfc3339(value)
+            .ok()?
+            .with_timezone(&Utc),
+    };
+    Some(time.to_rfc3339_opts(SecondsFormat::Millis, true))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(id: &str, timestamp: &str, model: &str, latency_ms: u64) -> TraceRecord {
+        TraceRecord {
+            id: id.into(),
+            timestamp: timestamp.into(),
+            model: model.into(),
+            latency_ms,
+            status: 200,
+            response_text: Some(format!("answer from {model}")),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_list_traces() {
+        let dir = std::env::temp_dir().join(format!("agent-panel-store-{}", rand::random::<u32>()));
+        let config = TraceConfig {
+            max_store_age: 0,
+            ..Default::default()
+        };
+        let store = TraceStore::open(&dir.join("traces.db"), &config).unwrap();
+        store
+            .insert(&record(
+                "req-1",
+                "2024-06-01T00:00:00.000Z",
+                "openai:gpt-4o",
+                100,
+            ))
+            .unwrap();
+        store
+            .insert(&record(
+                "req-2",
+                "2024-06-02T00:00:00.000Z",
+                "claude:claude-3-haiku",
+                900,
+            ))
+            .unwrap();
+        store
+            .insert(&record(
+                "req-3",
+                "2024-06-03T00:00:00.000Z",
+                "openai:gpt-4o",
+                500,
+            ))
+            .unwrap();
+
+        let query = TraceQuery::from_query_str("model=openai%3Agpt-4o&limit=1").unwrap();
+        let page = store.list(&query).unwrap();
+        assert_eq!(page.data[0].id, "req-3");
+        assert!(page.data[0].response_text.is_none());
+        assert_eq!(page.next_offset, Some(1));
+
+        let query =
+            TraceQuery::from_query_str("since=2024-06-02T00:00:00Z&min_latency_ms=600").unwrap();
+        let page = store.list(&query).unwrap();
+        assert_eq!(page.data.len(), 1);
+        assert_eq!(page.data[0].id, "req-2");
+        assert!(!page.has_more);
+
+        let query = TraceQuery::from_query_str("q=from+claude").unwrap();
+        assert_eq!(store.list(&query).unwrap().data.len(), 1);
+
+        let full = store.get("req-1").unwrap().unwrap();
+        assert!(full.response_text.is_some());
+
+        let store = TraceStore::open(
+            &dir.join("traces.db"),
+            &TraceConfig {
+                max_store_age: 0,
+                max_store_rows: 2,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(store.get("req-1").unwrap().is_none());
+        assert!(store.get("req-3").unwrap().is_some());
+        let store = TraceStore::open(&dir.join("traces.db"), &TraceConfig::default()).unwrap();
+        assert!(store.get("req-3").unwrap().is_none());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
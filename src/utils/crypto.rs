@@ -1,5 +1,14 @@
-use base64::{engine::general_purpose::STANDARD, Engine};
+use anyhow::{anyhow, Result};
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
 use hmac::{Hmac, Mac};
+use ring::{
+    rand::SystemRandom,
+    signature::{RsaKeyPair, RSA_PKCS1_SHA256},
+};
+use serde_json::Value;
 use sha2::{Digest, Sha256};
 
 pub fn sha256(input: &str) -> String {
@@ -20,6 +29,11 @@ pub fn hex_encode(bytes: &[u8]) -> String {
         .fold(String::new(), |acc, b| acc + &format!("{:02x}", b))
 }
 
+pub fn random_hex(len: usize) -> String {
+    let bytes: Vec<u8> = (0..len).map(|_| rand::random::<u8>()).collect();
+    hex_encode(&bytes)
+}
+
 pub fn encode_uri(uri: &str) -> String {
     uri.split('/')
         .map(|v| urlencoding::encode(v))
@@ -33,3 +47,39 @@ pub fn base64_encode<T: AsRef<[u8]>>(input: T) -> String {
 pub fn base64_decode<T: AsRef<[u8]>>(input: T) -> Result<Vec<u8>, base64::DecodeError> {
     STANDARD.decode(input)
 }
+
+pub fn base64_url_encode<T: AsRef<[u8]>>(input: T) -> String {
+    URL_SAFE_NO_PAD.encode(input)
+}
+
+/// Decodes the first `-----BEGIN {label}-----` block of a PEM document.
+pub fn pem_decode(pem: &str, label: &str) -> Option<Vec<u8>> {
+    let begin = format!("-----BEGIN {label}-----");
+    let end = format!("-----END {label}-----");
+    let data = pem.split_once(&begin)?.1.split_once(&end)?.0;
+    let data: String = data.split_whitespace().collect();
+    base64_decode(data).ok()
+}
+
+/// Builds an RS256 JWT signed with a PEM-encoded PKCS#8 private key.
+pub fn sign_jwt(header: &Value, claims: &Value, private_key: &str) -> Result<String> {
+    let der = pem_decode(private_key, "PRIVATE KEY")
+        .ok_or_else(|| anyhow!("Invalid private key, expect a PKCS#8 PEM"))?;
+    let key_pair =
+        RsaKeyPair::from_pkcs8(&der).map_err(|err| anyhow!("Invalid private key, {err}"))?;
+    let message = format!(
+        "{}.{}",
+        base64_url_encode(header.to_string()),
+        base64_url_encode(claims.to_string())
+    );
+    let mut signature = vec![0; key_pair.public().modulus_len()];
+    key_pair
+        .sign(
+            &RSA_PKCS1_SHA256,
+            &SystemRandom::new(),
+            message.as_bytes(),
+            &mut signature,
+        )
+        .map_err(|_| anyhow!("Failed to sign the JWT"))?;
+    Ok(format!("{message}.{}", base64_url_encode(signature)))
+}
@@ -2,11 +2,13 @@ mod abort_signal;
 mod clipboard;
 mod crypto;
 mod prompt_input;
+mod secret;
 
 pub use self::abort_signal::*;
 pub use self::clipboard::set_text;
 pub use self::crypto::*;
 pub use self::prompt_input::*;
+pub use self::secret::*;
 
 use fancy_regex::Regex;
 use is_terminal::IsTerminal;
@@ -47,24 +49,6 @@ pub fn tokenize(text: &str) -> Vec<&str> {
     }
 }
 
-pub fn estimate_token_length(text: &str) -> usize {
-    let mut token_length: f32 = 0.0;
-
-    for char in text.chars() {
-        if char.is_ascii() {
-            if char.is_ascii_alphabetic() {
-                token_length += 0.25;
-            } else {
-                token_length += 0.5;
-            }
-        } else {
-            token_length += 1.5;
-        }
-    }
-
-    token_length.ceil() as usize
-}
-
 pub fn light_theme_from_colorfgbg(colorfgbg: &str) -> Option<bool> {
     let parts: Vec<_> = colorfgbg.split(';').collect();
     let bg = match parts.len() {
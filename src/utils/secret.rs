@@ -0,0 +1,247 @@
+use anyhow::{anyhow, bail, Context, Result};
+use lazy_static::lazy_static;
+use parking_lot::RwLock;
+use serde_yaml::Value;
+use std::{
+    borrow::Cow,
+    env,
+    io::Read,
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+const REDACTED: &str = "***";
+const MIN_SECRET_LEN: usize = 4;
+/// How long a `cmd:` reference may run.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+lazy_static! {
+    static ref SECRETS: RwLock<Vec<String>> = RwLock::new(vec![]);
+}
+
+/// Interpolates `${ENV}` in every string of the config, and resolves `file:<path>` and
+/// `cmd:<command>` references in its secret fields. Only secret fields are registered for redaction.
+pub fn resolve_secret_refs(value: &mut Value) -> Result<()> {
+    resolve_value(value, None)
+}
+
+/// Whether a config field or header holds a credential.
+pub fn is_secret_key(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    ["key", "secret", "token", "password", "authorization"]
+        .iter()
+        .any(|v| name.ends_with(v))
+}
+
+pub fn register_secret(secret: &str) {
+    let secret = secret.trim();
+    if secret.len() < MIN_SECRET_LEN || SECRETS.read().iter().any(|v| v == secret) {
+        return;
+    }
+    let mut secrets = SECRETS.write();
+    secrets.push(secret.to_string());
+    // Longest first, so a secret containing another is replaced whole
+    secrets.sort_by_key(|v| std::cmp::Reverse(v.len()));
+}
+
+pub fn redact_secrets(text: &str) -> Cow<'_, str> {
+    let secrets = SECRETS.read();
+    let mut text = Cow::Borrowed(text);
+    for secret in secrets.iter() {
+        if text.contains(secret.as_str()) {
+            text = Cow::Owned(text.replace(secret.as_str(), REDACTED));
+        }
+    }
+    text
+}
+
+fn resolve_value(value: &mut Value, key: Option<&str>) -> Result<()> {
+    match value {
+        Value::String(text) => {
+            let secret = key.is_some_and(is_secret_key);
+            let resolved = if secret {
+                resolve_text(text)
+            } else {
+                interpolate_env(text)
+            };
+            let resolved = resolved
+                .with_context(|| format!("Failed to resolve '{}'", key.unwrap_or_default()))?;
+            if secret {
+                register_secret(&resolved);
+            }
+            *text = resolved;
+        }
+        Value::Sequence(items) => {
+            for item in items {
+                resolve_value(item, key)?;
+            }
+        }
+        Value::Mapping(map) => {
+            for (key, item) in map.iter_mut() {
+                resolve_value(item, key.as_str())?;
+            }
+        }
+        Value::Tagged(tagged) => resolve_value(&mut tagged.value, key)?,
+        _ => {}
+    }
+    Ok(())
+}
+
+fn resolve_text(text: &str) -> Result<String> {
+    if let Some(path) = text.strip_prefix("file:").filter(|v| !v.starts_with("//")) {
+        let path = interpolate_env(path.trim())?;
+        let data = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read secret file '{path}'"))?;
+        return Ok(data.trim().to_string());
+    }
+    if let Some(command) = text.strip_prefix("cmd:") {
+        return run_command(command.trim());
+    }
+    interpolate_env(text)
+}
+
+/// Replaces `${VAR}` and `${VAR:-default}`; `$${` stays a literal `${`.
+fn interpolate_env(text: &str) -> Result<String> {
+    let mut output = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            output.push_str(&rest[..start - 1]);
+            output.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        output.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .map(|v| start + v)
+            .ok_or_else(|| anyhow!("Unclosed '${{'"))?;
+        let expr = &rest[start + 2..end];
+        let (name, default) = match expr.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (expr, None),
+        };
+        let value = match (env::var(name), default) {
+            (Ok(value), _) if !value.is_empty() => value,
+            (_, Some(default)) => default.to_string(),
+            (Ok(value), None) => value,
+            (Err(_), None) => bail!("Env '{name}' is not set"),
+        };
+        output.push_str(&value);
+        rest = &rest[end + 1..];
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+fn run_command(command: &str) -> Result<String> {
+    let (shell, arg) = if cfg!(windows) {
+        ("cmd", "/C")
+    } else {
+        ("sh", "-c")
+    };
+    let mut child = Command::new(shell)
+        .arg(arg)
+        .arg(command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run '{command}'"))?;
+    // Drained while waiting, so a full pipe cannot stall the command.
+    let stdout = read_pipe(child.stdout.take());
+    let stderr = read_pipe(child.stderr.take());
+    let deadline = Instant::now() + COMMAND_TIMEOUT;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            bail!(
+                "Failed to run '{command}', timed out after {}s",
+                COMMAND_TIMEOUT.as_secs()
+            );
+        }
+        thread::sleep(Duration::from_millis(20));
+    };
+    let stdout = stdout.join().unwrap_or_default();
+    if !status.success() {
+        let stderr = stderr.join().unwrap_or_default();
+        bail!(
+            "Failed to run '{command}', {status}, {}",
+            String::from_utf8_lossy(&stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&stdout).trim().to_string())
+}
+
+fn read_pipe(pipe: Option<impl Read + Send + 'static>) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut data = vec![];
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut data);
+        }
+        data
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_secret_refs() {
+        env::set_var("SECRET_TEST_BASE", "http://127.0.0.1:8080");
+        env::set_var("SECRET_TEST_KEY", "sk-from-env");
+        let path = env::temp_dir().join("secret_test_token");
+        std::fs::write(&path, "tok-from-file\n").unwrap();
+        let mut value: Value = serde_yaml::from_str(&format!(
+            r#"
+clients:
+  - api_base: ${{SECRET_TEST_BASE}}/v1
+    api_key: ${{SECRET_TEST_KEY}}
+    headers:
+      Authorization: file:{}
+    secret_access_key: cmd:echo pw-from-cmd
+    session_token: $${{literal}} ${{SECRET_TEST_UNSET:-fallback}}
+    extra:
+      proxy: cmd:echo not-run
+"#,
+            path.display()
+        ))
+        .unwrap();
+        resolve_secret_refs(&mut value).unwrap();
+        let client = &value["clients"][0];
+        // Env vars are interpolated everywhere, references only in secret fields
+        assert_eq!(
+            client["api_base"].as_str(),
+            Some("http://127.0.0.1:8080/v1")
+        );
+        assert_eq!(client["extra"]["proxy"].as_str(), Some("cmd:echo not-run"));
+        assert_eq!(
+            client["headers"]["Authorization"].as_str(),
+            Some("tok-from-file")
+        );
+        assert_eq!(client["secret_access_key"].as_str(), Some("pw-from-cmd"));
+        assert_eq!(
+            client["session_token"].as_str(),
+            Some("${literal} fallback")
+        );
+        assert_eq!(
+            redact_secrets(
+                "key=sk-from-env auth=tok-from-file pw=pw-from-cmd base=http://127.0.0.1:8080"
+            ),
+            "key=*** auth=*** pw=*** base=http://127.0.0.1:8080"
+        );
+
+        let mut value: Value = serde_yaml::from_str("api_key: ${SECRET_TEST_MISSING}").unwrap();
+        let err = resolve_secret_refs(&mut value).unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "Failed to resolve 'api_key': Env 'SECRET_TEST_MISSING' is not set"
+        );
+    }
+}